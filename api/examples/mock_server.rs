@@ -0,0 +1,22 @@
+//! Exercises a client against the in-process mock server.
+//!
+//! Run with `cargo run -p ckeylock-api --example mock_server --features testing`.
+
+use ckeylock_api::testing::MockServer;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let server = MockServer::with_password("secret").await?;
+    println!("Mock server listening on {}", server.addr());
+
+    let connection = server.api().connect().await?;
+    connection
+        .set(b"greeting".to_vec(), b"hello".to_vec())
+        .await?;
+    let value = connection.get(b"greeting".to_vec()).await?;
+    println!(
+        "greeting = {:?}",
+        value.map(|value| String::from_utf8_lossy(&value).into_owned())
+    );
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use ckeylock_core::Request;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Client-side cache of `get` results, see [`crate::CKeyLockAPI::with_cache`].
+///
+/// Every mutation sent through the connection bumps an epoch, and a read
+/// only fills the cache if no mutation happened while it was in flight, so a
+/// slow read can't put back a value that a concurrent write replaced.
+pub(crate) struct ResponseCache {
+    ttl: Duration,
+    state: Mutex<CacheState>,
+}
+
+struct CacheState {
+    entries: HashMap<Vec<u8>, (Option<Vec<u8>>, Instant)>,
+    epoch: u64,
+    purged_at: Instant,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                epoch: 0,
+                purged_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// The cached result for `key`: `Some(None)` means the key was missing.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        match state.entries.get(key) {
+            Some((value, expires_at)) if *expires_at > Instant::now() => Some(value.clone()),
+            Some(_) => {
+                state.entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub(crate) fn epoch(&self) -> u64 {
+        self.state.lock().unwrap().epoch
+    }
+
+    /// Caches a read that started at `epoch`, unless a mutation has been sent
+    /// since.
+    pub(crate) fn insert(&self, key: Vec<u8>, value: Option<Vec<u8>>, epoch: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.epoch != epoch {
+            return;
+        }
+        let now = Instant::now();
+        if now.duration_since(state.purged_at) >= self.ttl {
+            state.entries.retain(|_, (_, expires_at)| *expires_at > now);
+            state.purged_at = now;
+        }
+        state.entries.insert(key, (value, now + self.ttl));
+    }
+
+    /// Drops whatever `request` may change.
+    #[cfg(test)]
+    pub(crate) fn invalidate(&self, request: &Request) {
+        if let Some(invalidation) = Invalidation::of(request) {
+            self.apply(&invalidation);
+        }
+    }
+
+    pub(crate) fn apply(&self, invalidation: &Invalidation) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch += 1;
+        match invalidation {
+            Invalidation::Key(key) => {
+                state.entries.remove(key);
+            }
+            Invalidation::Prefix(prefix) => {
+                state.entries.retain(|key, _| !key.starts_with(prefix));
+            }
+            Invalidation::All => state.entries.clear(),
+        }
+    }
+}
+
+/// The entries a mutation may change, taken from the request up front so
+/// the request itself can be sent without keeping a copy around.
+pub(crate) enum Invalidation {
+    Key(Vec<u8>),
+    Prefix(Vec<u8>),
+    All,
+}
+
+impl Invalidation {
+    /// `None` for requests that don't change anything.
+    pub(crate) fn of(request: &Request) -> Option<Self> {
+        if !request.is_mutation() {
+            return None;
+        }
+        Some(match request {
+            Request::DeletePrefix { prefix } => Self::Prefix(prefix.clone()),
+            request => match request.key() {
+                Some(key) => Self::Key(key.to_vec()),
+                None => Self::All,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalidation() {
+        let cache = ResponseCache::new(Duration::from_secs(60));
+        let epoch = cache.epoch();
+        for key in [b"a:1", b"a:2", b"b:1"] {
+            cache.insert(key.to_vec(), Some(b"v".to_vec()), epoch);
+        }
+        cache.invalidate(&Request::Get {
+            key: b"a:1".to_vec(),
+            bypass_cache: false,
+        });
+        assert_eq!(cache.get(b"a:1"), Some(Some(b"v".to_vec())));
+
+        cache.invalidate(&Request::Set {
+            key: b"a:1".to_vec(),
+            value: b"w".to_vec(),
+        });
+        assert_eq!(cache.get(b"a:1"), None);
+        assert!(cache.get(b"a:2").is_some());
+
+        cache.invalidate(&Request::DeletePrefix {
+            prefix: b"a:".to_vec(),
+        });
+        assert_eq!(cache.get(b"a:2"), None);
+        assert!(cache.get(b"b:1").is_some());
+
+        cache.invalidate(&Request::Clear);
+        assert_eq!(cache.get(b"b:1"), None);
+    }
+
+    #[test]
+    fn test_stale_reads_are_not_cached() {
+        let cache = ResponseCache::new(Duration::from_secs(60));
+        let epoch = cache.epoch();
+        cache.invalidate(&Request::Set {
+            key: b"key".to_vec(),
+            value: b"new".to_vec(),
+        });
+        cache.insert(b"key".to_vec(), Some(b"old".to_vec()), epoch);
+        assert_eq!(cache.get(b"key"), None);
+    }
+
+    #[test]
+    fn test_entries_expire() {
+        let cache = ResponseCache::new(Duration::from_millis(10));
+        cache.insert(b"key".to_vec(), None, cache.epoch());
+        assert_eq!(cache.get(b"key"), Some(None));
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get(b"key"), None);
+    }
+}
@@ -0,0 +1,162 @@
+//! How [`TypedConnection`](crate::TypedConnection) turns values into bytes.
+//!
+//! JSON is the default and what other clients are most likely to read.
+//! Bincode (the `bincode` feature) is the most compact, and MessagePack (the
+//! `msgpack` feature) sits in between while staying readable from most
+//! languages. Anything else can implement [`Codec`].
+
+use crate::Error;
+use serde::{Serialize, de::DeserializeOwned};
+use std::marker::PhantomData;
+
+#[allow(clippy::result_large_err)]
+pub trait Codec {
+    /// Identifies the codec in values written through [`Tagged`]. Tags up to
+    /// 15 are reserved for the built-in codecs.
+    const TAG: u8;
+
+    fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, Error>;
+    fn decode<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, Error>;
+}
+
+pub struct Json;
+
+#[allow(clippy::result_large_err)]
+impl Codec for Json {
+    const TAG: u8 = 1;
+
+    fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, Error> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, Error> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+#[cfg(feature = "bincode")]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+#[allow(clippy::result_large_err)]
+impl Codec for Bincode {
+    const TAG: u8 = 2;
+
+    fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, Error> {
+        bincode::serde::encode_to_vec(value, bincode::config::standard())
+            .map_err(|e| Error::Codec(e.to_string()))
+    }
+
+    fn decode<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, Error> {
+        let (value, _) = bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+            .map_err(|e| Error::Codec(e.to_string()))?;
+        Ok(value)
+    }
+}
+
+/// Structs are written as maps with their field names, so other languages
+/// can decode them without knowing the field order.
+#[cfg(feature = "msgpack")]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+#[allow(clippy::result_large_err)]
+impl Codec for MessagePack {
+    const TAG: u8 = 3;
+
+    fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, Error> {
+        rmp_serde::to_vec_named(value).map_err(|e| Error::Codec(e.to_string()))
+    }
+
+    fn decode<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, Error> {
+        rmp_serde::from_slice(bytes).map_err(|e| Error::Codec(e.to_string()))
+    }
+}
+
+/// Prefixes values with the inner codec's [`Codec::TAG`]. Use it when clients
+/// with different codecs share keys: a mismatched value fails to decode with a
+/// clear error instead of decoding as garbage.
+pub struct Tagged<C>(PhantomData<C>);
+
+#[allow(clippy::result_large_err)]
+impl<C: Codec> Codec for Tagged<C> {
+    const TAG: u8 = C::TAG;
+
+    fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![C::TAG];
+        bytes.extend(C::encode(value)?);
+        Ok(bytes)
+    }
+
+    fn decode<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, Error> {
+        match bytes.split_first() {
+            Some((&tag, rest)) if tag == C::TAG => C::decode(rest),
+            Some((&tag, _)) => Err(Error::Codec(format!(
+                "Value is tagged with codec {}, expected {}",
+                tag,
+                C::TAG
+            ))),
+            None => Err(Error::Codec("Tagged value is empty".to_string())),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "bincode", feature = "msgpack"))]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Account {
+        name: String,
+        tokens: Vec<String>,
+        balance: u64,
+    }
+
+    fn account() -> Account {
+        Account {
+            name: "alice".to_string(),
+            tokens: vec!["a".to_string(), "b".to_string()],
+            balance: 42,
+        }
+    }
+
+    fn roundtrip<C: Codec>() -> usize {
+        let bytes = C::encode(&account()).unwrap();
+        assert_eq!(C::decode::<Account>(&bytes).unwrap(), account());
+        bytes.len()
+    }
+
+    #[test]
+    fn test_codecs_roundtrip() {
+        let json = roundtrip::<Json>();
+        let msgpack = roundtrip::<MessagePack>();
+        let bincode = roundtrip::<Bincode>();
+        assert!(json > msgpack, "json {} vs msgpack {}", json, msgpack);
+        assert!(
+            msgpack > bincode,
+            "msgpack {} vs bincode {}",
+            msgpack,
+            bincode
+        );
+        assert_eq!(roundtrip::<Tagged<Bincode>>(), bincode + 1);
+    }
+
+    #[test]
+    fn test_tag_mismatch() {
+        let bytes = Tagged::<Json>::encode(&account()).unwrap();
+        assert_eq!(bytes[0], Json::TAG);
+        assert!(matches!(
+            Tagged::<MessagePack>::decode::<Account>(&bytes),
+            Err(Error::Codec(_))
+        ));
+        assert!(matches!(
+            Tagged::<Json>::decode::<Account>(&[]),
+            Err(Error::Codec(_))
+        ));
+        assert!(matches!(
+            Bincode::decode::<Account>(b"\xff"),
+            Err(Error::Codec(_))
+        ));
+    }
+}
@@ -1,22 +1,65 @@
+use cache::{Invalidation, ResponseCache};
+use std::collections::{BTreeSet, HashMap};
 use std::str::FromStr;
 
-use ckeylock_core::response::ErrorResponse;
+use ckeylock_core::response::{ErrorCode, ErrorResponse, ServerMessage};
 use ckeylock_core::{Request, RequestWrapper, Response};
+use futures_util::Stream;
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 use thiserror::Error;
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore, broadcast, mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
 use tokio_tungstenite::tungstenite::Error as WsError;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest as _;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::{
     MaybeTlsStream, WebSocketStream, connect_async,
-    tungstenite::{ClientRequestBuilder, http::Uri, protocol::Message},
+    tungstenite::{
+        ClientRequestBuilder,
+        http::{StatusCode, Uri},
+        protocol::Message,
+    },
 };
 
+mod cache;
+pub mod codec;
+mod lock;
+mod pipeline;
+mod reader;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod typed;
+
+pub use lock::LockGuard;
+pub use pipeline::Pipeline;
+pub use reader::ValueReader;
+pub use typed::TypedConnection;
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// How many times a request the server turns away busy is sent again before
+/// failing with [`Error::Busy`].
+const BUSY_RETRIES: u32 = 20;
+/// How long to wait before sending a busy request again when the server gave
+/// no hint.
+const DEFAULT_BUSY_RETRY_AFTER: Duration = Duration::from_millis(50);
+
 pub struct CKeyLockAPI {
     bind: String,
     password: Option<String>,
+    max_in_flight: Option<usize>,
+    signing_secret: Option<Vec<u8>>,
+    batch_interval: Option<Duration>,
+    no_delay: Option<bool>,
+    sequence_numbers: bool,
+    store: Option<String>,
+    cache_ttl: Option<Duration>,
+    binary_values: Option<usize>,
 }
 
 impl CKeyLockAPI {
@@ -24,70 +67,433 @@ impl CKeyLockAPI {
         CKeyLockAPI {
             bind: bind.to_owned(),
             password: password.map(|p| p.to_owned()),
+            max_in_flight: None,
+            signing_secret: None,
+            batch_interval: None,
+            no_delay: Some(true),
+            sequence_numbers: false,
+            store: None,
+            cache_ttl: None,
+            binary_values: None,
         }
     }
 
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = Some(max_in_flight);
+        self
+    }
+
+    /// Signs every request and verifies every response with this shared secret.
+    /// Must match the server's `signing_secret`.
+    pub fn with_signing_secret(mut self, secret: impl Into<Vec<u8>>) -> Self {
+        self.signing_secret = Some(secret.into());
+        self
+    }
+
+    /// Queues request frames instead of writing each one immediately, flushing
+    /// them every `interval` or on [`CKeyLockConnection::flush`]. Fewer, larger
+    /// writes raise throughput under many concurrent requests, at the cost of
+    /// up to `interval` of added latency per request.
+    pub fn with_batching(mut self, interval: Duration) -> Self {
+        self.batch_interval = Some(interval);
+        self
+    }
+
+    /// Sets `TCP_NODELAY` on the socket, on by default. Disabling Nagle's
+    /// algorithm keeps small request frames from waiting for the previous
+    /// response's ACK; turning it off lets the kernel coalesce them.
+    pub fn with_no_delay(mut self, no_delay: bool) -> Self {
+        self.no_delay = Some(no_delay);
+        self
+    }
+
+    /// Numbers every request on the connection and checks that the server
+    /// echoes the number back. A diagnostic for flaky transports, see
+    /// [`CKeyLockConnection::sequence_gaps`].
+    pub fn with_sequence_numbers(mut self) -> Self {
+        self.sequence_numbers = true;
+        self
+    }
+
+    /// Caches `get` results locally for `ttl`, so repeated reads of the same
+    /// key skip the network. Writes sent through the connection drop the
+    /// entries they touch, but changes made by other clients can go unseen
+    /// for up to `ttl`.
+    pub fn with_cache(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Sends values of at least `min_len` bytes in a binary frame after their
+    /// request instead of inside its JSON, where every byte costs up to four
+    /// characters. See [`ckeylock_core::binary`].
+    pub fn with_binary_values(mut self, min_len: usize) -> Self {
+        self.binary_values = Some(min_len);
+        self
+    }
+
+    /// Works on the server's store named `store` instead of the default one.
+    pub fn with_store(mut self, store: &str) -> Self {
+        self.store = Some(store.to_owned());
+        self
+    }
+
     pub async fn connect(&self) -> Result<CKeyLockConnection, Error> {
         let url = format!("ws://{}", self.bind);
-        let request = match &self.password {
-            Some(password) => ClientRequestBuilder::new(Uri::from_str(&url)?)
-                .with_header("Authorization", password)
-                .into_client_request()
-                .map_err(|e| Error::Custom(format!("Failed to build client request: {}", e)))?,
-            None => url
-                .into_client_request()
-                .map_err(|e| Error::Custom(format!("Failed to build client request: {}", e)))?,
-        };
-        let (ws_stream, _) = connect_async(request)
-            .await
-            .map_err(|e| Error::Custom(format!("Failed to connect to WebSocket: {}", e)))?;
+        let mut builder = ClientRequestBuilder::new(Uri::from_str(&url)?);
+        if let Some(password) = &self.password {
+            builder = builder.with_header("Authorization", password);
+        }
+        if let Some(store) = &self.store {
+            builder = builder.with_header(ckeylock_core::STORE_HEADER, store);
+        }
+        let request = builder
+            .into_client_request()
+            .map_err(|e| Error::Custom(format!("Failed to build client request: {}", e)))?;
+        let (ws_stream, response) = connect_async(request).await.map_err(|e| match e {
+            WsError::Http(response) if response.status() == StatusCode::UNAUTHORIZED => {
+                Error::Unauthorized
+            }
+            WsError::Http(response) if response.status() == StatusCode::NOT_FOUND => {
+                Error::NotFound(format!(
+                    "Unknown store {:?}",
+                    self.store.as_deref().unwrap_or("")
+                ))
+            }
+            e => Error::Custom(format!("Failed to connect to WebSocket: {}", e)),
+        })?;
+
+        if let Some(no_delay) = self.no_delay
+            && let MaybeTlsStream::Plain(stream) = ws_stream.get_ref()
+        {
+            stream
+                .set_nodelay(no_delay)
+                .map_err(|e| Error::Custom(format!("Failed to set TCP_NODELAY: {}", e)))?;
+        }
 
+        // Servers that predate the header leave it out; the client then works
+        // as before, without checking sizes locally.
+        let config = response
+            .headers()
+            .get(ckeylock_core::CONFIG_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|json| serde_json::from_str(json).ok())
+            .map(Arc::new);
+
+        let inner: Arc<CkeyLockConnectionInner> = CkeyLockConnectionInner::new(ws_stream).into();
+        if let Some(interval) = self.batch_interval {
+            tokio::spawn(flush_loop(Arc::downgrade(&inner), interval));
+        }
         Ok(CKeyLockConnection {
-            inner: CkeyLockConnectionInner::new(ws_stream).into(),
+            inner,
+            in_flight: self.max_in_flight.map(|n| Arc::new(Semaphore::new(n))),
+            signing_secret: self.signing_secret.clone(),
+            batched: self.batch_interval.is_some(),
+            sequenced: self.sequence_numbers,
+            cache: self.cache_ttl.map(|ttl| Arc::new(ResponseCache::new(ttl))),
+            binary_values: self.binary_values,
+            config,
         })
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deleted {
+    pub key: Vec<u8>,
+    /// The removed value, if the key existed.
+    pub value: Option<Vec<u8>>,
+    pub existed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyList {
+    pub keys: Vec<Vec<u8>>,
+    /// Set when the server capped the listing and `keys` is incomplete.
+    pub truncated: bool,
+    pub total_estimate: usize,
+}
+
+/// One page of a [`CKeyLockConnection::scan`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanPage {
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Where the next page starts, `None` once the scan is complete. It stays
+    /// valid across reconnects and server restarts.
+    pub cursor: Option<Vec<u8>>,
+}
+
+/// Dump sizes in bytes around a [`CKeyLockConnection::compact`] call. Both
+/// are zero on servers that don't persist to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compaction {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyStat {
+    pub exists: bool,
+    pub size: Option<usize>,
+    pub ttl_ms: Option<u64>,
+    /// When the key was last read, in milliseconds since the Unix epoch. Reads
+    /// with `get_uncached` don't count.
+    pub last_access_ms: Option<u64>,
+    /// When the key was last set, in milliseconds since the Unix epoch.
+    pub last_modified_ms: Option<u64>,
+}
+
+/// A problem the background reader ran into that no pending request owns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionError {
+    /// A text frame that isn't a valid server message.
+    MalformedFrame(String),
+    /// A frame of a type the server never sends, such as binary.
+    UnexpectedFrame(&'static str),
+    /// The underlying WebSocket failed; the connection closes after this.
+    Transport(String),
+    /// The server rejected something it couldn't tie to a request, such as a
+    /// frame it failed to parse.
+    Rejected { code: ErrorCode, message: String },
+    /// A response came back with a different sequence number than its request.
+    SequenceMismatch {
+        expected: u64,
+        received: Option<u64>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Open,
+    Closing,
+    Closed,
+}
+
+#[derive(Clone)]
 pub struct CKeyLockConnection {
     inner: Arc<CkeyLockConnectionInner>,
+    in_flight: Option<Arc<Semaphore>>,
+    signing_secret: Option<Vec<u8>>,
+    batched: bool,
+    sequenced: bool,
+    cache: Option<Arc<ResponseCache>>,
+    binary_values: Option<usize>,
+    config: Option<Arc<ckeylock_core::ConnectionConfig>>,
 }
 
+/// A request ready to go out: its id, the frames to send, and its sequence
+/// number if the connection numbers requests.
+type Prepared = (Arc<[u8]>, Vec<Message>, Option<u64>);
+
 impl CKeyLockConnection {
+    /// What the server advertised in the handshake, `None` for servers too
+    /// old to send it.
+    pub fn server_config(&self) -> Option<&ckeylock_core::ConnectionConfig> {
+        self.config.as_deref()
+    }
+
     async fn send_request(&self, request: Request) -> Result<Response, Error> {
-        let request = RequestWrapper::new(request);
+        self.send_wrapped(RequestWrapper::new(request)).await
+    }
 
-        self.inner
-            .send(request_into_message(request.clone()))
+    async fn send_compressed_request(&self, request: Request) -> Result<Response, Error> {
+        let mut response = self
+            .send_wrapped(RequestWrapper::new(request).compressed())
             .await?;
+        response
+            .decompress()
+            .map_err(|e| Error::Custom(format!("Failed to decompress response: {}", e)))?;
+        Ok(response)
+    }
 
-        while let Some(msg) = self.inner.lock().await.next().await {
-            let msg =
-                msg.map_err(|e| Error::Custom(format!("Failed to receive message: {}", e)))?;
-            if let Some(parsed_response) = self.parse_response(&msg, request.id()) {
-                return parsed_response;
-            }
+    async fn send_wrapped(&self, request: RequestWrapper) -> Result<Response, Error> {
+        // Invalidate both before and after, so a read racing the write can't
+        // cache the value it replaces.
+        let invalidation = match &self.cache {
+            Some(cache) => Invalidation::of(request.req()).map(|i| (cache, i)),
+            None => None,
+        };
+        if let Some((cache, invalidation)) = &invalidation {
+            cache.apply(invalidation);
         }
-        Err(Error::Custom(
-            "Response with matching ID not found".to_string(),
-        ))
+        let response = self.send_uncached(request).await;
+        if let Some((cache, invalidation)) = &invalidation {
+            cache.apply(invalidation);
+        }
+        response
     }
 
-    fn parse_response(&self, msg: &Message, req_id: Vec<u8>) -> Option<Result<Response, Error>> {
-        if let Message::Text(text) = msg {
-            if let Ok(response) = serde_json::from_str::<Response>(text) {
-                if response.reqid() == req_id {
-                    return Some(Ok(response));
-                }
-            } else if let Ok(err_response) = serde_json::from_str::<ErrorResponse>(text) {
-                if err_response.reqid == req_id {
-                    return Some(Err(Error::Custom(format!(
-                        "Error response received: {}",
-                        err_response.message
-                    ))));
+    /// Sends `request` and waits for its response, sending it again after the
+    /// server's hint while the server answers busy.
+    async fn send_uncached(&self, request: RequestWrapper) -> Result<Response, Error> {
+        let (reqid, messages, seq) = self.prepare(request)?;
+        let _permit = self.acquire_permit().await?;
+        let mut retries = 0;
+        loop {
+            let response = self
+                .inner
+                .shared
+                .register(Arc::clone(&reqid))
+                .ok_or(Error::ConnectionClosed)?;
+            self.transmit(&reqid, messages.clone()).await?;
+            let response = response.await.map_err(|_| Error::ConnectionClosed)?;
+            match response {
+                Err(Error::Busy { retry_after, .. }) if retries < BUSY_RETRIES => {
+                    retries += 1;
+                    tokio::time::sleep(retry_after.unwrap_or(DEFAULT_BUSY_RETRY_AFTER)).await;
                 }
+                response => return self.check_response(seq, response),
+            }
+        }
+    }
+
+    /// Sends a streamed request, returning each frame of its response as it
+    /// arrives. The permit, if any, should be held until the last frame.
+    async fn send_streamed(
+        &self,
+        request: RequestWrapper,
+    ) -> Result<
+        (
+            mpsc::UnboundedReceiver<Result<Response, Error>>,
+            Option<u64>,
+            Option<OwnedSemaphorePermit>,
+        ),
+        Error,
+    > {
+        let (reqid, messages, seq) = self.prepare(request.streamed())?;
+        let permit = self.acquire_permit().await?;
+        let frames = self
+            .inner
+            .shared
+            .register_stream(Arc::clone(&reqid))
+            .ok_or(Error::ConnectionClosed)?;
+        self.transmit(&reqid, messages).await?;
+        Ok((frames, seq, permit))
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn prepare(&self, request: RequestWrapper) -> Result<Prepared, Error> {
+        let seq = self
+            .sequenced
+            .then(|| self.inner.shared.sequence.lock().unwrap().next());
+        let request = match seq {
+            Some(seq) => request.with_seq(seq),
+            None => request,
+        };
+        let request = match self.binary_values {
+            Some(min_len) if request.req().value().is_some_and(|v| v.len() >= min_len) => {
+                request.with_binary_value()
+            }
+            _ => request,
+        };
+        let request = match &self.signing_secret {
+            Some(secret) => request.signed(secret)?,
+            None => request,
+        };
+        let mut request = request;
+        let value = if request.binary_value() {
+            request.take_value()
+        } else {
+            None
+        };
+        let reqid: Arc<[u8]> = Arc::from(request.id());
+        let mut messages = vec![request_into_message(request)?];
+        if let Some(value) = value {
+            let frame = ckeylock_core::binary::encode_frame(&reqid, &value)
+                .map_err(|e| Error::Custom(e.to_string()))?;
+            messages.push(Message::Binary(frame.into()));
+        }
+        // The server closes connections sending too much, failing every
+        // request in flight, so refuse up front instead.
+        if let Some(max) = self.config.as_ref().and_then(|c| c.max_message_size)
+            && let Some(message) = messages.iter().find(|message| message.len() > max)
+        {
+            // The number was never sent, so it mustn't show up as a gap.
+            if let Some(seq) = seq {
+                self.inner.shared.sequence.lock().unwrap().answered(seq);
+            }
+            return Err(Error::TooLarge(format!(
+                "Message of {} bytes exceeds the server's limit of {} bytes",
+                message.len(),
+                max
+            )));
+        }
+        Ok((reqid, messages, seq))
+    }
+
+    async fn acquire_permit(&self) -> Result<Option<OwnedSemaphorePermit>, Error> {
+        match &self.in_flight {
+            Some(semaphore) => Ok(Some(
+                Arc::clone(semaphore)
+                    .acquire_owned()
+                    .await
+                    .map_err(|_| Error::ConnectionClosed)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    async fn transmit(&self, reqid: &[u8], messages: Vec<Message>) -> Result<(), Error> {
+        for message in messages {
+            let sent = if self.batched {
+                self.inner.send_batched(message).await
+            } else {
+                self.inner.send(message).await
+            };
+            if let Err(e) = sent {
+                self.inner.shared.unregister(reqid);
+                return Err(e);
             }
         }
-        None
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn check_response(
+        &self,
+        seq: Option<u64>,
+        response: Result<Response, Error>,
+    ) -> Result<Response, Error> {
+        if let Some(seq) = seq {
+            self.inner.shared.answered(seq, &response);
+        }
+        let response = response?;
+        if let Some(secret) = &self.signing_secret
+            && !response.verify(secret)
+        {
+            return Err(Error::InvalidSignature);
+        }
+        Ok(response)
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        *self.inner.shared.state.borrow()
+    }
+
+    /// Subscribes to errors hit by the background reader as they happen.
+    /// Only errors after the call are delivered.
+    pub fn errors(&self) -> broadcast::Receiver<ConnectionError> {
+        self.inner.shared.errors.subscribe()
+    }
+
+    /// Sequence numbers that are still unanswered even though a later request
+    /// got its response, which points at dropped or reordered frames. Requests
+    /// running concurrently show up here until they complete. Always empty
+    /// unless the connection was opened with sequence numbers.
+    pub fn sequence_gaps(&self) -> Vec<u64> {
+        self.inner.shared.sequence.lock().unwrap().gaps()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.inner.shared.pending.lock().unwrap().len()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state() == ConnectionState::Open
+    }
+
+    pub async fn wait_closed(&self) {
+        let mut state = self.inner.shared.state.subscribe();
+        let _ = state.wait_for(|s| *s == ConnectionState::Closed).await;
     }
 
     pub async fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Vec<u8>, Error> {
@@ -100,13 +506,60 @@ impl CKeyLockConnection {
     }
 
     pub async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
-        let res = self.send_request(Request::Get { key }).await?;
+        let epoch = match &self.cache {
+            Some(cache) => match cache.get(&key) {
+                Some(value) => return Ok(value),
+                None => Some(cache.epoch()),
+            },
+            None => None,
+        };
+        let res = self
+            .send_request(Request::Get {
+                key: key.clone(),
+                bypass_cache: false,
+            })
+            .await?;
+        if let Some(ckeylock_core::ResponseData::GetResponse { value }) = res.data() {
+            if let (Some(cache), Some(epoch)) = (&self.cache, epoch) {
+                cache.insert(key, value.clone(), epoch);
+            }
+            Ok(value.as_ref().map(|v| v.to_vec()))
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+    /// Like [`get`](Self::get), but the server reads straight from its store
+    /// and leaves its cache untouched. Useful when debugging stale reads.
+    pub async fn get_uncached(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+        let res = self
+            .send_request(Request::Get {
+                key,
+                bypass_cache: true,
+            })
+            .await?;
         if let Some(ckeylock_core::ResponseData::GetResponse { value }) = res.data() {
             Ok(value.as_ref().map(|v| v.to_vec()))
         } else {
             Err(Error::WrongResponseFormat)
         }
     }
+    /// Reads bytes `start..end` of a value, clamped to its length. Fails with
+    /// [`Error::BadRequest`] if `start > end`.
+    pub async fn get_range(
+        &self,
+        key: Vec<u8>,
+        start: usize,
+        end: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let res = self
+            .send_request(Request::GetRange { key, start, end })
+            .await?;
+        if let Some(ckeylock_core::ResponseData::GetRangeResponse { value }) = res.data() {
+            Ok(value.clone())
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
     pub async fn batch_get(&self, keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>, Error> {
         let res = self.send_request(Request::BatchGet { keys }).await?;
         if let Some(ckeylock_core::ResponseData::BatchGetResponse { values }) = res.data() {
@@ -115,24 +568,156 @@ impl CKeyLockConnection {
             Err(Error::WrongResponseFormat)
         }
     }
-    pub async fn delete(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
-        let res = self.send_request(Request::Delete { key }).await?;
-        if let Some(ckeylock_core::ResponseData::DeleteResponse { key }) = res.data() {
-            Ok(key.as_ref().map(|v| v.to_vec()))
+    /// Counts how many of `keys` exist, without sending back which ones.
+    pub async fn batch_exists_count(&self, keys: Vec<Vec<u8>>) -> Result<usize, Error> {
+        let res = self
+            .send_request(Request::BatchExistsCount { keys })
+            .await?;
+        if let Some(ckeylock_core::ResponseData::CountResponse { count }) = res.data() {
+            Ok(*count)
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+    /// Stores every entry in one request, each with its own TTL. Entries
+    /// with `None` are persistent. Returns how many were stored.
+    pub async fn batch_set_ex(
+        &self,
+        entries: Vec<(Vec<u8>, Vec<u8>, Option<Duration>)>,
+    ) -> Result<usize, Error> {
+        let res = self.send_request(Request::BatchSetEx { entries }).await?;
+        if let Some(ckeylock_core::ResponseData::BatchSetExResponse { stored }) = res.data() {
+            Ok(*stored)
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+    pub async fn batch_get_compressed(
+        &self,
+        keys: Vec<Vec<u8>>,
+    ) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        let res = self
+            .send_compressed_request(Request::BatchGet { keys })
+            .await?;
+        if let Some(ckeylock_core::ResponseData::BatchGetResponse { values }) = res.data() {
+            Ok(values.clone())
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+    pub async fn delete(&self, key: Vec<u8>) -> Result<Deleted, Error> {
+        let res = self
+            .send_request(Request::Delete { key: key.clone() })
+            .await?;
+        if let Some(ckeylock_core::ResponseData::DeleteResponse {
+            key: deleted,
+            value,
+            existed,
+        }) = res.data()
+        {
+            Ok(Deleted {
+                key,
+                value: value.clone(),
+                // Older servers only send back the key of deleted entries.
+                existed: *existed || deleted.is_some(),
+            })
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
+    /// Deletes `key`, returning the value it held, if any.
+    pub async fn delete_value(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.delete(key).await?.value)
+    }
+
+    /// Restores a deleted key, if the server keeps deleted values (its
+    /// `soft_delete_secs` setting) and the key's grace period hasn't run out.
+    /// Returns whether the key was restored.
+    pub async fn undelete(&self, key: Vec<u8>) -> Result<bool, Error> {
+        let res = self.send_request(Request::Undelete { key }).await?;
+        if let Some(ckeylock_core::ResponseData::UndeleteResponse { restored }) = res.data() {
+            Ok(*restored)
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
+    pub async fn delete_prefix(&self, prefix: Vec<u8>) -> Result<usize, Error> {
+        let res = self.send_request(Request::DeletePrefix { prefix }).await?;
+        if let Some(ckeylock_core::ResponseData::DeletePrefixResponse { deleted }) = res.data() {
+            Ok(*deleted)
         } else {
             Err(Error::WrongResponseFormat)
         }
     }
 
+    /// Lists keys, which may be incomplete if the server caps listings; use
+    /// [`list_detailed`](Self::list_detailed) to find out.
     pub async fn list(&self) -> Result<Vec<Vec<u8>>, Error> {
+        Ok(self.list_detailed().await?.keys)
+    }
+
+    pub async fn list_detailed(&self) -> Result<KeyList, Error> {
         let res = self.send_request(Request::List).await?;
-        if let Some(ckeylock_core::ResponseData::ListResponse { keys }) = res.data() {
+        if let Some(ckeylock_core::ResponseData::ListResponse {
+            keys,
+            truncated,
+            total_estimate,
+        }) = res.data()
+        {
+            Ok(KeyList {
+                keys: keys.clone(),
+                truncated: *truncated,
+                total_estimate: *total_estimate,
+            })
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
+    pub async fn list_compressed(&self) -> Result<Vec<Vec<u8>>, Error> {
+        let res = self.send_compressed_request(Request::List).await?;
+        if let Some(ckeylock_core::ResponseData::ListResponse { keys, .. }) = res.data() {
             Ok(keys.clone())
         } else {
             Err(Error::WrongResponseFormat)
         }
     }
 
+    /// Lists keys, letting the server split them over several frames instead
+    /// of buffering them into one. Each item holds one frame's worth of keys,
+    /// so a large listing can be processed as it arrives.
+    pub async fn list_stream(
+        &self,
+    ) -> Result<impl Stream<Item = Result<Vec<Vec<u8>>, Error>> + use<>, Error> {
+        let (frames, seq, permit) = self
+            .send_streamed(RequestWrapper::new(Request::List))
+            .await?;
+        let connection = self.clone();
+        Ok(futures_util::stream::unfold(
+            Some((frames, permit)),
+            move |state| {
+                let connection = connection.clone();
+                async move {
+                    let (mut frames, permit) = state?;
+                    let frame = frames.recv().await.unwrap_or(Err(Error::ConnectionClosed));
+                    let response = match connection.check_response(seq, frame) {
+                        Ok(response) => response,
+                        Err(e) => return Some((Err(e), None)),
+                    };
+                    let Some(ckeylock_core::ResponseData::ListResponse { keys, .. }) =
+                        response.data()
+                    else {
+                        return Some((Err(Error::WrongResponseFormat), None));
+                    };
+                    let next = response.more().then_some((frames, permit));
+                    Some((Ok(keys.clone()), next))
+                }
+            },
+        ))
+    }
+
     pub async fn exists(&self, key: Vec<u8>) -> Result<bool, Error> {
         let res = self.send_request(Request::Exists { key }).await?;
         if let Some(ckeylock_core::ResponseData::ExistsResponse { exists }) = res.data() {
@@ -142,6 +727,93 @@ impl CKeyLockConnection {
         }
     }
 
+    pub async fn expire(&self, key: Vec<u8>, ttl: std::time::Duration) -> Result<bool, Error> {
+        let res = self.send_request(Request::Expire { key, ttl }).await?;
+        if let Some(ckeylock_core::ResponseData::ExpireResponse { existed }) = res.data() {
+            Ok(*existed)
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
+    /// Stores `value` only if `key` doesn't exist yet. Returns whether it was
+    /// stored.
+    pub async fn set_if_absent(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<bool, Error> {
+        let res = self
+            .send_request(Request::SetIfAbsent { key, value, ttl })
+            .await?;
+        if let Some(ckeylock_core::ResponseData::SetIfAbsentResponse { stored }) = res.data() {
+            Ok(*stored)
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
+    /// Deletes `key` only if it still holds `value`.
+    pub async fn delete_if_equals(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool, Error> {
+        let res = self
+            .send_request(Request::DeleteIfEquals { key, value })
+            .await?;
+        if let Some(ckeylock_core::ResponseData::DeleteIfEqualsResponse { deleted }) = res.data() {
+            Ok(*deleted)
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
+    /// Sets a TTL on `key` only if it still holds `value`.
+    pub async fn expire_if_equals(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool, Error> {
+        let res = self
+            .send_request(Request::ExpireIfEquals { key, value, ttl })
+            .await?;
+        if let Some(ckeylock_core::ResponseData::ExpireResponse { existed }) = res.data() {
+            Ok(*existed)
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
+    /// Stores `value` only if the current value of `key` meets `condition`.
+    /// Returns whether it was stored.
+    pub async fn set_if(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        condition: ckeylock_core::Condition,
+    ) -> Result<bool, Error> {
+        let res = self
+            .send_request(Request::SetIf {
+                key,
+                value,
+                condition,
+            })
+            .await?;
+        if let Some(ckeylock_core::ResponseData::SetIfResponse { stored }) = res.data() {
+            Ok(*stored)
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
+    pub async fn persist(&self, key: Vec<u8>) -> Result<bool, Error> {
+        let res = self.send_request(Request::Persist { key }).await?;
+        if let Some(ckeylock_core::ResponseData::PersistResponse { existed }) = res.data() {
+            Ok(*existed)
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
     pub async fn count(&self) -> Result<usize, Error> {
         let res = self.send_request(Request::Count).await?;
         if let Some(ckeylock_core::ResponseData::CountResponse { count }) = res.data() {
@@ -151,6 +823,42 @@ impl CKeyLockConnection {
         }
     }
 
+    /// Counts the keys starting with `prefix`, without transferring them.
+    pub async fn count_prefix(&self, prefix: Vec<u8>) -> Result<usize, Error> {
+        let res = self.send_request(Request::CountPrefix { prefix }).await?;
+        if let Some(ckeylock_core::ResponseData::CountResponse { count }) = res.data() {
+            Ok(*count)
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
+    /// Fetches up to `limit` entries starting with `prefix`, in key order. Pass
+    /// `None` to start and the previous page's cursor to continue; a scan
+    /// interrupted by a disconnect resumes from the last cursor received.
+    pub async fn scan(
+        &self,
+        prefix: Vec<u8>,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<ScanPage, Error> {
+        let res = self
+            .send_request(Request::Scan {
+                prefix,
+                after: cursor,
+                limit,
+            })
+            .await?;
+        if let Some(ckeylock_core::ResponseData::ScanResponse { entries, cursor }) = res.data() {
+            Ok(ScanPage {
+                entries: entries.clone(),
+                cursor: cursor.clone(),
+            })
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
     pub async fn clear(&self) -> Result<(), Error> {
         let res = self.send_request(Request::Clear).await?;
         if let Some(ckeylock_core::ResponseData::ClearResponse) = res.data() {
@@ -160,57 +868,559 @@ impl CKeyLockConnection {
         }
     }
 
-    pub async fn close(&self) -> Result<(), Box<dyn std::error::Error>> {
-        self.inner
-            .lock()
-            .await
-            .close(None)
-            .await
-            .map_err(|e| Box::new(Error::Custom(format!("Failed to close WebSocket: {}", e))) as _)
+    /// Turns the server's maintenance mode on or off. While on, writes fail with
+    /// [`Error::Maintenance`] and reads keep working. A `ttl` ends maintenance
+    /// automatically after that long. Servers only accept this when their
+    /// `enabled_operations` list `"maintenance"`.
+    pub async fn set_maintenance(
+        &self,
+        enabled: bool,
+        ttl: Option<Duration>,
+    ) -> Result<bool, Error> {
+        let res = self
+            .send_request(Request::Maintenance { enabled, ttl })
+            .await?;
+        if let Some(ckeylock_core::ResponseData::MaintenanceResponse { enabled }) = res.data() {
+            Ok(*enabled)
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
     }
-}
 
-fn request_into_message(req: ckeylock_core::RequestWrapper) -> Message {
-    Message::Text(req.to_string().into())
-}
+    /// Atomically replaces everything stored on the server with `entries`,
+    /// dropping all TTLs. Returns the number of keys now stored.
+    pub async fn replace_all(&self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> Result<usize, Error> {
+        let res = self
+            .send_request(Request::ReplaceAll {
+                entries,
+                validate_only: false,
+            })
+            .await?;
+        if let Some(ckeylock_core::ResponseData::ReplaceAllResponse { loaded }) = res.data() {
+            Ok(*loaded)
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
 
-pub struct CkeyLockConnectionInner(Mutex<WebSocketStream<MaybeTlsStream<TcpStream>>>);
+    /// Checks `entries` against the server's limits the way
+    /// [`replace_all`](Self::replace_all) would, without writing anything.
+    /// Returns one result per entry, with the reason for each failure.
+    pub async fn validate_replace_all(
+        &self,
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<Vec<Result<(), String>>, Error> {
+        let res = self
+            .send_request(Request::ReplaceAll {
+                entries,
+                validate_only: true,
+            })
+            .await?;
+        if let Some(ckeylock_core::ResponseData::ValidationResponse { results }) = res.data() {
+            Ok(results.clone())
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
 
-impl CkeyLockConnectionInner {
-    pub fn new(ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>) -> Self {
-        CkeyLockConnectionInner(Mutex::new(ws_stream))
+    /// Drops the server's read cache without touching stored keys. Returns the
+    /// number of cached entries dropped.
+    pub async fn clear_cache(&self) -> Result<usize, Error> {
+        let res = self.send_request(Request::ClearCache).await?;
+        if let Some(ckeylock_core::ResponseData::ClearCacheResponse { dropped }) = res.data() {
+            Ok(*dropped)
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
     }
 
-    pub async fn send(&self, msg: Message) -> Result<(), Error> {
-        self.0
-            .lock()
-            .await
-            .send(msg)
-            .await
-            .map_err(|e| Error::Custom(format!("Failed to send message: {}", e)))
+    pub async fn stats(&self) -> Result<ckeylock_core::Stats, Error> {
+        let res = self.send_request(Request::Stats).await?;
+        if let Some(ckeylock_core::ResponseData::StatsResponse { stats }) = res.data() {
+            Ok(stats.clone())
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
+    /// Returns whether `key` exists, its value size, remaining TTL and when it was
+    /// last read and written, without transferring the value itself.
+    pub async fn stat(&self, key: Vec<u8>) -> Result<KeyStat, Error> {
+        let res = self.send_request(Request::Stat { key }).await?;
+        if let Some(ckeylock_core::ResponseData::StatResponse {
+            exists,
+            size,
+            ttl_ms,
+            last_access_ms,
+            last_modified_ms,
+        }) = res.data()
+        {
+            Ok(KeyStat {
+                exists: *exists,
+                size: *size,
+                ttl_ms: *ttl_ms,
+                last_access_ms: *last_access_ms,
+                last_modified_ms: *last_modified_ms,
+            })
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
     }
-    pub async fn lock(
+
+    /// Applies `ops` with no other request interleaved and returns one result
+    /// per executed op. Unless `continue_on_error` is set, ops after the first
+    /// failure are skipped, but those before it stay applied.
+    pub async fn transaction(
         &self,
-    ) -> tokio::sync::MutexGuard<'_, WebSocketStream<MaybeTlsStream<TcpStream>>> {
-        self.0.lock().await
+        ops: Vec<Request>,
+        continue_on_error: bool,
+    ) -> Result<Vec<Result<ckeylock_core::ResponseData, String>>, Error> {
+        let res = self
+            .send_request(Request::Transaction {
+                ops,
+                continue_on_error,
+            })
+            .await?;
+        if let Some(ckeylock_core::ResponseData::TransactionResponse { results }) = res.data() {
+            Ok(results.clone())
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
     }
-}
 
-#[derive(Error, Debug)]
-pub enum Error {
-    #[error("WebSocket error: {0}")]
-    WsError(#[from] WsError),
+    /// Liveness probe: succeeds whenever the server answers at all.
+    pub async fn live(&self) -> Result<(), Error> {
+        let res = self.send_request(Request::Live).await?;
+        if let Some(ckeylock_core::ResponseData::LiveResponse) = res.data() {
+            Ok(())
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
+    /// Readiness probe: false while storage is loading or the server is in
+    /// maintenance mode.
+    pub async fn ready(&self) -> Result<bool, Error> {
+        let res = self.send_request(Request::Ready).await?;
+        if let Some(ckeylock_core::ResponseData::ReadyResponse { ready }) = res.data() {
+            Ok(*ready)
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
+    /// The server's effective settings. Secrets such as passwords are never
+    /// part of the answer.
+    pub async fn info(&self) -> Result<ckeylock_core::ServerInfo, Error> {
+        let res = self.send_request(Request::Info).await?;
+        if let Some(ckeylock_core::ResponseData::InfoResponse { info }) = res.data() {
+            Ok(info.as_ref().clone())
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
+    /// Makes the server rewrite its dump from scratch to reclaim space.
+    /// Meant for off-peak hours: the store answers nothing else meanwhile.
+    pub async fn compact(&self) -> Result<Compaction, Error> {
+        let res = self.send_request(Request::Compact).await?;
+        if let Some(ckeylock_core::ResponseData::CompactResponse {
+            bytes_before,
+            bytes_after,
+        }) = res.data()
+        {
+            Ok(Compaction {
+                bytes_before: *bytes_before,
+                bytes_after: *bytes_after,
+            })
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
+    pub async fn echo(&self, payload: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let res = self.send_request(Request::Echo { payload }).await?;
+        if let Some(ckeylock_core::ResponseData::EchoResponse { payload }) = res.data() {
+            Ok(payload.clone())
+        } else {
+            Err(Error::WrongResponseFormat)
+        }
+    }
+
+    /// Writes out any frames queued by a batching connection.
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.inner.flush().await
+    }
+
+    pub async fn close(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.inner.shared.set_state(ConnectionState::Closing);
+        self.inner
+            .write
+            .lock()
+            .await
+            .close()
+            .await
+            .map_err(|e| Box::new(Error::Custom(format!("Failed to close WebSocket: {}", e))) as _)
+    }
+}
+
+fn request_into_message(req: ckeylock_core::RequestWrapper) -> Result<Message, serde_json::Error> {
+    Ok(Message::Text(req.to_json()?.into()))
+}
+
+struct CkeyLockConnectionInner {
+    write: Mutex<SplitSink<WsStream, Message>>,
+    shared: Arc<Shared>,
+    reader: JoinHandle<()>,
+    flushes: AtomicUsize,
+}
+
+impl CkeyLockConnectionInner {
+    fn new(ws_stream: WsStream) -> Self {
+        let (write, read) = ws_stream.split();
+        let shared = Arc::new(Shared {
+            pending: std::sync::Mutex::new(HashMap::new()),
+            state: watch::Sender::new(ConnectionState::Open),
+            errors: broadcast::Sender::new(ERROR_EVENTS_CAPACITY),
+            sequence: std::sync::Mutex::default(),
+        });
+        let reader = tokio::spawn(read_loop(read, Arc::clone(&shared)));
+        CkeyLockConnectionInner {
+            write: Mutex::new(write),
+            shared,
+            reader,
+            flushes: AtomicUsize::new(0),
+        }
+    }
+
+    async fn send(&self, msg: Message) -> Result<(), Error> {
+        self.write
+            .lock()
+            .await
+            .send(msg)
+            .await
+            .map_err(|e| Error::Custom(format!("Failed to send message: {}", e)))?;
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn send_batched(&self, msg: Message) -> Result<(), Error> {
+        self.write
+            .lock()
+            .await
+            .feed(msg)
+            .await
+            .map_err(|e| Error::Custom(format!("Failed to queue message: {}", e)))
+    }
+
+    async fn flush(&self) -> Result<(), Error> {
+        self.write
+            .lock()
+            .await
+            .flush()
+            .await
+            .map_err(|e| Error::Custom(format!("Failed to flush messages: {}", e)))?;
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+async fn flush_loop(inner: Weak<CkeyLockConnectionInner>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let Some(inner) = inner.upgrade() else {
+            break;
+        };
+        if *inner.shared.state.borrow() == ConnectionState::Closed {
+            break;
+        }
+        let _ = inner.flush().await;
+    }
+}
+
+impl Drop for CkeyLockConnectionInner {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+enum PendingResponse {
+    Once(oneshot::Sender<Result<Response, Error>>),
+    /// Stays registered until a frame without `more` arrives.
+    Stream(mpsc::UnboundedSender<Result<Response, Error>>),
+}
+
+impl PendingResponse {
+    fn send(self, result: Result<Response, Error>) {
+        match self {
+            PendingResponse::Once(tx) => {
+                let _ = tx.send(result);
+            }
+            PendingResponse::Stream(tx) => {
+                let _ = tx.send(result);
+            }
+        }
+    }
+}
+
+const ERROR_EVENTS_CAPACITY: usize = 16;
+
+struct Shared {
+    pending: std::sync::Mutex<HashMap<Arc<[u8]>, PendingResponse>>,
+    state: watch::Sender<ConnectionState>,
+    errors: broadcast::Sender<ConnectionError>,
+    sequence: std::sync::Mutex<SequenceTracker>,
+}
+
+/// Hands out sequence numbers and records which ones have been answered.
+#[derive(Debug, Default)]
+struct SequenceTracker {
+    next: u64,
+    /// Every sequence number below this one has been answered.
+    answered_below: u64,
+    answered: BTreeSet<u64>,
+}
+
+impl SequenceTracker {
+    fn next(&mut self) -> u64 {
+        let seq = self.next;
+        self.next += 1;
+        seq
+    }
+
+    fn answered(&mut self, seq: u64) {
+        if seq < self.answered_below {
+            return;
+        }
+        self.answered.insert(seq);
+        while self.answered.remove(&self.answered_below) {
+            self.answered_below += 1;
+        }
+    }
+
+    fn gaps(&self) -> Vec<u64> {
+        let Some(&highest) = self.answered.last() else {
+            return Vec::new();
+        };
+        (self.answered_below..highest)
+            .filter(|seq| !self.answered.contains(seq))
+            .collect()
+    }
+}
+
+impl Shared {
+    /// Records that the request numbered `seq` got an answer. Server errors
+    /// count as answers; a closed connection doesn't.
+    fn answered(&self, seq: u64, result: &Result<Response, Error>) {
+        match result {
+            Err(Error::ConnectionClosed | Error::RequestLimitReached) => return,
+            Ok(response) if response.seq() != Some(seq) => {
+                self.report(ConnectionError::SequenceMismatch {
+                    expected: seq,
+                    received: response.seq(),
+                });
+            }
+            _ => {}
+        }
+        self.sequence.lock().unwrap().answered(seq);
+    }
+
+    fn register(&self, reqid: Arc<[u8]>) -> Option<oneshot::Receiver<Result<Response, Error>>> {
+        let mut pending = self.pending.lock().unwrap();
+        if *self.state.borrow() == ConnectionState::Closed {
+            return None;
+        }
+        let (tx, rx) = oneshot::channel();
+        pending.insert(reqid, PendingResponse::Once(tx));
+        Some(rx)
+    }
+
+    fn register_stream(
+        &self,
+        reqid: Arc<[u8]>,
+    ) -> Option<mpsc::UnboundedReceiver<Result<Response, Error>>> {
+        let mut pending = self.pending.lock().unwrap();
+        if *self.state.borrow() == ConnectionState::Closed {
+            return None;
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        pending.insert(reqid, PendingResponse::Stream(tx));
+        Some(rx)
+    }
+
+    fn unregister(&self, reqid: &[u8]) {
+        self.pending.lock().unwrap().remove(reqid);
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        self.state.send_if_modified(|current| {
+            if *current == ConnectionState::Closed || *current == state {
+                return false;
+            }
+            *current = state;
+            true
+        });
+    }
+
+    fn complete(&self, reqid: &[u8], result: Result<Response, Error>) {
+        let mut pending = self.pending.lock().unwrap();
+        let more = matches!(&result, Ok(response) if response.more());
+        if more && let Some(PendingResponse::Stream(tx)) = pending.get(reqid) {
+            let _ = tx.send(result);
+        } else if let Some(tx) = pending.remove(reqid) {
+            tx.send(result);
+        }
+    }
+
+    fn dispatch(&self, text: &str) {
+        match serde_json::from_str::<ServerMessage>(text) {
+            Ok(ServerMessage::Error(err_response)) if err_response.reqid.is_empty() => {
+                self.report(ConnectionError::Rejected {
+                    code: err_response.code,
+                    message: err_response.message,
+                });
+            }
+            Ok(ServerMessage::Error(err_response)) => {
+                let reqid = err_response.reqid.clone();
+                self.complete(&reqid, Err(err_response.into()));
+            }
+            Ok(ServerMessage::Response(response)) => {
+                self.complete(&response.reqid(), Ok(response));
+            }
+            Err(e) => self.report(ConnectionError::MalformedFrame(e.to_string())),
+        }
+    }
+
+    fn report(&self, error: ConnectionError) {
+        let _ = self.errors.send(error);
+    }
+
+    fn close(&self, frame: Option<CloseFrame>) {
+        let error = || match &frame {
+            Some(frame) if u16::from(frame.code) == ckeylock_core::close::MAX_REQUESTS_REACHED => {
+                Error::RequestLimitReached
+            }
+            Some(frame) if u16::from(frame.code) == ckeylock_core::close::MESSAGE_TOO_LARGE => {
+                Error::TooLarge(frame.reason.to_string())
+            }
+            _ => Error::ConnectionClosed,
+        };
+        let mut pending = self.pending.lock().unwrap();
+        self.set_state(ConnectionState::Closed);
+        for (_, tx) in pending.drain() {
+            tx.send(Err(error()));
+        }
+    }
+}
+
+async fn read_loop(mut read: SplitStream<WsStream>, shared: Arc<Shared>) {
+    let mut close_frame = None;
+    while let Some(msg) = read.next().await {
+        match msg {
+            Ok(Message::Text(text)) => shared.dispatch(&text),
+            Ok(Message::Close(frame)) => {
+                close_frame = frame;
+                break;
+            }
+            Ok(Message::Binary(_)) => shared.report(ConnectionError::UnexpectedFrame("binary")),
+            Ok(_) => {}
+            Err(e) => {
+                shared.report(ConnectionError::Transport(e.to_string()));
+                break;
+            }
+        }
+    }
+    shared.close(close_frame);
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("WebSocket error: {0}")]
+    WsError(#[from] WsError),
     #[error("Wrong response format")]
     WrongResponseFormat,
+    #[error("Connection closed by server: request limit reached")]
+    RequestLimitReached,
+    #[error("Connection closed")]
+    ConnectionClosed,
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    /// A value failed to encode or decode with a non-JSON
+    /// [`Codec`](codec::Codec).
+    #[error("Codec error: {0}")]
+    Codec(String),
     #[error("Failed to parse uri: {0}")]
     UriParseError(#[from] tokio_tungstenite::tungstenite::http::uri::InvalidUri),
+    #[error("Unauthorized")]
+    Unauthorized,
+    #[error("Not found: {0}")]
+    NotFound(String),
+    /// `retry_after` is the server's hint for when to try again, if it sent
+    /// one.
+    #[error("Rate limited: {message}")]
+    RateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    /// The server turned the request away because its queue was full, as
+    /// many times in a row as the connection retries busy requests.
+    #[error("Server busy: {message}")]
+    Busy {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    #[error("Too large: {0}")]
+    TooLarge(String),
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    #[error("Server error: {0}")]
+    Server(String),
+    #[error("Server in maintenance: {0}")]
+    Maintenance(String),
+    #[error("Response signature is invalid or missing")]
+    InvalidSignature,
     #[error("{0}")]
     Custom(String),
 }
+
+impl From<ErrorResponse> for Error {
+    fn from(err: ErrorResponse) -> Self {
+        let message = if err.operation.is_empty() {
+            err.message
+        } else {
+            format!("{} failed: {}", err.operation, err.message)
+        };
+        match err.code {
+            ErrorCode::Unauthorized => Error::Unauthorized,
+            ErrorCode::NotFound => Error::NotFound(message),
+            ErrorCode::RateLimited => Error::RateLimited {
+                message,
+                retry_after: err.retry_after_ms.map(Duration::from_millis),
+            },
+            ErrorCode::TooLarge => Error::TooLarge(message),
+            ErrorCode::BadRequest => Error::BadRequest(message),
+            ErrorCode::Maintenance => Error::Maintenance(message),
+            ErrorCode::RequestLimitReached => Error::RequestLimitReached,
+            ErrorCode::Busy => Error::Busy {
+                message,
+                retry_after: err.retry_after_ms.map(Duration::from_millis),
+            },
+            ErrorCode::Internal => Error::Server(message),
+        }
+    }
+}
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_no_delay_defaults_on() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", None);
+        assert_eq!(api.no_delay, Some(true));
+        assert_eq!(api.with_no_delay(false).no_delay, Some(false));
+    }
+
     #[tokio::test]
     async fn test_set() {
         let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
@@ -240,6 +1450,29 @@ mod tests {
         println!("Value: {:?}", unwrapped_value);
     }
 
+    #[tokio::test]
+    async fn test_get_uncached() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+
+        let key = b"test_get_uncached".to_vec();
+        connection
+            .set(key.clone(), b"value".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            connection.get_uncached(key.clone()).await.unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(
+            connection
+                .get_uncached(b"test_get_uncached_missing".to_vec())
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
     #[tokio::test]
     async fn test_delete() {
         let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
@@ -251,7 +1484,45 @@ mod tests {
         connection.set(key.clone(), value.clone()).await.unwrap();
         let result = connection.delete(key.clone()).await;
         assert!(result.is_ok());
-        assert_eq!(result.unwrap(), Some(key));
+        assert_eq!(result.unwrap().key, key);
+    }
+
+    #[tokio::test]
+    async fn test_delete_reports_existence() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+
+        let key = b"test_delete_reports_existence".to_vec();
+        connection
+            .set(key.clone(), b"value".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            connection.delete(key.clone()).await.unwrap(),
+            Deleted {
+                key: key.clone(),
+                value: Some(b"value".to_vec()),
+                existed: true,
+            }
+        );
+        assert_eq!(
+            connection.delete(key.clone()).await.unwrap(),
+            Deleted {
+                key: key.clone(),
+                value: None,
+                existed: false,
+            }
+        );
+
+        connection
+            .set(key.clone(), b"again".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            connection.delete_value(key.clone()).await.unwrap(),
+            Some(b"again".to_vec())
+        );
+        assert_eq!(connection.delete_value(key).await.unwrap(), None);
     }
 
     #[tokio::test]
@@ -271,6 +1542,10 @@ mod tests {
         let keys = result.unwrap();
         assert!(keys.contains(&key1));
         assert!(keys.contains(&key2));
+
+        let detailed = connection.list_detailed().await.unwrap();
+        assert!(!detailed.truncated);
+        assert!(detailed.total_estimate >= detailed.keys.len());
     }
     #[tokio::test]
     async fn test_batch_get() {
@@ -297,4 +1572,814 @@ mod tests {
         assert_eq!(values[1], Some(value2));
         assert_eq!(values[2], None);
     }
+
+    #[tokio::test]
+    async fn test_list_stream() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+        let keys: BTreeSet<Vec<u8>> = (0..2100)
+            .map(|i| format!("stream_test:{:04}", i).into_bytes())
+            .collect();
+        futures_util::future::try_join_all(
+            keys.iter()
+                .map(|key| connection.set(key.clone(), b"v".to_vec())),
+        )
+        .await
+        .unwrap();
+
+        let buffered: BTreeSet<Vec<u8>> = connection.list().await.unwrap().into_iter().collect();
+        assert!(buffered.is_superset(&keys));
+
+        let frames: Vec<Vec<Vec<u8>>> = connection
+            .list_stream()
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert!(frames.len() > 1);
+        let streamed: BTreeSet<Vec<u8>> = frames.into_iter().flatten().collect();
+        assert!(streamed.is_superset(&keys));
+        assert_eq!(connection.in_flight(), 0);
+
+        connection
+            .delete_prefix(b"stream_test:".to_vec())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cached_gets() {
+        let server = testing::MockServer::start().await.unwrap();
+        let connection = server
+            .api()
+            .with_cache(Duration::from_secs(60))
+            .connect()
+            .await
+            .unwrap();
+        connection
+            .set(b"key".to_vec(), b"one".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            connection.get(b"key".to_vec()).await.unwrap(),
+            Some(b"one".to_vec())
+        );
+        let requests = server.requests();
+        assert_eq!(
+            connection.get(b"key".to_vec()).await.unwrap(),
+            Some(b"one".to_vec())
+        );
+        assert_eq!(server.requests(), requests);
+
+        connection
+            .set(b"key".to_vec(), b"two".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            connection.get(b"key".to_vec()).await.unwrap(),
+            Some(b"two".to_vec())
+        );
+        assert_eq!(server.requests(), requests + 2);
+
+        connection.delete(b"key".to_vec()).await.unwrap();
+        assert_eq!(connection.get(b"key".to_vec()).await.unwrap(), None);
+        assert_eq!(connection.get(b"key".to_vec()).await.unwrap(), None);
+        assert_eq!(server.requests(), requests + 4);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_config_limits_message_size() {
+        let server = testing::MockServer::start().await.unwrap();
+        server.set_max_message_size(1024);
+        let connection = server.api().connect().await.unwrap();
+        let config = connection.server_config().unwrap();
+        assert_eq!(config.max_message_size, Some(1024));
+        assert_eq!(config.protocol_version, ckeylock_core::PROTOCOL_VERSION);
+
+        let requests = server.requests();
+        let oversized = connection.set(b"key".to_vec(), vec![0; 2048]).await;
+        assert!(matches!(oversized, Err(Error::TooLarge(reason)) if reason.contains("1024")));
+        assert_eq!(server.requests(), requests);
+        connection.set(b"key".to_vec(), vec![0; 16]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_uncached_by_default() {
+        let server = testing::MockServer::start().await.unwrap();
+        let connection = server.api().connect().await.unwrap();
+        connection.get(b"key".to_vec()).await.unwrap();
+        connection.get(b"key".to_vec()).await.unwrap();
+        assert_eq!(server.requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_probes() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+        connection.live().await.unwrap();
+        assert!(connection.ready().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_info() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+        let info = connection.info().await.unwrap();
+        assert!(info.cache_size > 0);
+        assert!(info.auth_required);
+        assert!(!info.version.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_compact() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+        let compaction = connection.compact().await.unwrap();
+        assert!(compaction.bytes_after > 0);
+        connection.echo(b"still serving".to_vec()).await.unwrap();
+    }
+
+    #[test]
+    fn test_sequence_gaps() {
+        let mut tracker = SequenceTracker::default();
+        let seqs: Vec<u64> = (0..5).map(|_| tracker.next()).collect();
+        assert_eq!(seqs, vec![0, 1, 2, 3, 4]);
+        for seq in [0, 1, 3, 4] {
+            tracker.answered(seq);
+        }
+        assert_eq!(tracker.gaps(), vec![2]);
+        tracker.answered(2);
+        assert!(tracker.gaps().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sequence_numbers() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld")).with_sequence_numbers();
+        let connection = api.connect().await.unwrap();
+        let mut errors = connection.errors();
+        for expected in 0..3 {
+            let response = connection.send_request(Request::Count).await.unwrap();
+            assert_eq!(response.seq(), Some(expected));
+        }
+        assert!(connection.sequence_gaps().is_empty());
+        assert!(errors.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_echo() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+
+        let payloads = vec![
+            Vec::new(),
+            vec![0u8, 255, 1, 254, 0, 0],
+            (0..256 * 1024)
+                .map(|i| (i % 251) as u8)
+                .collect::<Vec<u8>>(),
+        ];
+        for payload in payloads {
+            let result = connection.echo(payload.clone()).await;
+            assert!(result.is_ok());
+            assert_eq!(result.unwrap(), payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_binary_values() {
+        let plain = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"))
+            .connect()
+            .await
+            .unwrap();
+        let hybrid = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"))
+            .with_binary_values(1024)
+            .connect()
+            .await
+            .unwrap();
+        let value: Vec<u8> = (0..512 * 1024).map(|i| (i % 251) as u8).collect();
+        let set = || {
+            RequestWrapper::new(Request::Set {
+                key: b"binary_values".to_vec(),
+                value: value.clone(),
+            })
+        };
+        let frame_bytes = |connection: &CKeyLockConnection| {
+            let (_, messages, _) = connection.prepare(set()).unwrap();
+            messages.iter().map(Message::len).sum::<usize>()
+        };
+        let (_, messages, _) = hybrid.prepare(set()).unwrap();
+        assert!(matches!(
+            messages[..],
+            [Message::Text(_), Message::Binary(_)]
+        ));
+        assert!(frame_bytes(&hybrid) < value.len() + 1024);
+        assert!(frame_bytes(&plain) > 3 * value.len());
+
+        hybrid
+            .set(b"binary_values".to_vec(), value.clone())
+            .await
+            .unwrap();
+        let stored = plain.get(b"binary_values".to_vec()).await.unwrap();
+        assert_eq!(stored, Some(value));
+        hybrid
+            .set(b"binary_values".to_vec(), b"small".to_vec())
+            .await
+            .unwrap();
+        let stored = hybrid.get(b"binary_values".to_vec()).await.unwrap();
+        assert_eq!(stored, Some(b"small".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_connection_state() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+        assert!(connection.is_connected());
+
+        connection.close().await.unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), connection.wait_closed())
+            .await
+            .unwrap();
+        assert!(!connection.is_connected());
+        assert_eq!(connection.state(), ConnectionState::Closed);
+        assert!(matches!(
+            connection.count().await,
+            Err(Error::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unsigned_response_is_rejected() {
+        let api =
+            CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld")).with_signing_secret("secret");
+        let connection = api.connect().await.unwrap();
+        assert!(matches!(
+            connection.echo(b"hello".to_vec()).await,
+            Err(Error::InvalidSignature)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_background_errors_are_reported() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            // Wait for the client to subscribe before misbehaving.
+            ws.next().await;
+            ws.send(Message::Text("not a server message".into()))
+                .await
+                .unwrap();
+            ws.send(Message::Binary(vec![1, 2, 3].into()))
+                .await
+                .unwrap();
+            while ws.next().await.is_some() {}
+        });
+
+        let connection = CKeyLockAPI::new(&addr, None).connect().await.unwrap();
+        let mut errors = connection.errors();
+        let received = async {
+            let first = errors.recv().await.unwrap();
+            let second = errors.recv().await.unwrap();
+            (first, second)
+        };
+        let (first, second) = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            tokio::select! {
+                received = received => received,
+                _ = connection.count() => panic!("The fake server never answers"),
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(first, ConnectionError::MalformedFrame(_)));
+        assert_eq!(second, ConnectionError::UnexpectedFrame("binary"));
+    }
+
+    #[test]
+    fn test_unattributed_error_is_reported() {
+        let shared = Shared {
+            pending: std::sync::Mutex::new(HashMap::new()),
+            state: watch::Sender::new(ConnectionState::Open),
+            errors: broadcast::Sender::new(ERROR_EVENTS_CAPACITY),
+            sequence: std::sync::Mutex::default(),
+        };
+        let mut errors = shared.errors.subscribe();
+        let json = ErrorResponse {
+            message: "Unsupported message type".to_string(),
+            code: ErrorCode::BadRequest,
+            reqid: Vec::new(),
+            operation: String::new(),
+            retry_after_ms: None,
+        }
+        .to_json()
+        .unwrap();
+        shared.dispatch(&json);
+        assert_eq!(
+            errors.try_recv().unwrap(),
+            ConnectionError::Rejected {
+                code: ErrorCode::BadRequest,
+                message: "Unsupported message type".to_string(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_oversized_close_fails_pending() {
+        let shared = Shared {
+            pending: std::sync::Mutex::new(HashMap::new()),
+            state: watch::Sender::new(ConnectionState::Open),
+            errors: broadcast::Sender::new(ERROR_EVENTS_CAPACITY),
+            sequence: std::sync::Mutex::default(),
+        };
+        let response = shared.register(Arc::from(&[1u8][..])).unwrap();
+        shared.close(Some(CloseFrame {
+            code: ckeylock_core::close::MESSAGE_TOO_LARGE.into(),
+            reason: "Message too long: 2048 > 1024".into(),
+        }));
+        match response.await.unwrap() {
+            Err(Error::TooLarge(reason)) => assert!(reason.contains("1024")),
+            other => panic!("Expected too large error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_with_wrong_password() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("wrong"));
+        assert!(matches!(api.connect().await, Err(Error::Unauthorized)));
+        let api = CKeyLockAPI::new("127.0.0.1:5830", None);
+        assert!(matches!(api.connect().await, Err(Error::Unauthorized)));
+    }
+
+    fn error_response(code: ErrorCode) -> ErrorResponse {
+        ErrorResponse {
+            message: "message".to_string(),
+            code,
+            reqid: Vec::new(),
+            operation: String::new(),
+            retry_after_ms: None,
+        }
+    }
+
+    #[test]
+    fn test_error_code_mapping() {
+        assert!(matches!(
+            Error::from(error_response(ErrorCode::Unauthorized)),
+            Error::Unauthorized
+        ));
+        assert!(matches!(
+            Error::from(error_response(ErrorCode::NotFound)),
+            Error::NotFound(m) if m == "message"
+        ));
+        assert!(matches!(
+            Error::from(error_response(ErrorCode::RateLimited)),
+            Error::RateLimited {
+                retry_after: None,
+                ..
+            }
+        ));
+        let hinted = ErrorResponse {
+            retry_after_ms: Some(250),
+            ..error_response(ErrorCode::RateLimited)
+        };
+        assert!(matches!(
+            Error::from(hinted),
+            Error::RateLimited { retry_after: Some(after), .. } if after == Duration::from_millis(250)
+        ));
+        assert!(matches!(
+            Error::from(error_response(ErrorCode::TooLarge)),
+            Error::TooLarge(_)
+        ));
+        assert!(matches!(
+            Error::from(error_response(ErrorCode::BadRequest)),
+            Error::BadRequest(_)
+        ));
+        assert!(matches!(
+            Error::from(error_response(ErrorCode::Maintenance)),
+            Error::Maintenance(_)
+        ));
+        assert!(matches!(
+            Error::from(error_response(ErrorCode::RequestLimitReached)),
+            Error::RequestLimitReached
+        ));
+        let busy = ErrorResponse {
+            retry_after_ms: Some(50),
+            ..error_response(ErrorCode::Busy)
+        };
+        assert!(matches!(
+            Error::from(busy),
+            Error::Busy { retry_after: Some(after), .. } if after == Duration::from_millis(50)
+        ));
+        assert!(matches!(
+            Error::from(error_response(ErrorCode::Internal)),
+            Error::Server(_)
+        ));
+        let named = ErrorResponse {
+            operation: "get_range".to_string(),
+            ..error_response(ErrorCode::BadRequest)
+        };
+        assert!(matches!(
+            Error::from(named),
+            Error::BadRequest(m) if m == "get_range failed: message"
+        ));
+    }
+
+    #[test]
+    fn test_unknown_error_code_maps_to_server_error() {
+        let err: ErrorResponse =
+            serde_json::from_str(r#"{"message":"m","code":"brand_new","reqid":[]}"#).unwrap();
+        assert!(matches!(Error::from(err), Error::Server(_)));
+    }
+
+    #[tokio::test]
+    async fn test_delete_prefix() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+
+        let value = b"value".to_vec();
+        connection
+            .set(b"prefix_test:a".to_vec(), value.clone())
+            .await
+            .unwrap();
+        connection
+            .set(b"prefix_test:b".to_vec(), value.clone())
+            .await
+            .unwrap();
+        connection
+            .set(b"prefix_test_other".to_vec(), value.clone())
+            .await
+            .unwrap();
+
+        let result = connection.delete_prefix(b"prefix_test:".to_vec()).await;
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), 2);
+        assert!(!connection.exists(b"prefix_test:a".to_vec()).await.unwrap());
+        assert!(
+            connection
+                .exists(b"prefix_test_other".to_vec())
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compressed_list_and_batch_get() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..200)
+            .map(|i| format!("compressed_test:{:04}", i).into_bytes())
+            .collect();
+        for key in &keys {
+            connection.set(key.clone(), key.clone()).await.unwrap();
+        }
+
+        let listed = connection.list_compressed().await.unwrap();
+        for key in &keys {
+            assert!(listed.contains(key));
+        }
+        let values = connection.batch_get_compressed(keys.clone()).await.unwrap();
+        assert_eq!(values, keys.into_iter().map(Some).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_batched_sends() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"))
+            .with_batching(Duration::from_millis(20))
+            .with_no_delay(true);
+        let connection = api.connect().await.unwrap();
+
+        let payloads: Vec<Vec<u8>> = (0..50u8).map(|i| vec![i; 16]).collect();
+        let echoed = futures_util::future::join_all(
+            payloads
+                .iter()
+                .map(|payload| connection.echo(payload.clone())),
+        )
+        .await;
+        for (payload, echoed) in payloads.iter().zip(echoed) {
+            assert_eq!(&echoed.unwrap(), payload);
+        }
+        assert!(connection.inner.flushes.load(Ordering::Relaxed) < payloads.len());
+    }
+
+    #[tokio::test]
+    async fn test_clear_cache() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+        let key = b"clear_cache_test_key".to_vec();
+        connection
+            .set(key.clone(), b"value".to_vec())
+            .await
+            .unwrap();
+
+        connection.clear_cache().await.unwrap();
+        let misses = connection.stats().await.unwrap().cache_misses;
+        assert_eq!(
+            connection.get(key.clone()).await.unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert!(connection.stats().await.unwrap().cache_misses > misses);
+        assert!(connection.exists(key).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_get_range() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+        let key = b"get_range_test_key".to_vec();
+        connection
+            .set(key.clone(), b"hello world".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            connection.get_range(key.clone(), 6, 11).await.unwrap(),
+            Some(b"world".to_vec())
+        );
+        assert_eq!(
+            connection.get_range(key.clone(), 20, 30).await.unwrap(),
+            Some(Vec::new())
+        );
+        assert!(matches!(
+            connection.get_range(key, 4, 2).await,
+            Err(Error::BadRequest(_))
+        ));
+        assert_eq!(
+            connection
+                .get_range(b"get_range_missing_key".to_vec(), 0, 1)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stat() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+        let key = b"stat_test_key".to_vec();
+        connection.set(key.clone(), vec![7; 32]).await.unwrap();
+
+        let stat = connection.stat(key.clone()).await.unwrap();
+        assert_eq!(
+            stat,
+            KeyStat {
+                exists: true,
+                size: Some(32),
+                ttl_ms: None,
+                last_access_ms: None,
+                last_modified_ms: stat.last_modified_ms,
+            }
+        );
+        assert!(stat.last_modified_ms.is_some());
+        connection.get(key.clone()).await.unwrap();
+        let read = connection.stat(key.clone()).await.unwrap();
+        assert!(read.last_access_ms >= stat.last_modified_ms);
+        assert_eq!(read.last_modified_ms, stat.last_modified_ms);
+        connection
+            .expire(key.clone(), std::time::Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert!(connection.stat(key).await.unwrap().ttl_ms.is_some());
+
+        let missing = connection.stat(b"stat_missing_key".to_vec()).await.unwrap();
+        assert!(!missing.exists);
+        assert_eq!(missing.size, None);
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+        let key = b"transaction_test_key".to_vec();
+
+        let results = connection
+            .transaction(
+                vec![
+                    Request::Set {
+                        key: key.clone(),
+                        value: b"value".to_vec(),
+                    },
+                    Request::Get {
+                        key: key.clone(),
+                        bypass_cache: false,
+                    },
+                    Request::Delete { key: key.clone() },
+                ],
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(matches!(
+            &results[1],
+            Ok(ckeylock_core::ResponseData::GetResponse { value: Some(v) }) if v == b"value"
+        ));
+        assert!(!connection.exists(key).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_undelete_without_soft_delete() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+        let key = b"undelete_test_key".to_vec();
+        connection.set(key.clone(), b"v".to_vec()).await.unwrap();
+        connection.delete(key.clone()).await.unwrap();
+
+        assert!(!connection.undelete(key.clone()).await.unwrap());
+        assert!(!connection.exists(key).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_batch_exists_count() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+        let prefix = format!("exists-count-{}:", uuid::Uuid::new_v4());
+        let key = |i: usize| format!("{}{}", prefix, i).into_bytes();
+        for i in 0..6 {
+            connection.set(key(i), b"v".to_vec()).await.unwrap();
+        }
+
+        let keys = (4..10).map(key).collect();
+        assert_eq!(connection.batch_exists_count(keys).await.unwrap(), 2);
+        assert_eq!(connection.batch_exists_count(Vec::new()).await.unwrap(), 0);
+        connection.delete_prefix(prefix.into_bytes()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_batch_set_ex() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+        let prefix = format!("batch-set-ex-{}:", uuid::Uuid::new_v4());
+        let key = |name: &str| format!("{}{}", prefix, name).into_bytes();
+        let entries = vec![
+            (
+                key("short"),
+                b"1".to_vec(),
+                Some(Duration::from_millis(100)),
+            ),
+            (key("long"), b"2".to_vec(), Some(Duration::from_secs(60))),
+            (key("persistent"), b"3".to_vec(), None),
+        ];
+        assert_eq!(connection.batch_set_ex(entries).await.unwrap(), 3);
+
+        assert!(
+            connection
+                .stat(key("persistent"))
+                .await
+                .unwrap()
+                .ttl_ms
+                .is_none()
+        );
+        let ttl = connection.stat(key("long")).await.unwrap().ttl_ms;
+        assert!(ttl.is_some_and(|ttl| ttl > 1000 && ttl <= 60_000));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!connection.exists(key("short")).await.unwrap());
+        assert_eq!(
+            connection.get(key("persistent")).await.unwrap(),
+            Some(b"3".to_vec())
+        );
+        connection.delete_prefix(prefix.into_bytes()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_count_prefix() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+        let prefix = format!("count-prefix-{}:", uuid::Uuid::new_v4());
+        let key = |name: &str| format!("{}{}", prefix, name).into_bytes();
+        for name in ["a", "a:1", "a:2", "b"] {
+            connection.set(key(name), b"v".to_vec()).await.unwrap();
+        }
+
+        assert_eq!(connection.count_prefix(key("")).await.unwrap(), 4);
+        assert_eq!(connection.count_prefix(key("a")).await.unwrap(), 3);
+        assert_eq!(connection.count_prefix(key("a:")).await.unwrap(), 2);
+        assert_eq!(connection.count_prefix(key("c")).await.unwrap(), 0);
+        connection.delete_prefix(key("")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scan_resumes_after_disconnect() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let prefix = format!("scan-{}:", uuid::Uuid::new_v4()).into_bytes();
+        let connection = api.connect().await.unwrap();
+        let mut expected = Vec::new();
+        for i in 0..25u8 {
+            let key = [prefix.as_slice(), format!("{:02}", i).as_bytes()].concat();
+            connection.set(key.clone(), vec![i]).await.unwrap();
+            expected.push((key, vec![i]));
+        }
+
+        let first = connection.scan(prefix.clone(), None, 10).await.unwrap();
+        drop(connection);
+        let mut scanned = first.entries;
+        let mut cursor = first.cursor;
+        let connection = api.connect().await.unwrap();
+        while cursor.is_some() {
+            let page = connection.scan(prefix.clone(), cursor, 10).await.unwrap();
+            assert!(page.entries.len() <= 10);
+            scanned.extend(page.entries);
+            cursor = page.cursor;
+        }
+        assert_eq!(scanned, expected);
+        connection.delete_prefix(prefix).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_set_if() {
+        use ckeylock_core::Condition;
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+        let key = format!("set-if-{}", uuid::Uuid::new_v4()).into_bytes();
+
+        assert!(
+            connection
+                .set_if(key.clone(), b"a".to_vec(), Condition::NotExists)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !connection
+                .set_if(key.clone(), b"b".to_vec(), Condition::Equals(b"b".to_vec()))
+                .await
+                .unwrap()
+        );
+        assert!(
+            connection
+                .set_if(
+                    key.clone(),
+                    b"bb".to_vec(),
+                    Condition::Equals(b"a".to_vec())
+                )
+                .await
+                .unwrap()
+        );
+        assert!(
+            !connection
+                .set_if(key.clone(), b"c".to_vec(), Condition::LengthLessThan(2))
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            connection.get(key.clone()).await.unwrap(),
+            Some(b"bb".to_vec())
+        );
+        connection.delete(key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expire_and_persist() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+
+        let expiring = b"expire_test_key".to_vec();
+        let persisted = b"persist_test_key".to_vec();
+        let value = b"value".to_vec();
+        connection
+            .set(expiring.clone(), value.clone())
+            .await
+            .unwrap();
+        connection
+            .set(persisted.clone(), value.clone())
+            .await
+            .unwrap();
+
+        let ttl = std::time::Duration::from_millis(200);
+        assert!(connection.expire(expiring.clone(), ttl).await.unwrap());
+        assert!(connection.expire(persisted.clone(), ttl).await.unwrap());
+        assert!(connection.persist(persisted.clone()).await.unwrap());
+        assert!(
+            !connection
+                .expire(b"expire_missing_key".to_vec(), ttl)
+                .await
+                .unwrap()
+        );
+
+        tokio::time::sleep(std::time::Duration::from_millis(400)).await;
+        assert_eq!(connection.get(expiring).await.unwrap(), None);
+        assert_eq!(connection.get(persisted).await.unwrap(), Some(value));
+    }
+
+    #[tokio::test]
+    async fn test_max_in_flight() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld")).with_max_in_flight(2);
+        let connection = Arc::new(api.connect().await.unwrap());
+
+        let tasks: Vec<_> = (0..20u8)
+            .map(|i| {
+                let connection = Arc::clone(&connection);
+                tokio::spawn(async move { connection.echo(vec![i; 1024]).await })
+            })
+            .collect();
+        let mut max_seen = 0;
+        while tasks.iter().any(|t| !t.is_finished()) {
+            max_seen = max_seen.max(connection.in_flight());
+            tokio::task::yield_now().await;
+        }
+        assert!(max_seen <= 2);
+        for (i, task) in tasks.into_iter().enumerate() {
+            assert_eq!(task.await.unwrap().unwrap(), vec![i as u8; 1024]);
+        }
+    }
 }
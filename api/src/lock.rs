@@ -0,0 +1,233 @@
+//! Cooperative locks built on set-if-absent with a TTL.
+//!
+//! A lock is a key holding a random token. Only the holder of that token can
+//! renew or release it, so a guard whose lease lapsed and was taken over by
+//! someone else can never delete the new holder's lock.
+
+use crate::{CKeyLockConnection, Error};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Prefix of the keys locks are stored under.
+pub const LOCK_PREFIX: &[u8] = b"lock:";
+
+impl CKeyLockConnection {
+    /// Tries to take the lock `name` for `ttl`. Returns `None` if it is held
+    /// by someone else. The returned guard renews the lease in the background
+    /// and releases it when dropped.
+    pub async fn acquire_lock(
+        &self,
+        name: &str,
+        ttl: Duration,
+    ) -> Result<Option<LockGuard>, Error> {
+        let key = [LOCK_PREFIX, name.as_bytes()].concat();
+        let token = uuid::Uuid::new_v4().to_string().into_bytes();
+        if !self
+            .set_if_absent(key.clone(), token.clone(), Some(ttl))
+            .await?
+        {
+            return Ok(None);
+        }
+        let held = Arc::new(AtomicBool::new(true));
+        let renewal = tokio::spawn(renew(
+            self.clone(),
+            key.clone(),
+            token.clone(),
+            ttl,
+            Arc::clone(&held),
+        ));
+        Ok(Some(LockGuard {
+            connection: self.clone(),
+            key,
+            token,
+            held,
+            renewal,
+            released: false,
+        }))
+    }
+}
+
+/// A held lock. Dropping it releases the lock in the background; use
+/// [`LockGuard::release`] to wait for that and see whether it was still held.
+pub struct LockGuard {
+    connection: CKeyLockConnection,
+    key: Vec<u8>,
+    token: Vec<u8>,
+    held: Arc<AtomicBool>,
+    renewal: JoinHandle<()>,
+    released: bool,
+}
+
+impl LockGuard {
+    /// The random token stored under the lock key, usable as a fencing token.
+    pub fn token(&self) -> &[u8] {
+        &self.token
+    }
+
+    /// Whether the lease is still believed to be held. Turns false once a
+    /// renewal finds the lock gone or owned by someone else, or renewals kept
+    /// failing for longer than the TTL.
+    pub fn is_held(&self) -> bool {
+        self.held.load(Ordering::Relaxed)
+    }
+
+    /// Releases the lock. Returns false if it had already been lost.
+    pub async fn release(mut self) -> Result<bool, Error> {
+        self.renewal.abort();
+        self.released = true;
+        self.held.store(false, Ordering::Relaxed);
+        self.connection
+            .delete_if_equals(self.key.clone(), self.token.clone())
+            .await
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        self.renewal.abort();
+        if self.released {
+            return;
+        }
+        self.held.store(false, Ordering::Relaxed);
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let connection = self.connection.clone();
+            let key = std::mem::take(&mut self.key);
+            let token = std::mem::take(&mut self.token);
+            handle.spawn(async move {
+                let _ = connection.delete_if_equals(key, token).await;
+            });
+        }
+    }
+}
+
+async fn renew(
+    connection: CKeyLockConnection,
+    key: Vec<u8>,
+    token: Vec<u8>,
+    ttl: Duration,
+    held: Arc<AtomicBool>,
+) {
+    let mut renewed_at = Instant::now();
+    // `interval_at` panics on a zero period, which a TTL under 3ns gives.
+    let period = (ttl / 3).max(Duration::from_millis(1));
+    let mut ticker = tokio::time::interval_at(renewed_at + period, period);
+    loop {
+        ticker.tick().await;
+        match connection
+            .expire_if_equals(key.clone(), token.clone(), ttl)
+            .await
+        {
+            Ok(true) => renewed_at = Instant::now(),
+            Ok(false) => break,
+            Err(_) if renewed_at.elapsed() >= ttl => break,
+            Err(_) => {}
+        }
+    }
+    held.store(false, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CKeyLockAPI;
+
+    async fn connect() -> CKeyLockConnection {
+        CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"))
+            .connect()
+            .await
+            .unwrap()
+    }
+
+    fn unique(name: &str) -> String {
+        format!("{}-{}", name, uuid::Uuid::new_v4())
+    }
+
+    #[tokio::test]
+    async fn test_contended_acquisition() {
+        let (first, second) = (connect().await, connect().await);
+        let name = unique("contended");
+        let ttl = Duration::from_secs(5);
+
+        let guard = first.acquire_lock(&name, ttl).await.unwrap().unwrap();
+        assert!(second.acquire_lock(&name, ttl).await.unwrap().is_none());
+        assert!(guard.release().await.unwrap());
+
+        let guard = second.acquire_lock(&name, ttl).await.unwrap().unwrap();
+        assert!(first.acquire_lock(&name, ttl).await.unwrap().is_none());
+        assert!(guard.release().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_released_on_drop() {
+        let (first, second) = (connect().await, connect().await);
+        let name = unique("dropped");
+        let ttl = Duration::from_secs(30);
+
+        let guard = first.acquire_lock(&name, ttl).await.unwrap().unwrap();
+        drop(guard);
+        let guard = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(guard) = second.acquire_lock(&name, ttl).await.unwrap() {
+                    break guard;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("lock was not released on drop");
+        assert!(guard.release().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_renewed_past_ttl() {
+        let (first, second) = (connect().await, connect().await);
+        let name = unique("renewed");
+        let ttl = Duration::from_millis(300);
+
+        let guard = first.acquire_lock(&name, ttl).await.unwrap().unwrap();
+        tokio::time::sleep(ttl * 3).await;
+        assert!(guard.is_held());
+        assert!(second.acquire_lock(&name, ttl).await.unwrap().is_none());
+        assert!(guard.release().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_tiny_ttl_lapses() {
+        let connection = connect().await;
+        let name = unique("tiny");
+
+        let guard = connection
+            .acquire_lock(&name, Duration::from_nanos(2))
+            .await
+            .unwrap()
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!guard.is_held());
+    }
+
+    #[tokio::test]
+    async fn test_release_is_fenced() {
+        let connection = connect().await;
+        let name = unique("fenced");
+        let key = [LOCK_PREFIX, name.as_bytes()].concat();
+
+        let guard = connection
+            .acquire_lock(&name, Duration::from_secs(5))
+            .await
+            .unwrap()
+            .unwrap();
+        connection
+            .set(key.clone(), b"someone else".to_vec())
+            .await
+            .unwrap();
+        assert!(!guard.release().await.unwrap());
+        assert_eq!(
+            connection.get(key.clone()).await.unwrap(),
+            Some(b"someone else".to_vec())
+        );
+        connection.delete(key).await.unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! Pipelines queue several requests and send them all at once.
+//!
+//! Every request goes out without waiting for the one before it to be
+//! answered, and the responses are awaited together over the multiplexed
+//! connection. Unlike a transaction, the requests aren't atomic: each one
+//! succeeds or fails on its own.
+
+use crate::{CKeyLockConnection, Error};
+use ckeylock_core::{Request, ResponseData};
+use std::time::Duration;
+
+impl CKeyLockConnection {
+    /// Starts an empty pipeline on this connection.
+    pub fn pipeline(&self) -> Pipeline {
+        Pipeline {
+            connection: self.clone(),
+            requests: Vec::new(),
+        }
+    }
+}
+
+pub struct Pipeline {
+    connection: CKeyLockConnection,
+    requests: Vec<Request>,
+}
+
+impl Pipeline {
+    /// Queues any request, for operations without a shorthand below.
+    pub fn request(mut self, request: Request) -> Self {
+        self.requests.push(request);
+        self
+    }
+
+    pub fn get(self, key: Vec<u8>) -> Self {
+        self.request(Request::Get {
+            key,
+            bypass_cache: false,
+        })
+    }
+
+    pub fn set(self, key: Vec<u8>, value: Vec<u8>) -> Self {
+        self.request(Request::Set { key, value })
+    }
+
+    pub fn delete(self, key: Vec<u8>) -> Self {
+        self.request(Request::Delete { key })
+    }
+
+    pub fn exists(self, key: Vec<u8>) -> Self {
+        self.request(Request::Exists { key })
+    }
+
+    pub fn expire(self, key: Vec<u8>, ttl: Duration) -> Self {
+        self.request(Request::Expire { key, ttl })
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Sends every queued request and returns their results in the order
+    /// they were queued.
+    pub async fn execute(self) -> Vec<Result<ResponseData, Error>> {
+        let connection = &self.connection;
+        futures_util::future::join_all(self.requests.into_iter().map(|request| async move {
+            let response = connection.send_request(request).await?;
+            response.data().cloned().ok_or(Error::WrongResponseFormat)
+        }))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CKeyLockAPI;
+
+    #[tokio::test]
+    async fn test_pipeline() {
+        let connection = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"))
+            .connect()
+            .await
+            .unwrap();
+        let prefix = format!("pipeline-{}-", uuid::Uuid::new_v4());
+        let key = |name: &str| format!("{}{}", prefix, name).into_bytes();
+        connection.set(key("old"), b"1".to_vec()).await.unwrap();
+        connection.set(key("gone"), b"3".to_vec()).await.unwrap();
+
+        let pipeline = connection
+            .pipeline()
+            .set(key("new"), b"2".to_vec())
+            .get(key("old"))
+            .delete(key("gone"))
+            .exists(key("old"))
+            .get(key("missing"))
+            .request(Request::GetRange {
+                key: key("old"),
+                start: 2,
+                end: 1,
+            });
+        assert_eq!(pipeline.len(), 6);
+        let results = pipeline.execute().await;
+        assert_eq!(results.len(), 6);
+        assert!(matches!(
+            &results[0],
+            Ok(ResponseData::SetResponse { key: k }) if *k == key("new")
+        ));
+        assert!(matches!(
+            &results[1],
+            Ok(ResponseData::GetResponse { value: Some(v) }) if v == b"1"
+        ));
+        assert!(matches!(
+            &results[2],
+            Ok(ResponseData::DeleteResponse { existed: true, .. })
+        ));
+        assert!(matches!(
+            &results[3],
+            Ok(ResponseData::ExistsResponse { exists: true })
+        ));
+        assert!(matches!(
+            &results[4],
+            Ok(ResponseData::GetResponse { value: None })
+        ));
+        assert!(matches!(&results[5], Err(Error::BadRequest(_))));
+
+        assert_eq!(
+            connection.get(key("new")).await.unwrap(),
+            Some(b"2".to_vec())
+        );
+        assert_eq!(connection.get(key("gone")).await.unwrap(), None);
+        connection.delete(key("new")).await.unwrap();
+        connection.delete(key("old")).await.unwrap();
+        assert!(connection.pipeline().execute().await.is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+//! Streams a stored value through [`AsyncRead`], fetching it with
+//! `GetRange` one chunk at a time, so a large value never has to be held in
+//! memory whole.
+
+use crate::{CKeyLockConnection, Error};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, ReadBuf};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+type Fetch = Pin<Box<dyn Future<Output = Result<Option<Vec<u8>>, Error>> + Send>>;
+
+impl CKeyLockConnection {
+    /// Reads the value of `key` in chunks of [`DEFAULT_CHUNK_SIZE`] bytes as
+    /// the reader is polled. Reading a key that doesn't exist fails with
+    /// [`io::ErrorKind::NotFound`].
+    pub fn value_reader(&self, key: Vec<u8>) -> ValueReader {
+        ValueReader {
+            connection: self.clone(),
+            key,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            offset: 0,
+            chunk: Vec::new(),
+            consumed: 0,
+            fetch: None,
+            finished: false,
+        }
+    }
+}
+
+/// Each chunk is a separate request, so a value written while it is being
+/// read may come out as a mix of the old and new value.
+pub struct ValueReader {
+    connection: CKeyLockConnection,
+    key: Vec<u8>,
+    chunk_size: usize,
+    /// Where the next chunk starts in the value.
+    offset: usize,
+    chunk: Vec<u8>,
+    /// How much of `chunk` was already read out.
+    consumed: usize,
+    fetch: Option<Fetch>,
+    finished: bool,
+}
+
+impl ValueReader {
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    fn start_fetch(&mut self) -> &mut Fetch {
+        let connection = self.connection.clone();
+        let key = self.key.clone();
+        let start = self.offset;
+        let end = start.saturating_add(self.chunk_size);
+        self.fetch.insert(Box::pin(async move {
+            connection.get_range(key, start, end).await
+        }))
+    }
+}
+
+impl AsyncRead for ValueReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let unread = &this.chunk[this.consumed..];
+            if !unread.is_empty() {
+                let len = unread.len().min(buf.remaining());
+                buf.put_slice(&unread[..len]);
+                this.consumed += len;
+                return Poll::Ready(Ok(()));
+            }
+            if this.finished {
+                return Poll::Ready(Ok(()));
+            }
+            let fetch = match &mut this.fetch {
+                Some(fetch) => fetch,
+                None => this.start_fetch(),
+            };
+            let fetched = ready!(fetch.as_mut().poll(cx));
+            this.fetch = None;
+            match fetched {
+                Ok(Some(chunk)) => {
+                    this.finished = chunk.len() < this.chunk_size;
+                    this.offset += chunk.len();
+                    this.chunk = chunk;
+                    this.consumed = 0;
+                }
+                Ok(None) => {
+                    this.finished = true;
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "Key does not exist",
+                    )));
+                }
+                Err(e) => {
+                    this.finished = true;
+                    return Poll::Ready(Err(io::Error::other(e)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CKeyLockAPI;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_value_reader() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+        let key = b"value_reader_test_blob".to_vec();
+        let value: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        connection.set(key.clone(), value.clone()).await.unwrap();
+
+        let mut streamed = Vec::new();
+        connection
+            .value_reader(key.clone())
+            .with_chunk_size(100_000)
+            .read_to_end(&mut streamed)
+            .await
+            .unwrap();
+        assert_eq!(
+            streamed,
+            connection.get(key.clone()).await.unwrap().unwrap()
+        );
+
+        // A chunk size that divides the value evenly ends on an empty chunk.
+        let mut small = [0u8; 7];
+        let mut reader = connection.value_reader(key.clone()).with_chunk_size(50_000);
+        reader.read_exact(&mut small).await.unwrap();
+        assert_eq!(small, value[..7]);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, value[7..]);
+
+        connection.delete(key).await.unwrap();
+        let missing = connection
+            .value_reader(b"value_reader_test_missing".to_vec())
+            .read_to_end(&mut Vec::new())
+            .await;
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,691 @@
+//! An in-process server for testing code built on this crate.
+//!
+//! [`MockServer`] accepts real WebSocket connections and speaks the same
+//! protocol as the ckeylock server, but keeps its keys in a plain in-memory
+//! map that lives as long as the mock. Nothing is written to disk.
+
+use crate::CKeyLockAPI;
+use ckeylock_core::response::{ErrorCode, ErrorResponse};
+use ckeylock_core::{
+    ConnectionConfig, Request, RequestWrapper, Response, ResponseData, ServerInfo, Stats,
+};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::accept_hdr_async_with_config;
+use tokio_tungstenite::tungstenite::handshake::server::{
+    ErrorResponse as HandshakeError, Request as HandshakeRequest, Response as HandshakeResponse,
+};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::{Message, WebSocketConfig};
+
+/// Keys per frame when a client asks for a streamed listing.
+const STREAM_FRAME_KEYS: usize = 1000;
+const COMPRESSION_MIN_SIZE: usize = 1024;
+
+/// A ckeylock server running inside the current Tokio runtime. It stops
+/// accepting connections when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    password: Option<String>,
+    requests: Arc<AtomicUsize>,
+    state: Arc<Mutex<State>>,
+    accept: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Starts a mock on a free local port that accepts any client.
+    pub async fn start() -> std::io::Result<Self> {
+        Self::spawn(None).await
+    }
+
+    /// Starts a mock that only accepts clients sending `password`.
+    pub async fn with_password(password: &str) -> std::io::Result<Self> {
+        Self::spawn(Some(password.to_owned())).await
+    }
+
+    async fn spawn(password: Option<String>) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State::default()));
+        let requests = Arc::new(AtomicUsize::new(0));
+        let accept = tokio::spawn({
+            let password = password.clone();
+            let requests = Arc::clone(&requests);
+            let state = Arc::clone(&state);
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve(
+                        stream,
+                        password.clone(),
+                        Arc::clone(&state),
+                        Arc::clone(&requests),
+                    ));
+                }
+            }
+        });
+        Ok(Self {
+            addr,
+            password,
+            requests,
+            state,
+            accept,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The `host:port` to hand to [`CKeyLockAPI::new`].
+    pub fn bind(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// How many requests the mock has received, across all connections.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Rejects requests past `max` in each `window`, across all connections,
+    /// with a `RateLimited` error saying when the window reopens.
+    pub fn set_rate_limit(&self, max: usize, window: Duration) {
+        self.state.lock().unwrap().rate_limit = Some(RateLimit {
+            max,
+            window,
+            started: Instant::now(),
+            used: 0,
+        });
+    }
+
+    /// Makes new connections advertise `max` as the largest message the mock
+    /// accepts, and drop clients that send more.
+    pub fn set_max_message_size(&self, max: usize) {
+        self.state.lock().unwrap().max_message_size = Some(max);
+    }
+
+    /// A client configured to connect to this mock.
+    pub fn api(&self) -> CKeyLockAPI {
+        CKeyLockAPI::new(&self.bind(), self.password.as_deref())
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.accept.abort();
+    }
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<Vec<u8>, Entry>,
+    maintenance: Option<Option<Instant>>,
+    rate_limit: Option<RateLimit>,
+    max_message_size: Option<usize>,
+}
+
+/// A fixed-window request limit.
+struct RateLimit {
+    max: usize,
+    window: Duration,
+    started: Instant,
+    used: usize,
+}
+
+impl RateLimit {
+    /// Counts a request, returning how long until the next window if it is
+    /// over the limit.
+    fn take(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        if now >= self.started + self.window {
+            self.started = now;
+            self.used = 0;
+        }
+        if self.used >= self.max {
+            return Some(self.started + self.window - now);
+        }
+        self.used += 1;
+        None
+    }
+}
+
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+}
+
+impl State {
+    fn purge_expired(&mut self) {
+        let now = Instant::now();
+        self.entries
+            .retain(|_, entry| entry.expires_at.is_none_or(|at| at > now));
+        if let Some(Some(until)) = self.maintenance
+            && until <= now
+        {
+            self.maintenance = None;
+        }
+    }
+
+    fn value(&self, key: &[u8]) -> Option<&Vec<u8>> {
+        self.entries.get(key).map(|entry| &entry.value)
+    }
+
+    fn apply(&mut self, request: Request) -> Result<ResponseData, (ErrorCode, String)> {
+        self.purge_expired();
+        if request.is_mutation() && self.maintenance.is_some() {
+            return Err((
+                ErrorCode::Maintenance,
+                "Server is in maintenance mode".to_string(),
+            ));
+        }
+        Ok(match request {
+            Request::Set { key, value } => {
+                self.entries.insert(
+                    key.clone(),
+                    Entry {
+                        value,
+                        expires_at: None,
+                    },
+                );
+                ResponseData::SetResponse { key }
+            }
+            Request::Get { key, .. } => ResponseData::GetResponse {
+                value: self.value(&key).cloned(),
+            },
+            Request::GetRange { key, start, end } => {
+                if start > end {
+                    return Err((
+                        ErrorCode::BadRequest,
+                        format!("Invalid range {}..{}", start, end),
+                    ));
+                }
+                ResponseData::GetRangeResponse {
+                    value: self.value(&key).map(|value| {
+                        let end = end.min(value.len());
+                        value[start.min(end)..end].to_vec()
+                    }),
+                }
+            }
+            Request::BatchExistsCount { keys } => ResponseData::CountResponse {
+                count: keys.iter().filter(|key| self.value(key).is_some()).count(),
+            },
+            Request::BatchSetEx { entries } => {
+                let stored = entries.len();
+                for (key, value, ttl) in entries {
+                    let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+                    self.entries.insert(key, Entry { value, expires_at });
+                }
+                ResponseData::BatchSetExResponse { stored }
+            }
+            Request::BatchGet { keys } => ResponseData::BatchGetResponse {
+                values: keys.iter().map(|key| self.value(key).cloned()).collect(),
+            },
+            Request::Delete { key } => match self.entries.remove(&key) {
+                Some(entry) => ResponseData::DeleteResponse {
+                    key: Some(key),
+                    value: Some(entry.value),
+                    existed: true,
+                },
+                None => ResponseData::DeleteResponse {
+                    key: None,
+                    value: None,
+                    existed: false,
+                },
+            },
+            Request::DeletePrefix { prefix } => {
+                let before = self.entries.len();
+                self.entries.retain(|key, _| !key.starts_with(&prefix));
+                ResponseData::DeletePrefixResponse {
+                    deleted: before - self.entries.len(),
+                }
+            }
+            Request::List => {
+                let mut keys: Vec<Vec<u8>> = self.entries.keys().cloned().collect();
+                keys.sort();
+                ResponseData::ListResponse {
+                    total_estimate: keys.len(),
+                    keys,
+                    truncated: false,
+                }
+            }
+            Request::Exists { key } => ResponseData::ExistsResponse {
+                exists: self.entries.contains_key(&key),
+            },
+            Request::Count => ResponseData::CountResponse {
+                count: self.entries.len(),
+            },
+            Request::CountPrefix { prefix } => ResponseData::CountResponse {
+                count: self
+                    .entries
+                    .keys()
+                    .filter(|key| key.starts_with(&prefix))
+                    .count(),
+            },
+            Request::Scan {
+                prefix,
+                after,
+                limit,
+            } => {
+                let mut keys: Vec<_> = self
+                    .entries
+                    .keys()
+                    .filter(|key| key.starts_with(&prefix))
+                    .filter(|key| after.as_ref().is_none_or(|after| *key > after))
+                    .collect();
+                keys.sort();
+                let limit = limit.max(1);
+                let cursor = (keys.len() > limit).then(|| keys[limit - 1].clone());
+                let entries = keys
+                    .into_iter()
+                    .take(limit)
+                    .map(|key| (key.clone(), self.entries[key].value.clone()))
+                    .collect();
+                ResponseData::ScanResponse { entries, cursor }
+            }
+            Request::Clear => {
+                self.entries.clear();
+                ResponseData::ClearResponse
+            }
+            Request::ClearCache => ResponseData::ClearCacheResponse { dropped: 0 },
+            Request::Echo { payload } => ResponseData::EchoResponse { payload },
+            Request::Stats => ResponseData::StatsResponse {
+                stats: Stats {
+                    keys: self.entries.len(),
+                    ..Default::default()
+                },
+            },
+            Request::Expire { key, ttl } => ResponseData::ExpireResponse {
+                existed: self.expire(&key, ttl),
+            },
+            // The mock doesn't keep deleted values.
+            Request::Undelete { .. } => ResponseData::UndeleteResponse { restored: false },
+            Request::Persist { key } => {
+                let entry = self.entries.get_mut(&key);
+                let existed = entry.is_some();
+                if let Some(entry) = entry {
+                    entry.expires_at = None;
+                }
+                ResponseData::PersistResponse { existed }
+            }
+            Request::Stat { key } => match self.entries.get(&key) {
+                Some(entry) => ResponseData::StatResponse {
+                    exists: true,
+                    size: Some(entry.value.len()),
+                    ttl_ms: entry
+                        .expires_at
+                        .map(|at| at.saturating_duration_since(Instant::now()).as_millis() as u64),
+                    last_access_ms: None,
+                    last_modified_ms: None,
+                },
+                None => ResponseData::StatResponse {
+                    exists: false,
+                    size: None,
+                    ttl_ms: None,
+                    last_access_ms: None,
+                    last_modified_ms: None,
+                },
+            },
+            Request::ReplaceAll {
+                entries,
+                validate_only: true,
+            } => ResponseData::ValidationResponse {
+                results: vec![Ok(()); entries.len()],
+            },
+            Request::ReplaceAll { entries, .. } => {
+                self.entries = entries
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let entry = Entry {
+                            value,
+                            expires_at: None,
+                        };
+                        (key, entry)
+                    })
+                    .collect();
+                ResponseData::ReplaceAllResponse {
+                    loaded: self.entries.len(),
+                }
+            }
+            Request::SetIfAbsent { key, value, ttl } => {
+                let stored = !self.entries.contains_key(&key);
+                if stored {
+                    let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+                    self.entries.insert(key, Entry { value, expires_at });
+                }
+                ResponseData::SetIfAbsentResponse { stored }
+            }
+            Request::DeleteIfEquals { key, value } => {
+                let deleted = self.value(&key) == Some(&value);
+                if deleted {
+                    self.entries.remove(&key);
+                }
+                ResponseData::DeleteIfEqualsResponse { deleted }
+            }
+            Request::ExpireIfEquals { key, value, ttl } => ResponseData::ExpireResponse {
+                existed: self.value(&key) == Some(&value) && self.expire(&key, ttl),
+            },
+            Request::SetIf {
+                key,
+                value,
+                condition,
+            } => {
+                let stored = condition.holds(self.value(&key).map(Vec::as_slice));
+                if stored {
+                    self.entries.insert(
+                        key,
+                        Entry {
+                            value,
+                            expires_at: None,
+                        },
+                    );
+                }
+                ResponseData::SetIfResponse { stored }
+            }
+            Request::Maintenance { enabled, ttl } => {
+                self.maintenance = enabled.then(|| ttl.map(|ttl| Instant::now() + ttl));
+                ResponseData::MaintenanceResponse { enabled }
+            }
+            Request::Live => ResponseData::LiveResponse,
+            Request::Ready => ResponseData::ReadyResponse {
+                ready: self.maintenance.is_none(),
+            },
+            Request::Info => ResponseData::InfoResponse {
+                info: Box::new(ServerInfo {
+                    version: "mock".to_string(),
+                    persistence: "none".to_string(),
+                    ..Default::default()
+                }),
+            },
+            Request::Compact => ResponseData::CompactResponse {
+                bytes_before: 0,
+                bytes_after: 0,
+            },
+            Request::Transaction {
+                ops,
+                continue_on_error,
+            } => {
+                if ops
+                    .iter()
+                    .any(|op| matches!(op, Request::Transaction { .. }))
+                {
+                    return Err((
+                        ErrorCode::BadRequest,
+                        "Nested transactions are not allowed".to_string(),
+                    ));
+                }
+                let mut results = Vec::with_capacity(ops.len());
+                for op in ops {
+                    let result = self.apply(op).map_err(|(_, message)| message);
+                    let failed = result.is_err();
+                    results.push(result);
+                    if failed && !continue_on_error {
+                        break;
+                    }
+                }
+                ResponseData::TransactionResponse { results }
+            }
+        })
+    }
+
+    fn expire(&mut self, key: &[u8], ttl: Duration) -> bool {
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                entry.expires_at = Some(Instant::now() + ttl);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    password: Option<String>,
+    state: Arc<Mutex<State>>,
+    requests: Arc<AtomicUsize>,
+) {
+    let max_message_size = state.lock().unwrap().max_message_size;
+    #[allow(clippy::result_large_err)]
+    let callback = |req: &HandshakeRequest,
+                    mut res: HandshakeResponse|
+     -> Result<HandshakeResponse, HandshakeError> {
+        let sent = req
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok());
+        if password.is_some() && sent != password.as_deref() {
+            let mut response = HandshakeError::new(Some("Unauthorized".to_string()));
+            *response.status_mut() = StatusCode::UNAUTHORIZED;
+            return Err(response);
+        }
+        let config = ConnectionConfig {
+            protocol_version: ckeylock_core::PROTOCOL_VERSION,
+            max_message_size,
+            compression: true,
+            signing_required: false,
+            features: vec!["binary_values".to_string(), "streaming".to_string()],
+        };
+        if let Some(value) = serde_json::to_string(&config)
+            .ok()
+            .and_then(|json| HeaderValue::try_from(json).ok())
+        {
+            res.headers_mut()
+                .insert(ckeylock_core::CONFIG_HEADER, value);
+        }
+        Ok(res)
+    };
+    let config = WebSocketConfig::default()
+        .max_message_size(max_message_size)
+        .max_frame_size(max_message_size);
+    let Ok(ws) = accept_hdr_async_with_config(stream, callback, Some(config)).await else {
+        return;
+    };
+    let (mut write, mut read) = ws.split();
+    // Requests whose value follows in a binary frame, which the client sends
+    // right after the request itself.
+    let mut awaiting_value = HashMap::new();
+    while let Some(Ok(message)) = read.next().await {
+        let request = match message {
+            Message::Text(text) => {
+                requests.fetch_add(1, Ordering::Relaxed);
+                match serde_json::from_str::<RequestWrapper>(&text) {
+                    Ok(request) if request.binary_value() => {
+                        awaiting_value.insert(request.id().to_vec(), request);
+                        continue;
+                    }
+                    Ok(request) => request,
+                    Err(e) => {
+                        let frame =
+                            error_frame(ErrorCode::BadRequest, e.to_string(), Vec::new(), "");
+                        if write.send(Message::Text(frame.into())).await.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                }
+            }
+            Message::Binary(frame) => {
+                let Ok((id, value)) = ckeylock_core::binary::decode_frame(&frame) else {
+                    continue;
+                };
+                let Some(mut request) = awaiting_value.remove(id) else {
+                    continue;
+                };
+                request.put_value(value.to_vec());
+                request
+            }
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        for frame in respond(request, &state) {
+            if write.send(Message::Text(frame.into())).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn respond(request: RequestWrapper, state: &Mutex<State>) -> Vec<String> {
+    let mut state = state.lock().unwrap();
+    if let Some(retry_after) = state.rate_limit.as_mut().and_then(RateLimit::take) {
+        let limited = ErrorResponse {
+            message: "Too many requests".to_string(),
+            code: ErrorCode::RateLimited,
+            reqid: request.id().to_vec(),
+            operation: request.req().name().to_string(),
+            // Rounded up, so a client waiting exactly this long is let in.
+            retry_after_ms: Some(retry_after.as_micros().div_ceil(1000) as u64),
+        };
+        return vec![limited.to_json().unwrap_or_default()];
+    }
+    let result = state.apply(request.req().clone());
+    drop(state);
+    let data = match result {
+        Ok(data) => data,
+        Err((code, message)) => {
+            return vec![error_frame(
+                code,
+                message,
+                request.id().to_vec(),
+                request.req().name(),
+            )];
+        }
+    };
+    let frames = match data {
+        ResponseData::ListResponse {
+            keys,
+            truncated,
+            total_estimate,
+        } if request.stream() && keys.len() > STREAM_FRAME_KEYS => {
+            let count = keys.len().div_ceil(STREAM_FRAME_KEYS);
+            keys.chunks(STREAM_FRAME_KEYS)
+                .enumerate()
+                .map(|(index, chunk)| {
+                    let data = ResponseData::ListResponse {
+                        keys: chunk.to_vec(),
+                        truncated,
+                        total_estimate,
+                    };
+                    Response::new(Some(data), "OK", request.id().to_vec())
+                        .with_more(index + 1 < count)
+                })
+                .collect()
+        }
+        data => vec![Response::new(Some(data), "OK", request.id().to_vec())],
+    };
+    frames
+        .into_iter()
+        .filter_map(|frame| {
+            let mut frame = frame.with_seq(request.seq());
+            if request.compress() {
+                frame.compress(COMPRESSION_MIN_SIZE).ok()?;
+            }
+            frame.to_json().ok()
+        })
+        .collect()
+}
+
+fn error_frame(code: ErrorCode, message: String, reqid: Vec<u8>, operation: &str) -> String {
+    ErrorResponse {
+        message,
+        code,
+        reqid,
+        operation: operation.to_string(),
+        retry_after_ms: None,
+    }
+    .to_json()
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    #[tokio::test]
+    async fn test_set_and_get() {
+        let server = MockServer::start().await.unwrap();
+        let connection = server.api().connect().await.unwrap();
+        connection
+            .set(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            connection.get(b"key".to_vec()).await.unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(connection.get(b"missing".to_vec()).await.unwrap(), None);
+        assert_eq!(connection.count().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_password_and_errors() {
+        let server = MockServer::with_password("secret").await.unwrap();
+        let wrong = CKeyLockAPI::new(&server.bind(), Some("wrong"));
+        assert!(matches!(wrong.connect().await, Err(Error::Unauthorized)));
+
+        let connection = server.api().connect().await.unwrap();
+        match connection.get_range(b"key".to_vec(), 4, 1).await {
+            Err(Error::BadRequest(message)) => assert!(message.starts_with("get_range failed")),
+            other => panic!("Expected bad request, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_hints() {
+        let server = MockServer::start().await.unwrap();
+        let window = Duration::from_millis(300);
+        server.set_rate_limit(2, window);
+        let connection = server.api().connect().await.unwrap();
+        connection.count().await.unwrap();
+        connection.count().await.unwrap();
+        let retry_after = match connection.count().await {
+            Err(Error::RateLimited {
+                retry_after: Some(retry_after),
+                ..
+            }) => retry_after,
+            other => panic!("Expected rate limit, got {:?}", other),
+        };
+        assert!(retry_after > Duration::ZERO && retry_after <= window);
+
+        tokio::time::sleep(retry_after).await;
+        connection.count().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_locks_and_expiry() {
+        let server = MockServer::start().await.unwrap();
+        let connection = server.api().connect().await.unwrap();
+        let guard = connection
+            .acquire_lock("job", Duration::from_secs(5))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            connection
+                .acquire_lock("job", Duration::from_secs(5))
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(guard.release().await.unwrap());
+
+        connection
+            .set(b"short".to_vec(), b"v".to_vec())
+            .await
+            .unwrap();
+        assert!(
+            connection
+                .expire(b"short".to_vec(), Duration::from_millis(20))
+                .await
+                .unwrap()
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!connection.exists(b"short".to_vec()).await.unwrap());
+    }
+}
@@ -0,0 +1,149 @@
+use crate::codec::{Codec, Json};
+use crate::{CKeyLockConnection, Error};
+use serde::{Serialize, de::DeserializeOwned};
+use std::marker::PhantomData;
+
+/// Wraps a [`CKeyLockConnection`] so values of type `V` are stored with the
+/// codec `C` (JSON unless chosen otherwise) and decoded on the way back, with
+/// keys taken from anything byte-like.
+pub struct TypedConnection<K, V, C = Json> {
+    connection: CKeyLockConnection,
+    _marker: PhantomData<fn(K, V, C)>,
+}
+
+impl<K: AsRef<[u8]>, V: Serialize + DeserializeOwned, C: Codec> TypedConnection<K, V, C> {
+    pub fn new(connection: CKeyLockConnection) -> Self {
+        Self {
+            connection,
+            _marker: PhantomData,
+        }
+    }
+
+    pub async fn set(&self, key: K, value: &V) -> Result<(), Error> {
+        let value = C::encode(value)?;
+        self.connection.set(key.as_ref().to_vec(), value).await?;
+        Ok(())
+    }
+
+    pub async fn get(&self, key: K) -> Result<Option<V>, Error> {
+        match self.connection.get(key.as_ref().to_vec()).await? {
+            Some(value) => Ok(Some(C::decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn batch_get(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> Result<Vec<Option<V>>, Error> {
+        let keys = keys.into_iter().map(|key| key.as_ref().to_vec()).collect();
+        let values = self.connection.batch_get(keys).await?;
+        let mut decoded = Vec::with_capacity(values.len());
+        for value in values {
+            decoded.push(match value {
+                Some(value) => Some(C::decode(&value)?),
+                None => None,
+            });
+        }
+        Ok(decoded)
+    }
+
+    pub async fn delete(&self, key: K) -> Result<bool, Error> {
+        Ok(self.connection.delete(key.as_ref().to_vec()).await?.existed)
+    }
+
+    pub async fn exists(&self, key: K) -> Result<bool, Error> {
+        self.connection.exists(key.as_ref().to_vec()).await
+    }
+
+    /// The underlying byte-level connection.
+    pub fn raw(&self) -> &CKeyLockConnection {
+        &self.connection
+    }
+
+    pub fn into_inner(self) -> CKeyLockConnection {
+        self.connection
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CKeyLockAPI;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Account {
+        name: String,
+        tokens: Vec<String>,
+        balance: u64,
+    }
+
+    #[tokio::test]
+    async fn test_typed_roundtrip() {
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let accounts: TypedConnection<&str, Account> =
+            TypedConnection::new(api.connect().await.unwrap());
+        let account = Account {
+            name: "alice".to_string(),
+            tokens: vec!["a".to_string(), "b".to_string()],
+            balance: 42,
+        };
+
+        accounts.set("typed_test_account", &account).await.unwrap();
+        assert_eq!(
+            accounts.get("typed_test_account").await.unwrap(),
+            Some(account.clone())
+        );
+        assert_eq!(
+            accounts
+                .batch_get(["typed_test_account", "typed_test_missing"])
+                .await
+                .unwrap(),
+            vec![Some(account), None]
+        );
+        assert!(accounts.delete("typed_test_account").await.unwrap());
+        assert_eq!(accounts.get("typed_test_account").await.unwrap(), None);
+
+        accounts
+            .raw()
+            .set(b"typed_test_garbage".to_vec(), b"not json".to_vec())
+            .await
+            .unwrap();
+        assert!(matches!(
+            accounts.get("typed_test_garbage").await,
+            Err(Error::SerializationError(_))
+        ));
+    }
+
+    #[cfg(all(feature = "bincode", feature = "msgpack"))]
+    #[tokio::test]
+    async fn test_typed_codecs() {
+        use crate::codec::{Bincode, MessagePack, Tagged};
+
+        let api = CKeyLockAPI::new("127.0.0.1:5830", Some("helloworld"));
+        let connection = api.connect().await.unwrap();
+        let account = Account {
+            name: "bob".to_string(),
+            tokens: vec!["c".to_string()],
+            balance: 7,
+        };
+        let key = "typed_test_codec_account";
+
+        let bincode: TypedConnection<&str, Account, Bincode> =
+            TypedConnection::new(connection.clone());
+        bincode.set(key, &account).await.unwrap();
+        assert_eq!(bincode.get(key).await.unwrap(), Some(account.clone()));
+        let msgpack: TypedConnection<&str, Account, MessagePack> =
+            TypedConnection::new(connection.clone());
+        msgpack.set(key, &account).await.unwrap();
+        assert_eq!(msgpack.get(key).await.unwrap(), Some(account.clone()));
+
+        let tagged: TypedConnection<&str, Account, Tagged<MessagePack>> =
+            TypedConnection::new(connection.clone());
+        assert!(matches!(tagged.get(key).await, Err(Error::Codec(_))));
+        tagged.set(key, &account).await.unwrap();
+        assert_eq!(tagged.get(key).await.unwrap(), Some(account));
+        assert!(tagged.delete(key).await.unwrap());
+    }
+}
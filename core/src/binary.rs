@@ -0,0 +1,87 @@
+//! Values sent in a binary frame beside their request.
+//!
+//! A request flagged with [`RequestWrapper::binary_value`] goes out as JSON
+//! with an empty value, followed by a binary frame carrying the value raw.
+//! Both frames hold the request id, which is how the server pairs them up.
+//! Large values skip the number-array encoding JSON would give them.
+//!
+//! The frame is the id length as a little-endian `u16`, the id, then the
+//! value.
+//!
+//! [`RequestWrapper::binary_value`]: crate::RequestWrapper::binary_value
+
+use thiserror::Error;
+
+const ID_LEN_BYTES: usize = 2;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BinaryFrameError {
+    #[error("Binary frame of {0} bytes is too short for its request id")]
+    Truncated(usize),
+    #[error("Request id of {0} bytes is too long for a binary frame")]
+    IdTooLong(usize),
+}
+
+pub fn encode_frame(id: &[u8], value: &[u8]) -> Result<Vec<u8>, BinaryFrameError> {
+    let id_len = u16::try_from(id.len()).map_err(|_| BinaryFrameError::IdTooLong(id.len()))?;
+    let mut frame = Vec::with_capacity(ID_LEN_BYTES + id.len() + value.len());
+    frame.extend_from_slice(&id_len.to_le_bytes());
+    frame.extend_from_slice(id);
+    frame.extend_from_slice(value);
+    Ok(frame)
+}
+
+/// Splits a frame into its request id and value.
+pub fn decode_frame(frame: &[u8]) -> Result<(&[u8], &[u8]), BinaryFrameError> {
+    let truncated = || BinaryFrameError::Truncated(frame.len());
+    let (id_len, rest) = frame
+        .split_first_chunk::<ID_LEN_BYTES>()
+        .ok_or_else(truncated)?;
+    let id_len = u16::from_le_bytes(*id_len) as usize;
+    if rest.len() < id_len {
+        return Err(truncated());
+    }
+    Ok(rest.split_at(id_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Request, RequestWrapper};
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let frame = encode_frame(b"id", b"value").unwrap();
+        assert_eq!(frame.len(), 2 + 2 + 5);
+        assert_eq!(decode_frame(&frame), Ok((&b"id"[..], &b"value"[..])));
+        assert_eq!(
+            decode_frame(&[5, 0, 1]),
+            Err(BinaryFrameError::Truncated(3))
+        );
+        assert_eq!(decode_frame(&[1]), Err(BinaryFrameError::Truncated(1)));
+        assert_eq!(
+            encode_frame(&vec![0; 70_000], b""),
+            Err(BinaryFrameError::IdTooLong(70_000))
+        );
+    }
+
+    #[test]
+    fn test_signature_covers_the_value() {
+        let mut request = RequestWrapper::new(Request::Set {
+            key: b"key".to_vec(),
+            value: vec![7; 64],
+        })
+        .with_binary_value()
+        .signed(b"secret")
+        .unwrap();
+        let value = request.take_value().unwrap();
+        assert!(request.binary_value());
+        assert!(!request.verify(b"secret"));
+
+        let mut tampered = request.clone();
+        tampered.put_value(vec![8; 64]);
+        assert!(!tampered.verify(b"secret"));
+        request.put_value(value);
+        assert!(request.verify(b"secret"));
+    }
+}
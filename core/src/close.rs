@@ -0,0 +1,4 @@
+pub const MAX_REQUESTS_REACHED: u16 = 4000;
+/// The standard "message too big" code, sent when a client frame exceeds the
+/// server's `max_message_size`.
+pub const MESSAGE_TOO_LARGE: u16 = 1009;
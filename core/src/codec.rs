@@ -0,0 +1,148 @@
+use crate::request::RequestWrapper;
+use crate::response::ServerMessage;
+use bytes::{Bytes, BytesMut};
+use serde::{Serialize, de::DeserializeOwned};
+use std::marker::PhantomData;
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+pub type ClientCodec = JsonCodec<RequestWrapper, ServerMessage>;
+pub type ServerCodec = JsonCodec<ServerMessage, RequestWrapper>;
+
+/// Length-delimited JSON frames: a 4-byte big-endian length followed by the payload.
+pub struct JsonCodec<E, D> {
+    inner: LengthDelimitedCodec,
+    _marker: PhantomData<fn(E) -> D>,
+}
+
+impl<E, D> JsonCodec<E, D> {
+    pub fn new() -> Self {
+        Self {
+            inner: LengthDelimitedCodec::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn with_max_frame_length(max_frame_length: usize) -> Self {
+        Self {
+            inner: LengthDelimitedCodec::builder()
+                .max_frame_length(max_frame_length)
+                .new_codec(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<E, D> Default for JsonCodec<E, D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E: Serialize, D> Encoder<E> for JsonCodec<E, D> {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: E, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let json = serde_json::to_vec(&item)?;
+        self.inner.encode(Bytes::from(json), dst)?;
+        Ok(())
+    }
+}
+
+impl<E, D: DeserializeOwned> Decoder for JsonCodec<E, D> {
+    type Item = D;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.inner.decode(src)? {
+            Some(frame) => Ok(Some(serde_json::from_slice(&frame)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CodecError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::{ErrorCode, ErrorResponse};
+    use crate::{Request, Response, ResponseData};
+
+    #[test]
+    fn test_request_round_trip() {
+        let request = RequestWrapper::new(Request::Set {
+            key: b"key".to_vec(),
+            value: vec![0, 1, 255],
+        });
+        let mut buf = BytesMut::new();
+        ClientCodec::new()
+            .encode(request.clone(), &mut buf)
+            .unwrap();
+
+        let decoded = ServerCodec::new().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.id(), request.id());
+        assert!(matches!(
+            decoded.req(),
+            Request::Set { key, value } if key == b"key" && value == &vec![0, 1, 255]
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_server_messages_round_trip() {
+        let mut buf = BytesMut::new();
+        let mut server = ServerCodec::new();
+        server
+            .encode(
+                ServerMessage::Response(Response::new(
+                    Some(ResponseData::CountResponse { count: 3 }),
+                    "Counted successfully.",
+                    vec![1],
+                )),
+                &mut buf,
+            )
+            .unwrap();
+        server
+            .encode(
+                ServerMessage::Error(ErrorResponse {
+                    message: "boom".to_string(),
+                    code: ErrorCode::BadRequest,
+                    reqid: vec![2],
+                    operation: "set".to_string(),
+                    retry_after_ms: None,
+                }),
+                &mut buf,
+            )
+            .unwrap();
+
+        let mut client = ClientCodec::new();
+        match client.decode(&mut buf).unwrap() {
+            Some(ServerMessage::Response(response)) => assert_eq!(response.reqid(), vec![1]),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+        match client.decode(&mut buf).unwrap() {
+            Some(ServerMessage::Error(err)) => assert_eq!(err.code, ErrorCode::BadRequest),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_partial_frame() {
+        let mut buf = BytesMut::new();
+        ClientCodec::new()
+            .encode(RequestWrapper::new(Request::Count), &mut buf)
+            .unwrap();
+        let mut partial = buf.split_to(buf.len() - 1);
+
+        let mut codec = ServerCodec::new();
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+        assert!(codec.decode(&mut partial).unwrap().is_some());
+    }
+}
@@ -1,5 +1,21 @@
+pub mod binary;
+pub mod close;
+#[cfg(feature = "codec")]
+pub mod codec;
+pub mod numeric;
 pub mod request;
 pub mod response;
+pub mod signing;
+#[cfg(test)]
+mod wire_format;
 
-pub use request::{Request, RequestWrapper};
-pub use response::{Response, ResponseData, ResponseStatus};
+pub use request::{Condition, Request, RequestWrapper};
+
+/// Handshake header naming the store a connection works on. Connections that
+/// don't send it use the server's default store.
+pub const STORE_HEADER: &str = "Ckeylock-Store";
+/// Handshake response header carrying the server's [`ConnectionConfig`] as JSON.
+pub const CONFIG_HEADER: &str = "Ckeylock-Config";
+/// Version of the wire protocol, bumped on incompatible changes.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub use response::{ConnectionConfig, Response, ResponseData, ResponseStatus, ServerInfo, Stats};
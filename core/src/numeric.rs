@@ -0,0 +1,104 @@
+//! The byte encoding of numeric values.
+//!
+//! Numbers are stored as fixed-width little-endian bytes, 8 bytes for every
+//! supported type, so the server's arithmetic and clients reading the raw
+//! value always agree on what a value means.
+
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum NumericError {
+    #[error("Numeric values are {expected} bytes, got {found}")]
+    InvalidLength { expected: usize, found: usize },
+}
+
+/// A number that can be stored as a value.
+pub trait NumericValue: Sized + Copy {
+    /// Width of the encoded value in bytes.
+    const WIDTH: usize;
+
+    fn encode(self) -> Vec<u8>;
+    fn decode(bytes: &[u8]) -> Result<Self, NumericError>;
+}
+
+macro_rules! numeric_value {
+    ($($ty:ty),*) => {$(
+        impl NumericValue for $ty {
+            const WIDTH: usize = size_of::<$ty>();
+
+            fn encode(self) -> Vec<u8> {
+                self.to_le_bytes().to_vec()
+            }
+
+            fn decode(bytes: &[u8]) -> Result<Self, NumericError> {
+                let bytes = bytes.try_into().map_err(|_| NumericError::InvalidLength {
+                    expected: Self::WIDTH,
+                    found: bytes.len(),
+                })?;
+                Ok(<$ty>::from_le_bytes(bytes))
+            }
+        }
+    )*};
+}
+
+numeric_value!(i64, u64, f64);
+
+pub fn encode<T: NumericValue>(value: T) -> Vec<u8> {
+    value.encode()
+}
+
+pub fn decode<T: NumericValue>(bytes: &[u8]) -> Result<T, NumericError> {
+    T::decode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip<T: NumericValue + PartialEq + std::fmt::Debug>(values: &[T]) {
+        for &value in values {
+            let bytes = encode(value);
+            assert_eq!(bytes.len(), T::WIDTH);
+            assert_eq!(decode::<T>(&bytes).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        roundtrip(&[0i64, 1, -1, 42, -42, i64::MIN, i64::MAX]);
+        roundtrip(&[0u64, 1, 42, u64::MAX]);
+        roundtrip(&[
+            0.0f64,
+            -0.0,
+            1.5,
+            -1.5,
+            f64::MIN,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ]);
+        assert!(decode::<f64>(&encode(f64::NAN)).unwrap().is_nan());
+    }
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(encode(1i64), vec![1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(encode(-1i64), vec![0xff; 8]);
+        assert_eq!(encode(u64::MAX), vec![0xff; 8]);
+        assert_eq!(encode(1.0f64), 1.0f64.to_le_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_rejects_wrong_width() {
+        assert_eq!(
+            decode::<i64>(b"1234"),
+            Err(NumericError::InvalidLength {
+                expected: 8,
+                found: 4
+            })
+        );
+        assert!(decode::<u64>(&[0; 9]).is_err());
+        assert!(decode::<f64>(&[]).is_err());
+    }
+}
@@ -1,20 +1,326 @@
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
-    Set { key: Vec<u8>, value: Vec<u8> },
-    Get { key: Vec<u8> },
-    Delete { key: Vec<u8> },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    /// With `bypass_cache`, reads straight from the store without touching
+    /// the server's cache. Meant for debugging stale cache entries.
+    Get {
+        key: Vec<u8>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        bypass_cache: bool,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    /// Brings back a key removed by `Delete`, if the server keeps deleted
+    /// values and the key's grace period hasn't run out.
+    Undelete {
+        key: Vec<u8>,
+    },
+    DeletePrefix {
+        prefix: Vec<u8>,
+    },
     List,
-    Exists { key: Vec<u8> },
+    Exists {
+        key: Vec<u8>,
+    },
     Count,
-    BatchGet { keys: Vec<Vec<u8>> },
+    /// Counts the keys starting with `prefix` without listing them.
+    CountPrefix {
+        prefix: Vec<u8>,
+    },
+    /// Returns up to `limit` entries starting with `prefix`, in key order,
+    /// beginning after the key `after`. Passing back the cursor of the last
+    /// page resumes where it ended, even on a new connection or after a
+    /// restart, since the cursor is just a key.
+    Scan {
+        #[serde(default)]
+        prefix: Vec<u8>,
+        #[serde(default)]
+        after: Option<Vec<u8>>,
+        limit: usize,
+    },
+    BatchGet {
+        keys: Vec<Vec<u8>>,
+    },
+    /// Counts how many of `keys` exist, answered with a `CountResponse`. A key
+    /// listed twice is counted twice.
+    BatchExistsCount {
+        keys: Vec<Vec<u8>>,
+    },
+    /// Stores every entry, each with its own optional TTL, and writes the dump
+    /// once at the end. Entries without a TTL are persistent. The first entry
+    /// that fails stops the batch; the ones before it stay stored.
+    BatchSetEx {
+        entries: Vec<(Vec<u8>, Vec<u8>, Option<Duration>)>,
+    },
     Clear,
+    ClearCache,
+    Echo {
+        payload: Vec<u8>,
+    },
+    Stats,
+    Expire {
+        key: Vec<u8>,
+        ttl: Duration,
+    },
+    Persist {
+        key: Vec<u8>,
+    },
+    Stat {
+        key: Vec<u8>,
+    },
+    GetRange {
+        key: Vec<u8>,
+        start: usize,
+        end: usize,
+    },
+    /// Replaces the whole store with `entries` in one step, dropping all TTLs.
+    /// Readers see either the old contents or the new ones, never a mix.
+    /// With `validate_only`, nothing is written; each entry is checked against
+    /// the store's limits instead, answered with a `ValidationResponse`.
+    ReplaceAll {
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        validate_only: bool,
+    },
+    /// Stores `value` only if `key` doesn't exist, optionally with a TTL.
+    SetIfAbsent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        #[serde(default)]
+        ttl: Option<Duration>,
+    },
+    /// Deletes `key` only if it currently holds `value`.
+    DeleteIfEquals {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    /// Sets a TTL on `key` only if it currently holds `value`.
+    ExpireIfEquals {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    /// Stores `value` only if the current value of `key` meets `condition`.
+    SetIf {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        condition: Condition,
+    },
+    /// Rejects mutations while enabled. With a `ttl`, maintenance ends on its
+    /// own after that long even if nobody turns it off. Servers only serve it
+    /// when their `enabled_operations` list it.
+    Maintenance {
+        enabled: bool,
+        #[serde(default)]
+        ttl: Option<Duration>,
+    },
+    /// Liveness probe, answered as soon as the server accepts connections.
+    Live,
+    /// Readiness probe: ready once storage has loaded and the server isn't in
+    /// maintenance mode.
+    Ready,
+    /// The server's effective settings, without passwords or other secrets.
+    Info,
+    /// Rewrites the dump from scratch to reclaim space, dropping keys whose
+    /// TTL ran out along the way. Writes are rejected as in maintenance mode
+    /// until it finishes.
+    Compact,
+    /// Runs `ops` back to back with no other request interleaved. Unless
+    /// `continue_on_error` is set, the first failing op skips the rest. Ops
+    /// that ran before a failure are not rolled back.
+    Transaction {
+        ops: Vec<Request>,
+        #[serde(default)]
+        continue_on_error: bool,
+    },
+}
+
+impl Request {
+    /// Every value [`Request::name`] can return.
+    pub const OPERATIONS: &'static [&'static str] = &[
+        "set",
+        "get",
+        "delete",
+        "undelete",
+        "delete_prefix",
+        "list",
+        "exists",
+        "count",
+        "count_prefix",
+        "scan",
+        "batch_get",
+        "batch_exists_count",
+        "batch_set_ex",
+        "clear",
+        "clear_cache",
+        "echo",
+        "stats",
+        "expire",
+        "persist",
+        "stat",
+        "get_range",
+        "replace_all",
+        "set_if_absent",
+        "delete_if_equals",
+        "expire_if_equals",
+        "set_if",
+        "maintenance",
+        "live",
+        "ready",
+        "info",
+        "compact",
+        "transaction",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Set { .. } => "set",
+            Request::Get { .. } => "get",
+            Request::Delete { .. } => "delete",
+            Request::Undelete { .. } => "undelete",
+            Request::DeletePrefix { .. } => "delete_prefix",
+            Request::List => "list",
+            Request::Exists { .. } => "exists",
+            Request::Count => "count",
+            Request::CountPrefix { .. } => "count_prefix",
+            Request::Scan { .. } => "scan",
+            Request::BatchGet { .. } => "batch_get",
+            Request::BatchExistsCount { .. } => "batch_exists_count",
+            Request::BatchSetEx { .. } => "batch_set_ex",
+            Request::Clear => "clear",
+            Request::ClearCache => "clear_cache",
+            Request::Echo { .. } => "echo",
+            Request::Stats => "stats",
+            Request::Expire { .. } => "expire",
+            Request::Persist { .. } => "persist",
+            Request::Stat { .. } => "stat",
+            Request::GetRange { .. } => "get_range",
+            Request::ReplaceAll { .. } => "replace_all",
+            Request::SetIfAbsent { .. } => "set_if_absent",
+            Request::DeleteIfEquals { .. } => "delete_if_equals",
+            Request::ExpireIfEquals { .. } => "expire_if_equals",
+            Request::SetIf { .. } => "set_if",
+            Request::Maintenance { .. } => "maintenance",
+            Request::Live => "live",
+            Request::Ready => "ready",
+            Request::Info => "info",
+            Request::Compact => "compact",
+            Request::Transaction { .. } => "transaction",
+        }
+    }
+    pub fn is_mutation(&self) -> bool {
+        match self {
+            Request::Set { .. }
+            | Request::Delete { .. }
+            | Request::Undelete { .. }
+            | Request::DeletePrefix { .. }
+            | Request::BatchSetEx { .. }
+            | Request::Clear
+            | Request::SetIfAbsent { .. }
+            | Request::DeleteIfEquals { .. }
+            | Request::ExpireIfEquals { .. }
+            | Request::SetIf { .. }
+            | Request::Expire { .. }
+            | Request::Persist { .. } => true,
+            Request::ReplaceAll { validate_only, .. } => !validate_only,
+            Request::Transaction { ops, .. } => ops.iter().any(Request::is_mutation),
+            _ => false,
+        }
+    }
+    /// The value the request writes or compares against, if it carries one.
+    pub fn value(&self) -> Option<&[u8]> {
+        match self {
+            Request::Set { value, .. }
+            | Request::SetIfAbsent { value, .. }
+            | Request::DeleteIfEquals { value, .. }
+            | Request::ExpireIfEquals { value, .. }
+            | Request::SetIf { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+    pub fn value_mut(&mut self) -> Option<&mut Vec<u8>> {
+        match self {
+            Request::Set { value, .. }
+            | Request::SetIfAbsent { value, .. }
+            | Request::DeleteIfEquals { value, .. }
+            | Request::ExpireIfEquals { value, .. }
+            | Request::SetIf { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+    /// The key (or prefix) the request operates on, if it targets a single one.
+    pub fn key(&self) -> Option<&[u8]> {
+        match self {
+            Request::Set { key, .. }
+            | Request::Get { key, .. }
+            | Request::Delete { key }
+            | Request::Undelete { key }
+            | Request::Exists { key }
+            | Request::Expire { key, .. }
+            | Request::Persist { key }
+            | Request::Stat { key }
+            | Request::GetRange { key, .. }
+            | Request::SetIfAbsent { key, .. }
+            | Request::DeleteIfEquals { key, .. }
+            | Request::ExpireIfEquals { key, .. }
+            | Request::SetIf { key, .. } => Some(key),
+            Request::DeletePrefix { prefix }
+            | Request::CountPrefix { prefix }
+            | Request::Scan { prefix, .. } => Some(prefix),
+            _ => None,
+        }
+    }
+}
+
+/// What [`Request::SetIf`] checks the current value against.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Condition {
+    /// The key holds exactly this value.
+    Equals(Vec<u8>),
+    /// The key doesn't exist, or its TTL ran out.
+    NotExists,
+    /// The key exists and its value is shorter than this many bytes.
+    LengthLessThan(usize),
+}
+
+impl Condition {
+    /// Whether the condition holds for `current`, the key's value or `None`
+    /// if it doesn't exist.
+    pub fn holds(&self, current: Option<&[u8]>) -> bool {
+        match (self, current) {
+            (Condition::Equals(expected), Some(current)) => expected == current,
+            (Condition::NotExists, current) => current.is_none(),
+            (Condition::LengthLessThan(len), Some(current)) => current.len() < *len,
+            (_, None) => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestWrapper {
     req: Request,
     id: Vec<u8>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    compress: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<Vec<u8>>,
+    /// Optional per-connection sequence number, echoed back in the response
+    /// so clients can spot dropped or reordered frames.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    /// Lets the server split a large result over several frames instead of
+    /// buffering it into one.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    /// The value follows in a binary frame, see [`crate::binary`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    binary_value: bool,
 }
 
 impl RequestWrapper {
@@ -22,15 +328,84 @@ impl RequestWrapper {
         Self {
             req,
             id: uuid::Uuid::new_v4().as_bytes().to_vec(),
+            compress: false,
+            signature: None,
+            seq: None,
+            stream: false,
+            binary_value: false,
+        }
+    }
+    pub fn streamed(mut self) -> Self {
+        self.stream = true;
+        self
+    }
+    pub fn stream(&self) -> bool {
+        self.stream
+    }
+    /// Marks the value to be sent in a binary frame. Call before signing, so
+    /// the signature covers the value, then move it out with `take_value`.
+    /// Requests without a value are left as they are.
+    pub fn with_binary_value(mut self) -> Self {
+        self.binary_value = self.req.value().is_some();
+        self
+    }
+    pub fn binary_value(&self) -> bool {
+        self.binary_value
+    }
+    /// Moves the value out, leaving an empty one in its place.
+    pub fn take_value(&mut self) -> Option<Vec<u8>> {
+        self.req.value_mut().map(std::mem::take)
+    }
+    /// Puts a value received in a binary frame back into the request.
+    pub fn put_value(&mut self, value: Vec<u8>) {
+        if let Some(slot) = self.req.value_mut() {
+            *slot = value;
         }
     }
-    pub fn id(&self) -> Vec<u8> {
-        self.id.clone()
+    pub fn with_seq(mut self, seq: u64) -> Self {
+        self.seq = Some(seq);
+        self
+    }
+    pub fn seq(&self) -> Option<u64> {
+        self.seq
+    }
+    pub fn compressed(mut self) -> Self {
+        self.compress = true;
+        self
+    }
+    pub fn compress(&self) -> bool {
+        self.compress
+    }
+    pub fn id(&self) -> &[u8] {
+        &self.id
     }
     pub fn req(&self) -> &Request {
         &self.req
     }
-    pub fn to_string(&self) -> String {
-        serde_json::to_string_pretty(self).unwrap()
+    /// Splits the wrapper into its id and request without copying either.
+    pub fn into_parts(self) -> (Vec<u8>, Request) {
+        (self.id, self.req)
+    }
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+    pub fn signed(mut self, secret: &[u8]) -> Result<Self, serde_json::Error> {
+        self.signature = None;
+        self.signature = Some(crate::signing::sign(secret, &serde_json::to_vec(&self)?));
+        Ok(self)
+    }
+    pub fn is_signed(&self) -> bool {
+        self.signature.is_some()
+    }
+    pub fn verify(&self, secret: &[u8]) -> bool {
+        let Some(signature) = &self.signature else {
+            return false;
+        };
+        let unsigned = Self {
+            signature: None,
+            ..self.clone()
+        };
+        serde_json::to_vec(&unsigned)
+            .is_ok_and(|payload| crate::signing::verify(secret, &payload, signature))
     }
 }
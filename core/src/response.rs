@@ -1,4 +1,6 @@
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
 use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ResponseStatus {
@@ -13,6 +15,15 @@ pub struct Response {
     message: String,
     data: Option<ResponseData>,
     reqid: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compressed: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    /// Set on every frame of a streamed response except the last.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    more: bool,
 }
 
 impl Response {
@@ -21,38 +32,364 @@ impl Response {
             message: message.to_string(),
             data,
             reqid,
+            compressed: None,
+            signature: None,
+            seq: None,
+            more: false,
         }
     }
+    /// Marks this frame as one of several, with more to follow.
+    pub fn with_more(mut self, more: bool) -> Self {
+        self.more = more;
+        self
+    }
+    pub fn more(&self) -> bool {
+        self.more
+    }
+    /// Echoes the sequence number of the request this answers.
+    pub fn with_seq(mut self, seq: Option<u64>) -> Self {
+        self.seq = seq;
+        self
+    }
+    pub fn seq(&self) -> Option<u64> {
+        self.seq
+    }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
     pub fn data(&self) -> Option<&ResponseData> {
         self.data.as_ref()
     }
-    pub fn to_string(&self) -> String {
-        serde_json::to_string(self).unwrap()
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
     }
     pub fn reqid(&self) -> Vec<u8> {
         self.reqid.clone()
     }
+    pub fn is_compressed(&self) -> bool {
+        self.compressed.is_some()
+    }
+    pub fn compress(&mut self, min_size: usize) -> std::io::Result<()> {
+        let Some(data) = &self.data else {
+            return Ok(());
+        };
+        let json = serde_json::to_vec(data)?;
+        if json.len() < min_size {
+            return Ok(());
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&json)?;
+        self.compressed = Some(encoder.finish()?);
+        self.data = None;
+        Ok(())
+    }
+    pub fn decompress(&mut self) -> std::io::Result<()> {
+        let Some(compressed) = &self.compressed else {
+            return Ok(());
+        };
+        let mut json = Vec::new();
+        GzDecoder::new(compressed.as_slice()).read_to_end(&mut json)?;
+        self.data = Some(serde_json::from_slice(&json)?);
+        self.compressed = None;
+        Ok(())
+    }
+    pub fn sign(&mut self, secret: &[u8]) -> Result<(), serde_json::Error> {
+        self.signature = None;
+        self.signature = Some(crate::signing::sign(secret, &serde_json::to_vec(self)?));
+        Ok(())
+    }
+    pub fn verify(&self, secret: &[u8]) -> bool {
+        let Some(signature) = &self.signature else {
+            return false;
+        };
+        let unsigned = Self {
+            signature: None,
+            ..self.clone()
+        };
+        serde_json::to_vec(&unsigned)
+            .is_ok_and(|payload| crate::signing::verify(secret, &payload, signature))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Unauthorized,
+    NotFound,
+    RateLimited,
+    TooLarge,
+    BadRequest,
+    Maintenance,
+    /// The connection already served its `max_requests_per_connection`; the
+    /// server closes it with [`crate::close::MAX_REQUESTS_REACHED`].
+    RequestLimitReached,
+    /// The server's queue for the connection is full. Retrying after
+    /// `retry_after_ms` usually succeeds.
+    Busy,
+    #[serde(other)]
+    Internal,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ErrorResponse {
     pub message: String,
+    pub code: ErrorCode,
     pub reqid: Vec<u8>,
+    /// Name of the request that failed, such as `"set"`. Empty for errors not
+    /// tied to a request.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub operation: String,
+    /// For `RateLimited` and `Busy`, how long the client should wait before
+    /// retrying.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
 }
 impl ErrorResponse {
-    pub fn to_string(&self) -> String {
-        serde_json::to_string_pretty(self).unwrap()
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ServerMessage {
+    Error(ErrorResponse),
+    Response(Response),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ResponseData {
-    SetResponse { key: Vec<u8> },
-    GetResponse { value: Option<Vec<u8>> },
-    DeleteResponse { key: Option<Vec<u8>> },
-    ListResponse { keys: Vec<Vec<u8>> },
-    ExistsResponse { exists: bool },
-    CountResponse { count: usize },
-    BatchGetResponse { values: Vec<Option<Vec<u8>>> },
+    SetResponse {
+        key: Vec<u8>,
+    },
+    GetResponse {
+        value: Option<Vec<u8>>,
+    },
+    /// `key` and `value` are only set when `existed` is.
+    DeleteResponse {
+        key: Option<Vec<u8>>,
+        #[serde(default)]
+        value: Option<Vec<u8>>,
+        #[serde(default)]
+        existed: bool,
+    },
+    DeletePrefixResponse {
+        deleted: usize,
+    },
+    /// `truncated` is set when the server capped the listing, in which case
+    /// `total_estimate` is how many keys there were when it was taken.
+    ListResponse {
+        keys: Vec<Vec<u8>>,
+        #[serde(default)]
+        truncated: bool,
+        #[serde(default)]
+        total_estimate: usize,
+    },
+    /// `cursor` is the `after` to request the next page with, or `None` once
+    /// the scan reached the end.
+    ScanResponse {
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        cursor: Option<Vec<u8>>,
+    },
+    ExistsResponse {
+        exists: bool,
+    },
+    CountResponse {
+        count: usize,
+    },
+    BatchGetResponse {
+        values: Vec<Option<Vec<u8>>>,
+    },
+    BatchSetExResponse {
+        stored: usize,
+    },
     ClearResponse,
+    ClearCacheResponse {
+        dropped: usize,
+    },
+    EchoResponse {
+        payload: Vec<u8>,
+    },
+    StatsResponse {
+        stats: Stats,
+    },
+    ExpireResponse {
+        existed: bool,
+    },
+    PersistResponse {
+        existed: bool,
+    },
+    UndeleteResponse {
+        restored: bool,
+    },
+    StatResponse {
+        exists: bool,
+        size: Option<usize>,
+        ttl_ms: Option<u64>,
+        /// Milliseconds since the Unix epoch of the last cached read.
+        #[serde(default)]
+        last_access_ms: Option<u64>,
+        /// Milliseconds since the Unix epoch of the last set.
+        #[serde(default)]
+        last_modified_ms: Option<u64>,
+    },
+    GetRangeResponse {
+        value: Option<Vec<u8>>,
+    },
+    ReplaceAllResponse {
+        loaded: usize,
+    },
+    /// One result per entry of a validate-only request, in order.
+    ValidationResponse {
+        results: Vec<Result<(), String>>,
+    },
+    MaintenanceResponse {
+        enabled: bool,
+    },
+    SetIfAbsentResponse {
+        stored: bool,
+    },
+    DeleteIfEqualsResponse {
+        deleted: bool,
+    },
+    SetIfResponse {
+        stored: bool,
+    },
+    LiveResponse,
+    ReadyResponse {
+        ready: bool,
+    },
+    InfoResponse {
+        info: Box<ServerInfo>,
+    },
+    CompactResponse {
+        bytes_before: u64,
+        bytes_after: u64,
+    },
+    TransactionResponse {
+        results: Vec<Result<ResponseData, String>>,
+    },
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Stats {
+    pub keys: usize,
+    pub compressed_values: usize,
+    pub compression_saved_bytes: u64,
+    pub memory_bytes: usize,
+    pub evicted_keys: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// WebSocket frame bytes received from and sent to clients since start.
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    /// Client frames rejected for exceeding the size limit.
+    pub rejected_frames: u64,
+    /// The last dump write ran out of disk space; writes are being rejected.
+    pub disk_full: bool,
+}
+
+/// What a server negotiates with each connection, sent in the handshake
+/// response so the client can configure itself without asking.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectionConfig {
+    pub protocol_version: u32,
+    /// Largest message the server accepts, in bytes. `None` if unlimited.
+    pub max_message_size: Option<usize>,
+    /// Whether responses are compressed for requests that ask for it.
+    pub compression: bool,
+    /// Whether every request must be signed.
+    pub signing_required: bool,
+    /// Optional protocol features the server understands, such as
+    /// `"binary_values"` or `"streaming"`.
+    pub features: Vec<String>,
+}
+
+/// The non-secret parts of a server's configuration. Passwords, signing
+/// secrets and dump paths are never included.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerInfo {
+    pub version: String,
+    /// Entries the server's read cache holds per store.
+    pub cache_size: usize,
+    pub persistence: String,
+    /// Named stores besides the default one.
+    pub stores: Vec<String>,
+    pub auth_required: bool,
+    pub signing_enabled: bool,
+    pub workers: Option<usize>,
+    pub max_requests_per_connection: Option<usize>,
+    pub max_message_size: Option<usize>,
+    pub queue_memory_limit: Option<usize>,
+    pub compression_threshold: Option<usize>,
+    pub max_memory_bytes: Option<usize>,
+    pub max_keys: Option<usize>,
+    pub eviction_policy: Option<String>,
+    pub cache_write: String,
+    pub require_utf8_values: bool,
+    pub lazy_clear: bool,
+    pub enabled_operations: Option<Vec<String>>,
+    pub autosync_interval_secs: Option<u64>,
+    pub max_list_keys: Option<usize>,
+    pub sync_retries: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn large_list() -> Response {
+        let keys: Vec<Vec<u8>> = (0..1000)
+            .map(|i| format!("user:{:05}:profile", i).into_bytes())
+            .collect();
+        Response::new(
+            Some(ResponseData::ListResponse {
+                total_estimate: keys.len(),
+                keys,
+                truncated: false,
+            }),
+            "Listed successfully.",
+            vec![1, 2, 3],
+        )
+    }
+
+    #[test]
+    fn test_compression_shrinks_large_payload() {
+        let plain = large_list();
+        let mut compressed = large_list();
+        compressed.compress(1024).unwrap();
+        assert!(compressed.is_compressed());
+        assert!(compressed.data().is_none());
+
+        let plain_len = plain.to_json().unwrap().len();
+        let compressed_len = compressed.to_json().unwrap().len();
+        assert!(compressed_len < plain_len / 2);
+
+        let json = compressed.to_json().unwrap();
+        let mut decompressed: Response = serde_json::from_str(&json).unwrap();
+        decompressed.decompress().unwrap();
+        assert!(!decompressed.is_compressed());
+        match (decompressed.data(), plain.data()) {
+            (
+                Some(ResponseData::ListResponse { keys: a, .. }),
+                Some(ResponseData::ListResponse { keys: b, .. }),
+            ) => assert_eq!(a, b),
+            _ => panic!("Unexpected response data"),
+        }
+    }
+
+    #[test]
+    fn test_small_payload_stays_uncompressed() {
+        let mut response = Response::new(
+            Some(ResponseData::CountResponse { count: 1 }),
+            "Counted successfully.",
+            Vec::new(),
+        );
+        response.compress(1024).unwrap();
+        assert!(!response.is_compressed());
+        assert!(!response.to_json().unwrap().contains("compressed"));
+    }
 }
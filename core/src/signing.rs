@@ -0,0 +1,67 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub fn sign(secret: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub fn verify(secret: &[u8], payload: &[u8], signature: &[u8]) -> bool {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    mac.verify_slice(signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Request, RequestWrapper, Response, ResponseData};
+
+    #[test]
+    fn test_signed_request_roundtrip() {
+        let request = RequestWrapper::new(Request::Get {
+            key: b"key".to_vec(),
+            bypass_cache: false,
+        })
+        .signed(b"secret")
+        .unwrap();
+        let parsed: RequestWrapper = serde_json::from_str(&request.to_json().unwrap()).unwrap();
+        assert!(parsed.verify(b"secret"));
+        assert!(!parsed.verify(b"other"));
+    }
+
+    #[test]
+    fn test_tampered_request_is_rejected() {
+        let request = RequestWrapper::new(Request::Get {
+            key: b"key".to_vec(),
+            bypass_cache: false,
+        })
+        .signed(b"secret")
+        .unwrap();
+        let json = request.to_json().unwrap().replace("107", "108");
+        let parsed: RequestWrapper = serde_json::from_str(&json).unwrap();
+        assert!(!parsed.verify(b"secret"));
+        assert!(!RequestWrapper::new(Request::Count).verify(b"secret"));
+    }
+
+    #[test]
+    fn test_signed_response() {
+        let mut response = Response::new(
+            Some(ResponseData::CountResponse { count: 1 }),
+            "ok",
+            vec![1],
+        );
+        response.sign(b"secret").unwrap();
+        let parsed: Response = serde_json::from_str(&response.to_json().unwrap()).unwrap();
+        assert!(parsed.verify(b"secret"));
+
+        let tampered = response
+            .to_json()
+            .unwrap()
+            .replace("\"count\":1", "\"count\":2");
+        let parsed: Response = serde_json::from_str(&tampered).unwrap();
+        assert!(!parsed.verify(b"secret"));
+    }
+}
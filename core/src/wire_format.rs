@@ -0,0 +1,395 @@
+//! Golden-file tests for the JSON wire format.
+//!
+//! Every `Request` and `ResponseData` variant, plus the message envelopes, is
+//! serialized and compared with the fixtures in `core/fixtures/wire`. A failure
+//! means the wire format changed, which breaks clients and servers built from
+//! other versions. When the change is intentional, regenerate the fixtures with
+//! `UPDATE_WIRE_FIXTURES=1 cargo test -p ckeylock-core wire_format`, review the
+//! diff and commit it together with the change.
+
+use crate::response::{ErrorCode, ErrorResponse};
+use crate::{Condition, Request, RequestWrapper, Response, ResponseData, ServerInfo, Stats};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const UPDATE_ENV: &str = "UPDATE_WIRE_FIXTURES";
+
+fn fixture_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures")
+        .join("wire")
+        .join(format!("{}.json", name))
+}
+
+/// Externally tagged enums serialize as `"Variant"` or `{"Variant": ...}`.
+fn variant_name(value: &Value) -> String {
+    match value {
+        Value::String(name) => name.clone(),
+        Value::Object(map) if map.len() == 1 => map.keys().next().unwrap().clone(),
+        other => panic!("Not an externally tagged enum: {}", other),
+    }
+}
+
+fn check<T: Serialize + DeserializeOwned>(name: &str, samples: BTreeMap<String, T>) {
+    let actual: BTreeMap<&String, Value> = samples
+        .iter()
+        .map(|(key, sample)| (key, serde_json::to_value(sample).unwrap()))
+        .collect();
+    let json = serde_json::to_string_pretty(&actual).unwrap() + "\n";
+    let path = fixture_path(name);
+    if std::env::var_os(UPDATE_ENV).is_some() {
+        std::fs::write(&path, &json).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(&path).unwrap_or_else(|_| {
+        panic!(
+            "Missing fixture {:?}, run with {}=1 to create it",
+            path, UPDATE_ENV
+        )
+    });
+    assert_eq!(
+        json, expected,
+        "Wire format of {} changed, see the wire_format module docs",
+        name
+    );
+
+    let fixtures: BTreeMap<String, Value> = serde_json::from_str(&expected).unwrap();
+    for (key, value) in fixtures {
+        let decoded: T = serde_json::from_value(value.clone())
+            .unwrap_or_else(|e| panic!("Fixture {}/{} no longer decodes: {}", name, key, e));
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), value);
+    }
+}
+
+fn by_variant<T: Serialize>(samples: Vec<T>) -> BTreeMap<String, T> {
+    let mut map = BTreeMap::new();
+    for sample in samples {
+        let name = variant_name(&serde_json::to_value(&sample).unwrap());
+        assert!(
+            map.insert(name.clone(), sample).is_none(),
+            "Duplicate sample for {}",
+            name
+        );
+    }
+    map
+}
+
+fn key() -> Vec<u8> {
+    b"key".to_vec()
+}
+
+fn requests() -> Vec<Request> {
+    vec![
+        Request::Set {
+            key: key(),
+            value: b"value".to_vec(),
+        },
+        Request::Get {
+            key: key(),
+            bypass_cache: true,
+        },
+        Request::Delete { key: key() },
+        Request::DeletePrefix {
+            prefix: b"user:".to_vec(),
+        },
+        Request::CountPrefix {
+            prefix: b"user:".to_vec(),
+        },
+        Request::Scan {
+            prefix: b"user:".to_vec(),
+            after: Some(key()),
+            limit: 100,
+        },
+        Request::List,
+        Request::Exists { key: key() },
+        Request::Count,
+        Request::BatchExistsCount {
+            keys: vec![key(), b"other".to_vec()],
+        },
+        Request::BatchGet {
+            keys: vec![key(), b"other".to_vec()],
+        },
+        Request::BatchSetEx {
+            entries: vec![
+                (key(), b"value".to_vec(), Some(Duration::from_secs(30))),
+                (b"other".to_vec(), b"value".to_vec(), None),
+            ],
+        },
+        Request::Clear,
+        Request::ClearCache,
+        Request::Echo {
+            payload: b"ping".to_vec(),
+        },
+        Request::Stats,
+        Request::Expire {
+            key: key(),
+            ttl: Duration::from_millis(1500),
+        },
+        Request::Persist { key: key() },
+        Request::Undelete { key: key() },
+        Request::Stat { key: key() },
+        Request::GetRange {
+            key: key(),
+            start: 1,
+            end: 4,
+        },
+        Request::ReplaceAll {
+            entries: vec![(key(), b"value".to_vec())],
+            validate_only: false,
+        },
+        Request::SetIfAbsent {
+            key: key(),
+            value: b"token".to_vec(),
+            ttl: Some(Duration::from_secs(30)),
+        },
+        Request::DeleteIfEquals {
+            key: key(),
+            value: b"token".to_vec(),
+        },
+        Request::ExpireIfEquals {
+            key: key(),
+            value: b"token".to_vec(),
+            ttl: Duration::from_secs(30),
+        },
+        Request::SetIf {
+            key: key(),
+            value: b"token".to_vec(),
+            condition: Condition::Equals(b"old".to_vec()),
+        },
+        Request::Maintenance {
+            enabled: true,
+            ttl: Some(Duration::from_secs(60)),
+        },
+        Request::Live,
+        Request::Ready,
+        Request::Info,
+        Request::Compact,
+        Request::Transaction {
+            ops: vec![
+                Request::Get {
+                    key: key(),
+                    bypass_cache: false,
+                },
+                Request::Count,
+            ],
+            continue_on_error: true,
+        },
+    ]
+}
+
+fn response_data() -> Vec<ResponseData> {
+    vec![
+        ResponseData::SetResponse { key: key() },
+        ResponseData::GetResponse {
+            value: Some(b"value".to_vec()),
+        },
+        ResponseData::DeleteResponse {
+            key: Some(key()),
+            value: Some(b"value".to_vec()),
+            existed: true,
+        },
+        ResponseData::DeletePrefixResponse { deleted: 3 },
+        ResponseData::ListResponse {
+            keys: vec![key(), b"other".to_vec()],
+            truncated: true,
+            total_estimate: 5,
+        },
+        ResponseData::ScanResponse {
+            entries: vec![(key(), b"value".to_vec())],
+            cursor: Some(key()),
+        },
+        ResponseData::ExistsResponse { exists: true },
+        ResponseData::CountResponse { count: 2 },
+        ResponseData::BatchGetResponse {
+            values: vec![Some(b"value".to_vec()), None],
+        },
+        ResponseData::BatchSetExResponse { stored: 2 },
+        ResponseData::ClearResponse,
+        ResponseData::ClearCacheResponse { dropped: 5 },
+        ResponseData::EchoResponse {
+            payload: b"ping".to_vec(),
+        },
+        ResponseData::StatsResponse {
+            stats: Stats {
+                keys: 1,
+                compressed_values: 2,
+                compression_saved_bytes: 3,
+                memory_bytes: 4,
+                evicted_keys: 5,
+                cache_hits: 6,
+                cache_misses: 7,
+                bytes_received: 8,
+                bytes_sent: 9,
+                rejected_frames: 10,
+                disk_full: true,
+            },
+        },
+        ResponseData::ExpireResponse { existed: true },
+        ResponseData::PersistResponse { existed: false },
+        ResponseData::UndeleteResponse { restored: true },
+        ResponseData::StatResponse {
+            exists: true,
+            size: Some(5),
+            ttl_ms: Some(1000),
+            last_access_ms: Some(1_700_000_000_500),
+            last_modified_ms: Some(1_700_000_000_000),
+        },
+        ResponseData::GetRangeResponse {
+            value: Some(b"alu".to_vec()),
+        },
+        ResponseData::ReplaceAllResponse { loaded: 1 },
+        ResponseData::ValidationResponse {
+            results: vec![Ok(()), Err("Value is not valid UTF-8".to_string())],
+        },
+        ResponseData::MaintenanceResponse { enabled: true },
+        ResponseData::SetIfAbsentResponse { stored: true },
+        ResponseData::DeleteIfEqualsResponse { deleted: false },
+        ResponseData::SetIfResponse { stored: true },
+        ResponseData::LiveResponse,
+        ResponseData::ReadyResponse { ready: false },
+        ResponseData::InfoResponse {
+            info: Box::new(ServerInfo {
+                version: "0.2.3".to_string(),
+                cache_size: 100,
+                persistence: "file".to_string(),
+                stores: vec!["cache".to_string()],
+                auth_required: true,
+                max_keys: Some(1000),
+                eviction_policy: Some("lru".to_string()),
+                cache_write: "through".to_string(),
+                enabled_operations: Some(vec!["get".to_string()]),
+                ..Default::default()
+            }),
+        },
+        ResponseData::CompactResponse {
+            bytes_before: 4096,
+            bytes_after: 1024,
+        },
+        ResponseData::TransactionResponse {
+            results: vec![
+                Ok(ResponseData::CountResponse { count: 1 }),
+                Err("failed".to_string()),
+            ],
+        },
+    ]
+}
+
+#[test]
+fn test_request_wire_format() {
+    check("requests", by_variant(requests()));
+}
+
+#[test]
+fn test_operations_cover_every_request() {
+    let names: std::collections::BTreeSet<&str> = requests().iter().map(Request::name).collect();
+    let operations: std::collections::BTreeSet<&str> =
+        Request::OPERATIONS.iter().copied().collect();
+    assert_eq!(names, operations);
+}
+
+#[test]
+fn test_condition_wire_format() {
+    let conditions = vec![
+        Condition::Equals(b"old".to_vec()),
+        Condition::NotExists,
+        Condition::LengthLessThan(16),
+    ];
+    check("conditions", by_variant(conditions));
+}
+
+#[test]
+fn test_response_data_wire_format() {
+    check("response_data", by_variant(response_data()));
+}
+
+#[test]
+fn test_envelope_wire_format() {
+    let reqid = vec![1, 2, 3, 4];
+    let wrapper = |value: Value| -> RequestWrapper { serde_json::from_value(value).unwrap() };
+    let wrappers = BTreeMap::from([
+        (
+            "plain".to_string(),
+            wrapper(serde_json::json!({ "req": "Count", "id": reqid })),
+        ),
+        (
+            "compressed_signed".to_string(),
+            wrapper(serde_json::json!({
+                "req": "List",
+                "id": reqid,
+                "compress": true,
+                "signature": [9, 9],
+            })),
+        ),
+        (
+            "sequenced".to_string(),
+            wrapper(serde_json::json!({ "req": "Count", "id": reqid, "seq": 7 })),
+        ),
+        (
+            "streamed".to_string(),
+            wrapper(serde_json::json!({ "req": "List", "id": reqid, "stream": true })),
+        ),
+        (
+            "binary_value".to_string(),
+            wrapper(serde_json::json!({
+                "req": { "Set": { "key": [1], "value": [] } },
+                "id": reqid,
+                "binary_value": true,
+            })),
+        ),
+    ]);
+    check("request_wrapper", wrappers);
+
+    let responses = BTreeMap::from([
+        (
+            "data".to_string(),
+            Response::new(
+                Some(ResponseData::CountResponse { count: 1 }),
+                "Counted successfully.",
+                reqid.clone(),
+            ),
+        ),
+        (
+            "empty".to_string(),
+            Response::new(None, "Nothing.", reqid.clone()),
+        ),
+        (
+            "sequenced".to_string(),
+            Response::new(None, "Nothing.", reqid.clone()).with_seq(Some(7)),
+        ),
+        (
+            "partial".to_string(),
+            Response::new(None, "Nothing.", reqid.clone()).with_more(true),
+        ),
+    ]);
+    check("response", responses);
+
+    let errors = [
+        ErrorCode::Unauthorized,
+        ErrorCode::NotFound,
+        ErrorCode::RateLimited,
+        ErrorCode::TooLarge,
+        ErrorCode::BadRequest,
+        ErrorCode::Maintenance,
+        ErrorCode::RequestLimitReached,
+        ErrorCode::Busy,
+        ErrorCode::Internal,
+    ]
+    .into_iter()
+    .map(|code| {
+        let error = ErrorResponse {
+            message: "Request failed".to_string(),
+            code,
+            reqid: reqid.clone(),
+            operation: "set".to_string(),
+            retry_after_ms: matches!(code, ErrorCode::RateLimited | ErrorCode::Busy)
+                .then_some(1500),
+        };
+        let name = serde_json::to_value(code).unwrap();
+        (name.as_str().unwrap().to_string(), error)
+    })
+    .collect();
+    check("error_response", errors);
+}
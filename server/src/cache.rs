@@ -0,0 +1,112 @@
+use lru::LruCache;
+use std::num::NonZero;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Eviction {
+    pub key: Vec<u8>,
+    pub size: usize,
+}
+
+pub struct Cache {
+    inner: LruCache<Vec<u8>, Vec<u8>>,
+    evictions: Option<mpsc::UnboundedSender<Eviction>>,
+}
+
+impl Cache {
+    pub fn new(capacity: NonZero<usize>) -> Self {
+        Self {
+            inner: LruCache::new(capacity),
+            evictions: None,
+        }
+    }
+
+    pub fn subscribe_evictions(&mut self) -> mpsc::UnboundedReceiver<Eviction> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.evictions = Some(tx);
+        rx
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Option<&Vec<u8>> {
+        self.inner.get(key)
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        let evicted = match &self.evictions {
+            Some(_) => self.inner.push(key.clone(), value),
+            None => {
+                self.inner.put(key, value);
+                return;
+            }
+        };
+        if let Some((evicted_key, evicted_value)) = evicted
+            && evicted_key != key
+        {
+            self.emit(evicted_key, evicted_value.len());
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.inner.contains(key)
+    }
+
+    /// Cached keys, least recently used first.
+    pub fn lru_keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.inner.iter().rev().map(|(key, _)| key)
+    }
+
+    pub fn pop(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.inner.pop(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.inner.clear();
+    }
+
+    fn emit(&mut self, key: Vec<u8>, size: usize) {
+        if let Some(tx) = &self.evictions
+            && tx.send(Eviction { key, size }).is_err()
+        {
+            self.evictions = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eviction_events() {
+        let mut cache = Cache::new(NonZero::new(2).unwrap());
+        let mut evictions = cache.subscribe_evictions();
+
+        cache.put(b"a".to_vec(), b"1".to_vec());
+        cache.put(b"b".to_vec(), b"22".to_vec());
+        cache.put(b"c".to_vec(), b"333".to_vec());
+        assert_eq!(
+            evictions.try_recv().unwrap(),
+            Eviction {
+                key: b"a".to_vec(),
+                size: 1
+            }
+        );
+
+        cache.get(b"b");
+        cache.put(b"d".to_vec(), b"4444".to_vec());
+        assert_eq!(
+            evictions.try_recv().unwrap(),
+            Eviction {
+                key: b"c".to_vec(),
+                size: 3
+            }
+        );
+
+        cache.put(b"d".to_vec(), b"55555".to_vec());
+        assert!(evictions.try_recv().is_err());
+    }
+}
@@ -0,0 +1,266 @@
+//! Chunked encryption for dumps.
+//!
+//! A dump is sealed as a series of independently encrypted chunks so loading
+//! it only ever holds one decrypted chunk in memory next to the map being
+//! decoded. Each chunk authenticates its index and whether it is the last one,
+//! so chunks can't be reordered, dropped or truncated without detection.
+//!
+//! Layout: `MAGIC` and a [`Header`], then per chunk a little-endian `u32`
+//! length followed by that many bytes of `nonce || ciphertext || tag`.
+//! Every chunk also authenticates what the dump is bound to, its path, and
+//! the header's write id, which is new on every write, so chunks of an
+//! earlier write of the same dump can't be mixed in.
+
+use crate::crypto::AES;
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use std::hash::Hasher as _;
+use std::io::{self, Read};
+use twox_hash::XxHash3_64;
+
+pub const MAGIC: &[u8; 4] = b"CKL2";
+/// Dumps written before they had a write id, whose chunks authenticate only
+/// the path.
+pub const PATH_BOUND_MAGIC: &[u8; 4] = b"CKLC";
+/// Bytes between [`MAGIC`] and the first chunk.
+pub const HEADER_LEN: usize = 16;
+/// Plaintext bytes per chunk.
+pub const CHUNK_SIZE: usize = 1 << 20;
+/// Sealed chunks larger than this are treated as corruption rather than
+/// allocated.
+const MAX_SEALED_LEN: usize = 64 << 20;
+
+pub struct Header {
+    write_id: [u8; HEADER_LEN],
+}
+
+impl Header {
+    /// A header for a new write, with a fresh write id.
+    pub fn fresh() -> Self {
+        let mut write_id = [0; HEADER_LEN];
+        OsRng.fill_bytes(&mut write_id);
+        Self { write_id }
+    }
+
+    /// Reads the header that follows [`MAGIC`].
+    pub fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let mut write_id = [0; HEADER_LEN];
+        reader
+            .read_exact(&mut write_id)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => invalid("dump ends inside its header"),
+                _ => e,
+            })?;
+        Ok(Self { write_id })
+    }
+
+    /// What every chunk of a dump bound to `binding` authenticates.
+    pub fn aad(&self, binding: &[u8]) -> Vec<u8> {
+        [binding, &self.write_id].concat()
+    }
+}
+
+/// Seals `content` into the chunked format, bound to `binding`.
+pub fn seal(
+    aes: &AES,
+    binding: &[u8],
+    content: &[u8],
+    chunk_size: usize,
+) -> Result<Vec<u8>, aes_gcm::Error> {
+    let header = Header::fresh();
+    let mut sealed = MAGIC.to_vec();
+    sealed.extend(header.write_id);
+    seal_chunks(aes, &header.aad(binding), content, chunk_size, &mut sealed)?;
+    Ok(sealed)
+}
+
+/// Appends the chunks of `content` to `sealed`, each authenticating `aad`.
+pub fn seal_chunks(
+    aes: &AES,
+    aad: &[u8],
+    content: &[u8],
+    chunk_size: usize,
+    sealed: &mut Vec<u8>,
+) -> Result<(), aes_gcm::Error> {
+    let chunks: Vec<&[u8]> = content.chunks(chunk_size).collect();
+    let count = chunks.len().max(1);
+    for index in 0..count {
+        let chunk = chunks.get(index).copied().unwrap_or_default();
+        let last = index + 1 == count;
+        let encrypted = aes.encrypt(chunk, None, &chunk_aad(aad, index as u64, last))?;
+        sealed.extend((encrypted.len() as u32).to_le_bytes());
+        sealed.extend(encrypted);
+    }
+    Ok(())
+}
+
+fn chunk_aad(aad: &[u8], index: u64, last: bool) -> Vec<u8> {
+    let mut chunk_aad = aad.to_vec();
+    chunk_aad.extend(index.to_le_bytes());
+    chunk_aad.push(last as u8);
+    chunk_aad
+}
+
+/// Decrypts a chunked dump as it is read. `inner` must be positioned just
+/// past the header, and `aad` is what the chunks authenticate: the header's
+/// [`Header::aad`], or the path for [`PATH_BOUND_MAGIC`] dumps. The checksum
+/// of everything read so far is kept, so callers can compare it with a
+/// freshly encoded dump without re-reading the file.
+pub struct ChunkReader<R> {
+    inner: R,
+    aes: AES,
+    aad: Vec<u8>,
+    index: u64,
+    chunk: Vec<u8>,
+    pos: usize,
+    finished: bool,
+    auth_error: Option<aes_gcm::Error>,
+    hasher: XxHash3_64,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(inner: R, aes: AES, aad: Vec<u8>) -> Self {
+        Self {
+            inner,
+            aes,
+            aad,
+            index: 0,
+            chunk: Vec::new(),
+            pos: 0,
+            finished: false,
+            auth_error: None,
+            hasher: XxHash3_64::new(),
+        }
+    }
+
+    /// Checksum of the plaintext read so far, matching
+    /// [`crate::crypto::checksum`] once everything has been read.
+    pub fn checksum(&self) -> u64 {
+        self.hasher.finish()
+    }
+
+    /// The decryption error behind a failed read, if a chunk failed to
+    /// authenticate, for example because the key is wrong.
+    pub fn take_auth_error(&mut self) -> Option<aes_gcm::Error> {
+        self.auth_error.take()
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let mut len = [0; 4];
+        self.inner
+            .read_exact(&mut len)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => invalid("dump ends before its last chunk"),
+                _ => e,
+            })?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_SEALED_LEN {
+            return Err(invalid("dump chunk is implausibly large"));
+        }
+        let mut sealed = vec![0; len];
+        self.inner.read_exact(&mut sealed)?;
+        for last in [false, true] {
+            if let Ok(chunk) = self
+                .aes
+                .decrypt(&sealed, &chunk_aad(&self.aad, self.index, last))
+            {
+                self.hasher.write(&chunk);
+                self.chunk = chunk;
+                self.pos = 0;
+                self.index += 1;
+                self.finished = last;
+                if last && self.inner.read(&mut [0])? != 0 {
+                    return Err(invalid("dump has data after its last chunk"));
+                }
+                return Ok(());
+            }
+        }
+        self.auth_error = Some(aes_gcm::Error);
+        Err(invalid("dump chunk failed to decrypt"))
+    }
+}
+
+impl<R: Read> Read for ChunkReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{checksum, hash};
+
+    fn open(sealed: &[u8], binding: &[u8]) -> io::Result<Vec<u8>> {
+        let mut body = sealed.strip_prefix(MAGIC.as_slice()).unwrap();
+        let header = Header::read(&mut body)?;
+        let mut reader = ChunkReader::new(body, AES::new(&hash(b"test")), header.aad(binding));
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        assert_eq!(reader.checksum(), checksum(&content));
+        Ok(content)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let aes = AES::new(&hash(b"test"));
+        for content in [
+            Vec::new(),
+            b"short".to_vec(),
+            (0..1000u32).map(|i| i as u8).collect(),
+        ] {
+            let sealed = seal(&aes, b"path", &content, 64).unwrap();
+            assert_eq!(open(&sealed, b"path").unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn test_rejects_tampering() {
+        let aes = AES::new(&hash(b"test"));
+        let content: Vec<u8> = (0..200u8).collect();
+        let sealed = seal(&aes, b"path", &content, 64).unwrap();
+        let header = MAGIC.len() + HEADER_LEN;
+        let chunk_len = 4 + 12 + 64 + 16;
+
+        assert!(open(&sealed, b"other").is_err());
+        let mut other_write = sealed.clone();
+        other_write[MAGIC.len()] ^= 1;
+        assert!(open(&other_write, b"path").is_err());
+        assert!(open(&sealed[..header + chunk_len], b"path").is_err());
+        let mut swapped = sealed[..header].to_vec();
+        swapped.extend(&sealed[header + chunk_len..header + 2 * chunk_len]);
+        swapped.extend(&sealed[header..header + chunk_len]);
+        swapped.extend(&sealed[header + 2 * chunk_len..]);
+        assert!(open(&swapped, b"path").is_err());
+        let mut trailing = sealed.clone();
+        trailing.push(0);
+        assert!(open(&trailing, b"path").is_err());
+    }
+
+    #[test]
+    fn test_rejects_chunks_of_an_earlier_write() {
+        let aes = AES::new(&hash(b"test"));
+        let older = seal(&aes, b"path", &[1; 200], 64).unwrap();
+        let newer = seal(&aes, b"path", &[2; 200], 64).unwrap();
+        let header = MAGIC.len() + HEADER_LEN;
+        let chunk_len = 4 + 12 + 64 + 16;
+
+        let second_chunk = header + chunk_len..header + 2 * chunk_len;
+        let mut spliced = newer.clone();
+        spliced[second_chunk.clone()].copy_from_slice(&older[second_chunk]);
+        assert!(open(&newer, b"path").is_ok());
+        assert!(open(&spliced, b"path").is_err());
+    }
+}
@@ -0,0 +1,43 @@
+//! Where storage reads the time for TTLs, so tests can move it forward by
+//! hand instead of sleeping.
+
+use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Debug + Send + Sync {
+    /// Milliseconds since the Unix epoch. Expirations are stored as these, so
+    /// they keep their meaning across restarts.
+    fn now_ms(&self) -> u64;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+}
+
+/// Stands still until [`MockClock::advance`] moves it.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MockClock(std::sync::atomic::AtomicU64);
+
+#[cfg(test)]
+impl MockClock {
+    pub fn advance(&self, by: std::time::Duration) {
+        self.0
+            .fetch_add(by.as_millis() as u64, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now_ms(&self) -> u64 {
+        self.0.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
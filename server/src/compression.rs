@@ -0,0 +1,104 @@
+use thiserror::Error;
+
+const RAW: u8 = 0;
+const LZ4: u8 = 1;
+
+pub fn encode(value: Vec<u8>, threshold: Option<usize>) -> Vec<u8> {
+    if let Some(threshold) = threshold
+        && value.len() >= threshold
+    {
+        let compressed = lz4_flex::compress_prepend_size(&value);
+        if compressed.len() < value.len() {
+            let mut stored = Vec::with_capacity(compressed.len() + 1);
+            stored.push(LZ4);
+            stored.extend_from_slice(&compressed);
+            return stored;
+        }
+    }
+    raw(value)
+}
+
+pub fn raw(value: Vec<u8>) -> Vec<u8> {
+    let mut stored = Vec::with_capacity(value.len() + 1);
+    stored.push(RAW);
+    stored.extend_from_slice(&value);
+    stored
+}
+
+pub fn decode(stored: &[u8]) -> Result<Vec<u8>, CompressionError> {
+    match stored.split_first() {
+        Some((&RAW, value)) => Ok(value.to_vec()),
+        Some((&LZ4, compressed)) => Ok(lz4_flex::decompress_size_prepended(compressed)?),
+        Some((flag, _)) => Err(CompressionError::UnknownFlag(*flag)),
+        None => Err(CompressionError::Empty),
+    }
+}
+
+/// Decodes only `start..end` of the value, clamped to its length. Raw values
+/// are sliced in place; compressed ones have to be decompressed first.
+pub fn decode_range(stored: &[u8], start: usize, end: usize) -> Result<Vec<u8>, CompressionError> {
+    match stored.split_first() {
+        Some((&RAW, value)) => Ok(clamped(value, start, end).to_vec()),
+        _ => decode(stored).map(|value| clamped(&value, start, end).to_vec()),
+    }
+}
+
+pub fn clamped(value: &[u8], start: usize, end: usize) -> &[u8] {
+    let end = end.min(value.len());
+    &value[start.min(end)..end]
+}
+
+pub fn original_len(stored: &[u8]) -> usize {
+    match stored.split_first() {
+        Some((&LZ4, compressed)) if compressed.len() >= 4 => {
+            u32::from_le_bytes([compressed[0], compressed[1], compressed[2], compressed[3]])
+                as usize
+        }
+        _ => stored.len().saturating_sub(1),
+    }
+}
+
+pub fn is_compressed(stored: &[u8]) -> bool {
+    stored.first() == Some(&LZ4)
+}
+
+#[derive(Error, Debug)]
+pub enum CompressionError {
+    #[error("Unknown value encoding flag: {0}")]
+    UnknownFlag(u8),
+    #[error("Stored value is empty")]
+    Empty,
+    #[error("LZ4 decompression error: {0}")]
+    Lz4(#[from] lz4_flex::block::DecompressError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_below_threshold_stays_raw() {
+        let value = vec![7u8; 16];
+        let stored = encode(value.clone(), Some(64));
+        assert!(!is_compressed(&stored));
+        assert_eq!(decode(&stored).unwrap(), value);
+    }
+
+    #[test]
+    fn test_above_threshold_is_compressed() {
+        let value = b"abcdefgh".repeat(1024);
+        let stored = encode(value.clone(), Some(64));
+        assert!(is_compressed(&stored));
+        assert!(stored.len() < value.len());
+        assert_eq!(original_len(&stored), value.len());
+        assert_eq!(decode(&stored).unwrap(), value);
+    }
+
+    #[test]
+    fn test_incompressible_value_stays_raw() {
+        let value: Vec<u8> = (0..256u32).map(|i| (i * 7919 % 256) as u8).collect();
+        let stored = encode(value.clone(), Some(1));
+        assert_eq!(decode(&stored).unwrap(), value);
+        assert_eq!(original_len(&stored), value.len());
+    }
+}
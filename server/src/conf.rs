@@ -1,16 +1,95 @@
+use crate::storage::{CacheWrite, EvictionPolicy, LRU_CACHE_SIZE};
+use crate::ws::UnsupportedMessage;
+use ckeylock_core::{Request, ServerInfo};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+
+const STDIN_PATH: &str = "-";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Persistence {
+    /// Keep data in an encrypted dump at `dump_path`.
+    #[default]
+    File,
+    /// Keep data in memory only, losing it on restart.
+    None,
+}
+
+/// A named store with its own dump, selected per connection with the
+/// `Ckeylock-Store` handshake header.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct StoreConfig {
+    pub persistence: Option<Persistence>,
+    /// Required unless `persistence = "none"`.
+    pub dump_path: Option<String>,
+    /// Defaults to the top-level `dump_password`.
+    pub dump_password: Option<String>,
+    /// A file holding the raw 32-byte dump key, instead of `dump_password`.
+    /// Defaults to the top-level `dump_key_file`.
+    pub dump_key_file: Option<String>,
+}
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Config {
     pub bind: String,
     pub password: Option<String>,
-    pub dump_password: String,
-    pub dump_path: String,
+    pub persistence: Option<Persistence>,
+    /// Required unless `persistence = "none"` or `dump_key_file` is set.
+    pub dump_password: Option<String>,
+    /// A file holding the raw 32-byte dump key, used instead of deriving one
+    /// from `dump_password`.
+    pub dump_key_file: Option<String>,
+    /// Required unless `persistence = "none"`.
+    pub dump_path: Option<String>,
     pub workers: Option<usize>,
+    pub max_requests_per_connection: Option<usize>,
+    pub log_cache_evictions: Option<bool>,
+    pub log_expirations: Option<bool>,
+    pub compression_threshold: Option<usize>,
+    pub log_sample_rate: Option<u64>,
+    pub signing_secret: Option<String>,
+    pub max_memory_bytes: Option<usize>,
+    pub max_keys: Option<usize>,
+    pub eviction_policy: Option<EvictionPolicy>,
+    pub cache_write: Option<CacheWrite>,
+    pub require_utf8_values: Option<bool>,
+    pub lazy_clear: Option<bool>,
+    pub enabled_operations: Option<Vec<String>>,
+    pub stores: Option<BTreeMap<String, StoreConfig>>,
+    pub slow_threshold_ms: Option<u64>,
+    pub autosync_interval_secs: Option<u64>,
+    pub max_list_keys: Option<usize>,
+    pub handshake_timeout_secs: Option<u64>,
+    pub shutdown_grace_secs: Option<u64>,
+    pub ping_interval_secs: Option<u64>,
+    pub max_missed_pongs: Option<u32>,
+    pub max_handshake_bytes: Option<usize>,
+    pub unpaired_timeout_secs: Option<u64>,
+    pub ttl_jitter: Option<f64>,
+    pub dead_letter_prefix: Option<String>,
+    pub soft_delete_secs: Option<u64>,
+    pub unsupported_message: Option<UnsupportedMessage>,
+    pub sync_retries: Option<u32>,
+    pub tcp_nodelay: Option<bool>,
+    pub max_message_size: Option<usize>,
+    pub queue_memory_limit: Option<usize>,
+    pub statsd_addr: Option<String>,
+    pub statsd_interval_secs: Option<u64>,
 }
 
 impl Config {
     pub fn from_toml(path: &str) -> Result<Self, ConfigError> {
+        Self::from_toml_or(path, || std::io::stdin().lock())
+    }
+
+    /// Like [`from_toml`](Self::from_toml), reading from `stdin` instead of
+    /// the process's standard input for the `-` path.
+    fn from_toml_or<R: Read>(path: &str, stdin: impl FnOnce() -> R) -> Result<Self, ConfigError> {
+        if path == STDIN_PATH {
+            return Self::from_reader(stdin());
+        }
         let data = match std::fs::read_to_string(path) {
             Ok(data) => data,
             Err(e) => {
@@ -22,9 +101,113 @@ impl Config {
             }
         };
         let config: Config = toml::from_str(&data)?;
-        Ok(config)
+        config.validate()
+    }
+
+    pub fn from_reader(mut reader: impl Read) -> Result<Self, ConfigError> {
+        let mut data = String::new();
+        reader.read_to_string(&mut data)?;
+        let config: Config = toml::from_str(&data)?;
+        config.validate()
+    }
+
+    /// The top-level persistence settings, which the default store uses.
+    pub fn default_store(&self) -> StoreConfig {
+        StoreConfig {
+            persistence: self.persistence,
+            dump_path: self.dump_path.clone(),
+            dump_password: self.dump_password.clone(),
+            dump_key_file: self.dump_key_file.clone(),
+        }
+    }
+
+    /// The settings `info` reports. Fields are copied one by one so a new
+    /// config option, secret or not, stays private until it is added here.
+    pub fn info(&self) -> ServerInfo {
+        ServerInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            cache_size: LRU_CACHE_SIZE,
+            persistence: setting_name(self.persistence.unwrap_or_default()),
+            stores: self
+                .stores
+                .iter()
+                .flatten()
+                .map(|(name, _)| name.clone())
+                .collect(),
+            auth_required: self.password.is_some(),
+            signing_enabled: self.signing_secret.is_some(),
+            workers: self.workers,
+            max_requests_per_connection: self.max_requests_per_connection,
+            max_message_size: self.max_message_size,
+            queue_memory_limit: self.queue_memory_limit,
+            compression_threshold: self.compression_threshold,
+            max_memory_bytes: self.max_memory_bytes,
+            max_keys: self.max_keys,
+            eviction_policy: self.eviction_policy.map(setting_name),
+            cache_write: setting_name(self.cache_write.unwrap_or_default()),
+            require_utf8_values: self.require_utf8_values.unwrap_or(false),
+            lazy_clear: self.lazy_clear.unwrap_or(false),
+            enabled_operations: self.enabled_operations.clone(),
+            autosync_interval_secs: self.autosync_interval_secs,
+            max_list_keys: self.max_list_keys,
+            sync_retries: self.sync_retries,
+        }
+    }
+
+    fn validate(mut self) -> Result<Self, ConfigError> {
+        if self.persistence.unwrap_or_default() == Persistence::File {
+            if self.dump_path.is_none() {
+                return Err(ConfigError::Missing("dump_path"));
+            }
+            if self.dump_password.is_none() && self.dump_key_file.is_none() {
+                return Err(ConfigError::Missing("dump_password"));
+            }
+        }
+        if self.dump_password.is_some() && self.dump_key_file.is_some() {
+            return Err(ConfigError::ConflictingKeys("default".to_string()));
+        }
+        let mut dump_paths = BTreeSet::new();
+        dump_paths.extend(self.dump_path.clone());
+        for (name, store) in self.stores.iter_mut().flatten() {
+            if store.persistence.unwrap_or_default() == Persistence::None {
+                continue;
+            }
+            let Some(dump_path) = &store.dump_path else {
+                return Err(ConfigError::StoreMissing(name.clone(), "dump_path"));
+            };
+            if !dump_paths.insert(dump_path.clone()) {
+                return Err(ConfigError::SharedDumpPath(dump_path.clone()));
+            }
+            if store.dump_password.is_some() && store.dump_key_file.is_some() {
+                return Err(ConfigError::ConflictingKeys(name.clone()));
+            }
+            if store.dump_password.is_none() && store.dump_key_file.is_none() {
+                store.dump_password = self.dump_password.clone();
+                store.dump_key_file = self.dump_key_file.clone();
+            }
+            if store.dump_password.is_none() && store.dump_key_file.is_none() {
+                return Err(ConfigError::StoreMissing(name.clone(), "dump_password"));
+            }
+        }
+        if let Some(unknown) = self
+            .enabled_operations
+            .iter()
+            .flatten()
+            .find(|name| !Request::OPERATIONS.contains(&name.as_str()))
+        {
+            return Err(ConfigError::UnknownOperation(unknown.clone()));
+        }
+        Ok(self)
+    }
+}
+/// The name a setting is spelled with in the config file.
+fn setting_name(value: impl Serialize) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
     }
 }
+
 #[derive(thiserror::Error, Debug)]
 pub enum ConfigError {
     #[error("File system error: {0}")]
@@ -33,4 +216,191 @@ pub enum ConfigError {
     Toml(#[from] toml::de::Error),
     #[error("Config not found")]
     NotFound,
+    #[error("Missing `{0}`, which file persistence requires")]
+    Missing(&'static str),
+    #[error("Unknown operation `{0}` in enabled_operations")]
+    UnknownOperation(String),
+    #[error("Store `{0}` is missing `{1}`, which file persistence requires")]
+    StoreMissing(String, &'static str),
+    #[error("Dump path {0:?} is used by more than one store")]
+    SharedDumpPath(String),
+    #[error("Store `{0}` sets both `dump_password` and `dump_key_file`, only one may be used")]
+    ConflictingKeys(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_reader() {
+        let toml =
+            b"bind = \"127.0.0.1:8080\"\ndump_path = \"dump.bin\"\ndump_password = \"secret\"\n";
+        let config = Config::from_reader(&toml[..]).unwrap();
+        assert_eq!(config.bind, "127.0.0.1:8080");
+        assert_eq!(config.dump_path.as_deref(), Some("dump.bin"));
+        assert!(config.password.is_none());
+    }
+
+    #[test]
+    fn test_from_toml_reads_stdin_for_dash() {
+        let toml = b"bind = \"127.0.0.1:8080\"\npersistence = \"none\"\n";
+        let config = Config::from_toml_or("-", || &toml[..]).unwrap();
+        assert_eq!(config.bind, "127.0.0.1:8080");
+
+        let path = std::env::temp_dir().join(format!("ckeylock-conf-{}.toml", std::process::id()));
+        std::fs::write(&path, toml).unwrap();
+        let config = Config::from_toml_or(path.to_str().unwrap(), || -> &[u8] {
+            panic!("a file path must not read stdin")
+        });
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.unwrap().persistence, Some(Persistence::None));
+    }
+
+    #[test]
+    fn test_persistence_none_needs_no_dump() {
+        let toml = b"bind = \"127.0.0.1:8080\"\npersistence = \"none\"\n";
+        let config = Config::from_reader(&toml[..]).unwrap();
+        assert_eq!(config.persistence, Some(Persistence::None));
+        assert!(config.dump_path.is_none());
+
+        let toml = b"bind = \"127.0.0.1:8080\"\ndump_password = \"secret\"\n";
+        assert!(matches!(
+            Config::from_reader(&toml[..]),
+            Err(ConfigError::Missing("dump_path"))
+        ));
+    }
+
+    #[test]
+    fn test_stores() {
+        let toml = br#"
+            bind = "127.0.0.1:8080"
+            dump_path = "main.bin"
+            dump_password = "secret"
+            [stores.a]
+            dump_path = "a.bin"
+            [stores.b]
+            dump_path = "b.bin"
+            dump_password = "other"
+            [stores.scratch]
+            persistence = "none"
+        "#;
+        let config = Config::from_reader(&toml[..]).unwrap();
+        let stores = config.stores.unwrap();
+        assert_eq!(stores["a"].dump_password.as_deref(), Some("secret"));
+        assert_eq!(stores["b"].dump_password.as_deref(), Some("other"));
+        assert!(stores["scratch"].dump_path.is_none());
+
+        let toml = br#"
+            bind = "127.0.0.1:8080"
+            dump_path = "main.bin"
+            dump_password = "secret"
+            [stores.a]
+            dump_path = "main.bin"
+        "#;
+        assert!(matches!(
+            Config::from_reader(&toml[..]),
+            Err(ConfigError::SharedDumpPath(path)) if path == "main.bin"
+        ));
+
+        let toml = br#"
+            bind = "127.0.0.1:8080"
+            persistence = "none"
+            [stores.a]
+            dump_path = "a.bin"
+        "#;
+        assert!(matches!(
+            Config::from_reader(&toml[..]),
+            Err(ConfigError::StoreMissing(name, "dump_password")) if name == "a"
+        ));
+    }
+
+    #[test]
+    fn test_dump_key_file() {
+        let toml = br#"
+            bind = "127.0.0.1:8080"
+            dump_path = "main.bin"
+            dump_key_file = "main.key"
+            [stores.a]
+            dump_path = "a.bin"
+            [stores.b]
+            dump_path = "b.bin"
+            dump_password = "other"
+        "#;
+        let config = Config::from_reader(&toml[..]).unwrap();
+        assert_eq!(
+            config.default_store().dump_key_file.as_deref(),
+            Some("main.key")
+        );
+        let stores = config.stores.unwrap();
+        assert_eq!(stores["a"].dump_key_file.as_deref(), Some("main.key"));
+        assert!(stores["b"].dump_key_file.is_none());
+
+        let toml = br#"
+            bind = "127.0.0.1:8080"
+            dump_path = "main.bin"
+            dump_password = "secret"
+            dump_key_file = "main.key"
+        "#;
+        assert!(matches!(
+            Config::from_reader(&toml[..]),
+            Err(ConfigError::ConflictingKeys(name)) if name == "default"
+        ));
+        let toml = br#"
+            bind = "127.0.0.1:8080"
+            dump_path = "main.bin"
+            dump_password = "secret"
+            [stores.a]
+            dump_path = "a.bin"
+            dump_password = "other"
+            dump_key_file = "a.key"
+        "#;
+        assert!(matches!(
+            Config::from_reader(&toml[..]),
+            Err(ConfigError::ConflictingKeys(name)) if name == "a"
+        ));
+    }
+
+    #[test]
+    fn test_info_leaves_out_secrets() {
+        let toml = br#"
+            bind = "127.0.0.1:8080"
+            password = "hunter2"
+            dump_path = "main.bin"
+            dump_password = "dump-secret"
+            signing_secret = "signing-secret"
+            eviction_policy = "random"
+            [stores.a]
+            dump_path = "a.bin"
+            dump_password = "store-secret"
+        "#;
+        let info = Config::from_reader(&toml[..]).unwrap().info();
+        assert_eq!(info.cache_size, LRU_CACHE_SIZE);
+        assert_eq!(info.persistence, "file");
+        assert_eq!(info.eviction_policy.as_deref(), Some("random"));
+        assert_eq!(info.cache_write, "through");
+        assert_eq!(info.stores, vec!["a".to_string()]);
+        assert!(info.auth_required && info.signing_enabled);
+
+        let json = serde_json::to_string(&info).unwrap();
+        for secret in ["password", "secret", "hunter2", ".bin"] {
+            assert!(!json.contains(secret), "info leaks {:?}: {}", secret, json);
+        }
+    }
+
+    #[test]
+    fn test_enabled_operations_are_validated() {
+        let toml = b"bind = \"127.0.0.1:8080\"\npersistence = \"none\"\nenabled_operations = [\"get\", \"set\"]\n";
+        let config = Config::from_reader(&toml[..]).unwrap();
+        assert_eq!(
+            config.enabled_operations,
+            Some(vec!["get".to_string(), "set".to_string()])
+        );
+
+        let toml = b"bind = \"127.0.0.1:8080\"\npersistence = \"none\"\nenabled_operations = [\"flushall\"]\n";
+        assert!(matches!(
+            Config::from_reader(&toml[..]),
+            Err(ConfigError::UnknownOperation(name)) if name == "flushall"
+        ));
+    }
 }
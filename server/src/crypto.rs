@@ -1,10 +1,24 @@
 use aes_gcm::{
     Aes256Gcm, Error, Key, Nonce,
-    aead::{Aead, AeadCore, KeyInit, OsRng},
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
 };
 use sha3::Digest;
+use std::path::Path;
 use std::sync::Arc;
 
+const KEY_LEN: usize = 32;
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeyFileError {
+    #[error("Failed to read key file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Key file must hold exactly {KEY_LEN} bytes, found {0}")]
+    WrongLength(usize),
+    #[error("Key file is accessible to other users (mode {0:o}), restrict it to its owner")]
+    Permissive(u32),
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone)]
 pub struct AES {
     cipher: Arc<Aes256Gcm>,
@@ -17,27 +31,46 @@ impl AES {
         }
     }
 
-    pub fn encrypt(&self, plaintext: &[u8], nonce: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+    /// Encrypts `plaintext`, authenticating `aad` alongside it. The same `aad`
+    /// must be passed to [`AES::decrypt`] for decryption to succeed.
+    pub fn encrypt(
+        &self,
+        plaintext: &[u8],
+        nonce: Option<&[u8]>,
+        aad: &[u8],
+    ) -> Result<Vec<u8>, Error> {
         let nonce = match nonce {
             Some(n) if n.len() == 12 => Nonce::from_slice(n).to_owned(),
             Some(_) => return Err(Error),
             None => Aes256Gcm::generate_nonce(&mut OsRng),
         };
 
-        let ciphertext = self.cipher.encrypt(&nonce, plaintext)?;
+        let ciphertext = self.cipher.encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )?;
         let mut result = nonce.to_vec();
         result.extend_from_slice(&ciphertext);
         Ok(result)
     }
 
-    pub fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    pub fn decrypt(&self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
         if ciphertext.len() < 12 {
             return Err(Error);
         }
 
         let (nonce, encrypted_data) = ciphertext.split_at(12);
         self.cipher
-            .decrypt(Nonce::from_slice(nonce), encrypted_data)
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: encrypted_data,
+                    aad,
+                },
+            )
             .map_err(|_| Error)
     }
 }
@@ -50,3 +83,82 @@ pub fn hash(data: &[u8]) -> [u8; 32] {
     hash.copy_from_slice(&result);
     hash
 }
+
+/// Reads a raw AES-256 key, used as is instead of being derived from a
+/// password. The file must not be readable or writable by anyone but its
+/// owner.
+pub fn read_key_file(path: impl AsRef<Path>) -> Result<[u8; KEY_LEN], KeyFileError> {
+    let path = path.as_ref();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path)?.permissions().mode() & 0o777;
+        if mode & 0o077 != 0 {
+            return Err(KeyFileError::Permissive(mode));
+        }
+    }
+    let key = std::fs::read(path)?;
+    key.as_slice()
+        .try_into()
+        .map_err(|_| KeyFileError::WrongLength(key.len()))
+}
+
+pub fn checksum(data: &[u8]) -> u64 {
+    twox_hash::XxHash3_64::oneshot(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Storage, StorageOptions};
+    use std::os::unix::fs::PermissionsExt;
+
+    fn key_file(name: &str, contents: &[u8], mode: u32) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("ckeylock-key-test-{}.key", name));
+        std::fs::write(&path, contents).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_key_file_roundtrip() {
+        let key: Vec<u8> = (0..32).collect();
+        let key_path = key_file("roundtrip", &key, 0o600);
+        let dump = std::env::temp_dir().join("ckeylock-key-test-roundtrip.bin");
+        let _ = std::fs::remove_file(&dump);
+
+        let aes = AES::new(&read_key_file(&key_path).unwrap());
+        let mut storage = Storage::new(&dump, aes, StorageOptions::default()).unwrap();
+        storage
+            .set(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        storage.sync().await.unwrap();
+        drop(storage);
+
+        let aes = AES::new(&read_key_file(&key_path).unwrap());
+        let storage = Storage::new(&dump, aes, StorageOptions::default()).unwrap();
+        assert_eq!(storage.count().unwrap(), 1);
+        // The raw key is not a password: hashing it gives a different key.
+        let hashed = AES::new(&hash(&key));
+        assert!(Storage::new(&dump, hashed, StorageOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_rejects_bad_key_files() {
+        let short = key_file("short", &[0; 31], 0o600);
+        assert!(matches!(
+            read_key_file(short),
+            Err(KeyFileError::WrongLength(31))
+        ));
+        let shared = key_file("shared", &[0; 32], 0o644);
+        assert!(matches!(
+            read_key_file(shared),
+            Err(KeyFileError::Permissive(0o644))
+        ));
+        assert!(matches!(
+            read_key_file("/nonexistent/ckeylock.key"),
+            Err(KeyFileError::Io(_))
+        ));
+    }
+}
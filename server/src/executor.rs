@@ -1,20 +1,250 @@
-use crate::{Error, storage::Storage};
-use ckeylock_core::{Request, Response, ResponseData, request::RequestWrapper};
-use std::sync::Arc;
+use crate::{
+    Error,
+    storage::{
+        KeyList, KeyStat, PendingSync, ScanPage, Storage, StorageError, SyncHealth, Traffic,
+    },
+    transform::Transform,
+};
+use ckeylock_core::{
+    Condition, Request, Response, ResponseData, ServerInfo, Stats, request::RequestWrapper,
+};
+use futures_util::{
+    StreamExt,
+    stream::{BoxStream, SelectAll},
+};
+use std::collections::HashSet;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
-use tracing::error;
+use tracing::{debug, error, info};
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Autosyncs fire up to this fraction of the interval late, so instances
+/// started together don't fsync in lockstep.
+const AUTOSYNC_JITTER: f64 = 0.1;
+const QUEUE_CAPACITY: usize = 32;
+/// What busy errors suggest waiting before trying again. A queued command
+/// usually runs in well under this.
+pub const BUSY_RETRY_AFTER: Duration = Duration::from_millis(50);
+/// How often a failed dump write is retried, so the executor notices when
+/// disk space frees up again.
+const FAILED_SYNC_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+type CommandQueue = BoxStream<'static, ExecutorCommands>;
+
 pub struct Executor {
-    command_tx: mpsc::Sender<ExecutorCommands>,
+    command_tx: Intake,
+    register_tx: mpsc::UnboundedSender<CommandQueue>,
+    /// Estimated memory held by commands waiting in unbounded queues, across
+    /// all sessions.
+    queued_bytes: Arc<AtomicUsize>,
+    maintenance: Arc<std::sync::Mutex<Option<Maintenance>>>,
+    traffic: Arc<Traffic>,
+    sync_health: Arc<SyncHealth>,
+    loaded: Arc<AtomicBool>,
+    options: Arc<ExecutorOptions>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ExecutorOptions {
+    /// Request names (see `Request::name`) the executor serves. Anything else
+    /// is rejected before it reaches storage. `None` allows everything.
+    pub enabled_operations: Option<HashSet<String>>,
+    /// What `Request::Info` answers with.
+    pub info: ServerInfo,
+    /// Queues commands without a count limit, turning new ones away as busy
+    /// once those waiting hold about this many bytes. `None` keeps the
+    /// fixed-size queues, which turn new ones away once they are full.
+    pub queue_memory_limit: Option<usize>,
+    /// Rewrites values as they are stored and read back.
+    pub transform: Option<Arc<dyn Transform>>,
+}
+
+/// A session's way into the executor.
+enum Intake {
+    Bounded(mpsc::Sender<ExecutorCommands>),
+    Unbounded {
+        tx: mpsc::UnboundedSender<(ExecutorCommands, usize)>,
+        queued_bytes: Arc<AtomicUsize>,
+        limit: usize,
+    },
+}
+
+impl Intake {
+    fn new(queued_bytes: &Arc<AtomicUsize>, limit: Option<usize>) -> (Self, CommandQueue) {
+        match limit {
+            None => {
+                let (tx, mut rx) = mpsc::channel(QUEUE_CAPACITY);
+                let queue = futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx));
+                (Intake::Bounded(tx), queue.boxed())
+            }
+            Some(limit) => {
+                let (tx, mut rx) = mpsc::unbounded_channel::<(ExecutorCommands, usize)>();
+                let dequeued = Arc::clone(queued_bytes);
+                let queue = futures_util::stream::poll_fn(move |cx| {
+                    rx.poll_recv(cx).map(|queued| {
+                        queued.map(|(command, size)| {
+                            dequeued.fetch_sub(size, Ordering::Relaxed);
+                            command
+                        })
+                    })
+                });
+                let intake = Intake::Unbounded {
+                    tx,
+                    queued_bytes: Arc::clone(queued_bytes),
+                    limit,
+                };
+                (intake, queue.boxed())
+            }
+        }
+    }
+
+    async fn send(&self, command: ExecutorCommands) -> Result<(), Error> {
+        match self {
+            Intake::Bounded(tx) => match tx.try_send(command) {
+                Ok(()) => Ok(()),
+                Err(mpsc::error::TrySendError::Full(_)) => Err(Error::Busy),
+                Err(mpsc::error::TrySendError::Closed(command)) => {
+                    Err(Error::TokioSendError(mpsc::error::SendError(command)))
+                }
+            },
+            Intake::Unbounded {
+                tx,
+                queued_bytes,
+                limit,
+            } => {
+                let size = command.size();
+                let queued = queued_bytes.fetch_add(size, Ordering::Relaxed);
+                // A command alone over the limit still gets in on an empty
+                // queue, or it could never run at all.
+                if queued > 0 && queued + size > *limit {
+                    queued_bytes.fetch_sub(size, Ordering::Relaxed);
+                    return Err(Error::Busy);
+                }
+                tx.send((command, size)).map_err(|e| {
+                    queued_bytes.fetch_sub(size, Ordering::Relaxed);
+                    Error::TokioSendError(mpsc::error::SendError(e.0.0))
+                })
+            }
+        }
+    }
+}
+
+struct Maintenance {
+    until: Option<Instant>,
+    /// Set by a running compaction rather than an operator, and cleared when
+    /// it finishes.
+    automatic: bool,
+}
+
+/// Operations that are only served when `enabled_operations` lists them,
+/// since any client could use them to block every other client's writes.
+const OPT_IN_OPERATIONS: &[&str] = &["maintenance"];
+
+/// Keeps the server in maintenance until dropped. Does nothing if it
+/// already was, so an operator's maintenance window outlives it.
+struct MaintenanceWindow<'a> {
+    executor: &'a Executor,
+}
+
+impl Drop for MaintenanceWindow<'_> {
+    fn drop(&mut self) {
+        let mut maintenance = self
+            .executor
+            .maintenance
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if maintenance.as_ref().is_some_and(|m| m.automatic) {
+            *maintenance = None;
+            info!("Compaction finished, accepting writes again");
+        }
+    }
 }
 
 impl Executor {
+    #[cfg(test)]
     pub async fn new(storage: Storage) -> Arc<Self> {
-        let (tx, mut rx) = mpsc::channel(32);
+        Self::loading(async move { Some(storage) }, ExecutorOptions::default()).await
+    }
+
+    /// Starts the executor before its storage is available. Commands wait in
+    /// their queues and readiness probes report not ready until `load`
+    /// resolves. If it resolves to `None`, the executor stops.
+    pub async fn loading(
+        load: impl Future<Output = Option<Storage>> + Send + 'static,
+        options: ExecutorOptions,
+    ) -> Arc<Self> {
+        let queued_bytes = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = Intake::new(&queued_bytes, options.queue_memory_limit);
+        let (register_tx, mut register_rx) = mpsc::unbounded_channel();
+        let traffic = Arc::new(Traffic::default());
+        let loaded = Arc::new(AtomicBool::new(false));
+        let sync_health = Arc::new(SyncHealth::default());
+        let shared_traffic = Arc::clone(&traffic);
+        let shared_sync_health = Arc::clone(&sync_health);
+        let shared_loaded = Arc::clone(&loaded);
+        let transform = options.transform.clone();
         tokio::spawn(async move {
-            let mut storage = storage;
+            let Some(mut storage) = load.await else {
+                error!("Storage failed to load, executor stopped");
+                return;
+            };
+            storage.set_traffic(shared_traffic);
+            storage.set_sync_health(shared_sync_health);
+            if let Some(transform) = transform {
+                storage.set_transform(transform);
+            }
+            shared_loaded.store(true, Ordering::Release);
+            info!("Storage loaded, ready to serve");
+            let mut sweep = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
+            let mut retry_sync = tokio::time::interval(FAILED_SYNC_RETRY_INTERVAL);
+            retry_sync.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let autosync = storage.options().autosync_interval;
+            let mut next_autosync = autosync.map(next_autosync_at);
+            // Every session's commands wait in their own queue. `SelectAll`
+            // takes one command from each ready queue in turn, so a flooding
+            // connection can't push everyone else to the back of the line.
+            let mut queues = SelectAll::new();
+            queues.push(rx);
+            let mut registering = true;
             loop {
+                if !registering && queues.is_empty() {
+                    if let Err(e) = storage.sync().await {
+                        error!("Failed to sync storage on shutdown: {:?}", e);
+                    }
+                    break;
+                }
                 tokio::select! {
-                    Some(cmd) = rx.recv() => {
+                    queue = register_rx.recv(), if registering => {
+                        match queue {
+                            Some(queue) => queues.push(queue),
+                            None => registering = false,
+                        }
+                    }
+                    _ = sweep.tick() => {
+                        if let Err(e) = storage.purge_expired().await {
+                            error!("Failed to purge expired keys: {:?}", e);
+                        }
+                        sync_in_background(storage.take_pending_sync(), "purged keys");
+                    }
+                    _ = tokio::time::sleep_until(next_autosync.unwrap_or_else(tokio::time::Instant::now)), if next_autosync.is_some() => {
+                        debug!("Running autosync");
+                        if let Err(e) = storage.schedule_sync() {
+                            error!("Failed to autosync storage: {:?}", e);
+                        }
+                        sync_in_background(storage.take_pending_sync(), "autosync");
+                        next_autosync = autosync.map(next_autosync_at);
+                    }
+                    _ = retry_sync.tick(), if storage.sync_health().write_failed() => {
+                        debug!("Retrying failed sync");
+                        if let Err(e) = storage.schedule_sync() {
+                            error!("Failed to retry sync: {:?}", e);
+                        }
+                        sync_in_background(storage.take_pending_sync(), "retried sync");
+                    }
+                    Some(cmd) = queues.next(), if !queues.is_empty() => {
                         match cmd{
                             ExecutorCommands::Set { key, value, respond_to } => {
                                 let result = storage.set(key, value).await;
@@ -22,24 +252,50 @@ impl Executor {
                                     error!("Failed to send set response: {:?}", e);
                                 }
                             }
-                            ExecutorCommands::Get { key, response } => {
-                                let result = storage.get(key).await;
+                            ExecutorCommands::Get { key, bypass_cache, response } => {
+                                let result = if bypass_cache {
+                                    storage.get_uncached(key)
+                                } else {
+                                    storage.get(key).await
+                                };
                                 if let Err(e) = response.send(result.map_err(|e| e.into())){
                                     error!("Failed to send get response: {:?}", e);
                                 }
                             }
+                            ExecutorCommands::GetRange { key, start, end, response } => {
+                                let result = storage.get_range(key, start, end).await;
+                                if let Err(e) = response.send(result.map_err(|e| e.into())){
+                                    error!("Failed to send get range response: {:?}", e);
+                                }
+                            }
                             ExecutorCommands::BatchGet { keys, response } => {
                                 let result = storage.batch_get(keys).await;
                                 if let Err(e) = response.send(result.map_err(|e| e.into())){
                                     error!("Failed to send batch get response: {:?}", e);
                                 }
                             }
-                            ExecutorCommands::Delete { key, response } => {
-                                let result = storage.delete(key).await;
+                            ExecutorCommands::BatchExistsCount { keys, response } => {
+                                let result = storage.batch_exists_count(&keys);
                                 if let Err(e) = response.send(result.map_err(|e| e.into())){
-                                    error!("Failed to send delete response: {:?}", e);
+                                    error!("Failed to send batch exists count response: {:?}", e);
                                 }
                             }
+                            ExecutorCommands::BatchSetEx { entries, response } => {
+                                let result = storage.batch_set_ex(entries).await;
+                                respond_after_sync(storage.take_pending_sync(), result, response, "batch set ex");
+                            }
+                            ExecutorCommands::Delete { key, response } => {
+                                let result = storage.delete(key).await;
+                                respond_after_sync(storage.take_pending_sync(), result, response, "delete");
+                            }
+                            ExecutorCommands::Undelete { key, response } => {
+                                let result = storage.undelete(key).await;
+                                respond_after_sync(storage.take_pending_sync(), result, response, "undelete");
+                            }
+                            ExecutorCommands::DeletePrefix { prefix, response } => {
+                                let result = storage.delete_prefix(prefix).await;
+                                respond_after_sync(storage.take_pending_sync(), result, response, "delete prefix");
+                            }
                             ExecutorCommands::List { response } => {
                                 let result = storage.list();
                                 if let Err(e) = response.send(result.map_err(|e| e.into())){
@@ -58,11 +314,84 @@ impl Executor {
                                     error!("Failed to send count response: {:?}", e);
                                 }
                             }
+                            ExecutorCommands::CountPrefix { prefix, response } => {
+                                let result = storage.count_prefix(&prefix);
+                                if let Err(e) = response.send(result.map_err(|e| e.into())){
+                                    error!("Failed to send count prefix response: {:?}", e);
+                                }
+                            }
+                            ExecutorCommands::Scan { prefix, after, limit, response } => {
+                                let result = storage.scan(&prefix, after.as_deref(), limit);
+                                if let Err(e) = response.send(result.map_err(|e| e.into())){
+                                    error!("Failed to send scan response: {:?}", e);
+                                }
+                            }
+                            ExecutorCommands::Stats { response } => {
+                                let result = storage.stats();
+                                if let Err(e) = response.send(result.map_err(|e| e.into())){
+                                    error!("Failed to send stats response: {:?}", e);
+                                }
+                            }
+                            ExecutorCommands::Expire { key, ttl, response } => {
+                                let result = storage.expire(key, ttl);
+                                respond_after_sync(storage.take_pending_sync(), result, response, "expire");
+                            }
+                            ExecutorCommands::SetIfAbsent { key, value, ttl, response } => {
+                                let result = storage.set_if_absent(key, value, ttl).await;
+                                respond_after_sync(storage.take_pending_sync(), result, response, "set if absent");
+                            }
+                            ExecutorCommands::DeleteIfEquals { key, value, response } => {
+                                let result = storage.delete_if_equals(key, value).await;
+                                respond_after_sync(storage.take_pending_sync(), result, response, "delete if equals");
+                            }
+                            ExecutorCommands::ExpireIfEquals { key, value, ttl, response } => {
+                                let result = storage.expire_if_equals(key, value, ttl);
+                                respond_after_sync(storage.take_pending_sync(), result, response, "expire if equals");
+                            }
+                            ExecutorCommands::SetIf { key, value, condition, response } => {
+                                let result = storage.set_if(key, value, &condition).await;
+                                if let Err(e) = response.send(result.map_err(|e| e.into())){
+                                    error!("Failed to send set if response: {:?}", e);
+                                }
+                            }
+                            ExecutorCommands::Stat { key, response } => {
+                                let result = storage.stat(key);
+                                if let Err(e) = response.send(result.map_err(|e| e.into())){
+                                    error!("Failed to send stat response: {:?}", e);
+                                }
+                            }
+                            ExecutorCommands::Persist { key, response } => {
+                                let result = storage.persist(key);
+                                respond_after_sync(storage.take_pending_sync(), result, response, "persist");
+                            }
+                            ExecutorCommands::Transaction { ops, continue_on_error, response } => {
+                                let results = run_transaction(&mut storage, ops, continue_on_error).await;
+                                respond_after_sync(storage.take_pending_sync(), Ok(results), response, "transaction");
+                            }
+                            ExecutorCommands::ClearCache { response } => {
+                                let result = storage.clear_cache().await;
+                                if let Err(e) = response.send(result.map_err(|e| e.into())){
+                                    error!("Failed to send clear cache response: {:?}", e);
+                                }
+                            }
+                            ExecutorCommands::Compact { response } => {
+                                let result = storage.compact().await;
+                                if let Err(e) = response.send(result.map_err(|e| e.into())){
+                                    error!("Failed to send compact response: {:?}", e);
+                                }
+                            }
                             ExecutorCommands::Clear { response } => {
                                 let result = storage.clear().await;
-                                if let Err(e) = response.send(result.map_err(|e| e.into())){
-                                 error!("Failed to send clear response: {:?}", e);
-
+                                respond_after_sync(storage.take_pending_sync(), result, response, "clear");
+                            }
+                            ExecutorCommands::ReplaceAll { entries, response } => {
+                                let result = storage.replace_all(entries).await;
+                                respond_after_sync(storage.take_pending_sync(), result, response, "replace all");
+                            }
+                            ExecutorCommands::ValidateAll { entries, response } => {
+                                let results = validation_results(storage.validate_all(&entries));
+                                if let Err(e) = response.send(Ok(results)){
+                                    error!("Failed to send validation response: {:?}", e);
                                 }
                             }
                         }
@@ -70,18 +399,91 @@ impl Executor {
                 }
             }
         });
-        Arc::new(Self { command_tx: tx })
+        Arc::new(Self {
+            command_tx: tx,
+            register_tx,
+            queued_bytes,
+            maintenance: Arc::new(std::sync::Mutex::new(None)),
+            traffic,
+            sync_health,
+            loaded,
+            options: Arc::new(options),
+        })
+    }
+
+    /// A handle sharing this executor's storage but with its own command
+    /// queue, served round-robin with every other session. Give each client
+    /// connection one so a busy client can't starve the others.
+    pub fn session(&self) -> Arc<Self> {
+        let (tx, rx) = Intake::new(&self.queued_bytes, self.options.queue_memory_limit);
+        if self.register_tx.send(rx).is_err() {
+            error!("Executor stopped, session commands will fail");
+        }
+        Arc::new(Self {
+            command_tx: tx,
+            register_tx: self.register_tx.clone(),
+            queued_bytes: Arc::clone(&self.queued_bytes),
+            maintenance: Arc::clone(&self.maintenance),
+            traffic: Arc::clone(&self.traffic),
+            sync_health: Arc::clone(&self.sync_health),
+            loaded: Arc::clone(&self.loaded),
+            options: Arc::clone(&self.options),
+        })
+    }
+
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+
+    /// Whether storage has loaded and the server isn't in maintenance mode.
+    pub fn is_ready(&self) -> bool {
+        self.loaded.load(Ordering::Acquire) && !self.in_maintenance()
+    }
+
+    /// The first operation in `request` the allowlist leaves out, looking
+    /// inside transactions too.
+    fn disabled_operation(&self, request: &Request) -> Option<&'static str> {
+        let enabled = match &self.options.enabled_operations {
+            Some(enabled) => enabled.contains(request.name()),
+            None => !OPT_IN_OPERATIONS.contains(&request.name()),
+        };
+        if !enabled {
+            return Some(request.name());
+        }
+        match request {
+            Request::Transaction { ops, .. } => {
+                ops.iter().find_map(|op| self.disabled_operation(op))
+            }
+            _ => None,
+        }
     }
 
     pub async fn execute(&self, request: RequestWrapper) -> Result<Response, Error> {
-        let original_request = request.req().clone();
-        match original_request {
+        if let Some(operation) = self.disabled_operation(request.req()) {
+            return Err(Error::OperationDisabled(operation));
+        }
+        if request.req().is_mutation() && self.in_maintenance() {
+            return Err(Error::Maintenance);
+        }
+        if request.req().is_mutation() && self.sync_health.is_disk_full() {
+            return Err(StorageError::DiskFull.into());
+        }
+        let (id, request) = request.into_parts();
+        match request {
+            Request::Maintenance { enabled, ttl } => {
+                self.set_maintenance(enabled, ttl);
+                Ok(Response::new(
+                    Some(ResponseData::MaintenanceResponse { enabled }),
+                    "Maintenance mode updated.",
+                    id,
+                ))
+            }
             Request::Set { key, value } => {
                 let result = self.set(key, value).await?;
                 Ok(Response::new(
                     Some(ResponseData::SetResponse { key: result }),
                     "Stored successfully.",
-                    request.id(),
+                    id,
                 ))
             }
             Request::BatchGet { keys } => {
@@ -89,31 +491,71 @@ impl Executor {
                 Ok(Response::new(
                     Some(ResponseData::BatchGetResponse { values: result }),
                     "Batch retrieved successfully.",
-                    request.id(),
+                    id,
+                ))
+            }
+            Request::BatchExistsCount { keys } => {
+                let count = self.batch_exists_count(keys).await?;
+                Ok(Response::new(
+                    Some(ResponseData::CountResponse { count }),
+                    "Counted successfully.",
+                    id,
+                ))
+            }
+            Request::BatchSetEx { entries } => {
+                let stored = self.batch_set_ex(entries).await?;
+                Ok(Response::new(
+                    Some(ResponseData::BatchSetExResponse { stored }),
+                    "Batch stored successfully.",
+                    id,
                 ))
             }
-            Request::Get { key } => {
-                let value = self.get(key).await?;
+            Request::Get { key, bypass_cache } => {
+                let value = self.get(key, bypass_cache).await?;
                 Ok(Response::new(
                     Some(ResponseData::GetResponse { value }),
                     "Retrieved successfully.",
-                    request.id(),
+                    id,
+                ))
+            }
+            Request::GetRange { key, start, end } => {
+                let value = self.get_range(key, start, end).await?;
+                Ok(Response::new(
+                    Some(ResponseData::GetRangeResponse { value }),
+                    "Range retrieved successfully.",
+                    id,
                 ))
             }
             Request::Delete { key } => {
-                let key = self.delete(key.clone()).await?;
+                let value = self.delete(key.clone()).await?;
                 Ok(Response::new(
-                    Some(ResponseData::DeleteResponse { key }),
+                    Some(delete_response(key, value)),
                     "Deleted successfully.",
-                    request.id(),
+                    id,
+                ))
+            }
+            Request::Undelete { key } => {
+                let restored = self.undelete(key).await?;
+                Ok(Response::new(
+                    Some(ResponseData::UndeleteResponse { restored }),
+                    "Undeleted successfully.",
+                    id,
+                ))
+            }
+            Request::DeletePrefix { prefix } => {
+                let deleted = self.delete_prefix(prefix).await?;
+                Ok(Response::new(
+                    Some(ResponseData::DeletePrefixResponse { deleted }),
+                    "Deleted by prefix successfully.",
+                    id,
                 ))
             }
             Request::List => {
-                let result = self.list().await?;
+                let (keys, total) = self.list().await?;
                 Ok(Response::new(
-                    Some(ResponseData::ListResponse { keys: result }),
+                    Some(list_response(keys, total)),
                     "Listed successfully.",
-                    request.id(),
+                    id,
                 ))
             }
             Request::Exists { key } => {
@@ -121,7 +563,7 @@ impl Executor {
                 Ok(Response::new(
                     Some(ResponseData::ExistsResponse { exists: result }),
                     "Existence checked successfully.",
-                    request.id(),
+                    id,
                 ))
             }
             Request::Count => {
@@ -129,7 +571,27 @@ impl Executor {
                 Ok(Response::new(
                     Some(ResponseData::CountResponse { count: value }),
                     "Counted successfully.",
-                    request.id(),
+                    id,
+                ))
+            }
+            Request::CountPrefix { prefix } => {
+                let count = self.count_prefix(prefix).await?;
+                Ok(Response::new(
+                    Some(ResponseData::CountResponse { count }),
+                    "Counted successfully.",
+                    id,
+                ))
+            }
+            Request::Scan {
+                prefix,
+                after,
+                limit,
+            } => {
+                let (entries, cursor) = self.scan(prefix, after, limit).await?;
+                Ok(Response::new(
+                    Some(ResponseData::ScanResponse { entries, cursor }),
+                    "Scanned successfully.",
+                    id,
                 ))
             }
             Request::Clear => {
@@ -137,10 +599,221 @@ impl Executor {
                 Ok(Response::new(
                     Some(ResponseData::ClearResponse),
                     "Cleared successfully.",
-                    request.id(),
+                    id,
+                ))
+            }
+            Request::ReplaceAll {
+                entries,
+                validate_only: true,
+            } => {
+                let results = self.validate_all(entries).await?;
+                Ok(Response::new(
+                    Some(ResponseData::ValidationResponse { results }),
+                    "Validated successfully.",
+                    id,
                 ))
             }
+            Request::ReplaceAll { entries, .. } => {
+                let loaded = self.replace_all(entries).await?;
+                Ok(Response::new(
+                    Some(ResponseData::ReplaceAllResponse { loaded }),
+                    "Replaced successfully.",
+                    id,
+                ))
+            }
+            Request::ClearCache => {
+                let dropped = self.clear_cache().await?;
+                Ok(Response::new(
+                    Some(ResponseData::ClearCacheResponse { dropped }),
+                    "Cache cleared successfully.",
+                    id,
+                ))
+            }
+            Request::Stats => {
+                let stats = self.stats().await?;
+                Ok(Response::new(
+                    Some(ResponseData::StatsResponse { stats }),
+                    "Stats collected successfully.",
+                    id,
+                ))
+            }
+            Request::Expire { key, ttl } => {
+                let existed = self.expire(key, ttl).await?;
+                Ok(Response::new(
+                    Some(ResponseData::ExpireResponse { existed }),
+                    "Expiration set successfully.",
+                    id,
+                ))
+            }
+            Request::SetIfAbsent { key, value, ttl } => {
+                let stored = self.set_if_absent(key, value, ttl).await?;
+                Ok(Response::new(
+                    Some(ResponseData::SetIfAbsentResponse { stored }),
+                    if stored {
+                        "Set successfully."
+                    } else {
+                        "Key already exists."
+                    },
+                    id,
+                ))
+            }
+            Request::DeleteIfEquals { key, value } => {
+                let deleted = self.delete_if_equals(key, value).await?;
+                Ok(Response::new(
+                    Some(ResponseData::DeleteIfEqualsResponse { deleted }),
+                    if deleted {
+                        "Deleted successfully."
+                    } else {
+                        "Value did not match."
+                    },
+                    id,
+                ))
+            }
+            Request::ExpireIfEquals { key, value, ttl } => {
+                let existed = self.expire_if_equals(key, value, ttl).await?;
+                Ok(Response::new(
+                    Some(ResponseData::ExpireResponse { existed }),
+                    if existed {
+                        "Expiration set successfully."
+                    } else {
+                        "Value did not match."
+                    },
+                    id,
+                ))
+            }
+            Request::SetIf {
+                key,
+                value,
+                condition,
+            } => {
+                let stored = self.set_if(key, value, condition).await?;
+                Ok(Response::new(
+                    Some(ResponseData::SetIfResponse { stored }),
+                    if stored {
+                        "Set successfully."
+                    } else {
+                        "Condition not met."
+                    },
+                    id,
+                ))
+            }
+            Request::Stat { key } => {
+                let stat = self.stat(key).await?;
+                Ok(Response::new(
+                    Some(stat_response(stat)),
+                    "Stat collected successfully.",
+                    id,
+                ))
+            }
+            Request::Persist { key } => {
+                let existed = self.persist(key).await?;
+                Ok(Response::new(
+                    Some(ResponseData::PersistResponse { existed }),
+                    "Persisted successfully.",
+                    id,
+                ))
+            }
+            Request::Transaction {
+                ops,
+                continue_on_error,
+            } => {
+                if ops
+                    .iter()
+                    .any(|op| matches!(op, Request::Transaction { .. }))
+                {
+                    return Err(Error::BadRequest(
+                        "Nested transactions are not allowed".to_string(),
+                    ));
+                }
+                let results = self.transaction(ops, continue_on_error).await?;
+                Ok(Response::new(
+                    Some(ResponseData::TransactionResponse { results }),
+                    "Transaction executed successfully.",
+                    id,
+                ))
+            }
+            Request::Echo { payload } => Ok(Response::new(
+                Some(ResponseData::EchoResponse { payload }),
+                "Echoed successfully.",
+                id,
+            )),
+            Request::Live => Ok(Response::new(
+                Some(ResponseData::LiveResponse),
+                "Alive.",
+                id,
+            )),
+            Request::Ready => {
+                let ready = self.is_ready();
+                Ok(Response::new(
+                    Some(ResponseData::ReadyResponse { ready }),
+                    if ready { "Ready." } else { "Not ready." },
+                    id,
+                ))
+            }
+            Request::Compact => {
+                let _window = self.maintenance_window();
+                let (bytes_before, bytes_after) = self.compact().await?;
+                Ok(Response::new(
+                    Some(ResponseData::CompactResponse {
+                        bytes_before,
+                        bytes_after,
+                    }),
+                    "Dump compacted successfully.",
+                    id,
+                ))
+            }
+            Request::Info => Ok(Response::new(
+                Some(ResponseData::InfoResponse {
+                    info: Box::new(self.options.info.clone()),
+                }),
+                "Info retrieved.",
+                id,
+            )),
+        }
+    }
+    pub fn set_maintenance(&self, enabled: bool, ttl: Option<Duration>) {
+        let mut maintenance = self
+            .maintenance
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        *maintenance = enabled.then(|| Maintenance {
+            until: ttl.map(|ttl| Instant::now() + ttl),
+            automatic: false,
+        });
+        info!(
+            "Maintenance mode {}",
+            if enabled { "enabled" } else { "disabled" }
+        );
+    }
+    /// Rejects writes until the returned window is dropped.
+    fn maintenance_window(&self) -> MaintenanceWindow<'_> {
+        let mut maintenance = self
+            .maintenance
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if maintenance.is_none() {
+            info!("Compaction started, rejecting writes until it finishes");
+            *maintenance = Some(Maintenance {
+                until: None,
+                automatic: true,
+            });
+        }
+        MaintenanceWindow { executor: self }
+    }
+    pub fn in_maintenance(&self) -> bool {
+        let mut maintenance = self
+            .maintenance
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(Maintenance {
+            until: Some(until), ..
+        }) = *maintenance
+            && until <= Instant::now()
+        {
+            info!("Maintenance window elapsed, accepting writes again");
+            *maintenance = None;
         }
+        maintenance.is_some()
     }
     pub async fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Vec<u8>, Error> {
         let (tx, rx) = oneshot::channel();
@@ -153,10 +826,31 @@ impl Executor {
             .await?;
         rx.await?
     }
-    pub async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+    pub async fn get(&self, key: Vec<u8>, bypass_cache: bool) -> Result<Option<Vec<u8>>, Error> {
         let (tx, rx) = oneshot::channel();
         self.command_tx
-            .send(ExecutorCommands::Get { key, response: tx })
+            .send(ExecutorCommands::Get {
+                key,
+                bypass_cache,
+                response: tx,
+            })
+            .await?;
+        rx.await?
+    }
+    pub async fn get_range(
+        &self,
+        key: Vec<u8>,
+        start: usize,
+        end: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::GetRange {
+                key,
+                start,
+                end,
+                response: tx,
+            })
             .await?;
         rx.await?
     }
@@ -167,6 +861,13 @@ impl Executor {
             .await?;
         rx.await?
     }
+    pub async fn batch_exists_count(&self, keys: Vec<Vec<u8>>) -> Result<usize, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::BatchExistsCount { keys, response: tx })
+            .await?;
+        rx.await?
+    }
     pub async fn delete(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
         let (tx, rx) = oneshot::channel();
         self.command_tx
@@ -174,7 +875,24 @@ impl Executor {
             .await?;
         rx.await?
     }
-    pub async fn list(&self) -> Result<Vec<Vec<u8>>, Error> {
+    pub async fn undelete(&self, key: Vec<u8>) -> Result<bool, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::Undelete { key, response: tx })
+            .await?;
+        rx.await?
+    }
+    pub async fn delete_prefix(&self, prefix: Vec<u8>) -> Result<usize, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::DeletePrefix {
+                prefix,
+                response: tx,
+            })
+            .await?;
+        rx.await?
+    }
+    pub async fn list(&self) -> Result<KeyList, Error> {
         let (tx, rx) = oneshot::channel();
         self.command_tx
             .send(ExecutorCommands::List { response: tx })
@@ -195,34 +913,457 @@ impl Executor {
             .await?;
         rx.await?
     }
-    pub async fn clear(&self) -> Result<(), Error> {
+    pub async fn count_prefix(&self, prefix: Vec<u8>) -> Result<usize, Error> {
         let (tx, rx) = oneshot::channel();
         self.command_tx
-            .send(ExecutorCommands::Clear { response: tx })
+            .send(ExecutorCommands::CountPrefix {
+                prefix,
+                response: tx,
+            })
             .await?;
         rx.await?
     }
-}
-pub enum ExecutorCommands {
-    Set {
+    pub async fn scan(
+        &self,
+        prefix: Vec<u8>,
+        after: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<ScanPage, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::Scan {
+                prefix,
+                after,
+                limit,
+                response: tx,
+            })
+            .await?;
+        rx.await?
+    }
+    pub async fn stats(&self) -> Result<Stats, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::Stats { response: tx })
+            .await?;
+        rx.await?
+    }
+    pub async fn expire(&self, key: Vec<u8>, ttl: Duration) -> Result<bool, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::Expire {
+                key,
+                ttl,
+                response: tx,
+            })
+            .await?;
+        rx.await?
+    }
+    pub async fn set_if_absent(
+        &self,
         key: Vec<u8>,
         value: Vec<u8>,
-        respond_to: oneshot::Sender<Result<Vec<u8>, Error>>,
-    },
-    Get {
-        key: Vec<u8>,
-        response: oneshot::Sender<Result<Option<Vec<u8>>, Error>>,
-    },
-    BatchGet {
-        keys: Vec<Vec<u8>>,
-        response: oneshot::Sender<Result<Vec<Option<Vec<u8>>>, Error>>,
-    },
-    Delete {
+        ttl: Option<Duration>,
+    ) -> Result<bool, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::SetIfAbsent {
+                key,
+                value,
+                ttl,
+                response: tx,
+            })
+            .await?;
+        rx.await?
+    }
+    pub async fn delete_if_equals(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::DeleteIfEquals {
+                key,
+                value,
+                response: tx,
+            })
+            .await?;
+        rx.await?
+    }
+    pub async fn expire_if_equals(
+        &self,
         key: Vec<u8>,
-        response: oneshot::Sender<Result<Option<Vec<u8>>, Error>>,
-    },
-    List {
-        response: oneshot::Sender<Result<Vec<Vec<u8>>, Error>>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::ExpireIfEquals {
+                key,
+                value,
+                ttl,
+                response: tx,
+            })
+            .await?;
+        rx.await?
+    }
+    pub async fn set_if(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        condition: Condition,
+    ) -> Result<bool, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::SetIf {
+                key,
+                value,
+                condition,
+                response: tx,
+            })
+            .await?;
+        rx.await?
+    }
+    pub async fn stat(&self, key: Vec<u8>) -> Result<Option<KeyStat>, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::Stat { key, response: tx })
+            .await?;
+        rx.await?
+    }
+    pub async fn persist(&self, key: Vec<u8>) -> Result<bool, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::Persist { key, response: tx })
+            .await?;
+        rx.await?
+    }
+    pub async fn transaction(
+        &self,
+        ops: Vec<Request>,
+        continue_on_error: bool,
+    ) -> Result<Vec<Result<ResponseData, String>>, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::Transaction {
+                ops,
+                continue_on_error,
+                response: tx,
+            })
+            .await?;
+        rx.await?
+    }
+    pub async fn clear_cache(&self) -> Result<usize, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::ClearCache { response: tx })
+            .await?;
+        rx.await?
+    }
+    pub async fn compact(&self) -> Result<(u64, u64), Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::Compact { response: tx })
+            .await?;
+        rx.await?
+    }
+    pub async fn clear(&self) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::Clear { response: tx })
+            .await?;
+        rx.await?
+    }
+    pub async fn batch_set_ex(
+        &self,
+        entries: Vec<(Vec<u8>, Vec<u8>, Option<Duration>)>,
+    ) -> Result<usize, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::BatchSetEx {
+                entries,
+                response: tx,
+            })
+            .await?;
+        rx.await?
+    }
+    pub async fn replace_all(&self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> Result<usize, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::ReplaceAll {
+                entries,
+                response: tx,
+            })
+            .await?;
+        rx.await?
+    }
+    pub async fn validate_all(
+        &self,
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<Vec<Result<(), String>>, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(ExecutorCommands::ValidateAll {
+                entries,
+                response: tx,
+            })
+            .await?;
+        rx.await?
+    }
+}
+
+fn next_autosync_at(interval: Duration) -> tokio::time::Instant {
+    let random = RandomState::new().build_hasher().finish();
+    let jitter = interval.mul_f64(AUTOSYNC_JITTER * (random as f64 / u64::MAX as f64));
+    tokio::time::Instant::now() + interval + jitter
+}
+
+fn sync_in_background(pending: Option<PendingSync>, what: &'static str) {
+    if let Some(pending) = pending {
+        tokio::spawn(async move {
+            if let Err(e) = pending.await.map_err(StorageError::from).and_then(|r| r) {
+                error!("Failed to sync {}: {:?}", what, e);
+            }
+        });
+    }
+}
+
+fn delete_response(key: Vec<u8>, value: Option<Vec<u8>>) -> ResponseData {
+    ResponseData::DeleteResponse {
+        existed: value.is_some(),
+        key: value.is_some().then_some(key),
+        value,
+    }
+}
+
+fn list_response(keys: Vec<Vec<u8>>, total: usize) -> ResponseData {
+    ResponseData::ListResponse {
+        truncated: keys.len() < total,
+        keys,
+        total_estimate: total,
+    }
+}
+
+fn validation_results(results: Vec<Result<(), StorageError>>) -> Vec<Result<(), String>> {
+    results
+        .into_iter()
+        .map(|result| result.map_err(|e| e.to_string()))
+        .collect()
+}
+
+fn stat_response(stat: Option<KeyStat>) -> ResponseData {
+    ResponseData::StatResponse {
+        exists: stat.is_some(),
+        size: stat.map(|stat| stat.size),
+        ttl_ms: stat.and_then(|stat| stat.ttl_ms),
+        last_access_ms: stat.and_then(|stat| stat.last_access_ms),
+        last_modified_ms: stat.and_then(|stat| stat.last_modified_ms),
+    }
+}
+
+async fn run_transaction(
+    storage: &mut Storage,
+    ops: Vec<Request>,
+    continue_on_error: bool,
+) -> Vec<Result<ResponseData, String>> {
+    let mut results = Vec::with_capacity(ops.len());
+    for op in ops {
+        let result = apply(storage, op).await.map_err(|e| e.to_string());
+        let failed = result.is_err();
+        results.push(result);
+        if failed && !continue_on_error {
+            break;
+        }
+    }
+    results
+}
+
+async fn apply(storage: &mut Storage, request: Request) -> Result<ResponseData, Error> {
+    Ok(match request {
+        Request::Set { key, value } => ResponseData::SetResponse {
+            key: storage.set(key, value).await?,
+        },
+        Request::Get { key, bypass_cache } => ResponseData::GetResponse {
+            value: if bypass_cache {
+                storage.get_uncached(key)?
+            } else {
+                storage.get(key).await?
+            },
+        },
+        Request::GetRange { key, start, end } => ResponseData::GetRangeResponse {
+            value: storage.get_range(key, start, end).await?,
+        },
+        Request::BatchExistsCount { keys } => ResponseData::CountResponse {
+            count: storage.batch_exists_count(&keys)?,
+        },
+        Request::BatchGet { keys } => ResponseData::BatchGetResponse {
+            values: storage.batch_get(keys).await?,
+        },
+        Request::BatchSetEx { entries } => ResponseData::BatchSetExResponse {
+            stored: storage.batch_set_ex(entries).await?,
+        },
+        Request::Delete { key } => {
+            let value = storage.delete(key.clone()).await?;
+            delete_response(key, value)
+        }
+        Request::Undelete { key } => ResponseData::UndeleteResponse {
+            restored: storage.undelete(key).await?,
+        },
+        Request::DeletePrefix { prefix } => ResponseData::DeletePrefixResponse {
+            deleted: storage.delete_prefix(prefix).await?,
+        },
+        Request::List => {
+            let (keys, total) = storage.list()?;
+            list_response(keys, total)
+        }
+        Request::Exists { key } => ResponseData::ExistsResponse {
+            exists: storage.exists(key)?,
+        },
+        Request::Count => ResponseData::CountResponse {
+            count: storage.count()?,
+        },
+        Request::CountPrefix { prefix } => ResponseData::CountResponse {
+            count: storage.count_prefix(&prefix)?,
+        },
+        Request::Scan {
+            prefix,
+            after,
+            limit,
+        } => {
+            let (entries, cursor) = storage.scan(&prefix, after.as_deref(), limit)?;
+            ResponseData::ScanResponse { entries, cursor }
+        }
+        Request::Clear => {
+            storage.clear().await?;
+            ResponseData::ClearResponse
+        }
+        Request::ReplaceAll {
+            entries,
+            validate_only: true,
+        } => ResponseData::ValidationResponse {
+            results: validation_results(storage.validate_all(&entries)),
+        },
+        Request::ReplaceAll { entries, .. } => ResponseData::ReplaceAllResponse {
+            loaded: storage.replace_all(entries).await?,
+        },
+        Request::ClearCache => ResponseData::ClearCacheResponse {
+            dropped: storage.clear_cache().await?,
+        },
+        Request::Stats => ResponseData::StatsResponse {
+            stats: storage.stats()?,
+        },
+        Request::Expire { key, ttl } => ResponseData::ExpireResponse {
+            existed: storage.expire(key, ttl)?,
+        },
+        Request::SetIfAbsent { key, value, ttl } => ResponseData::SetIfAbsentResponse {
+            stored: storage.set_if_absent(key, value, ttl).await?,
+        },
+        Request::DeleteIfEquals { key, value } => ResponseData::DeleteIfEqualsResponse {
+            deleted: storage.delete_if_equals(key, value).await?,
+        },
+        Request::ExpireIfEquals { key, value, ttl } => ResponseData::ExpireResponse {
+            existed: storage.expire_if_equals(key, value, ttl)?,
+        },
+        Request::SetIf {
+            key,
+            value,
+            condition,
+        } => ResponseData::SetIfResponse {
+            stored: storage.set_if(key, value, &condition).await?,
+        },
+        Request::Stat { key } => stat_response(storage.stat(key)?),
+        Request::Persist { key } => ResponseData::PersistResponse {
+            existed: storage.persist(key)?,
+        },
+        Request::Echo { payload } => ResponseData::EchoResponse { payload },
+        Request::Transaction { .. } => {
+            return Err(Error::BadRequest(
+                "Nested transactions are not allowed".to_string(),
+            ));
+        }
+        Request::Maintenance { .. } => {
+            return Err(Error::BadRequest(
+                "Maintenance cannot be toggled inside a transaction".to_string(),
+            ));
+        }
+        Request::Live | Request::Ready => {
+            return Err(Error::BadRequest(
+                "Health probes cannot run inside a transaction".to_string(),
+            ));
+        }
+        Request::Info => {
+            return Err(Error::BadRequest(
+                "Info cannot run inside a transaction".to_string(),
+            ));
+        }
+        Request::Compact => {
+            return Err(Error::BadRequest(
+                "Compaction cannot run inside a transaction".to_string(),
+            ));
+        }
+    })
+}
+
+/// Sends the response once the sync scheduled by the command is on disk,
+/// without holding up the executor loop in the meantime.
+fn respond_after_sync<T: Send + 'static>(
+    pending: Option<PendingSync>,
+    result: Result<T, StorageError>,
+    response: oneshot::Sender<Result<T, Error>>,
+    what: &'static str,
+) {
+    let Some(pending) = pending else {
+        if response.send(result.map_err(|e| e.into())).is_err() {
+            error!("Failed to send {} response", what);
+        }
+        return;
+    };
+    tokio::spawn(async move {
+        let result = match pending.await.map_err(StorageError::from).and_then(|r| r) {
+            Ok(()) => result,
+            Err(e) => Err(e),
+        };
+        if response.send(result.map_err(|e| e.into())).is_err() {
+            error!("Failed to send {} response", what);
+        }
+    });
+}
+
+pub enum ExecutorCommands {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        respond_to: oneshot::Sender<Result<Vec<u8>, Error>>,
+    },
+    Get {
+        key: Vec<u8>,
+        bypass_cache: bool,
+        response: oneshot::Sender<Result<Option<Vec<u8>>, Error>>,
+    },
+    GetRange {
+        key: Vec<u8>,
+        start: usize,
+        end: usize,
+        response: oneshot::Sender<Result<Option<Vec<u8>>, Error>>,
+    },
+    BatchGet {
+        keys: Vec<Vec<u8>>,
+        response: oneshot::Sender<Result<Vec<Option<Vec<u8>>>, Error>>,
+    },
+    BatchExistsCount {
+        keys: Vec<Vec<u8>>,
+        response: oneshot::Sender<Result<usize, Error>>,
+    },
+    Delete {
+        key: Vec<u8>,
+        response: oneshot::Sender<Result<Option<Vec<u8>>, Error>>,
+    },
+    Undelete {
+        key: Vec<u8>,
+        response: oneshot::Sender<Result<bool, Error>>,
+    },
+    DeletePrefix {
+        prefix: Vec<u8>,
+        response: oneshot::Sender<Result<usize, Error>>,
+    },
+    List {
+        response: oneshot::Sender<Result<KeyList, Error>>,
     },
     Exists {
         key: Vec<u8>,
@@ -231,7 +1372,876 @@ pub enum ExecutorCommands {
     Count {
         response: oneshot::Sender<Result<usize, Error>>,
     },
+    CountPrefix {
+        prefix: Vec<u8>,
+        response: oneshot::Sender<Result<usize, Error>>,
+    },
+    Scan {
+        prefix: Vec<u8>,
+        after: Option<Vec<u8>>,
+        limit: usize,
+        response: oneshot::Sender<Result<ScanPage, Error>>,
+    },
+    Stats {
+        response: oneshot::Sender<Result<Stats, Error>>,
+    },
+    Expire {
+        key: Vec<u8>,
+        ttl: Duration,
+        response: oneshot::Sender<Result<bool, Error>>,
+    },
+    Stat {
+        key: Vec<u8>,
+        response: oneshot::Sender<Result<Option<KeyStat>, Error>>,
+    },
+    SetIfAbsent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+        response: oneshot::Sender<Result<bool, Error>>,
+    },
+    DeleteIfEquals {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        response: oneshot::Sender<Result<bool, Error>>,
+    },
+    ExpireIfEquals {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+        response: oneshot::Sender<Result<bool, Error>>,
+    },
+    SetIf {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        condition: Condition,
+        response: oneshot::Sender<Result<bool, Error>>,
+    },
+    Persist {
+        key: Vec<u8>,
+        response: oneshot::Sender<Result<bool, Error>>,
+    },
     Clear {
         response: oneshot::Sender<Result<(), Error>>,
     },
+    ReplaceAll {
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        response: oneshot::Sender<Result<usize, Error>>,
+    },
+    BatchSetEx {
+        entries: Vec<(Vec<u8>, Vec<u8>, Option<Duration>)>,
+        response: oneshot::Sender<Result<usize, Error>>,
+    },
+    ValidateAll {
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        response: oneshot::Sender<Result<Vec<Result<(), String>>, Error>>,
+    },
+    ClearCache {
+        response: oneshot::Sender<Result<usize, Error>>,
+    },
+    Compact {
+        response: oneshot::Sender<Result<(u64, u64), Error>>,
+    },
+    Transaction {
+        ops: Vec<Request>,
+        continue_on_error: bool,
+        response: oneshot::Sender<Result<Vec<Result<ResponseData, String>>, Error>>,
+    },
+}
+
+impl ExecutorCommands {
+    /// Roughly the memory the command holds while it waits in a queue.
+    fn size(&self) -> usize {
+        let payload = match self {
+            ExecutorCommands::Set { key, value, .. }
+            | ExecutorCommands::SetIfAbsent { key, value, .. }
+            | ExecutorCommands::DeleteIfEquals { key, value, .. }
+            | ExecutorCommands::ExpireIfEquals { key, value, .. } => key.len() + value.len(),
+            ExecutorCommands::SetIf {
+                key,
+                value,
+                condition,
+                ..
+            } => {
+                let operand = match condition {
+                    Condition::Equals(expected) => expected.len(),
+                    _ => 0,
+                };
+                key.len() + value.len() + operand
+            }
+            ExecutorCommands::Get { key, .. }
+            | ExecutorCommands::GetRange { key, .. }
+            | ExecutorCommands::Delete { key, .. }
+            | ExecutorCommands::Undelete { key, .. }
+            | ExecutorCommands::Exists { key, .. }
+            | ExecutorCommands::Expire { key, .. }
+            | ExecutorCommands::Stat { key, .. }
+            | ExecutorCommands::Persist { key, .. } => key.len(),
+            ExecutorCommands::DeletePrefix { prefix, .. }
+            | ExecutorCommands::CountPrefix { prefix, .. } => prefix.len(),
+            ExecutorCommands::Scan { prefix, after, .. } => {
+                prefix.len() + after.as_ref().map_or(0, Vec::len)
+            }
+            ExecutorCommands::BatchGet { keys, .. }
+            | ExecutorCommands::BatchExistsCount { keys, .. } => keys.iter().map(Vec::len).sum(),
+            ExecutorCommands::ReplaceAll { entries, .. }
+            | ExecutorCommands::ValidateAll { entries, .. } => entries
+                .iter()
+                .map(|(key, value)| key.len() + value.len())
+                .sum(),
+            ExecutorCommands::BatchSetEx { entries, .. } => entries
+                .iter()
+                .map(|(key, value, _)| key.len() + value.len())
+                .sum(),
+            ExecutorCommands::Transaction { ops, .. } => ops
+                .iter()
+                .map(|op| op.key().map_or(0, <[u8]>::len) + op.value().map_or(0, <[u8]>::len))
+                .sum(),
+            ExecutorCommands::List { .. }
+            | ExecutorCommands::Count { .. }
+            | ExecutorCommands::Stats { .. }
+            | ExecutorCommands::Clear { .. }
+            | ExecutorCommands::ClearCache { .. }
+            | ExecutorCommands::Compact { .. } => 0,
+        };
+        std::mem::size_of::<Self>() + payload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{AES, hash};
+    use crate::storage::StorageOptions;
+
+    async fn executor(name: &str) -> Arc<Executor> {
+        executor_with(name, ExecutorOptions::default()).await
+    }
+
+    async fn executor_with(name: &str, options: ExecutorOptions) -> Arc<Executor> {
+        let path = std::env::temp_dir().join(format!("ckeylock-executor-test-{}.bin", name));
+        let _ = std::fs::remove_file(&path);
+        let storage_options = StorageOptions {
+            max_memory_bytes: Some(64),
+            ..Default::default()
+        };
+        let storage = Storage::new(&path, AES::new(&hash(b"test")), storage_options).unwrap();
+        Executor::loading(async move { Some(storage) }, options).await
+    }
+
+    /// Serves every operation, including the opt-in ones.
+    fn all_operations() -> ExecutorOptions {
+        ExecutorOptions {
+            enabled_operations: Some(
+                Request::OPERATIONS
+                    .iter()
+                    .map(|op| op.to_string())
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    fn set(key: &[u8], value: Vec<u8>) -> Request {
+        Request::Set {
+            key: key.to_vec(),
+            value,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let executor = executor("transaction").await;
+        let results = executor
+            .transaction(
+                vec![
+                    set(b"a", b"1".to_vec()),
+                    set(b"b", b"2".to_vec()),
+                    Request::Delete { key: b"a".to_vec() },
+                    Request::Count,
+                ],
+                false,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(results.len(), 4);
+        assert!(matches!(
+            results[3],
+            Ok(ResponseData::CountResponse { count: 1 })
+        ));
+        assert_eq!(
+            executor.get(b"b".to_vec(), false).await.unwrap(),
+            Some(b"2".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failing_op_aborts_transaction() {
+        let executor = executor("transaction-abort").await;
+        let ops = vec![
+            set(b"a", b"1".to_vec()),
+            set(b"big", vec![0; 128]),
+            set(b"c", b"3".to_vec()),
+        ];
+
+        let results = executor.transaction(ops.clone(), false).await.unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results[1].is_err());
+        assert!(!executor.exists(b"c".to_vec()).await.unwrap());
+        // The failure doesn't undo the ops that ran before it.
+        assert_eq!(
+            executor.get(b"a".to_vec(), false).await.unwrap(),
+            Some(b"1".to_vec())
+        );
+
+        let results = executor.transaction(ops, true).await.unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(executor.exists(b"c".to_vec()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_list_reports_truncation() {
+        let path = std::env::temp_dir().join("ckeylock-executor-test-list-cap.bin");
+        let _ = std::fs::remove_file(&path);
+        let options = StorageOptions {
+            max_list_keys: Some(2),
+            ..Default::default()
+        };
+        let executor =
+            Executor::new(Storage::new(&path, AES::new(&hash(b"test")), options).unwrap()).await;
+        for i in 0..5u8 {
+            executor
+                .execute(RequestWrapper::new(set(&[i], vec![i])))
+                .await
+                .unwrap();
+        }
+
+        let response = executor
+            .execute(RequestWrapper::new(Request::List))
+            .await
+            .unwrap();
+        match response.data() {
+            Some(ResponseData::ListResponse {
+                keys,
+                truncated,
+                total_estimate,
+            }) => {
+                assert_eq!(keys.len(), 2);
+                assert!(truncated);
+                assert_eq!(*total_estimate, 5);
+            }
+            other => panic!("Unexpected response data: {:?}", other),
+        }
+
+        executor
+            .execute(RequestWrapper::new(Request::DeletePrefix {
+                prefix: vec![],
+            }))
+            .await
+            .unwrap();
+        executor
+            .execute(RequestWrapper::new(set(b"a", vec![1])))
+            .await
+            .unwrap();
+        let response = executor
+            .execute(RequestWrapper::new(Request::List))
+            .await
+            .unwrap();
+        assert!(matches!(
+            response.data(),
+            Some(ResponseData::ListResponse {
+                truncated: false,
+                total_estimate: 1,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_replace_all_is_atomic_for_readers() {
+        let path = std::env::temp_dir().join("ckeylock-executor-test-replace-all.bin");
+        let _ = std::fs::remove_file(&path);
+        let executor = Executor::new(
+            Storage::new(&path, AES::new(&hash(b"test")), StorageOptions::default()).unwrap(),
+        )
+        .await;
+        for i in 0..10u8 {
+            executor.set(vec![i], vec![i]).await.unwrap();
+        }
+
+        let reader = executor.session();
+        let observed = tokio::spawn(async move {
+            let mut observed = std::collections::BTreeSet::new();
+            for _ in 0..200 {
+                observed.insert(reader.count().await.unwrap());
+            }
+            observed
+        });
+        let entries = (0..1000u16)
+            .map(|i| (i.to_be_bytes().to_vec(), vec![1]))
+            .collect();
+        let response = executor
+            .execute(RequestWrapper::new(Request::ReplaceAll {
+                entries,
+                validate_only: false,
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            response.data(),
+            Some(ResponseData::ReplaceAllResponse { loaded: 1000 })
+        ));
+
+        let observed = observed.await.unwrap();
+        assert!(observed.iter().all(|count| *count == 10 || *count == 1000));
+        assert_eq!(executor.get(vec![0], false).await.unwrap(), None);
+        assert_eq!(executor.count().await.unwrap(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_rejects_writes() {
+        let executor = executor_with("maintenance", all_operations()).await;
+        executor.set(b"a".to_vec(), b"1".to_vec()).await.unwrap();
+
+        let toggle = |enabled| RequestWrapper::new(Request::Maintenance { enabled, ttl: None });
+        executor.execute(toggle(true)).await.unwrap();
+        let err = executor
+            .execute(RequestWrapper::new(set(b"b", b"2".to_vec())))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ckeylock_core::response::ErrorCode::Maintenance);
+        let read = executor
+            .execute(RequestWrapper::new(Request::Get {
+                key: b"a".to_vec(),
+                bypass_cache: false,
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            read.data(),
+            Some(ResponseData::GetResponse { value: Some(_) })
+        ));
+
+        executor.execute(toggle(false)).await.unwrap();
+        executor
+            .execute(RequestWrapper::new(set(b"b", b"2".to_vec())))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_is_opt_in() {
+        let executor = executor("maintenance-opt-in").await;
+        let err = executor
+            .execute(RequestWrapper::new(Request::Maintenance {
+                enabled: true,
+                ttl: None,
+            }))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::OperationDisabled("maintenance")));
+        assert!(!executor.in_maintenance());
+    }
+
+    #[tokio::test]
+    async fn test_compaction_holds_maintenance_until_done() {
+        let path = std::env::temp_dir().join("ckeylock-executor-test-compact-maintenance.bin");
+        let _ = std::fs::remove_file(&path);
+        // Commands wait until storage loads, so the compaction stays running
+        // until the test releases it.
+        let (load_tx, load_rx) = oneshot::channel();
+        let executor = Executor::loading(
+            async move { load_rx.await.ok() },
+            ExecutorOptions::default(),
+        )
+        .await;
+        let compaction = tokio::spawn({
+            let executor = Arc::clone(&executor);
+            async move {
+                executor
+                    .execute(RequestWrapper::new(Request::Compact))
+                    .await
+            }
+        });
+        while !executor.in_maintenance() {
+            tokio::task::yield_now().await;
+        }
+        let err = executor
+            .execute(RequestWrapper::new(set(b"a", b"1".to_vec())))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Maintenance));
+
+        let storage =
+            Storage::new(&path, AES::new(&hash(b"test")), StorageOptions::default()).unwrap();
+        load_tx.send(storage).unwrap();
+        compaction.await.unwrap().unwrap();
+        assert!(!executor.in_maintenance());
+        executor.set(b"a".to_vec(), b"1".to_vec()).await.unwrap();
+
+        // Maintenance an operator turned on outlives the compaction.
+        executor.set_maintenance(true, None);
+        executor
+            .execute(RequestWrapper::new(Request::Compact))
+            .await
+            .unwrap();
+        assert!(executor.in_maintenance());
+    }
+
+    #[tokio::test]
+    async fn test_validate_only_replace_all_writes_nothing() {
+        let executor = executor_with("validate-only", all_operations()).await;
+        executor.set(b"a".to_vec(), b"1".to_vec()).await.unwrap();
+        // Validation isn't a write, so maintenance doesn't block it.
+        executor
+            .execute(RequestWrapper::new(Request::Maintenance {
+                enabled: true,
+                ttl: None,
+            }))
+            .await
+            .unwrap();
+
+        let entries = vec![
+            (b"b".to_vec(), vec![0; 10]),
+            (b"big".to_vec(), vec![0; 100]),
+            (b"c".to_vec(), vec![0; 40]),
+            (b"d".to_vec(), vec![0; 20]),
+        ];
+        let response = executor
+            .execute(RequestWrapper::new(Request::ReplaceAll {
+                entries,
+                validate_only: true,
+            }))
+            .await
+            .unwrap();
+        let Some(ResponseData::ValidationResponse { results }) = response.data() else {
+            panic!("Unexpected response: {:?}", response.data());
+        };
+        let passed: Vec<_> = results.iter().map(Result::is_ok).collect();
+        assert_eq!(passed, [true, false, true, false]);
+        assert!(results[1].as_ref().unwrap_err().contains("memory limit"));
+
+        assert_eq!(executor.count().await.unwrap(), 1);
+        assert_eq!(
+            executor.get(b"a".to_vec(), false).await.unwrap(),
+            Some(b"1".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flooding_session_does_not_starve_others() {
+        let executor = executor("fairness").await;
+        let flooder = executor.session();
+        let polite = executor.session();
+        let served = Arc::new(std::sync::Mutex::new(Vec::new()));
+        // On a current-thread runtime all of these queue their command before
+        // the executor runs again, so its service order is observable.
+        let mut tasks = Vec::new();
+        for (name, session) in std::iter::repeat_n(("flooder", &flooder), QUEUE_CAPACITY - 2)
+            .chain([("polite", &polite)])
+        {
+            let session = Arc::clone(session);
+            let served = Arc::clone(&served);
+            tasks.push(tokio::spawn(async move {
+                session.get(b"key".to_vec(), false).await.unwrap();
+                served.lock().unwrap().push(name);
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let served = served.lock().unwrap();
+        let position = served.iter().position(|name| *name == "polite").unwrap();
+        assert!(position <= 1, "served after {} flooding requests", position);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_operations_are_rejected() {
+        let path = std::env::temp_dir().join("ckeylock-executor-test-allowlist.bin");
+        let _ = std::fs::remove_file(&path);
+        let storage =
+            Storage::new(&path, AES::new(&hash(b"test")), StorageOptions::default()).unwrap();
+        let options = ExecutorOptions {
+            enabled_operations: Some(
+                ["set", "get", "count", "transaction"]
+                    .map(String::from)
+                    .into(),
+            ),
+            ..Default::default()
+        };
+        let executor = Executor::loading(async move { Some(storage) }, options).await;
+        let run = |req| executor.execute(RequestWrapper::new(req));
+
+        run(set(b"key", b"value".to_vec())).await.unwrap();
+        assert!(matches!(
+            run(Request::Clear).await,
+            Err(Error::OperationDisabled("clear"))
+        ));
+        assert!(matches!(
+            run(Request::Transaction {
+                ops: vec![Request::Count, Request::Clear],
+                continue_on_error: true,
+            })
+            .await,
+            Err(Error::OperationDisabled("clear"))
+        ));
+        assert_eq!(executor.count().await.unwrap(), 1);
+        run(Request::Get {
+            key: b"key".to_vec(),
+            bypass_cache: false,
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let path = std::env::temp_dir().join("ckeylock-executor-test-readiness.bin");
+        let _ = std::fs::remove_file(&path);
+        let (load_tx, load_rx) = oneshot::channel();
+        let executor = Executor::loading(
+            async move { load_rx.await.ok() },
+            ExecutorOptions::default(),
+        )
+        .await;
+        let probe = |req| executor.execute(RequestWrapper::new(req));
+        let ready = |response: Response| match response.data() {
+            Some(ResponseData::ReadyResponse { ready }) => *ready,
+            other => panic!("Unexpected response data: {:?}", other),
+        };
+
+        probe(Request::Live).await.unwrap();
+        assert!(!ready(probe(Request::Ready).await.unwrap()));
+        let count = tokio::spawn({
+            let executor = executor.session();
+            async move { executor.count().await }
+        });
+
+        let storage =
+            Storage::new(&path, AES::new(&hash(b"test")), StorageOptions::default()).unwrap();
+        load_tx.send(storage).unwrap();
+        assert_eq!(count.await.unwrap().unwrap(), 0);
+        assert!(ready(probe(Request::Ready).await.unwrap()));
+
+        executor.set_maintenance(true, None);
+        assert!(!ready(probe(Request::Ready).await.unwrap()));
+        executor.set_maintenance(false, None);
+        assert!(ready(probe(Request::Ready).await.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_info() {
+        let options = ExecutorOptions {
+            info: ServerInfo {
+                cache_size: 100,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let executor = Executor::loading(
+            async { Some(Storage::volatile(Default::default())) },
+            options,
+        )
+        .await;
+        let response = executor
+            .execute(RequestWrapper::new(Request::Info))
+            .await
+            .unwrap();
+        assert!(matches!(
+            response.data(),
+            Some(ResponseData::InfoResponse { info }) if info.cache_size == 100
+        ));
+        let response = executor
+            .execute(RequestWrapper::new(Request::Transaction {
+                ops: vec![Request::Info],
+                continue_on_error: false,
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            response.data(),
+            Some(ResponseData::TransactionResponse { results }) if results[0].is_err()
+        ));
+    }
+
+    /// Uppercases values as they are stored and turns away empty ones.
+    #[derive(Debug)]
+    struct Uppercase;
+
+    impl Transform for Uppercase {
+        fn on_set(&self, _key: &[u8], value: Vec<u8>) -> Result<Vec<u8>, String> {
+            if value.is_empty() {
+                return Err("Empty values are not allowed".to_string());
+            }
+            Ok(value.to_ascii_uppercase())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transform_rewrites_values() {
+        let options = ExecutorOptions {
+            transform: Some(Arc::new(Uppercase)),
+            ..Default::default()
+        };
+        let load = async { Some(Storage::volatile(Default::default())) };
+        let executor = Executor::loading(load, options).await;
+        executor
+            .set(b"greeting".to_vec(), b"hello".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            executor.get(b"greeting".to_vec(), false).await.unwrap(),
+            Some(b"HELLO".to_vec())
+        );
+        assert_eq!(
+            executor.get(b"greeting".to_vec(), true).await.unwrap(),
+            Some(b"HELLO".to_vec())
+        );
+        assert_eq!(
+            executor
+                .get_range(b"greeting".to_vec(), 1, 3)
+                .await
+                .unwrap(),
+            Some(b"EL".to_vec())
+        );
+        assert_eq!(
+            executor
+                .batch_get(vec![b"greeting".to_vec(), b"missing".to_vec()])
+                .await
+                .unwrap(),
+            vec![Some(b"HELLO".to_vec()), None]
+        );
+
+        let rejected = executor.set(b"empty".to_vec(), Vec::new()).await;
+        assert!(matches!(
+            rejected,
+            Err(Error::StorageError(StorageError::Transform(_)))
+        ));
+        assert_eq!(
+            rejected.unwrap_err().code(),
+            ckeylock_core::response::ErrorCode::BadRequest
+        );
+    }
+
+    /// Records where the last value it was handed lives in memory.
+    #[derive(Debug, Default)]
+    struct RecordAddress(AtomicUsize);
+
+    impl Transform for RecordAddress {
+        fn on_set(&self, _key: &[u8], value: Vec<u8>) -> Result<Vec<u8>, String> {
+            self.0.store(value.as_ptr() as usize, Ordering::SeqCst);
+            Ok(value)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_does_not_copy_the_request() {
+        let recorded = Arc::new(RecordAddress::default());
+        let options = ExecutorOptions {
+            transform: Some(Arc::clone(&recorded) as Arc<dyn Transform>),
+            ..Default::default()
+        };
+        let load = async { Some(Storage::volatile(Default::default())) };
+        let executor = Executor::loading(load, options).await;
+
+        let value = vec![1; 1 << 20];
+        let address = value.as_ptr() as usize;
+        let request = RequestWrapper::new(Request::Set {
+            key: b"executed".to_vec(),
+            value,
+        });
+        let response = executor.execute(request).await.unwrap();
+        assert!(matches!(
+            response.data(),
+            Some(ResponseData::SetResponse { .. })
+        ));
+        // The value reaches storage in the buffer it was sent in.
+        assert_eq!(recorded.0.load(Ordering::SeqCst), address);
+    }
+
+    /// An executor whose storage loads only once the returned sender fires,
+    /// so commands pile up in its queues until then.
+    async fn held_executor(
+        queue_memory_limit: Option<usize>,
+    ) -> (Arc<Executor>, tokio::sync::oneshot::Sender<()>) {
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let options = ExecutorOptions {
+            queue_memory_limit,
+            ..Default::default()
+        };
+        let load = async move {
+            released.await.ok()?;
+            Some(Storage::volatile(Default::default()))
+        };
+        (Executor::loading(load, options).await, release)
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_answers_busy() {
+        let (executor, release) = held_executor(None).await;
+        let sets: Vec<_> = (0..QUEUE_CAPACITY as u8)
+            .map(|i| {
+                let executor = Arc::clone(&executor);
+                tokio::spawn(async move { executor.set(vec![i], b"v".to_vec()).await })
+            })
+            .collect();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !matches!(&executor.command_tx, Intake::Bounded(tx) if tx.capacity() == 0) {
+            assert!(Instant::now() < deadline, "commands were not queued");
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        // Turned away at once instead of waiting for the queue to drain.
+        let busy = tokio::time::timeout(Duration::from_secs(1), executor.count())
+            .await
+            .expect("a full queue made the sender wait");
+        let err = busy.unwrap_err();
+        assert!(matches!(err, Error::Busy));
+        assert_eq!(err.code(), ckeylock_core::response::ErrorCode::Busy);
+        assert_eq!(err.retry_after(), Some(BUSY_RETRY_AFTER));
+
+        release.send(()).unwrap();
+        for set in sets {
+            set.await.unwrap().unwrap();
+        }
+        assert_eq!(executor.count().await.unwrap(), QUEUE_CAPACITY);
+    }
+
+    async fn wait_queued(executor: &Executor, bytes: usize) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while executor.queued_bytes.load(Ordering::Relaxed) != bytes {
+            assert!(Instant::now() < deadline, "commands were not queued");
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unbounded_queue_absorbs_bursts() {
+        let (executor, release) = held_executor(Some(1 << 20)).await;
+        let sets: Vec<_> = (0..100u8)
+            .map(|i| {
+                let executor = Arc::clone(&executor);
+                tokio::spawn(async move { executor.set(vec![i], vec![0; 1024]).await })
+            })
+            .collect();
+        // Far more than a fixed-size queue holds before senders wait.
+        let per_set = std::mem::size_of::<ExecutorCommands>() + 1 + 1024;
+        wait_queued(&executor, 100 * per_set).await;
+
+        release.send(()).unwrap();
+        for set in sets {
+            set.await.unwrap().unwrap();
+        }
+        assert_eq!(executor.queued_bytes.load(Ordering::Relaxed), 0);
+        assert_eq!(executor.count().await.unwrap(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_queue_memory_limit_sheds_load() {
+        let per_set = std::mem::size_of::<ExecutorCommands>() + 1 + 1024;
+        let (executor, release) = held_executor(Some(3 * per_set)).await;
+        let sets: Vec<_> = (0..3u8)
+            .map(|i| {
+                let executor = Arc::clone(&executor);
+                tokio::spawn(async move { executor.set(vec![i], vec![0; 1024]).await })
+            })
+            .collect();
+        wait_queued(&executor, 3 * per_set).await;
+
+        let err = executor.set(vec![3], vec![0; 1024]).await.unwrap_err();
+        assert!(matches!(err, Error::Busy));
+        assert_eq!(err.code(), ckeylock_core::response::ErrorCode::Busy);
+        assert!(matches!(executor.count().await, Err(Error::Busy)));
+
+        release.send(()).unwrap();
+        for set in sets {
+            set.await.unwrap().unwrap();
+        }
+        executor.set(vec![3], vec![0; 1024]).await.unwrap();
+        assert_eq!(executor.count().await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_autosync_persists_writes() {
+        let path = std::env::temp_dir().join("ckeylock-executor-test-autosync.bin");
+        let _ = std::fs::remove_file(&path);
+        let options = StorageOptions {
+            autosync_interval: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let executor =
+            Executor::new(Storage::new(&path, AES::new(&hash(b"test")), options).unwrap()).await;
+        executor.set(b"a".to_vec(), b"1".to_vec()).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let reloaded =
+            Storage::new(&path, AES::new(&hash(b"test")), StorageOptions::default()).unwrap();
+        assert_eq!(reloaded.count().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_autosync_jitter_is_bounded() {
+        let interval = Duration::from_secs(10);
+        for _ in 0..100 {
+            let delay = next_autosync_at(interval) - tokio::time::Instant::now();
+            assert!(delay <= interval.mul_f64(1.0 + AUTOSYNC_JITTER));
+            assert!(delay + Duration::from_millis(100) >= interval);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expiry_follows_the_storage_clock() {
+        let clock = Arc::new(crate::clock::MockClock::default());
+        let executor = Executor::new(Storage::volatile(StorageOptions {
+            clock: Some(clock.clone()),
+            ..Default::default()
+        }))
+        .await;
+        executor.set(b"a".to_vec(), b"1".to_vec()).await.unwrap();
+        assert!(
+            executor
+                .expire(b"a".to_vec(), Duration::from_secs(3600))
+                .await
+                .unwrap()
+        );
+
+        clock.advance(Duration::from_secs(3599));
+        assert_eq!(
+            executor.get(b"a".to_vec(), false).await.unwrap(),
+            Some(b"1".to_vec())
+        );
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(executor.get(b"a".to_vec(), false).await.unwrap(), None);
+        assert_eq!(executor.count().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_expires() {
+        let executor = executor("maintenance-ttl").await;
+        executor.set_maintenance(true, Some(Duration::from_millis(50)));
+        assert!(executor.in_maintenance());
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert!(!executor.in_maintenance());
+        executor
+            .execute(RequestWrapper::new(set(b"a", b"1".to_vec())))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_nested_transaction_is_rejected() {
+        let executor = executor("transaction-nested").await;
+        let request = RequestWrapper::new(Request::Transaction {
+            ops: vec![Request::Transaction {
+                ops: vec![],
+                continue_on_error: false,
+            }],
+            continue_on_error: false,
+        });
+        let err = executor.execute(request).await.unwrap_err();
+        assert_eq!(err.code(), ckeylock_core::response::ErrorCode::BadRequest);
+    }
 }
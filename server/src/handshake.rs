@@ -0,0 +1,162 @@
+//! Bounded reading of the HTTP request that opens a WebSocket handshake.
+//!
+//! The handshake library buffers the request head for as long as the client
+//! keeps sending headers. [`read_head`] reads it first, up to a size limit,
+//! and hands the bytes back through [`Rewound`] so the handshake proper still
+//! sees the whole request.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+pub const DEFAULT_MAX_HEAD_SIZE: usize = 16 * 1024;
+const HEAD_END: &[u8] = b"\r\n\r\n";
+const TOO_LARGE_RESPONSE: &[u8] = b"HTTP/1.1 431 Request Header Fields Too Large\r\n\
+    Content-Length: 0\r\nConnection: close\r\n\r\n";
+/// How long a rejected client gets to stop sending before the socket closes.
+/// Closing with its headers still unread would reset the connection and could
+/// discard the 431 before the client reads it.
+const LINGER: Duration = Duration::from_millis(500);
+
+/// A stream that first replays bytes already read from it.
+#[derive(Debug)]
+pub struct Rewound<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewound<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pos < self.prefix.len() {
+            let rest = &self.prefix[self.pos..];
+            let len = rest.len().min(buf.remaining());
+            buf.put_slice(&rest[..len]);
+            self.pos += len;
+            if self.pos == self.prefix.len() {
+                self.prefix = Vec::new();
+                self.pos = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewound<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Reads the request head, answering `431 Request Header Fields Too Large`
+/// and failing once it passes `max` bytes without ending.
+pub async fn read_head(mut stream: TcpStream, max: usize) -> io::Result<Rewound<TcpStream>> {
+    let mut head = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        // The end marker may straddle the previous chunk.
+        let from = head.len().saturating_sub(HEAD_END.len() - 1);
+        head.extend_from_slice(&chunk[..read]);
+        if let Some(at) = find(&head[from..], HEAD_END) {
+            if from + at + HEAD_END.len() > max {
+                break;
+            }
+            return Ok(Rewound {
+                prefix: head,
+                pos: 0,
+                inner: stream,
+            });
+        }
+        if head.len() > max {
+            break;
+        }
+    }
+    if stream.write_all(TOO_LARGE_RESPONSE).await.is_ok() && stream.shutdown().await.is_ok() {
+        let drain =
+            async { while matches!(stream.read(&mut chunk).await, Ok(read) if read > 0) {} };
+        let _ = tokio::time::timeout(LINGER, drain).await;
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("handshake request head exceeds {} bytes", max),
+    ))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_head_is_replayed() {
+        let (mut client, server) = pair().await;
+        let head = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+        // Split so the end marker straddles two reads.
+        client.write_all(&head[..head.len() - 2]).await.unwrap();
+        let reading = tokio::spawn(read_head(server, 1024));
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        client.write_all(&head[head.len() - 2..]).await.unwrap();
+        client.write_all(b"rest").await.unwrap();
+        drop(client);
+
+        let mut stream = reading.await.unwrap().unwrap();
+        let mut replayed = Vec::new();
+        stream.read_to_end(&mut replayed).await.unwrap();
+        assert_eq!(replayed, [&head[..], b"rest"].concat());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_head_is_rejected() {
+        let (mut client, server) = pair().await;
+        let reading = tokio::spawn(read_head(server, 64));
+        client
+            .write_all(format!("GET / HTTP/1.1\r\nX-Big: {}\r\n", "a".repeat(100)).as_bytes())
+            .await
+            .unwrap();
+
+        let err = reading.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 431"));
+    }
+}
@@ -1,25 +1,63 @@
+mod cache;
+mod chunked;
+mod clock;
+mod compression;
 mod conf;
 mod crypto;
 mod executor;
+mod handshake;
+mod sampling;
+#[cfg(feature = "statsd")]
+mod statsd;
 mod storage;
+mod transform;
 mod ws;
 
-use clap::Parser;
-use conf::Config;
+use ckeylock_core::response::ErrorCode;
+use clap::{Parser, Subcommand};
+use conf::{Config, Persistence, StoreConfig};
 use crypto::hash;
-use storage::Storage;
+use std::sync::Arc;
+use std::time::Duration;
+use storage::{Storage, StorageOptions};
 use tokio::select;
 use tokio::signal;
-use ws::WsServer;
+use tokio::sync::mpsc;
+use ws::{ServerOptions, Stores, WsServer};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
+    /// Path to the TOML config, or `-` to read it from stdin.
     #[arg(short, long, default_value = CKEYLOCK_CONFIG_PATH)]
     pub config: String,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Print the settings the `info` request reports for this config, with
+    /// passwords and other secrets left out.
+    Info,
+    /// Rewrite every store's dump from scratch to reclaim space, then exit.
+    /// Run it while the server is stopped; a running one compacts on the
+    /// `compact` request instead.
+    Compact,
+    /// Print how many keys in each store start with `prefix`, then exit.
+    CountPrefix { prefix: String },
+    /// Delete the keys in each store that start with `--prefix`, then exit.
+    /// Run it while the server is stopped; a running one deletes on the
+    /// `delete_prefix` request instead.
+    Delete {
+        #[arg(long)]
+        prefix: String,
+    },
 }
 
 const CKEYLOCK_CONFIG_PATH: &str = "Ckeylock.toml";
+/// Exit code when a dump can't be decrypted with its configured key.
+const EXIT_DUMP_KEY: i32 = 3;
 
 #[tokio::main]
 async fn main() {
@@ -34,30 +72,321 @@ async fn main() {
 
     let args = Args::parse();
     let conf = Config::from_toml(&args.config).unwrap_or_else(|e| {
-        panic!("Failed to load config: {}", e.to_string());
-    });
-    let key = hash(conf.dump_password.as_bytes());
-    let aes = crypto::AES::new(&key);
-    let storage = Storage::new(conf.dump_path, aes).unwrap_or_else(|e| {
-        panic!("Failed to initialize storage: {}", e.to_string());
+        panic!("Failed to load config: {}", e);
     });
-    let executor = executor::Executor::new(storage).await;
-
-    let ws_server = WsServer::new(&conf.bind, conf.password, executor, conf.workers);
+    if let Some(Command::Info) = args.command {
+        let info = serde_json::to_string_pretty(&conf.info()).expect("Info serializes to JSON");
+        println!("{}", info);
+        return;
+    }
+    if let Err(e) = check_dump_keys(&conf) {
+        eprintln!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+    let options = StorageOptions {
+        compression_threshold: conf.compression_threshold,
+        max_memory_bytes: conf.max_memory_bytes,
+        max_keys: conf.max_keys,
+        eviction_policy: conf.eviction_policy,
+        cache_write: conf.cache_write.unwrap_or_default(),
+        require_utf8_values: conf.require_utf8_values.unwrap_or(false),
+        lazy_clear: conf.lazy_clear.unwrap_or(false),
+        autosync_interval: conf.autosync_interval_secs.map(Duration::from_secs),
+        max_list_keys: conf.max_list_keys,
+        ttl_jitter: conf.ttl_jitter,
+        sync_retries: conf.sync_retries,
+        dead_letter_prefix: conf.dead_letter_prefix.clone().map(String::into_bytes),
+        soft_delete: conf.soft_delete_secs.map(Duration::from_secs),
+        clock: None,
+    };
+    if let Some(Command::Compact) = args.command {
+        compact_dumps(&conf, options).await;
+        return;
+    }
+    if let Some(Command::CountPrefix { prefix }) = &args.command {
+        count_prefix(&conf, options, prefix.as_bytes()).await;
+        return;
+    }
+    if let Some(Command::Delete { prefix }) = &args.command {
+        delete_prefix(&conf, options, prefix.as_bytes()).await;
+        return;
+    }
+    let log_cache_evictions = conf.log_cache_evictions.unwrap_or(false);
+    let log_expirations = conf.log_expirations.unwrap_or(false);
+    let executor_options = executor::ExecutorOptions {
+        enabled_operations: conf
+            .enabled_operations
+            .clone()
+            .map(|operations| operations.into_iter().collect()),
+        info: conf.info(),
+        queue_memory_limit: conf.queue_memory_limit,
+        transform: None,
+    };
+    // Dumps load in the background so liveness probes are answered while a
+    // large one is decrypted; readiness probes report not ready until then.
+    let (load_errors_tx, mut load_errors) = mpsc::unbounded_channel();
+    let start = |name: String, store: StoreConfig| {
+        start_store(
+            name,
+            store,
+            options.clone(),
+            executor_options.clone(),
+            log_cache_evictions,
+            log_expirations,
+            load_errors_tx.clone(),
+        )
+    };
+    let default_executor = start("default".to_string(), conf.default_store()).await;
+    let mut executors = vec![("default".to_string(), Arc::clone(&default_executor))];
+    let mut stores = Stores::new(default_executor);
+    for (name, store) in conf.stores.clone().unwrap_or_default() {
+        let executor = start(name.clone(), store).await;
+        executors.push((name.clone(), Arc::clone(&executor)));
+        stores = stores.with_store(name, executor);
+    }
+    drop(load_errors_tx);
+    start_statsd(&conf, executors).await;
 
+    let options = ServerOptions {
+        concurrent_limit: conf.workers,
+        max_requests: conf.max_requests_per_connection,
+        log_sample_rate: conf.log_sample_rate,
+        signing_secret: conf.signing_secret.map(String::into_bytes),
+        slow_threshold: conf.slow_threshold_ms.map(Duration::from_millis),
+        handshake_timeout: conf.handshake_timeout_secs.map(Duration::from_secs),
+        unsupported_message: conf.unsupported_message.unwrap_or_default(),
+        no_delay: conf.tcp_nodelay,
+        max_message_size: conf.max_message_size,
+        shutdown_grace: conf.shutdown_grace_secs.map(Duration::from_secs),
+        ping_interval: conf.ping_interval_secs.map(Duration::from_secs),
+        max_missed_pongs: conf.max_missed_pongs,
+        max_handshake_size: conf.max_handshake_bytes,
+        unpaired_timeout: conf.unpaired_timeout_secs.map(Duration::from_secs),
+        max_unpaired_bytes: conf.queue_memory_limit,
+    };
     let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate()).unwrap();
+    let shutdown = async move {
+        select! {
+            _ = signal::ctrl_c() => {
+                tracing::info!("Received SIGINT (Ctrl+C), shutting down.");
+            }
+            _ = sigterm.recv() => {
+                tracing::info!("Received SIGTERM, shutting down.");
+            }
+        }
+    };
+    let ws_server = WsServer::serve_until(&conf.bind, conf.password, stores, options, shutdown);
 
     select! {
         res = ws_server => {
-            res.unwrap_or_else(|e| {
-                panic!("Failed to start WebSocket server: {}", e.to_string());
+            let report = res.unwrap_or_else(|e| {
+                panic!("Failed to start WebSocket server: {}", e);
+            });
+            tracing::info!("Shutdown complete: {:?}", report);
+        }
+        Some(e) = load_errors.recv() => {
+            panic!("Failed to initialize storage: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "statsd")]
+async fn start_statsd(conf: &Config, executors: Vec<(String, Arc<executor::Executor>)>) {
+    let Some(addr) = &conf.statsd_addr else {
+        return;
+    };
+    let interval = conf
+        .statsd_interval_secs
+        .map_or(statsd::DEFAULT_INTERVAL, Duration::from_secs);
+    match statsd::Exporter::new(addr, executors).await {
+        Ok(exporter) => {
+            tracing::info!("Pushing metrics to statsd at {} every {:?}", addr, interval);
+            exporter.spawn(interval);
+        }
+        Err(e) => tracing::error!("Failed to start statsd exporter for {}: {}", addr, e),
+    }
+}
+
+#[cfg(not(feature = "statsd"))]
+async fn start_statsd(conf: &Config, _executors: Vec<(String, Arc<executor::Executor>)>) {
+    if conf.statsd_addr.is_some() {
+        tracing::warn!("statsd_addr is set, but this build has no statsd support");
+    }
+}
+
+/// Starts an executor for one store while its storage loads in the
+/// background. A failed load is reported on `load_errors`.
+async fn start_store(
+    name: String,
+    store: StoreConfig,
+    options: StorageOptions,
+    executor_options: executor::ExecutorOptions,
+    log_cache_evictions: bool,
+    log_expirations: bool,
+    load_errors: mpsc::UnboundedSender<String>,
+) -> Arc<executor::Executor> {
+    let load = tokio::task::spawn_blocking(move || open_storage(store, options));
+    let load = async move {
+        let mut storage = match load.await {
+            Ok(Ok(storage)) => storage,
+            Ok(Err(e)) => {
+                let _ = load_errors.send(format!("{} store: {}", name, e));
+                return None;
+            }
+            Err(e) => {
+                let _ = load_errors.send(format!("{} store: {}", name, e));
+                return None;
+            }
+        };
+        if log_cache_evictions {
+            let mut evictions = storage.subscribe_evictions();
+            tokio::spawn(async move {
+                while let Some(eviction) = evictions.recv().await {
+                    tracing::debug!(
+                        "Evicted key {:?} ({} bytes) from cache",
+                        hex::encode(&eviction.key),
+                        eviction.size
+                    );
+                }
             });
         }
-        _ = signal::ctrl_c() => {
-            tracing::info!("Received SIGINT (Ctrl+C), shutting down.");
+        if log_expirations {
+            let mut expirations = storage.subscribe_expirations();
+            tokio::spawn(async move {
+                while let Some(expiration) = expirations.recv().await {
+                    tracing::info!(
+                        "Key {:?} expired ({} bytes)",
+                        hex::encode(&expiration.key),
+                        expiration.value.len()
+                    );
+                }
+            });
         }
-        _ = sigterm.recv() => {
-            tracing::info!("Received SIGTERM, shutting down.");
+        Some(storage)
+    };
+    executor::Executor::loading(load, executor_options).await
+}
+
+fn open_storage(store: StoreConfig, options: StorageOptions) -> Result<Storage, String> {
+    match store.persistence.unwrap_or_default() {
+        Persistence::None => Ok(Storage::volatile(options)),
+        Persistence::File => {
+            let key = dump_key(&store)?;
+            let Some(dump_path) = store.dump_path else {
+                unreachable!("Config validation requires dump settings for file persistence");
+            };
+            Storage::new(dump_path, crypto::AES::new(&key), options).map_err(|e| e.to_string())
+        }
+    }
+}
+
+fn dump_key(store: &StoreConfig) -> Result<[u8; 32], String> {
+    match (&store.dump_password, &store.dump_key_file) {
+        (Some(dump_password), _) => Ok(hash(dump_password.as_bytes())),
+        (None, Some(dump_key_file)) => {
+            crypto::read_key_file(dump_key_file).map_err(|e| e.to_string())
+        }
+        (None, None) => {
+            unreachable!("Config validation requires a dump key for file persistence")
+        }
+    }
+}
+
+/// Decrypts the start of every dump before anything else runs, so a wrong
+/// key stops startup with a clear message instead of failing the load later.
+fn check_dump_keys(conf: &Config) -> Result<(), StartupError> {
+    for (name, store) in persisted_stores(conf) {
+        let key = dump_key(&store).map_err(|reason| StartupError::Dump {
+            store: name.clone(),
+            reason,
+        })?;
+        let Some(path) = store.dump_path else {
+            unreachable!("Config validation requires dump settings for file persistence");
+        };
+        match Storage::verify_dump_key(&path, &crypto::AES::new(&key)) {
+            Ok(()) => {}
+            Err(storage::StorageError::Aes(_)) => {
+                return Err(StartupError::DumpKey { store: name, path });
+            }
+            Err(e) => {
+                return Err(StartupError::Dump {
+                    store: name,
+                    reason: e.to_string(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The default store and every named one that keeps a dump on disk.
+fn persisted_stores(conf: &Config) -> Vec<(String, StoreConfig)> {
+    let mut stores = vec![("default".to_string(), conf.default_store())];
+    stores.extend(conf.stores.clone().unwrap_or_default());
+    stores.retain(|(_, store)| store.persistence.unwrap_or_default() != Persistence::None);
+    stores
+}
+
+async fn load_offline(name: &str, store: StoreConfig, options: StorageOptions) -> Storage {
+    let opened = tokio::task::spawn_blocking(move || open_storage(store, options))
+        .await
+        .expect("Loading storage panicked");
+    opened.unwrap_or_else(|e| {
+        panic!("Failed to load {} store: {}", name, e);
+    })
+}
+
+async fn compact_dumps(conf: &Config, options: StorageOptions) {
+    for (name, store) in persisted_stores(conf) {
+        let mut storage = load_offline(&name, store, options.clone()).await;
+        let (before, after) = storage.compact().await.unwrap_or_else(|e| {
+            panic!("Failed to compact {} store: {}", name, e);
+        });
+        println!("{}: {} -> {} bytes", name, before, after);
+    }
+}
+
+async fn count_prefix(conf: &Config, options: StorageOptions, prefix: &[u8]) {
+    for (name, store) in persisted_stores(conf) {
+        let storage = load_offline(&name, store, options.clone()).await;
+        let count = storage.count_prefix(prefix).unwrap_or_else(|e| {
+            panic!("Failed to count {} store: {}", name, e);
+        });
+        println!("{}: {}", name, count);
+    }
+}
+
+async fn delete_prefix(conf: &Config, options: StorageOptions, prefix: &[u8]) {
+    for (name, store) in persisted_stores(conf) {
+        let mut storage = load_offline(&name, store, options.clone()).await;
+        let deleted = storage
+            .delete_prefix(prefix.to_vec())
+            .await
+            .unwrap_or_else(|e| {
+                panic!("Failed to delete from {} store: {}", name, e);
+            });
+        storage.flush().await.unwrap_or_else(|e| {
+            panic!("Failed to write {} store: {}", name, e);
+        });
+        println!("{}: {} deleted", name, deleted);
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum StartupError {
+    #[error(
+        "Cannot open the {store} store's dump at {path}: dump password incorrect or file corrupt"
+    )]
+    DumpKey { store: String, path: String },
+    #[error("Cannot open the {store} store's dump: {reason}")]
+    Dump { store: String, reason: String },
+}
+
+impl StartupError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            StartupError::DumpKey { .. } => EXIT_DUMP_KEY,
+            StartupError::Dump { .. } => 1,
         }
     }
 }
@@ -74,4 +403,114 @@ pub enum Error {
     TokioSendError(#[from] tokio::sync::mpsc::error::SendError<executor::ExecutorCommands>),
     #[error("Oneshot recv error: {0}")]
     OneshotRecvError(#[from] oneshot::RecvError),
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    #[error("Server is in maintenance mode, writes are rejected")]
+    Maintenance,
+    #[error("Server is busy, too many commands are queued")]
+    Busy,
+    #[error("Operation `{0}` is disabled on this server")]
+    OperationDisabled(&'static str),
+}
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::StorageError(
+                storage::StorageError::ValueTooLarge { .. }
+                | storage::StorageError::CapacityExceeded { .. },
+            ) => ErrorCode::TooLarge,
+            Error::StorageError(
+                storage::StorageError::InvalidRange { .. }
+                | storage::StorageError::InvalidUtf8 { .. }
+                | storage::StorageError::Transform(_),
+            ) => ErrorCode::BadRequest,
+            Error::StorageError(storage::StorageError::DiskFull) => ErrorCode::Maintenance,
+            Error::BadRequest(_) | Error::OperationDisabled(_) => ErrorCode::BadRequest,
+            Error::Maintenance => ErrorCode::Maintenance,
+            Error::Busy => ErrorCode::Busy,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// How long the client should wait before retrying, for errors that
+    /// clear up on their own.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::Busy => Some(executor::BUSY_RETRY_AFTER),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wrong_dump_password_stops_startup() {
+        let dir = std::env::temp_dir();
+        let main = dir.join("ckeylock-main-test-dump-key.bin");
+        let tenant = dir.join("ckeylock-main-test-dump-key-tenant.bin");
+        for (path, password) in [(&main, "main-secret"), (&tenant, "tenant-secret")] {
+            let _ = std::fs::remove_file(path);
+            let aes = crypto::AES::new(&hash(password.as_bytes()));
+            let mut storage = Storage::new(path, aes, StorageOptions::default()).unwrap();
+            storage.set(b"k".to_vec(), b"v".to_vec()).await.unwrap();
+            storage.sync().await.unwrap();
+        }
+        let config = |tenant_password: &str| {
+            let toml = format!(
+                "bind = \"127.0.0.1:0\"\n\
+                 dump_path = {:?}\n\
+                 dump_password = \"main-secret\"\n\
+                 [stores.tenant]\n\
+                 dump_path = {:?}\n\
+                 dump_password = {:?}\n",
+                main, tenant, tenant_password
+            );
+            Config::from_reader(toml.as_bytes()).unwrap()
+        };
+
+        check_dump_keys(&config("tenant-secret")).unwrap();
+        let err = check_dump_keys(&config("main-secret")).unwrap_err();
+        assert_eq!(err.exit_code(), EXIT_DUMP_KEY);
+        let message = err.to_string();
+        assert!(message.contains("tenant store"), "{}", message);
+        assert!(
+            message.contains("dump password incorrect or file corrupt"),
+            "{}",
+            message
+        );
+
+        // A dump that doesn't exist yet is created on the first sync.
+        std::fs::remove_file(&tenant).unwrap();
+        check_dump_keys(&config("main-secret")).unwrap();
+        std::fs::remove_file(&main).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delete_prefix_command() {
+        let path = std::env::temp_dir().join("ckeylock-main-test-delete-prefix.bin");
+        let _ = std::fs::remove_file(&path);
+        let aes = || crypto::AES::new(&hash(b"secret"));
+        let mut storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        for key in ["user:1", "user:2", "users", "other"] {
+            storage.set(key.into(), b"v".to_vec()).await.unwrap();
+        }
+        storage.sync().await.unwrap();
+        let toml = format!(
+            "bind = \"127.0.0.1:0\"\ndump_path = {:?}\ndump_password = \"secret\"\n",
+            path
+        );
+        let conf = Config::from_reader(toml.as_bytes()).unwrap();
+
+        delete_prefix(&conf, StorageOptions::default(), b"user:").await;
+
+        let storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        assert!(!storage.exists(b"user:1".to_vec()).unwrap());
+        assert!(!storage.exists(b"user:2".to_vec()).unwrap());
+        assert_eq!(storage.count().unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
 }
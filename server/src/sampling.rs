@@ -0,0 +1,43 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub struct LogSampler {
+    rate: u64,
+    counter: AtomicU64,
+}
+
+impl LogSampler {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate: rate.unwrap_or(1).max(1),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    pub fn sample(&self) -> bool {
+        self.rate == 1
+            || self
+                .counter
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(self.rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_samples_one_in_n() {
+        let sampler = LogSampler::new(Some(10));
+        let sampled = (0..1000).filter(|_| sampler.sample()).count();
+        assert_eq!(sampled, 100);
+    }
+
+    #[test]
+    fn test_default_samples_everything() {
+        let sampler = LogSampler::new(None);
+        assert!((0..100).all(|_| sampler.sample()));
+        let sampler = LogSampler::new(Some(0));
+        assert!((0..100).all(|_| sampler.sample()));
+    }
+}
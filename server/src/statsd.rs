@@ -0,0 +1,167 @@
+//! Pushes each store's stats to a statsd agent over UDP, for monitoring
+//! setups that collect metrics instead of scraping them.
+//!
+//! Metrics are named `ckeylock.<store>.<stat>`. Totals that only grow, such
+//! as cache hits, go out as counters holding the increase since the last
+//! push. Everything else is a gauge. Plain statsd and dogstatsd both accept
+//! this format.
+
+use crate::executor::Executor;
+use ckeylock_core::Stats;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+const PREFIX: &str = "ckeylock";
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct Exporter {
+    socket: UdpSocket,
+    stores: Vec<(String, Arc<Executor>)>,
+    /// Stats from the previous push, which counters are reported against.
+    previous: HashMap<String, Stats>,
+}
+
+impl Exporter {
+    pub async fn new(addr: &str, stores: Vec<(String, Arc<Executor>)>) -> std::io::Result<Self> {
+        let target = tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("statsd address {} did not resolve", addr),
+            )
+        })?;
+        let local = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(target).await?;
+        Ok(Self {
+            socket,
+            stores,
+            previous: HashMap::new(),
+        })
+    }
+
+    /// Pushes every `interval` until the task is aborted.
+    pub fn spawn(mut self, interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                self.flush().await;
+            }
+        })
+    }
+
+    /// Sends one packet per store. A store whose stats can't be read, such as
+    /// one whose storage failed to load, is skipped.
+    pub async fn flush(&mut self) {
+        for (name, executor) in &self.stores {
+            let stats = match executor.stats().await {
+                Ok(stats) => stats,
+                Err(e) => {
+                    debug!("Skipping statsd push for {} store: {}", name, e);
+                    continue;
+                }
+            };
+            let packet = lines(name, &stats, self.previous.get(name)).join("\n");
+            if let Err(e) = self.socket.send(packet.as_bytes()).await {
+                warn!("Failed to push metrics to statsd: {}", e);
+            }
+            self.previous.insert(name.clone(), stats);
+        }
+    }
+}
+
+fn lines(store: &str, stats: &Stats, previous: Option<&Stats>) -> Vec<String> {
+    let gauge = |name: &str, value: u64| format!("{}.{}.{}:{}|g", PREFIX, store, name, value);
+    let counter = |name: &str, total: u64, previous: Option<u64>| {
+        let delta = total.saturating_sub(previous.unwrap_or(0));
+        format!("{}.{}.{}:{}|c", PREFIX, store, name, delta)
+    };
+    vec![
+        gauge("keys", stats.keys as u64),
+        gauge("memory_bytes", stats.memory_bytes as u64),
+        gauge("compressed_values", stats.compressed_values as u64),
+        gauge("disk_full", stats.disk_full as u64),
+        counter(
+            "evicted_keys",
+            stats.evicted_keys,
+            previous.map(|p| p.evicted_keys),
+        ),
+        counter(
+            "cache_hits",
+            stats.cache_hits,
+            previous.map(|p| p.cache_hits),
+        ),
+        counter(
+            "cache_misses",
+            stats.cache_misses,
+            previous.map(|p| p.cache_misses),
+        ),
+        counter(
+            "bytes_received",
+            stats.bytes_received,
+            previous.map(|p| p.bytes_received),
+        ),
+        counter(
+            "bytes_sent",
+            stats.bytes_sent,
+            previous.map(|p| p.bytes_sent),
+        ),
+        counter(
+            "rejected_frames",
+            stats.rejected_frames,
+            previous.map(|p| p.rejected_frames),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+
+    async fn receive(agent: &UdpSocket) -> Vec<String> {
+        let mut buf = vec![0; 65536];
+        let len = tokio::time::timeout(Duration::from_secs(1), agent.recv(&mut buf))
+            .await
+            .expect("no packet from the exporter")
+            .unwrap();
+        String::from_utf8(buf[..len].to_vec())
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_pushes_statsd_lines() {
+        let agent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let executor = Executor::new(Storage::volatile(Default::default())).await;
+        executor.set(b"a".to_vec(), b"1".to_vec()).await.unwrap();
+        executor.get(b"a".to_vec(), false).await.unwrap();
+        let stores = vec![("default".to_string(), Arc::clone(&executor))];
+        let mut exporter = Exporter::new(&agent.local_addr().unwrap().to_string(), stores)
+            .await
+            .unwrap();
+
+        exporter.flush().await;
+        let lines = receive(&agent).await;
+        assert!(lines.contains(&"ckeylock.default.keys:1|g".to_string()));
+        assert!(lines.contains(&"ckeylock.default.cache_hits:1|c".to_string()));
+        assert!(lines.contains(&"ckeylock.default.disk_full:0|g".to_string()));
+
+        executor.get(b"a".to_vec(), false).await.unwrap();
+        executor.get(b"a".to_vec(), false).await.unwrap();
+        exporter.flush().await;
+        let lines = receive(&agent).await;
+        assert!(lines.contains(&"ckeylock.default.cache_hits:2|c".to_string()));
+        assert!(lines.contains(&"ckeylock.default.rejected_frames:0|c".to_string()));
+    }
+}
@@ -1,109 +1,541 @@
-use crate::crypto::{AES, hash};
+use crate::cache::{Cache, Eviction};
+use crate::chunked::{self, ChunkReader};
+use crate::clock::{Clock, SystemClock};
+use crate::compression::{self, CompressionError};
+use crate::crypto::{AES, checksum};
+use crate::transform::Transform;
+use ckeylock_core::{Condition, response::Stats};
 use dashmap::DashMap;
-use lru::LruCache;
+use serde::{Deserialize, Serialize};
 use std::{
-    fs::{File, OpenOptions},
-    io::{BufReader, BufWriter, Read, Seek as _, SeekFrom, Write},
-    path::Path,
+    collections::{BTreeMap, BinaryHeap, HashMap, hash_map::RandomState},
+    fs::File,
+    hash::{BuildHasher, Hasher},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, PoisonError,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
 };
 use thiserror::Error;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, mpsc};
+use tokio::task::{JoinError, JoinHandle};
 use tracing::{debug, error, info, warn};
 
-const LRU_CACHE_SIZE: usize = 100;
-pub struct Storage {
-    data: Box<DashMap<Vec<u8>, Vec<u8>>>,
-    file: File,
+pub const LRU_CACHE_SIZE: usize = 100;
+const DUMP_MAGIC: &[u8; 4] = b"CKLK";
+const DUMP_VERSION: u8 = 2;
+const DEFAULT_SYNC_RETRIES: u32 = 3;
+const SYNC_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Default, Clone)]
+pub struct StorageOptions {
+    pub compression_threshold: Option<usize>,
+    pub max_memory_bytes: Option<usize>,
+    /// Caps the number of stored keys. Sets of new keys past the cap fail with
+    /// `CapacityExceeded` unless an eviction policy is configured.
+    pub max_keys: Option<usize>,
+    /// Which keys to evict first. The memory limit always evicts, defaulting
+    /// to LRU; the key cap only evicts when this is set.
+    pub eviction_policy: Option<EvictionPolicy>,
+    pub cache_write: CacheWrite,
+    /// Rejects values that aren't valid UTF-8.
+    pub require_utf8_values: bool,
+    /// Makes `clear` leave the dump alone until the next sync, so a clear
+    /// followed by a reload costs one write. Until then a restart brings the
+    /// cleared keys back.
+    pub lazy_clear: bool,
+    pub autosync_interval: Option<Duration>,
+    pub max_list_keys: Option<usize>,
+    /// Stretches each TTL by a random amount up to this fraction of it, so
+    /// keys given the same TTL together don't all expire at once. Keys never
+    /// expire early, but may outlive their TTL by up to this fraction.
+    pub ttl_jitter: Option<f64>,
+    /// How many times a sync retries after a transient IO error, such as an
+    /// interrupted write or a briefly full disk. Defaults to 3.
+    pub sync_retries: Option<u32>,
+    /// Moves keys removed by the expiry sweep under this prefix, without a
+    /// TTL, instead of dropping them.
+    pub dead_letter_prefix: Option<Vec<u8>>,
+    /// Keeps the values of deleted keys for this long, so `undelete` can bring
+    /// them back. Kept in memory only: a restart drops them.
+    pub soft_delete: Option<Duration>,
+    /// Where TTLs get the current time. Defaults to the system clock.
+    pub clock: Option<Arc<dyn Clock>>,
+}
+
+/// A key the expiry sweep removed, with the value it held.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expiration {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    /// Keys missing from the read cache go first, then the cache's LRU order.
+    #[default]
+    Lru,
+    /// Keys go in the map's hash order, which is effectively random.
+    Random,
+}
+
+/// Whether `set` puts the written value in the read cache.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheWrite {
+    /// Written values go into the cache, as if they had just been read.
+    #[default]
+    Through,
+    /// Writes bypass the cache and drop any stale copy, so it only holds keys
+    /// that were actually read.
+    Around,
+}
+
+type Data = DashMap<Vec<u8>, Vec<u8>>;
+type Expirations = DashMap<Vec<u8>, u64>;
+pub type PendingSync = JoinHandle<Result<(), StorageError>>;
+/// Listed keys and the total number of live keys, which is larger when the
+/// listing was capped by `max_list_keys`.
+pub type KeyList = (Vec<Vec<u8>>, usize);
+/// Entries of one scan page and the key to resume after, `None` at the end.
+pub type ScanPage = (Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>);
+
+/// What `stat` reports about a key. Times are milliseconds since the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyStat {
+    /// Size of the value before compression.
+    pub size: usize,
+    pub ttl_ms: Option<u64>,
+    /// Last cached read; reads that bypass the cache don't count.
+    pub last_access_ms: Option<u64>,
+    /// Last set. Unknown for keys loaded from a dump until they are written again.
+    pub last_modified_ms: Option<u64>,
+}
+
+/// A deleted key's value as it was stored, kept while `soft_delete` allows
+/// undeleting it.
+#[derive(Debug)]
+struct Tombstone {
+    stored: Vec<u8>,
+    expires_at: Option<u64>,
+    deleted_at: u64,
+}
+
+/// Access and modification times of one key, 0 until they happen. Atomic so
+/// reads can record them through `&Storage`.
+#[derive(Debug, Default)]
+struct KeyTimes {
+    accessed: AtomicU64,
+    modified: AtomicU64,
+}
+
+/// Server-wide WebSocket traffic, reported alongside the storage stats.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    received: AtomicU64,
+    sent: AtomicU64,
+    rejected_frames: AtomicU64,
+}
+
+impl Traffic {
+    pub fn record_received(&self, bytes: u64) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_sent(&self, bytes: u64) {
+        self.sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_rejected_frame(&self) {
+        self.rejected_frames.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// How the last dump write went, shared with the executor so it can turn
+/// writes away while the disk is full.
+#[derive(Debug, Default)]
+pub struct SyncHealth {
+    write_failed: AtomicBool,
+    disk_full: AtomicBool,
+}
+
+impl SyncHealth {
+    pub fn is_disk_full(&self) -> bool {
+        self.disk_full.load(Ordering::Acquire)
+    }
+
+    pub fn write_failed(&self) -> bool {
+        self.write_failed.load(Ordering::Acquire)
+    }
+
+    fn record(&self, result: &Result<(), StorageError>) {
+        self.write_failed.store(result.is_err(), Ordering::Release);
+        let full = matches!(result, Err(StorageError::DiskFull));
+        if self.disk_full.swap(full, Ordering::AcqRel) != full {
+            if full {
+                error!("Disk is full, rejecting writes until the dump can be written again.");
+            } else {
+                info!("Dump written again, accepting writes.");
+            }
+        }
+    }
+}
+
+struct DumpFile {
+    path: PathBuf,
+    generation: u64,
+    /// Writes the sealed dump to `path`. Tests swap it to fail like a full
+    /// disk would.
+    write: fn(&Path, &[u8]) -> std::io::Result<()>,
+}
+
+/// A live key and its stored value, borrowed from the map. The value is only
+/// decoded when asked for.
+pub struct StoredEntry<'a>(dashmap::mapref::multiple::RefMulti<'a, Vec<u8>, Vec<u8>>);
+
+impl StoredEntry<'_> {
+    pub fn key(&self) -> &[u8] {
+        self.0.key()
+    }
+
+    // Nothing in the server reads values in bulk yet; this is for exports.
+    #[allow(dead_code)]
+    pub fn value(&self) -> Result<Vec<u8>, StorageError> {
+        Ok(compression::decode(self.0.value())?)
+    }
+}
+
+/// Where and how a file-backed storage persists its data.
+struct Dump {
+    file: Arc<std::sync::Mutex<DumpFile>>,
     aes: AES,
-    checksum: Vec<u8>,
-    cache: Mutex<LruCache<Vec<u8>, Vec<u8>>>,
+    /// What the dump's chunks authenticate, see [`dump_aad`].
+    binding: Vec<u8>,
+}
+
+impl Dump {
+    fn new(path: &Path, aes: AES, binding: Vec<u8>) -> Self {
+        Self {
+            file: Arc::new(std::sync::Mutex::new(DumpFile {
+                path: path.to_path_buf(),
+                generation: 0,
+                write: replace_file,
+            })),
+            aes,
+            binding,
+        }
+    }
+}
+
+pub struct Storage {
+    data: Box<Data>,
+    expirations: Expirations,
+    times: DashMap<Vec<u8>, KeyTimes>,
+    tombstones: DashMap<Vec<u8>, Tombstone>,
+    /// `None` for volatile storage, which never touches the disk.
+    dump: Option<Dump>,
+    checksum: u64,
+    generation: u64,
+    pending_sync: Option<PendingSync>,
+    memory_bytes: AtomicUsize,
+    evicted_keys: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    traffic: Arc<Traffic>,
+    sync_health: Arc<SyncHealth>,
+    expired: Option<mpsc::UnboundedSender<Expiration>>,
+    transform: Option<Arc<dyn Transform>>,
+    clock: Arc<dyn Clock>,
+    cache: Mutex<Cache>,
+    options: StorageOptions,
 }
 
 impl Storage {
-    pub fn new(path: impl AsRef<Path>, aes: AES) -> Result<Self, StorageError> {
+    pub fn new(
+        path: impl AsRef<Path>,
+        aes: AES,
+        options: StorageOptions,
+    ) -> Result<Self, StorageError> {
         info!("Initializing storage from path: {:?}", path.as_ref());
         if path.as_ref().exists() {
-            Self::from_file(path, aes)
+            Self::from_file(path, aes, options)
         } else {
-            Self::new_empty(path, aes)
+            Self::new_empty(path, aes, options)
         }
     }
 
-    pub fn new_empty(path: impl AsRef<Path>, aes: AES) -> Result<Self, StorageError> {
+    pub fn new_empty(
+        path: impl AsRef<Path>,
+        aes: AES,
+        options: StorageOptions,
+    ) -> Result<Self, StorageError> {
         info!("Creating new empty storage at path: {:?}", path.as_ref());
         let path = path.as_ref();
-        let mut file = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(path)?;
-        let dashmap: DashMap<Vec<u8>, Vec<u8>> = DashMap::new();
-        let content = bincode::serde::encode_to_vec(&dashmap, bincode::config::standard())?;
-        let checksum = hash(&content);
-        let encrypted_content = aes
-            .encrypt(&content, None)
-            .map_err(|e| StorageError::Aes(e))?;
-        file.write_all(&encrypted_content)?;
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+            && !parent.exists()
+        {
+            info!("Creating dump directory: {:?}", parent);
+            std::fs::create_dir_all(parent)?;
+        }
+        let dashmap: Data = DashMap::new();
+        let expirations: Expirations = DashMap::new();
+        let content = encode_dump(&dashmap, &expirations)?;
+        let checksum = checksum(&content);
+        let binding = dump_aad(&resolved_path(path)?);
+        let encrypted_content = chunked::seal(&aes, &binding, &content, chunked::CHUNK_SIZE)
+            .map_err(StorageError::Aes)?;
+        replace_file(path, &encrypted_content)?;
         info!("Empty storage created successfully.");
         Ok(Self {
             data: Box::new(dashmap),
-            file,
-            aes,
-            checksum: checksum.to_vec(),
-            cache: Mutex::new(LruCache::new(
-                std::num::NonZero::new(LRU_CACHE_SIZE).unwrap(),
-            )),
+            expirations,
+            times: DashMap::new(),
+            tombstones: DashMap::new(),
+            dump: Some(Dump::new(path, aes, binding)),
+            checksum,
+            generation: 0,
+            pending_sync: None,
+            memory_bytes: AtomicUsize::new(0),
+            evicted_keys: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            traffic: Arc::default(),
+            sync_health: Arc::default(),
+            expired: None,
+            transform: None,
+            clock: options
+                .clock
+                .clone()
+                .unwrap_or_else(|| Arc::new(SystemClock)),
+            cache: Mutex::new(Cache::new(std::num::NonZero::new(LRU_CACHE_SIZE).unwrap())),
+            options,
         })
     }
 
-    pub fn from_file(path: impl AsRef<Path>, aes: AES) -> Result<Self, StorageError> {
+    pub fn from_file(
+        path: impl AsRef<Path>,
+        aes: AES,
+        options: StorageOptions,
+    ) -> Result<Self, StorageError> {
         info!("Loading storage from file at path: {:?}", path.as_ref());
         let path = path.as_ref();
-        let file = OpenOptions::new().read(true).write(true).open(path)?;
-        let mut reader = BufReader::new(&file);
-        let mut content = Vec::new();
-        reader.read_to_end(&mut content)?;
-        let checksum = hash(&content);
-        let decrypted_content = aes.decrypt(&content).map_err(|e| StorageError::Aes(e))?;
-        let (decoded_data, _) =
-            bincode::serde::decode_from_slice(&decrypted_content, bincode::config::standard())?;
+        let mut reader = BufReader::new(File::open(path)?);
+        let binding = dump_aad(&resolved_path(path)?);
+        // Dumps in older formats get a checksum of zero, which no snapshot
+        // matches, so the next sync rewrites them in the current one.
+        let (decoded_data, expirations, checksum) = match read_format(&mut reader)? {
+            DumpFormat::Chunked(header) => load_chunked(reader, &aes, &header.aad(&binding))?,
+            DumpFormat::PathBound => {
+                info!("Dump has no write id yet, it gets one on the next sync.");
+                let (data, expirations, _) = load_chunked(reader, &aes, &dump_aad(path))?;
+                (data, expirations, 0)
+            }
+            DumpFormat::Whole => {
+                let mut content = Vec::new();
+                reader.read_to_end(&mut content)?;
+                let (data, expirations, _) = load_whole(&content, &aes, &dump_aad(path))?;
+                (data, expirations, 0)
+            }
+        };
+        let memory_bytes = decoded_data
+            .iter()
+            .map(|entry| entry_size(entry.key(), entry.value()))
+            .sum();
         info!("Storage loaded successfully from file.");
         Ok(Self {
-            data: decoded_data,
-            file,
-            aes,
-            checksum: checksum.to_vec(),
-            cache: Mutex::new(LruCache::new(
-                std::num::NonZero::new(LRU_CACHE_SIZE).unwrap(),
-            )),
+            data: Box::new(decoded_data),
+            expirations,
+            times: DashMap::new(),
+            tombstones: DashMap::new(),
+            dump: Some(Dump::new(path, aes, binding)),
+            checksum,
+            generation: 0,
+            pending_sync: None,
+            memory_bytes: AtomicUsize::new(memory_bytes),
+            evicted_keys: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            traffic: Arc::default(),
+            sync_health: Arc::default(),
+            expired: None,
+            transform: None,
+            clock: options
+                .clock
+                .clone()
+                .unwrap_or_else(|| Arc::new(SystemClock)),
+            cache: Mutex::new(Cache::new(std::num::NonZero::new(LRU_CACHE_SIZE).unwrap())),
+            options,
         })
     }
 
-    pub fn sync(&mut self) -> Result<(), StorageError> {
+    /// Checks that `aes` opens the dump at `path` by decrypting only its
+    /// first chunk, so a wrong key shows up before a large dump is loaded.
+    /// A missing dump passes, it is created on the first sync.
+    pub fn verify_dump_key(path: impl AsRef<Path>, aes: &AES) -> Result<(), StorageError> {
+        let path = path.as_ref();
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut reader = BufReader::new(file);
+        let aad = match read_format(&mut reader)? {
+            DumpFormat::Chunked(header) => header.aad(&dump_aad(&resolved_path(path)?)),
+            DumpFormat::PathBound => dump_aad(path),
+            DumpFormat::Whole => {
+                let mut content = Vec::new();
+                reader.read_to_end(&mut content)?;
+                return load_whole(&content, aes, &dump_aad(path)).map(|_| ());
+            }
+        };
+        let mut chunks = ChunkReader::new(reader, aes.clone(), aad);
+        match chunks.read_exact(&mut [0]) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(chunks.take_auth_error().map_or(e.into(), StorageError::Aes)),
+        }
+    }
+
+    /// Storage that lives only in memory. Syncs are no-ops, so everything is
+    /// lost when the process exits.
+    pub fn volatile(options: StorageOptions) -> Self {
+        info!("Creating volatile storage, nothing will be persisted.");
+        Self {
+            data: Box::new(DashMap::new()),
+            expirations: DashMap::new(),
+            times: DashMap::new(),
+            tombstones: DashMap::new(),
+            dump: None,
+            checksum: 0,
+            generation: 0,
+            pending_sync: None,
+            memory_bytes: AtomicUsize::new(0),
+            evicted_keys: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            traffic: Arc::default(),
+            sync_health: Arc::default(),
+            expired: None,
+            transform: None,
+            clock: options
+                .clock
+                .clone()
+                .unwrap_or_else(|| Arc::new(SystemClock)),
+            cache: Mutex::new(Cache::new(std::num::NonZero::new(LRU_CACHE_SIZE).unwrap())),
+            options,
+        }
+    }
+
+    pub fn subscribe_evictions(&mut self) -> mpsc::UnboundedReceiver<Eviction> {
+        self.cache.get_mut().subscribe_evictions()
+    }
+
+    /// Receives every key the expiry sweep removes. Keys read after their
+    /// TTL but before the sweep are reported once the sweep gets to them.
+    pub fn subscribe_expirations(&mut self) -> mpsc::UnboundedReceiver<Expiration> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.expired = Some(tx);
+        rx
+    }
+
+    pub async fn sync(&mut self) -> Result<(), StorageError> {
+        self.schedule_sync()?;
+        self.flush().await
+    }
+
+    /// Waits for the most recent scheduled sync to reach the disk.
+    pub async fn flush(&mut self) -> Result<(), StorageError> {
+        match self.pending_sync.take() {
+            Some(pending) => pending.await?,
+            None => Ok(()),
+        }
+    }
+
+    /// Reports `traffic` in stats from now on, in place of this storage's own
+    /// counters.
+    pub fn set_traffic(&mut self, traffic: Arc<Traffic>) {
+        self.traffic = traffic;
+    }
+
+    /// Records dump writes in `health` from now on.
+    pub fn set_sync_health(&mut self, health: Arc<SyncHealth>) {
+        self.sync_health = health;
+    }
+
+    /// Passes values through `transform` as they are stored and read from
+    /// now on.
+    pub fn set_transform(&mut self, transform: Arc<dyn Transform>) {
+        self.transform = Some(transform);
+    }
+
+    fn transform_set(&self, key: &[u8], value: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+        match &self.transform {
+            Some(transform) => transform
+                .on_set(key, value)
+                .map_err(StorageError::Transform),
+            None => Ok(value),
+        }
+    }
+
+    fn transform_get(&self, key: &[u8], value: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+        match &self.transform {
+            Some(transform) => transform
+                .on_get(key, value)
+                .map_err(StorageError::Transform),
+            None => Ok(value),
+        }
+    }
+
+    pub fn sync_health(&self) -> &SyncHealth {
+        &self.sync_health
+    }
+
+    pub fn options(&self) -> &StorageOptions {
+        &self.options
+    }
+
+    pub fn take_pending_sync(&mut self) -> Option<PendingSync> {
+        self.pending_sync.take()
+    }
+
+    /// Encodes a snapshot of the current state and writes it on the blocking
+    /// thread pool, so the caller does not wait for encryption and fsync.
+    pub fn schedule_sync(&mut self) -> Result<(), StorageError> {
+        self.schedule_write(false)
+    }
+
+    /// Like [`schedule_sync`](Self::schedule_sync), but `force` writes the
+    /// snapshot even when nothing changed since the last one.
+    fn schedule_write(&mut self, force: bool) -> Result<(), StorageError> {
+        let Some(dump) = &self.dump else {
+            return Ok(());
+        };
         debug!("Syncing storage to file.");
-        let content = bincode::serde::encode_to_vec(&self.data, bincode::config::standard())?;
-        let new_checksum = hash(&content).to_vec();
-
-        if new_checksum != self.checksum {
-            let encrypted_content = self
-                .aes
-                .encrypt(&content, None)
-                .map_err(StorageError::Aes)?;
-
-            let file = &mut self.file;
-            file.set_len(0)?;
-            file.seek(SeekFrom::Start(0))?;
-            let mut writer = BufWriter::new(file);
-            writer.write_all(&encrypted_content)?;
-            writer.flush()?;
-            drop(writer);
-            self.file.sync_all()?;
-
-            self.checksum = new_checksum;
-            info!("Storage synced successfully.");
-        } else {
+        let content = encode_dump(&self.data, &self.expirations)?;
+        let new_checksum = checksum(&content);
+
+        // A failed write left the disk behind the checksum, so write it again.
+        if !force && new_checksum == self.checksum && !self.sync_health.write_failed() {
             debug!("No changes detected, skipping sync.");
+            return Ok(());
         }
+        self.checksum = new_checksum;
+        self.generation += 1;
+        let generation = self.generation;
+        let file = Arc::clone(&dump.file);
+        let aes = dump.aes.clone();
+        let binding = dump.binding.clone();
+        let retries = self.options.sync_retries.unwrap_or(DEFAULT_SYNC_RETRIES);
+        let health = Arc::clone(&self.sync_health);
+        self.pending_sync = Some(tokio::task::spawn_blocking(move || {
+            let result = write_dump(&file, &aes, &binding, &content, generation, retries);
+            health.record(&result);
+            result
+        }));
         Ok(())
     }
 
@@ -113,27 +545,217 @@ impl Storage {
             hex::encode(&key),
             value.len()
         );
-        self.data.insert(key.clone(), value.clone());
-        self.cache.lock().await.put(key.clone(), value.clone());
+        let value = self.transform_set(&key, value)?;
+        self.check_utf8(&value)?;
+        let stored = compression::encode(value.clone(), self.options.compression_threshold);
+        let size = entry_size(&key, &stored);
+        if let Some(max) = self.options.max_memory_bytes
+            && size > max
+        {
+            warn!(
+                "Rejecting key {:?}: {} bytes exceed the memory limit of {}.",
+                hex::encode(&key),
+                size,
+                max
+            );
+            return Err(StorageError::ValueTooLarge { size, max });
+        }
+        if let Some(max) = self.options.max_keys
+            && self.options.eviction_policy.is_none()
+            && self.data.len() >= max
+            && !self.data.contains_key(&key)
+        {
+            warn!(
+                "Rejecting key {:?}: storage is at its cap of {} keys.",
+                hex::encode(&key),
+                max
+            );
+            return Err(StorageError::CapacityExceeded { max });
+        }
+        if let Some(old) = self.data.insert(key.clone(), stored) {
+            self.memory_bytes
+                .fetch_sub(entry_size(&key, &old), Ordering::Relaxed);
+        }
+        self.memory_bytes.fetch_add(size, Ordering::Relaxed);
+        self.expirations.remove(&key);
+        self.times
+            .entry(key.clone())
+            .or_default()
+            .modified
+            .store(self.clock.now_ms(), Ordering::Relaxed);
+        let mut cache = self.cache.lock().await;
+        match self.options.cache_write {
+            CacheWrite::Through => {
+                cache.put(key.clone(), value.clone());
+            }
+            CacheWrite::Around => {
+                cache.pop(&key);
+            }
+        }
+        self.evict_over_limit(&mut cache, &key);
         info!("Key {:?} set successfully.", hex::encode(&key));
         Ok(key)
     }
 
+    fn check_utf8(&self, value: &[u8]) -> Result<(), StorageError> {
+        if !self.options.require_utf8_values {
+            return Ok(());
+        }
+        std::str::from_utf8(value).map_err(|e| StorageError::InvalidUtf8 {
+            valid_up_to: e.valid_up_to(),
+        })?;
+        Ok(())
+    }
+
+    fn over_limit(&self) -> bool {
+        self.options
+            .max_memory_bytes
+            .is_some_and(|max| self.memory_bytes.load(Ordering::Relaxed) > max)
+            || self
+                .options
+                .max_keys
+                .is_some_and(|max| self.data.len() > max)
+    }
+
+    fn evict_over_limit(&self, cache: &mut Cache, keep: &[u8]) {
+        if !self.over_limit() {
+            return;
+        }
+        let candidates: Vec<Vec<u8>> = match self.options.eviction_policy.unwrap_or_default() {
+            EvictionPolicy::Lru => self
+                .data
+                .iter()
+                .map(|entry| entry.key().clone())
+                .filter(|key| !cache.contains(key))
+                .chain(cache.lru_keys().cloned())
+                .collect(),
+            EvictionPolicy::Random => self.data.iter().map(|entry| entry.key().clone()).collect(),
+        };
+        for key in candidates {
+            if !self.over_limit() {
+                break;
+            }
+            if key == keep {
+                continue;
+            }
+            cache.pop(&key);
+            self.expirations.remove(&key);
+            self.remove_data(&key);
+            self.evicted_keys.fetch_add(1, Ordering::Relaxed);
+            info!(
+                "Evicted key {:?} to stay within storage limits.",
+                hex::encode(&key)
+            );
+        }
+    }
+
+    fn remove_data(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let (key, value) = self.data.remove(key)?;
+        self.memory_bytes
+            .fetch_sub(entry_size(&key, &value), Ordering::Relaxed);
+        self.times.remove(&key);
+        Some(value)
+    }
+
+    fn record_access(&self, key: &[u8]) {
+        let now = self.clock.now_ms();
+        match self.times.get(key) {
+            Some(times) => times.accessed.store(now, Ordering::Relaxed),
+            None => self
+                .times
+                .entry(key.to_vec())
+                .or_default()
+                .accessed
+                .store(now, Ordering::Relaxed),
+        }
+    }
+
     pub async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, StorageError> {
         debug!("Getting value for key: {:?}", hex::encode(&key));
-        if let Some(value) = self.cache.lock().await.get(&key) {
+        if self.is_expired(&key) {
+            warn!("Key {:?} has expired.", hex::encode(&key));
+            return Ok(None);
+        }
+        let cached = self.cache.lock().await.get(&key).cloned();
+        if let Some(value) = cached {
             info!("Cache hit for key: {:?}", hex::encode(&key));
-            return Ok(Some(value.clone()));
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            self.record_access(&key);
+            return Ok(Some(self.transform_get(&key, value)?));
         }
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
 
-        let value = self.data.get(&key).map(|v| v.clone());
+        let value = match self.data.get(&key) {
+            Some(stored) => Some(compression::decode(&stored)?),
+            None => None,
+        };
         if let Some(ref v) = value {
             self.cache.lock().await.put(key.clone(), v.clone());
+            self.record_access(&key);
             info!("Key {:?} found.", hex::encode(&key));
         } else {
             warn!("Key {:?} not found.", hex::encode(&key));
         }
-        Ok(value)
+        value.map(|v| self.transform_get(&key, v)).transpose()
+    }
+
+    /// Reads a value straight from the store, neither consulting nor filling
+    /// the cache, and without counting towards cache hits or misses.
+    pub fn get_uncached(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, StorageError> {
+        debug!(
+            "Getting value for key {:?}, bypassing the cache.",
+            hex::encode(&key)
+        );
+        if self.is_expired(&key) {
+            warn!("Key {:?} has expired.", hex::encode(&key));
+            return Ok(None);
+        }
+        match self.data.get(&key) {
+            Some(stored) => Ok(Some(
+                self.transform_get(&key, compression::decode(&stored)?)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads `start..end` of a value, clamped to its length, without copying the
+    /// rest of it.
+    pub async fn get_range(
+        &self,
+        key: Vec<u8>,
+        start: usize,
+        end: usize,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        debug!(
+            "Getting range {}..{} of key: {:?}",
+            start,
+            end,
+            hex::encode(&key)
+        );
+        if start > end {
+            return Err(StorageError::InvalidRange { start, end });
+        }
+        // A transformed value can only be sliced once it is read back whole.
+        if self.transform.is_some() {
+            let value = self.get(key).await?;
+            return Ok(value.map(|value| compression::clamped(&value, start, end).to_vec()));
+        }
+        if self.is_expired(&key) {
+            return Ok(None);
+        }
+        if let Some(value) = self.cache.lock().await.get(&key) {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            self.record_access(&key);
+            return Ok(Some(compression::clamped(value, start, end).to_vec()));
+        }
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        let Some(stored) = self.data.get(&key) else {
+            return Ok(None);
+        };
+        let range = compression::decode_range(&stored, start, end)?;
+        drop(stored);
+        self.record_access(&key);
+        Ok(Some(range))
     }
 
     pub async fn batch_get(
@@ -146,16 +768,24 @@ impl Storage {
 
         for key in keys {
             debug!("Processing key: {:?}", hex::encode(&key));
-            if let Some(value) = cache.get(&key) {
+            if self.is_expired(&key) {
+                warn!("Key {:?} has expired.", hex::encode(&key));
+                results.push(None);
+            } else if let Some(value) = cache.get(&key) {
                 info!("Cache hit for key: {:?}", hex::encode(&key));
-                results.push(Some(value.clone()));
+                self.cache_hits.fetch_add(1, Ordering::Relaxed);
+                self.record_access(&key);
+                results.push(Some(self.transform_get(&key, value.clone())?));
             } else if let Some(value) = self.data.get(&key) {
-                let val = value.clone();
+                self.cache_misses.fetch_add(1, Ordering::Relaxed);
+                let val = compression::decode(&value)?;
                 cache.put(key.clone(), val.clone());
+                self.record_access(&key);
                 info!("Key {:?} found in storage.", hex::encode(&key));
-                results.push(Some(val));
+                results.push(Some(self.transform_get(&key, val)?));
             } else {
                 warn!("Key {:?} not found.", hex::encode(&key));
+                self.cache_misses.fetch_add(1, Ordering::Relaxed);
                 results.push(None);
             }
         }
@@ -164,11 +794,29 @@ impl Storage {
         Ok(results)
     }
 
+    /// Removes `key`, returning the value it held if it was live.
     pub async fn delete(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>, StorageError> {
         debug!("Deleting key: {:?}", hex::encode(&key));
         self.cache.lock().await.pop(&key);
-        let value = self.data.remove(&key).map(|v| v.clone()).map(|(k, _)| k);
-        self.sync()?;
+        let now = self.clock.now_ms();
+        let expires_at = self.expirations.remove(&key).map(|(_, at)| at);
+        let expired = expires_at.is_some_and(|at| at <= now);
+        let value = match self.remove_data(&key).filter(|_| !expired) {
+            Some(stored) => {
+                let value = self.transform_get(&key, compression::decode(&stored)?)?;
+                if self.options.soft_delete.is_some() {
+                    let tombstone = Tombstone {
+                        stored,
+                        expires_at,
+                        deleted_at: now,
+                    };
+                    self.tombstones.insert(key.clone(), tombstone);
+                }
+                Some(value)
+            }
+            None => None,
+        };
+        self.schedule_sync()?;
         if value.is_some() {
             info!("Key {:?} deleted successfully.", hex::encode(&key));
         } else {
@@ -177,16 +825,193 @@ impl Storage {
         Ok(value)
     }
 
-    pub fn list(&self) -> Result<Vec<Vec<u8>>, StorageError> {
+    /// Restores a key `delete` removed less than `soft_delete` ago, with the
+    /// TTL it had. Returns false if there is nothing to restore, including when
+    /// the key has been set again since.
+    pub async fn undelete(&mut self, key: Vec<u8>) -> Result<bool, StorageError> {
+        debug!("Undeleting key: {:?}", hex::encode(&key));
+        let Some(window) = self.options.soft_delete else {
+            warn!(
+                "Undelete of {:?} without soft delete enabled.",
+                hex::encode(&key)
+            );
+            return Ok(false);
+        };
+        if self.exists(key.clone())? {
+            warn!("Key {:?} exists, not undeleting it.", hex::encode(&key));
+            return Ok(false);
+        }
+        let now = self.clock.now_ms();
+        let Some((key, tombstone)) = self.tombstones.remove(&key) else {
+            warn!(
+                "No deleted value to restore for key {:?}.",
+                hex::encode(&key)
+            );
+            return Ok(false);
+        };
+        if tombstone.deleted_at + window.as_millis() as u64 <= now
+            || tombstone.expires_at.is_some_and(|at| at <= now)
+        {
+            warn!(
+                "Deleted key {:?} is past its grace period.",
+                hex::encode(&key)
+            );
+            return Ok(false);
+        }
+        if let Some(max) = self.options.max_keys
+            && self.options.eviction_policy.is_none()
+            && self.data.len() >= max
+        {
+            self.tombstones.insert(key, tombstone);
+            return Err(StorageError::CapacityExceeded { max });
+        }
+        self.memory_bytes
+            .fetch_add(entry_size(&key, &tombstone.stored), Ordering::Relaxed);
+        if let Some(old) = self.data.insert(key.clone(), tombstone.stored) {
+            self.memory_bytes
+                .fetch_sub(entry_size(&key, &old), Ordering::Relaxed);
+        }
+        // Drops the TTL of an expired copy the sweep hasn't removed yet.
+        self.expirations.remove(&key);
+        if let Some(at) = tombstone.expires_at {
+            self.expirations.insert(key.clone(), at);
+        }
+        self.times
+            .entry(key.clone())
+            .or_default()
+            .modified
+            .store(now, Ordering::Relaxed);
+        let mut cache = self.cache.lock().await;
+        cache.pop(&key);
+        self.evict_over_limit(&mut cache, &key);
+        drop(cache);
+        self.schedule_sync()?;
+        info!("Key {:?} undeleted.", hex::encode(&key));
+        Ok(true)
+    }
+
+    pub async fn delete_prefix(&mut self, prefix: Vec<u8>) -> Result<usize, StorageError> {
+        debug!("Deleting keys with prefix: {:?}", hex::encode(&prefix));
+        let keys: Vec<Vec<u8>> = self
+            .data
+            .iter()
+            .filter(|entry| entry.key().starts_with(&prefix))
+            .map(|entry| entry.key().clone())
+            .collect();
+        let mut cache = self.cache.lock().await;
+        let mut deleted = 0;
+        for key in keys {
+            cache.pop(&key);
+            let expired = self.is_expired(&key);
+            self.expirations.remove(&key);
+            if self.remove_data(&key).is_some() && !expired {
+                deleted += 1;
+            }
+        }
+        drop(cache);
+        if deleted > 0 {
+            self.schedule_sync()?;
+        }
+        info!(
+            "Deleted {} keys with prefix {:?}.",
+            deleted,
+            hex::encode(&prefix)
+        );
+        Ok(deleted)
+    }
+
+    /// Iterates over live entries without collecting them first. Each item
+    /// holds a read lock on its shard of the map, so writes to that shard
+    /// wait until the item is dropped; don't hold items across awaits.
+    pub fn iter(&self) -> impl Iterator<Item = StoredEntry<'_>> {
+        self.data
+            .iter()
+            .filter(|entry| !self.is_expired(entry.key()))
+            .map(StoredEntry)
+    }
+
+    pub fn list(&self) -> Result<KeyList, StorageError> {
         debug!("Listing all keys in storage.");
-        let keys: Vec<Vec<u8>> = self.data.iter().map(|v| v.key().clone()).collect();
-        info!("Listed {} keys.", keys.len());
-        Ok(keys)
+        let limit = self.options.max_list_keys.unwrap_or(usize::MAX);
+        let mut keys = Vec::new();
+        let mut total = 0;
+        for entry in self.iter() {
+            if keys.len() < limit {
+                keys.push(entry.key().to_vec());
+            }
+            total += 1;
+        }
+        if keys.len() < total {
+            warn!(
+                "Listed {} of {} keys, the rest were cut off.",
+                keys.len(),
+                total
+            );
+        } else {
+            info!("Listed {} keys.", keys.len());
+        }
+        Ok((keys, total))
+    }
+
+    /// Returns up to `limit` live entries starting with `prefix` whose keys sort
+    /// after `after`, in key order. Capped by `max_list_keys`, and like
+    /// `get_uncached` it leaves the cache and access times alone.
+    pub fn scan(
+        &self,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<ScanPage, StorageError> {
+        debug!(
+            "Scanning {} keys with prefix {:?} after {:?}.",
+            limit,
+            hex::encode(prefix),
+            after.map(hex::encode)
+        );
+        // Pages hold at least one entry, even under a zero cap, so a scan
+        // always makes progress.
+        let limit = limit
+            .min(self.options.max_list_keys.unwrap_or(usize::MAX))
+            .max(1);
+        // The smallest `limit + 1` keys past the cursor; the extra one tells
+        // whether another page follows.
+        let mut smallest = BinaryHeap::new();
+        for entry in self.iter() {
+            let key = entry.key();
+            if !key.starts_with(prefix) || after.is_some_and(|after| key <= after) {
+                continue;
+            }
+            if smallest.len() <= limit {
+                smallest.push(key.to_vec());
+            } else if smallest
+                .peek()
+                .is_some_and(|largest| key < largest.as_slice())
+            {
+                smallest.pop();
+                smallest.push(key.to_vec());
+            }
+        }
+        let mut keys = smallest.into_sorted_vec();
+        let more = keys.len() > limit;
+        keys.truncate(limit);
+        let cursor = if more { keys.last().cloned() } else { None };
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            let Some(stored) = self.data.get(&key) else {
+                continue;
+            };
+            let value = compression::decode(&stored)?;
+            drop(stored);
+            let value = self.transform_get(&key, value)?;
+            entries.push((key, value));
+        }
+        info!("Scanned {} entries.", entries.len());
+        Ok((entries, cursor))
     }
 
     pub fn exists(&self, key: Vec<u8>) -> Result<bool, StorageError> {
         debug!("Checking existence of key: {:?}", hex::encode(&key));
-        let exists = self.data.contains_key(&key);
+        let exists = self.data.contains_key(&key) && !self.is_expired(&key);
         if exists {
             info!("Key {:?} exists.", hex::encode(&key));
         } else {
@@ -195,31 +1020,2200 @@ impl Storage {
         Ok(exists)
     }
 
+    pub fn batch_exists_count(&self, keys: &[Vec<u8>]) -> Result<usize, StorageError> {
+        debug!("Counting which of {} keys exist.", keys.len());
+        let count = keys
+            .iter()
+            .filter(|key| self.data.contains_key(*key) && !self.is_expired(key))
+            .count();
+        info!("{} of {} keys exist.", count, keys.len());
+        Ok(count)
+    }
+
     pub fn count(&self) -> Result<usize, StorageError> {
         debug!("Counting keys in storage.");
-        let count = self.data.len();
+        let count = self.data.len() - self.expired_count();
         info!("Storage contains {} keys.", count);
         Ok(count)
     }
 
+    pub fn count_prefix(&self, prefix: &[u8]) -> Result<usize, StorageError> {
+        debug!("Counting keys with prefix: {:?}", hex::encode(prefix));
+        let count = self
+            .iter()
+            .filter(|entry| entry.key().starts_with(prefix))
+            .count();
+        info!("Storage contains {} keys with the prefix.", count);
+        Ok(count)
+    }
+
+    /// Returns the value's original size, remaining TTL and access times without
+    /// decoding it.
+    pub fn stat(&self, key: Vec<u8>) -> Result<Option<KeyStat>, StorageError> {
+        debug!("Stat for key: {:?}", hex::encode(&key));
+        if self.is_expired(&key) {
+            return Ok(None);
+        }
+        let Some(stored) = self.data.get(&key) else {
+            return Ok(None);
+        };
+        let ttl_ms = self
+            .expirations
+            .get(&key)
+            .map(|expires_at| expires_at.saturating_sub(self.clock.now_ms()));
+        let (last_access_ms, last_modified_ms) = match self.times.get(&key) {
+            Some(times) => (
+                Some(times.accessed.load(Ordering::Relaxed)).filter(|&ms| ms > 0),
+                Some(times.modified.load(Ordering::Relaxed)).filter(|&ms| ms > 0),
+            ),
+            None => (None, None),
+        };
+        Ok(Some(KeyStat {
+            size: compression::original_len(&stored),
+            ttl_ms,
+            last_access_ms,
+            last_modified_ms,
+        }))
+    }
+
+    pub fn expire(&mut self, key: Vec<u8>, ttl: Duration) -> Result<bool, StorageError> {
+        let existed = self.set_ttl(key, ttl);
+        if existed {
+            self.schedule_sync()?;
+        }
+        Ok(existed)
+    }
+
+    /// Sets the TTL of `key` without scheduling a sync. Returns whether the
+    /// key exists.
+    fn set_ttl(&mut self, key: Vec<u8>, ttl: Duration) -> bool {
+        debug!("Setting TTL of {:?} on key: {:?}", ttl, hex::encode(&key));
+        if !self.data.contains_key(&key) || self.is_expired(&key) {
+            warn!("Key {:?} not found for expire.", hex::encode(&key));
+            return false;
+        }
+        let ttl = match self.options.ttl_jitter {
+            Some(fraction) => jittered(ttl, fraction),
+            None => ttl,
+        };
+        let expires_at = self.clock.now_ms().saturating_add(ttl.as_millis() as u64);
+        self.expirations.insert(key.clone(), expires_at);
+        info!("Key {:?} will expire in {:?}.", hex::encode(&key), ttl);
+        true
+    }
+
+    /// Sets every entry, with its TTL if it has one, and schedules a single
+    /// sync at the end. Returns how many entries were stored.
+    pub async fn batch_set_ex(
+        &mut self,
+        entries: Vec<(Vec<u8>, Vec<u8>, Option<Duration>)>,
+    ) -> Result<usize, StorageError> {
+        debug!("Setting {} entries with TTLs.", entries.len());
+        let mut stored = 0;
+        let mut result = Ok(());
+        for (key, value, ttl) in entries {
+            if let Err(e) = self.set(key.clone(), value).await {
+                result = Err(e);
+                break;
+            }
+            if let Some(ttl) = ttl {
+                self.set_ttl(key, ttl);
+            }
+            stored += 1;
+        }
+        // Entries stored before a failure are kept, so they get written too.
+        if stored > 0 {
+            self.schedule_sync()?;
+        }
+        result.map(|()| stored)
+    }
+
+    /// Sets `key` unless it already exists. Returns whether it was stored.
+    pub async fn set_if_absent(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<bool, StorageError> {
+        if self.exists(key.clone())? {
+            return Ok(false);
+        }
+        self.set(key.clone(), value).await?;
+        if let Some(ttl) = ttl {
+            self.expire(key, ttl)?;
+        }
+        Ok(true)
+    }
+
+    /// Deletes `key` if it holds `value`. Returns whether it was deleted.
+    pub async fn delete_if_equals(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<bool, StorageError> {
+        if self.get_uncached(key.clone())? != Some(value) {
+            return Ok(false);
+        }
+        self.delete(key).await?;
+        Ok(true)
+    }
+
+    /// Stores `value` if the current value of `key` meets `condition`.
+    /// Returns whether it was stored.
+    pub async fn set_if(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        condition: &Condition,
+    ) -> Result<bool, StorageError> {
+        let current = self.get_uncached(key.clone())?;
+        if !condition.holds(current.as_deref()) {
+            return Ok(false);
+        }
+        self.set(key, value).await?;
+        Ok(true)
+    }
+
+    /// Sets a TTL on `key` if it holds `value`. Returns whether it did.
+    pub fn expire_if_equals(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool, StorageError> {
+        if self.get_uncached(key.clone())? != Some(value) {
+            return Ok(false);
+        }
+        self.expire(key, ttl)
+    }
+
+    pub fn persist(&mut self, key: Vec<u8>) -> Result<bool, StorageError> {
+        debug!("Removing TTL from key: {:?}", hex::encode(&key));
+        if !self.data.contains_key(&key) || self.is_expired(&key) {
+            warn!("Key {:?} not found for persist.", hex::encode(&key));
+            return Ok(false);
+        }
+        self.expirations.remove(&key);
+        self.schedule_sync()?;
+        info!("Key {:?} persisted.", hex::encode(&key));
+        Ok(true)
+    }
+
+    pub async fn purge_expired(&mut self) -> Result<usize, StorageError> {
+        let now = self.clock.now_ms();
+        if let Some(window) = self.options.soft_delete {
+            let window = window.as_millis() as u64;
+            self.tombstones
+                .retain(|_, tombstone| tombstone.deleted_at + window > now);
+        }
+        let expired: Vec<Vec<u8>> = self
+            .expirations
+            .iter()
+            .filter(|entry| *entry.value() <= now)
+            .map(|entry| entry.key().clone())
+            .collect();
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        let keep_values = self.expired.is_some() || self.options.dead_letter_prefix.is_some();
+        let mut removed = Vec::new();
+        let mut cache = self.cache.lock().await;
+        for key in &expired {
+            cache.pop(key);
+            self.expirations.remove(key);
+            if let Some(stored) = self.remove_data(key)
+                && keep_values
+            {
+                let value = compression::decode(&stored)
+                    .map_err(StorageError::from)
+                    .and_then(|value| self.transform_get(key, value));
+                match value {
+                    Ok(value) => removed.push((key.clone(), value)),
+                    Err(e) => warn!("Failed to decode expired key {:?}: {}", hex::encode(key), e),
+                }
+            }
+        }
+        drop(cache);
+        let dead_letter_prefix = self.options.dead_letter_prefix.clone();
+        for (key, value) in removed {
+            if let Some(prefix) = &dead_letter_prefix {
+                let dead_key = [prefix.as_slice(), &key].concat();
+                if let Err(e) = self.set(dead_key, value.clone()).await {
+                    warn!(
+                        "Failed to move expired key {:?} to dead letters: {}",
+                        hex::encode(&key),
+                        e
+                    );
+                }
+            }
+            if let Some(tx) = &self.expired
+                && tx.send(Expiration { key, value }).is_err()
+            {
+                self.expired = None;
+            }
+        }
+        self.schedule_sync()?;
+        info!("Purged {} expired keys.", expired.len());
+        Ok(expired.len())
+    }
+
+    /// Drops keys whose TTL ran out and rewrites the dump from scratch,
+    /// reclaiming what expired keys and a lazily cleared dump still hold.
+    /// Returns the dump's size in bytes before and after. Volatile storage
+    /// has no dump and reports zero for both.
+    pub async fn compact(&mut self) -> Result<(u64, u64), StorageError> {
+        self.flush().await?;
+        let before = self.dump_len()?;
+        self.purge_expired().await?;
+        self.schedule_write(true)?;
+        self.flush().await?;
+        let after = self.dump_len()?;
+        info!("Compacted dump from {} to {} bytes.", before, after);
+        Ok((before, after))
+    }
+
+    fn dump_len(&self) -> Result<u64, StorageError> {
+        let Some(dump) = &self.dump else {
+            return Ok(0);
+        };
+        let dump = dump.file.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(std::fs::metadata(&dump.path)?.len())
+    }
+
+    fn is_expired(&self, key: &[u8]) -> bool {
+        self.expirations
+            .get(key)
+            .is_some_and(|expires_at| *expires_at <= self.clock.now_ms())
+    }
+
+    fn expired_count(&self) -> usize {
+        let now = self.clock.now_ms();
+        self.expirations
+            .iter()
+            .filter(|entry| *entry.value() <= now)
+            .count()
+    }
+
+    pub fn stats(&self) -> Result<Stats, StorageError> {
+        debug!("Collecting storage stats.");
+        let mut stats = Stats {
+            keys: self.count()?,
+            memory_bytes: self.memory_bytes.load(Ordering::Relaxed),
+            evicted_keys: self.evicted_keys.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            bytes_received: self.traffic.received.load(Ordering::Relaxed),
+            bytes_sent: self.traffic.sent.load(Ordering::Relaxed),
+            rejected_frames: self.traffic.rejected_frames.load(Ordering::Relaxed),
+            disk_full: self.sync_health.is_disk_full(),
+            ..Default::default()
+        };
+        for entry in self.data.iter() {
+            if compression::is_compressed(entry.value()) {
+                stats.compressed_values += 1;
+                stats.compression_saved_bytes += compression::original_len(entry.value())
+                    .saturating_sub(entry.value().len() - 1)
+                    as u64;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Empties the read cache, leaving stored keys untouched.
+    pub async fn clear_cache(&self) -> Result<usize, StorageError> {
+        let mut cache = self.cache.lock().await;
+        let dropped = cache.len();
+        cache.clear();
+        info!("Dropped {} cached entries.", dropped);
+        Ok(dropped)
+    }
+
     pub async fn clear(&mut self) -> Result<(), StorageError> {
         debug!("Clearing all keys in storage.");
         self.data.clear();
+        self.memory_bytes.store(0, Ordering::Relaxed);
+        self.expirations.clear();
+        self.times.clear();
         self.cache.lock().await.clear();
-        self.sync()?;
+        if !self.options.lazy_clear {
+            self.schedule_sync()?;
+        }
         info!("Storage cleared successfully.");
         Ok(())
     }
+
+    /// Checks `entries` the way `replace_all` would, without storing anything.
+    /// An entry fails on its own, or when it would take the entries that passed
+    /// before it over `max_memory_bytes` or `max_keys`.
+    pub fn validate_all(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<Result<(), StorageError>> {
+        debug!("Validating {} entries.", entries.len());
+        let mut sizes: HashMap<&[u8], usize> = HashMap::new();
+        let mut memory_bytes = 0;
+        entries
+            .iter()
+            .map(|(key, value)| {
+                let value = self.transform_set(key, value.clone())?;
+                self.check_utf8(&value)?;
+                let stored = compression::encode(value, self.options.compression_threshold);
+                let size = entry_size(key, &stored);
+                let previous = sizes.get(key.as_slice()).copied();
+                let total = memory_bytes + size - previous.unwrap_or(0);
+                if let Some(max) = self.options.max_memory_bytes
+                    && total > max
+                {
+                    return Err(StorageError::ValueTooLarge { size: total, max });
+                }
+                if let Some(max) = self.options.max_keys
+                    && previous.is_none()
+                    && sizes.len() >= max
+                {
+                    return Err(StorageError::CapacityExceeded { max });
+                }
+                memory_bytes = total;
+                sizes.insert(key, size);
+                Ok(())
+            })
+            .collect()
+    }
+
+    /// Swaps in a map built from `entries` and returns how many keys it holds.
+    /// The old contents, TTLs and cache entries are dropped in the same step.
+    pub async fn replace_all(
+        &mut self,
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<usize, StorageError> {
+        debug!("Replacing storage contents with {} entries.", entries.len());
+        let data: Data = DashMap::with_capacity(entries.len());
+        let mut memory_bytes = 0;
+        for (key, value) in entries {
+            let value = self.transform_set(&key, value)?;
+            self.check_utf8(&value)?;
+            let stored = compression::encode(value, self.options.compression_threshold);
+            memory_bytes += entry_size(&key, &stored);
+            if let Some(old) = data.insert(key.clone(), stored) {
+                memory_bytes -= entry_size(&key, &old);
+            }
+        }
+        if let Some(max) = self.options.max_memory_bytes
+            && memory_bytes > max
+        {
+            warn!(
+                "Rejecting replacement: {} bytes exceed the memory limit of {}.",
+                memory_bytes, max
+            );
+            return Err(StorageError::ValueTooLarge {
+                size: memory_bytes,
+                max,
+            });
+        }
+        let loaded = data.len();
+        if let Some(max) = self.options.max_keys
+            && loaded > max
+        {
+            warn!(
+                "Rejecting replacement: {} keys exceed the cap of {}.",
+                loaded, max
+            );
+            return Err(StorageError::CapacityExceeded { max });
+        }
+        let now = self.clock.now_ms();
+        self.times = data
+            .iter()
+            .map(|entry| {
+                let times = KeyTimes::default();
+                times.modified.store(now, Ordering::Relaxed);
+                (entry.key().clone(), times)
+            })
+            .collect();
+        *self.data = data;
+        self.memory_bytes.store(memory_bytes, Ordering::Relaxed);
+        self.expirations.clear();
+        self.cache.lock().await.clear();
+        self.schedule_sync()?;
+        info!("Storage contents replaced with {} keys.", loaded);
+        Ok(loaded)
+    }
 }
 
-#[derive(Error, Debug)]
-pub enum StorageError {
-    #[error("IO error: {0}")]
-    Io(#[from] std::io::Error),
-    #[error("Encode bincode error: {0}")]
-    EncodeBincode(#[from] bincode::error::EncodeError),
+fn jittered(ttl: Duration, fraction: f64) -> Duration {
+    let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+    ttl + ttl.mul_f64(fraction.clamp(0.0, 1.0) * random)
+}
+
+/// Associated data binding a dump to the path it was written to, so a dump
+/// swapped in from elsewhere fails to decrypt even under the same key.
+fn dump_aad(path: &Path) -> Vec<u8> {
+    path.as_os_str().as_encoded_bytes().to_vec()
+}
+
+/// `path` made absolute with symlinks resolved, so how the configured path
+/// is spelled doesn't change what the dump is bound to. Dumps written before
+/// write ids are bound to the path as configured instead.
+fn resolved_path(path: &Path) -> std::io::Result<PathBuf> {
+    match std::fs::canonicalize(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let parent = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            let name = path.file_name().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "dump path has no file name",
+                )
+            })?;
+            Ok(std::fs::canonicalize(parent)?.join(name))
+        }
+        resolved => resolved,
+    }
+}
+
+/// The layouts a dump on disk can have, newest first.
+enum DumpFormat {
+    Chunked(chunked::Header),
+    PathBound,
+    /// Encrypted as a single blob, from before dumps were chunked.
+    Whole,
+}
+
+/// Tells the dump's layout from its start, consuming the chunked header.
+fn read_format(reader: &mut impl BufRead) -> std::io::Result<DumpFormat> {
+    let head = reader.fill_buf()?;
+    if head.starts_with(chunked::MAGIC) {
+        reader.consume(chunked::MAGIC.len());
+        chunked::Header::read(reader).map(DumpFormat::Chunked)
+    } else if head.starts_with(chunked::PATH_BOUND_MAGIC) {
+        reader.consume(chunked::PATH_BOUND_MAGIC.len());
+        Ok(DumpFormat::PathBound)
+    } else {
+        Ok(DumpFormat::Whole)
+    }
+}
+
+fn entry_size(key: &[u8], stored: &[u8]) -> usize {
+    key.len() + stored.len()
+}
+
+fn write_dump(
+    file: &std::sync::Mutex<DumpFile>,
+    aes: &AES,
+    binding: &[u8],
+    content: &[u8],
+    generation: u64,
+    retries: u32,
+) -> Result<(), StorageError> {
+    let encrypted_content =
+        chunked::seal(aes, binding, content, chunked::CHUNK_SIZE).map_err(StorageError::Aes)?;
+    let mut dump = file.lock().unwrap_or_else(PoisonError::into_inner);
+    if dump.generation > generation {
+        debug!("Newer snapshot already written, skipping sync.");
+        return Ok(());
+    }
+    retry_transient(retries, || (dump.write)(&dump.path, &encrypted_content))
+        .map_err(write_error)?;
+    dump.generation = generation;
+    info!("Storage synced successfully.");
+    Ok(())
+}
+
+/// Writes `content` to a temporary file next to `path` and renames it over
+/// `path` once it is on disk, so a write that fails or is cut short by a
+/// crash leaves the previous dump in place. A symlink at `path` is kept and
+/// the file it points to replaced.
+fn replace_file(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    replace_file_with(path, |file| file.write_all(content))
+}
+
+fn replace_file_with(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let path = resolved_path(path)?;
+    let temp = temp_path_for(&path);
+    let written = create_temp(&path, &temp).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        std::fs::rename(&temp, &path)
+    });
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e);
+    }
+    sync_parent(&path)
+}
+
+/// A name next to `path` that no other write uses, from this process or
+/// another one such as `ckeylock compact`.
+fn temp_path_for(path: &Path) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    PathBuf::from(temp)
+}
+
+/// Creates `temp` with the permissions of the file it replaces, or readable
+/// by its owner only when there is none yet.
+fn create_temp(path: &Path, temp: &Path) -> std::io::Result<File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(temp)?;
+    match std::fs::metadata(path) {
+        Ok(metadata) => file.set_permissions(metadata.permissions())?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    Ok(file)
+}
+
+/// Makes a rename into `path`'s directory durable.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> std::io::Result<()> {
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    File::open(parent)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+fn write_error(error: std::io::Error) -> StorageError {
+    use std::io::ErrorKind;
+    match error.kind() {
+        ErrorKind::StorageFull | ErrorKind::QuotaExceeded => {
+            error!("Failed to write dump: {}", error);
+            StorageError::DiskFull
+        }
+        _ => error.into(),
+    }
+}
+
+/// Runs `op`, retrying up to `retries` times with exponential backoff while it
+/// fails with an error that may go away on its own. Other errors fail at once.
+fn retry_transient<T>(
+    retries: u32,
+    mut op: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                let backoff = SYNC_RETRY_BACKOFF * 2u32.pow(attempt);
+                warn!("Transient IO error, retrying in {:?}: {}", backoff, e);
+                std::thread::sleep(backoff);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient(error: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        error.kind(),
+        ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::StorageFull
+            | ErrorKind::ResourceBusy
+    )
+}
+
+fn encode_dump(data: &Data, expirations: &Expirations) -> Result<Vec<u8>, StorageError> {
+    let data: BTreeMap<Vec<u8>, Vec<u8>> = data
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect();
+    let expirations: BTreeMap<Vec<u8>, u64> = expirations
+        .iter()
+        .map(|entry| (entry.key().clone(), *entry.value()))
+        .collect();
+    let mut content = DUMP_MAGIC.to_vec();
+    content.push(DUMP_VERSION);
+    content.extend(bincode::serde::encode_to_vec(
+        (data, expirations),
+        bincode::config::standard(),
+    )?);
+    Ok(content)
+}
+
+/// Decodes a chunked dump while it is decrypted, so peak memory is the decoded
+/// map plus a single chunk.
+fn load_chunked(
+    reader: impl Read,
+    aes: &AES,
+    aad: &[u8],
+) -> Result<(Data, Expirations, u64), StorageError> {
+    let mut reader = ChunkReader::new(reader, aes.clone(), aad.to_vec());
+    let decoded = decode_dump_from(&mut reader).and_then(|dump| {
+        // Reads any trailing chunks so they are authenticated and checksummed.
+        std::io::copy(&mut reader, &mut std::io::sink())?;
+        Ok(dump)
+    });
+    match decoded {
+        Ok((data, expirations)) => Ok((data, expirations, reader.checksum())),
+        Err(e) => Err(reader.take_auth_error().map_or(e, StorageError::Aes)),
+    }
+}
+
+/// Loads a dump written before chunking, encrypted as a single blob.
+fn load_whole(
+    content: &[u8],
+    aes: &AES,
+    aad: &[u8],
+) -> Result<(Data, Expirations, u64), StorageError> {
+    let decrypted_content = match aes.decrypt(content, aad) {
+        Ok(decrypted) => decrypted,
+        Err(err) => match aes.decrypt(content, &[]) {
+            Ok(decrypted) => {
+                warn!("Dump is not bound to its path yet, it will be on the next sync.");
+                decrypted
+            }
+            Err(_) => return Err(StorageError::Aes(err)),
+        },
+    };
+    let (data, expirations) = decode_dump(&decrypted_content)?;
+    Ok((data, expirations, checksum(&decrypted_content)))
+}
+
+/// Decodes a current-version dump straight from `reader` without buffering it.
+fn decode_dump_from(reader: &mut impl Read) -> Result<(Data, Expirations), StorageError> {
+    let mut header = [0; 5];
+    reader.read_exact(&mut header)?;
+    match header.split_at(DUMP_MAGIC.len()) {
+        (magic, [DUMP_VERSION]) if magic == DUMP_MAGIC => Ok(bincode::serde::decode_from_std_read(
+            reader,
+            bincode::config::standard(),
+        )?),
+        (magic, [version]) if magic == DUMP_MAGIC => {
+            Err(StorageError::UnsupportedDumpVersion(*version))
+        }
+        _ => Err(StorageError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "dump is missing its header",
+        ))),
+    }
+}
+
+fn decode_dump(content: &[u8]) -> Result<(Data, Expirations), StorageError> {
+    match content.strip_prefix(DUMP_MAGIC) {
+        Some([DUMP_VERSION, rest @ ..]) => {
+            let (dump, _) = bincode::serde::decode_from_slice(rest, bincode::config::standard())?;
+            Ok(dump)
+        }
+        Some([1, rest @ ..]) => {
+            let (data, _) = bincode::serde::decode_from_slice(rest, bincode::config::standard())?;
+            Ok((data, DashMap::new()))
+        }
+        Some([version, ..]) => Err(StorageError::UnsupportedDumpVersion(*version)),
+        _ => {
+            info!("Loading legacy dump format.");
+            let (data, _): (Data, _) =
+                bincode::serde::decode_from_slice(content, bincode::config::standard())?;
+            let data = data
+                .into_iter()
+                .map(|(k, v)| (k, compression::raw(v)))
+                .collect();
+            Ok((data, DashMap::new()))
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Encode bincode error: {0}")]
+    EncodeBincode(#[from] bincode::error::EncodeError),
     #[error("Decode bincode error: {0}")]
     DecodeBincode(#[from] bincode::error::DecodeError),
     #[error("AES error: {0}")]
     Aes(aes_gcm::Error),
+    #[error("Compression error: {0}")]
+    Compression(#[from] CompressionError),
+    #[error("Entry of {size} bytes exceeds the memory limit of {max} bytes")]
+    ValueTooLarge { size: usize, max: usize },
+    #[error("Value is not valid UTF-8 (invalid byte at offset {valid_up_to})")]
+    InvalidUtf8 { valid_up_to: usize },
+    #[error("Storage is full: at most {max} keys are allowed")]
+    CapacityExceeded { max: usize },
+    #[error("Invalid range: start {start} is past end {end}")]
+    InvalidRange { start: usize, end: usize },
+    #[error("Unsupported dump version: {0}")]
+    UnsupportedDumpVersion(u8),
+    #[error("Disk is full, writes are rejected until the dump can be written")]
+    DiskFull,
+    #[error("Sync task failed: {0}")]
+    SyncTask(#[from] JoinError),
+    #[error("Transform failed: {0}")]
+    Transform(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::crypto::hash;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("ckeylock-storage-test-{}.bin", name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn aes() -> AES {
+        AES::new(&hash(b"test"))
+    }
+
+    /// Runs TTLs off a clock that only moves when the test advances it.
+    fn mock_clock(options: StorageOptions) -> (StorageOptions, Arc<MockClock>) {
+        let clock = Arc::new(MockClock::default());
+        let options = StorageOptions {
+            clock: Some(clock.clone()),
+            ..options
+        };
+        (options, clock)
+    }
+
+    #[tokio::test]
+    async fn test_compression_is_transparent() {
+        let path = temp_path("compression");
+        let options = StorageOptions {
+            compression_threshold: Some(64),
+            ..Default::default()
+        };
+        let mut storage = Storage::new(&path, aes(), options.clone()).unwrap();
+        let small = b"small".to_vec();
+        let large = b"compressible".repeat(512);
+        storage.set(b"small".to_vec(), small.clone()).await.unwrap();
+        storage.set(b"large".to_vec(), large.clone()).await.unwrap();
+        storage.cache.lock().await.clear();
+
+        assert_eq!(
+            storage.get(b"small".to_vec()).await.unwrap(),
+            Some(small.clone())
+        );
+        assert_eq!(
+            storage.get(b"large".to_vec()).await.unwrap(),
+            Some(large.clone())
+        );
+
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.keys, 2);
+        assert_eq!(stats.compressed_values, 1);
+        assert!(stats.compression_saved_bytes > 0);
+
+        storage.sync().await.unwrap();
+        drop(storage);
+        let storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        assert_eq!(
+            storage
+                .batch_get(vec![b"small".to_vec(), b"large".to_vec()])
+                .await
+                .unwrap(),
+            vec![Some(small), Some(large)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_volatile_storage_is_not_persisted() {
+        let mut storage = Storage::volatile(StorageOptions::default());
+        storage
+            .set(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        storage.delete(b"key".to_vec()).await.unwrap();
+        storage
+            .set(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        storage.clear().await.unwrap();
+        storage
+            .set(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        assert!(storage.take_pending_sync().is_none());
+        storage.sync().await.unwrap();
+        assert!(storage.dump.is_none());
+        drop(storage);
+
+        let storage = Storage::volatile(StorageOptions::default());
+        assert_eq!(storage.count().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_iter_visits_every_entry_once() {
+        let path = temp_path("iter");
+        let options = StorageOptions {
+            compression_threshold: Some(16),
+            ..Default::default()
+        };
+        let mut storage = Storage::new(&path, aes(), options).unwrap();
+        for i in 0..100u8 {
+            storage.set(vec![i], vec![i; i as usize]).await.unwrap();
+        }
+        storage
+            .set(b"expired".to_vec(), b"v".to_vec())
+            .await
+            .unwrap();
+        storage.expire(b"expired".to_vec(), Duration::ZERO).unwrap();
+
+        let mut seen = std::collections::HashMap::new();
+        for entry in storage.iter() {
+            let previous = seen.insert(entry.key().to_vec(), entry.value().unwrap());
+            assert!(previous.is_none(), "visited {:?} twice", entry.key());
+        }
+        assert_eq!(seen.len(), 100);
+        for i in 0..100u8 {
+            assert_eq!(seen[&vec![i]], vec![i; i as usize]);
+        }
+    }
+
+    #[test]
+    fn test_retry_transient_io_errors() {
+        use std::io::{Error, ErrorKind};
+
+        let mut calls = 0;
+        let result = retry_transient(3, || {
+            calls += 1;
+            if calls == 1 {
+                Err(Error::from(ErrorKind::Interrupted))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+
+        let mut calls = 0;
+        let result: std::io::Result<()> = retry_transient(3, || {
+            calls += 1;
+            Err(Error::from(ErrorKind::PermissionDenied))
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let result: std::io::Result<()> = retry_transient(2, || {
+            calls += 1;
+            Err(Error::from(ErrorKind::StorageFull))
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::StorageFull);
+        assert_eq!(calls, 3);
+    }
+
+    #[tokio::test]
+    async fn test_replace_all() {
+        let path = temp_path("replace-all");
+        let mut storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        storage.set(b"old".to_vec(), b"1".to_vec()).await.unwrap();
+        storage
+            .set(b"kept".to_vec(), b"old".to_vec())
+            .await
+            .unwrap();
+        storage
+            .expire(b"kept".to_vec(), Duration::from_millis(1))
+            .unwrap();
+        storage.get(b"old".to_vec()).await.unwrap();
+
+        let entries = vec![
+            (b"kept".to_vec(), b"new".to_vec()),
+            (b"fresh".to_vec(), b"2".to_vec()),
+        ];
+        assert_eq!(storage.replace_all(entries).await.unwrap(), 2);
+        storage.sync().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        assert_eq!(storage.get(b"old".to_vec()).await.unwrap(), None);
+        assert_eq!(
+            storage.get(b"kept".to_vec()).await.unwrap(),
+            Some(b"new".to_vec())
+        );
+        assert_eq!(storage.stats().unwrap().memory_bytes, 4 + 4 + 5 + 2);
+        drop(storage);
+
+        let storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        let (mut keys, _) = storage.list().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec![b"fresh".to_vec(), b"kept".to_vec()]);
+    }
+
+    #[test]
+    fn test_validate_all() {
+        let storage = Storage::volatile(StorageOptions {
+            max_keys: Some(2),
+            require_utf8_values: true,
+            ..Default::default()
+        });
+        let entries = vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), vec![0xff]),
+            (b"b".to_vec(), b"2".to_vec()),
+            (b"a".to_vec(), b"3".to_vec()),
+            (b"c".to_vec(), b"4".to_vec()),
+        ];
+        let results = storage.validate_all(&entries);
+        assert!(matches!(results[1], Err(StorageError::InvalidUtf8 { .. })));
+        assert!(matches!(
+            results[4],
+            Err(StorageError::CapacityExceeded { max: 2 })
+        ));
+        assert!([0, 2, 3].iter().all(|&i| results[i].is_ok()));
+        assert_eq!(storage.count().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_undelete() {
+        let (options, clock) = mock_clock(StorageOptions {
+            soft_delete: Some(Duration::from_secs(60)),
+            ..Default::default()
+        });
+        let mut storage = Storage::volatile(options);
+        storage.set(b"key".to_vec(), b"v1".to_vec()).await.unwrap();
+        storage
+            .expire(b"key".to_vec(), Duration::from_secs(600))
+            .unwrap();
+        storage.delete(b"key".to_vec()).await.unwrap();
+        assert!(!storage.exists(b"key".to_vec()).unwrap());
+
+        clock.advance(Duration::from_secs(30));
+        assert!(storage.undelete(b"key".to_vec()).await.unwrap());
+        assert_eq!(
+            storage.get(b"key".to_vec()).await.unwrap(),
+            Some(b"v1".to_vec())
+        );
+        let stat = storage.stat(b"key".to_vec()).unwrap().unwrap();
+        assert_eq!(stat.ttl_ms, Some(570_000));
+        assert!(!storage.undelete(b"key".to_vec()).await.unwrap());
+
+        // A key set again after its delete isn't overwritten.
+        storage.delete(b"key".to_vec()).await.unwrap();
+        storage.set(b"key".to_vec(), b"v2".to_vec()).await.unwrap();
+        assert!(!storage.undelete(b"key".to_vec()).await.unwrap());
+        assert_eq!(
+            storage.get(b"key".to_vec()).await.unwrap(),
+            Some(b"v2".to_vec())
+        );
+
+        storage.delete(b"key".to_vec()).await.unwrap();
+        clock.advance(Duration::from_secs(61));
+        storage.purge_expired().await.unwrap();
+        assert!(storage.tombstones.is_empty());
+        assert!(!storage.undelete(b"key".to_vec()).await.unwrap());
+        assert_eq!(storage.get(b"key".to_vec()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_delete_returns_value() {
+        let path = temp_path("delete");
+        let options = StorageOptions {
+            compression_threshold: Some(16),
+            ..Default::default()
+        };
+        let mut storage = Storage::new(&path, aes(), options).unwrap();
+        storage.set(b"key".to_vec(), vec![7; 64]).await.unwrap();
+
+        assert_eq!(
+            storage.delete(b"key".to_vec()).await.unwrap(),
+            Some(vec![7; 64])
+        );
+        assert_eq!(storage.delete(b"key".to_vec()).await.unwrap(), None);
+        assert_eq!(storage.stats().unwrap().memory_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn test_delete_prefix() {
+        let path = temp_path("delete-prefix");
+        let mut storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        for key in ["user:1", "user:2", "user:1:session", "users", "admin:1"] {
+            storage
+                .set(key.as_bytes().to_vec(), b"value".to_vec())
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(storage.delete_prefix(b"user:".to_vec()).await.unwrap(), 3);
+        let (mut keys, _) = storage.list().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec![b"admin:1".to_vec(), b"users".to_vec()]);
+        assert_eq!(storage.get(b"user:1".to_vec()).await.unwrap(), None);
+        assert_eq!(storage.delete_prefix(b"missing".to_vec()).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_checksum_detects_changes() {
+        let path = temp_path("checksum");
+        let mut storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        let initial = storage.checksum;
+        storage.sync().await.unwrap();
+        assert_eq!(storage.checksum, initial);
+
+        storage
+            .set(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        storage.sync().await.unwrap();
+        assert_ne!(storage.checksum, initial);
+
+        let after_set = storage.checksum;
+        storage
+            .set(b"key".to_vec(), b"other".to_vec())
+            .await
+            .unwrap();
+        storage.sync().await.unwrap();
+        assert_ne!(storage.checksum, after_set);
+
+        let synced = storage.checksum;
+        drop(storage);
+        let storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        assert_eq!(storage.checksum, synced);
+    }
+
+    #[test]
+    fn test_creates_missing_dump_directory() {
+        let dir = std::env::temp_dir().join("ckeylock-storage-test-nested");
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("a").join("b").join("dump.bin");
+
+        Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        assert!(path.exists());
+        Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+    }
+
+    async fn dump_with_key(path: &Path) {
+        let mut storage = Storage::new(path, aes(), StorageOptions::default()).unwrap();
+        storage
+            .set(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        storage.sync().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_substituted_dump_fails_to_load() {
+        let path = temp_path("aad-original");
+        let other = temp_path("aad-other");
+        dump_with_key(&other).await;
+
+        std::fs::rename(&other, &path).unwrap();
+        assert!(matches!(
+            Storage::new(&path, aes(), StorageOptions::default()),
+            Err(StorageError::Aes(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dump_of_another_store_fails_to_load() {
+        let path = temp_path("aad-store");
+        let other = temp_path("aad-other-store");
+        dump_with_key(&path).await;
+        dump_with_key(&other).await;
+
+        std::fs::copy(&other, &path).unwrap();
+        assert!(matches!(
+            Storage::new(&path, aes(), StorageOptions::default()),
+            Err(StorageError::Aes(_))
+        ));
+        assert!(matches!(
+            Storage::verify_dump_key(&path, &aes()),
+            Err(StorageError::Aes(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dump_loads_however_its_path_is_spelled() {
+        let path = temp_path("aad-spelling");
+        dump_with_key(&path).await;
+
+        let spelled = path
+            .parent()
+            .unwrap()
+            .join(".")
+            .join(path.file_name().unwrap());
+        Storage::verify_dump_key(&spelled, &aes()).unwrap();
+        let storage = Storage::new(&spelled, aes(), StorageOptions::default()).unwrap();
+        assert!(storage.exists(b"key".to_vec()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_legacy_dump_is_rebound_on_sync() {
+        let path = temp_path("aad-legacy");
+        let data = DashMap::from_iter([(b"key".to_vec(), compression::raw(b"value".to_vec()))]);
+        let content = encode_dump(&data, &DashMap::new()).unwrap();
+        std::fs::write(&path, aes().encrypt(&content, None, &[]).unwrap()).unwrap();
+
+        Storage::verify_dump_key(&path, &aes()).unwrap();
+        let mut storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        storage.sync().await.unwrap();
+        drop(storage);
+
+        assert!(std::fs::read(&path).unwrap().starts_with(chunked::MAGIC));
+        let storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        assert!(storage.exists(b"key".to_vec()).unwrap());
+        drop(storage);
+        let moved = temp_path("aad-legacy-moved");
+        std::fs::rename(&path, &moved).unwrap();
+        assert!(matches!(
+            Storage::new(&moved, aes(), StorageOptions::default()),
+            Err(StorageError::Aes(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_path_bound_dump_gets_write_id_on_sync() {
+        let path = temp_path("path-bound");
+        let data = DashMap::from_iter([(b"key".to_vec(), compression::raw(b"value".to_vec()))]);
+        let content = encode_dump(&data, &DashMap::new()).unwrap();
+        let mut dump = chunked::PATH_BOUND_MAGIC.to_vec();
+        chunked::seal_chunks(&aes(), &dump_aad(&path), &content, 64, &mut dump).unwrap();
+        std::fs::write(&path, dump).unwrap();
+
+        Storage::verify_dump_key(&path, &aes()).unwrap();
+        let mut storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        storage.sync().await.unwrap();
+        drop(storage);
+
+        assert!(std::fs::read(&path).unwrap().starts_with(chunked::MAGIC));
+        let storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        assert!(storage.exists(b"key".to_vec()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_chunked_load_matches_bulk_decode() {
+        let path = temp_path("chunked");
+        let mut storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        for i in 0..300u32 {
+            let value = (0..10_000u32).map(|j| (i ^ j) as u8).collect();
+            storage.set(i.to_le_bytes().to_vec(), value).await.unwrap();
+        }
+        storage
+            .expire(0u32.to_le_bytes().to_vec(), Duration::from_secs(60))
+            .unwrap();
+        storage.sync().await.unwrap();
+        let content = encode_dump(&storage.data, &storage.expirations).unwrap();
+        assert!(content.len() > 2 * chunked::CHUNK_SIZE);
+        drop(storage);
+
+        let loaded = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        let (data, expirations) = decode_dump(&content).unwrap();
+        assert_eq!(loaded.checksum, checksum(&content));
+        assert_eq!(loaded.data.len(), data.len());
+        for entry in data.iter() {
+            assert_eq!(*loaded.data.get(entry.key()).unwrap(), *entry.value());
+        }
+        assert_eq!(loaded.expirations.len(), expirations.len());
+        assert_eq!(
+            encode_dump(&loaded.data, &loaded.expirations).unwrap(),
+            content
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ttl_jitter_spreads_expirations() {
+        let path = temp_path("ttl-jitter");
+        let (options, _clock) = mock_clock(StorageOptions {
+            ttl_jitter: Some(0.5),
+            ..Default::default()
+        });
+        let mut storage = Storage::new(&path, aes(), options).unwrap();
+        let ttl = Duration::from_secs(100);
+        for i in 0..50u8 {
+            storage.set(vec![i], b"v".to_vec()).await.unwrap();
+            assert!(storage.expire(vec![i], ttl).unwrap());
+        }
+
+        let expirations: std::collections::BTreeSet<u64> =
+            storage.expirations.iter().map(|e| *e.value()).collect();
+        assert!(expirations.len() > 10);
+        for expires_at in expirations {
+            assert!((100_000..=150_000).contains(&expires_at));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expire_and_persist_survive_restart() {
+        let path = temp_path("expire-restart");
+        let (options, _clock) = mock_clock(StorageOptions::default());
+        let mut storage = Storage::new(&path, aes(), options.clone()).unwrap();
+        storage
+            .set(b"expiring".to_vec(), b"v".to_vec())
+            .await
+            .unwrap();
+        storage
+            .set(b"persisted".to_vec(), b"v".to_vec())
+            .await
+            .unwrap();
+        storage
+            .expire(b"persisted".to_vec(), Duration::from_secs(60))
+            .unwrap();
+        storage.sync().await.unwrap();
+
+        // Nothing but the TTL changes, so only these calls can write it.
+        storage
+            .expire(b"expiring".to_vec(), Duration::from_secs(30))
+            .unwrap();
+        storage.flush().await.unwrap();
+        storage.persist(b"persisted".to_vec()).unwrap();
+        storage.flush().await.unwrap();
+        drop(storage);
+
+        let storage = Storage::new(&path, aes(), options).unwrap();
+        let ttl = |key: &[u8]| storage.stat(key.to_vec()).unwrap().unwrap().ttl_ms;
+        assert_eq!(ttl(b"expiring"), Some(30_000));
+        assert_eq!(ttl(b"persisted"), None);
+    }
+
+    #[tokio::test]
+    async fn test_expire_and_persist() {
+        let path = temp_path("expire");
+        let (options, clock) = mock_clock(StorageOptions::default());
+        let mut storage = Storage::new(&path, aes(), options).unwrap();
+        storage.set(b"short".to_vec(), b"v".to_vec()).await.unwrap();
+        storage.set(b"kept".to_vec(), b"v".to_vec()).await.unwrap();
+
+        assert!(
+            storage
+                .expire(b"short".to_vec(), Duration::from_millis(50))
+                .unwrap()
+        );
+        assert!(
+            storage
+                .expire(b"kept".to_vec(), Duration::from_millis(50))
+                .unwrap()
+        );
+        assert!(storage.persist(b"kept".to_vec()).unwrap());
+        assert!(
+            !storage
+                .expire(b"missing".to_vec(), Duration::from_millis(50))
+                .unwrap()
+        );
+        assert!(!storage.persist(b"missing".to_vec()).unwrap());
+
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(storage.get(b"short".to_vec()).await.unwrap(), None);
+        assert!(!storage.exists(b"short".to_vec()).unwrap());
+        assert_eq!(storage.count().unwrap(), 1);
+        assert_eq!(storage.list().unwrap(), (vec![b"kept".to_vec()], 1));
+        assert!(!storage.persist(b"short".to_vec()).unwrap());
+
+        assert_eq!(storage.purge_expired().await.unwrap(), 1);
+        assert_eq!(
+            storage.get(b"kept".to_vec()).await.unwrap(),
+            Some(b"v".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expiration_events() {
+        let (options, clock) = mock_clock(StorageOptions::default());
+        let mut storage = Storage::volatile(options);
+        let mut expirations = storage.subscribe_expirations();
+        storage
+            .set(b"session".to_vec(), b"user-1".to_vec())
+            .await
+            .unwrap();
+        storage.set(b"kept".to_vec(), b"v".to_vec()).await.unwrap();
+        storage
+            .expire(b"session".to_vec(), Duration::from_millis(20))
+            .unwrap();
+
+        clock.advance(Duration::from_millis(20));
+        assert!(expirations.try_recv().is_err());
+        assert_eq!(storage.purge_expired().await.unwrap(), 1);
+        assert_eq!(
+            expirations.try_recv().unwrap(),
+            Expiration {
+                key: b"session".to_vec(),
+                value: b"user-1".to_vec()
+            }
+        );
+        assert!(expirations.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter_prefix() {
+        let (options, clock) = mock_clock(StorageOptions {
+            dead_letter_prefix: Some(b"expired/".to_vec()),
+            ..Default::default()
+        });
+        let mut storage = Storage::volatile(options);
+        storage
+            .set(b"session".to_vec(), b"user-1".to_vec())
+            .await
+            .unwrap();
+        storage
+            .expire(b"session".to_vec(), Duration::from_millis(20))
+            .unwrap();
+
+        clock.advance(Duration::from_millis(20));
+        assert_eq!(storage.purge_expired().await.unwrap(), 1);
+        assert_eq!(storage.get(b"session".to_vec()).await.unwrap(), None);
+        assert_eq!(
+            storage.get(b"expired/session".to_vec()).await.unwrap(),
+            Some(b"user-1".to_vec())
+        );
+        assert_eq!(
+            storage
+                .stat(b"expired/session".to_vec())
+                .unwrap()
+                .map(|stat| (stat.size, stat.ttl_ms)),
+            Some((6, None))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_range() {
+        let path = temp_path("get-range");
+        let options = StorageOptions {
+            compression_threshold: Some(64),
+            ..Default::default()
+        };
+        let mut storage = Storage::new(&path, aes(), options).unwrap();
+        let large = b"0123456789".repeat(20);
+        storage
+            .set(b"small".to_vec(), b"hello world".to_vec())
+            .await
+            .unwrap();
+        storage.set(b"large".to_vec(), large.clone()).await.unwrap();
+        storage.clear_cache().await.unwrap();
+
+        let range = |key: &[u8], start, end| storage.get_range(key.to_vec(), start, end);
+        assert_eq!(
+            range(b"small", 0, 5).await.unwrap(),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(
+            range(b"small", 6, 100).await.unwrap(),
+            Some(b"world".to_vec())
+        );
+        assert_eq!(range(b"small", 50, 100).await.unwrap(), Some(Vec::new()));
+        assert_eq!(range(b"small", 3, 3).await.unwrap(), Some(Vec::new()));
+        assert_eq!(
+            range(b"large", 195, 205).await.unwrap(),
+            Some(large[195..].to_vec())
+        );
+        assert_eq!(range(b"missing", 0, 1).await.unwrap(), None);
+        assert!(matches!(
+            range(b"small", 5, 1).await,
+            Err(StorageError::InvalidRange { start: 5, end: 1 })
+        ));
+
+        storage.get(b"small".to_vec()).await.unwrap();
+        assert_eq!(
+            range(b"small", 0, 5).await.unwrap(),
+            Some(b"hello".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_clear_cache() {
+        let path = temp_path("clear-cache");
+        let mut storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        storage.set(b"a".to_vec(), b"1".to_vec()).await.unwrap();
+        storage.set(b"b".to_vec(), b"2".to_vec()).await.unwrap();
+        storage.get(b"a".to_vec()).await.unwrap();
+        assert_eq!(storage.stats().unwrap().cache_hits, 1);
+
+        assert_eq!(storage.clear_cache().await.unwrap(), 2);
+        assert_eq!(storage.count().unwrap(), 2);
+        assert_eq!(
+            storage.get(b"a".to_vec()).await.unwrap(),
+            Some(b"1".to_vec())
+        );
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.cache_hits, 1);
+        assert_eq!(stats.cache_misses, 1);
+
+        storage.get(b"a".to_vec()).await.unwrap();
+        assert_eq!(storage.stats().unwrap().cache_hits, 2);
+    }
+
+    #[tokio::test]
+    async fn test_get_uncached_skips_cache() {
+        let path = temp_path("get-uncached");
+        let mut storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        storage.set(b"a".to_vec(), b"1".to_vec()).await.unwrap();
+        storage.clear_cache().await.unwrap();
+
+        assert_eq!(
+            storage.get_uncached(b"a".to_vec()).unwrap(),
+            Some(b"1".to_vec())
+        );
+        assert_eq!(storage.get_uncached(b"missing".to_vec()).unwrap(), None);
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.cache_hits, 0);
+        assert_eq!(stats.cache_misses, 0);
+
+        storage.get(b"a".to_vec()).await.unwrap();
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.cache_hits, 0);
+        assert_eq!(stats.cache_misses, 1);
+    }
+
+    #[tokio::test]
+    async fn test_stat() {
+        let path = temp_path("stat");
+        let options = StorageOptions {
+            compression_threshold: Some(64),
+            ..Default::default()
+        };
+        let mut storage = Storage::new(&path, aes(), options).unwrap();
+        storage.set(b"small".to_vec(), vec![1; 10]).await.unwrap();
+        storage
+            .set(b"large".to_vec(), b"compressible".repeat(100))
+            .await
+            .unwrap();
+        storage
+            .expire(b"small".to_vec(), Duration::from_secs(60))
+            .unwrap();
+
+        let stat = storage.stat(b"small".to_vec()).unwrap().unwrap();
+        assert_eq!(stat.size, 10);
+        assert!(stat.ttl_ms.is_some_and(|ttl| ttl > 0 && ttl <= 60_000));
+        let stat = storage.stat(b"large".to_vec()).unwrap().unwrap();
+        assert_eq!((stat.size, stat.ttl_ms), (1200, None));
+        assert_eq!(storage.stat(b"missing".to_vec()).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_stat_access_times() {
+        let (options, clock) = mock_clock(StorageOptions::default());
+        let mut storage = Storage::volatile(options);
+        let times = |storage: &Storage| {
+            let stat = storage.stat(b"key".to_vec()).unwrap().unwrap();
+            (stat.last_access_ms, stat.last_modified_ms)
+        };
+        clock.advance(Duration::from_millis(1000));
+        storage.set(b"key".to_vec(), b"v1".to_vec()).await.unwrap();
+        assert_eq!(times(&storage), (None, Some(1000)));
+
+        clock.advance(Duration::from_millis(500));
+        storage.get(b"key".to_vec()).await.unwrap();
+        assert_eq!(times(&storage), (Some(1500), Some(1000)));
+
+        clock.advance(Duration::from_millis(500));
+        storage.get_uncached(b"key".to_vec()).unwrap();
+        assert_eq!(times(&storage), (Some(1500), Some(1000)));
+        storage.get_range(b"key".to_vec(), 0, 1).await.unwrap();
+        assert_eq!(times(&storage), (Some(2000), Some(1000)));
+
+        clock.advance(Duration::from_millis(500));
+        storage.set(b"key".to_vec(), b"v2".to_vec()).await.unwrap();
+        assert_eq!(times(&storage), (Some(2000), Some(2500)));
+
+        storage.delete(b"key".to_vec()).await.unwrap();
+        storage.set(b"key".to_vec(), b"v3".to_vec()).await.unwrap();
+        assert_eq!(times(&storage), (None, Some(2500)));
+    }
+
+    #[tokio::test]
+    async fn test_scan_resumes_after_restart() {
+        let path = temp_path("scan-resume");
+        let mut storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        let mut expected = Vec::new();
+        for i in (0..25).rev() {
+            let key = format!("item:{:02}", i).into_bytes();
+            storage.set(key.clone(), vec![i]).await.unwrap();
+            expected.insert(0, (key, vec![i]));
+        }
+        storage.set(b"other".to_vec(), b"v".to_vec()).await.unwrap();
+
+        let (mut scanned, cursor) = storage.scan(b"item:", None, 10).unwrap();
+        assert_eq!(scanned, expected[..10]);
+        storage.sync().await.unwrap();
+        drop(storage);
+
+        let storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        let mut cursor = cursor;
+        while let Some(after) = cursor {
+            let (entries, next) = storage.scan(b"item:", Some(&after), 10).unwrap();
+            scanned.extend(entries);
+            cursor = next;
+        }
+        assert_eq!(scanned, expected);
+    }
+
+    #[tokio::test]
+    async fn test_scan_under_zero_cap() {
+        let mut storage = Storage::volatile(StorageOptions {
+            max_list_keys: Some(0),
+            ..Default::default()
+        });
+        for key in [b"item:1", b"item:2"] {
+            storage.set(key.to_vec(), b"v".to_vec()).await.unwrap();
+        }
+
+        let (entries, cursor) = storage.scan(b"item:", None, 10).unwrap();
+        assert_eq!(entries, [(b"item:1".to_vec(), b"v".to_vec())]);
+        assert_eq!(cursor, Some(b"item:1".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_expirations_survive_reload() {
+        let path = temp_path("expire-reload");
+        let mut storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        storage.set(b"key".to_vec(), b"v".to_vec()).await.unwrap();
+        storage
+            .expire(b"key".to_vec(), Duration::from_secs(60))
+            .unwrap();
+        storage.sync().await.unwrap();
+        drop(storage);
+
+        let storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        assert!(storage.expirations.contains_key(b"key".as_slice()));
+    }
+
+    #[tokio::test]
+    async fn test_slow_flush_does_not_block_reads() {
+        let path = temp_path("slow-flush");
+        let mut storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        storage.set(b"key".to_vec(), b"v".to_vec()).await.unwrap();
+        storage.set(b"other".to_vec(), b"v".to_vec()).await.unwrap();
+        let file = Arc::clone(&storage.dump.as_ref().unwrap().file);
+        let executor = crate::executor::Executor::new(storage).await;
+
+        let (locked_tx, locked_rx) = tokio::sync::oneshot::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        std::thread::spawn(move || {
+            let _guard = file.lock().unwrap();
+            locked_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        locked_rx.await.unwrap();
+
+        let delete = tokio::spawn({
+            let executor = Arc::clone(&executor);
+            async move { executor.delete(b"other".to_vec()).await }
+        });
+        let value =
+            tokio::time::timeout(Duration::from_secs(1), executor.get(b"key".to_vec(), false))
+                .await
+                .expect("read blocked by flush")
+                .unwrap();
+        assert_eq!(value, Some(b"v".to_vec()));
+        assert!(!delete.is_finished());
+
+        release_tx.send(()).unwrap();
+        assert_eq!(delete.await.unwrap().unwrap(), Some(b"v".to_vec()));
+        let storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        assert!(!storage.data.contains_key(b"other".as_slice()));
+    }
+
+    /// Gets halfway through writing the dump before running out of space.
+    fn write_to_full_disk(path: &Path, content: &[u8]) -> std::io::Result<()> {
+        replace_file_with(path, |file| {
+            file.write_all(&content[..content.len() / 2])?;
+            file.flush()?;
+            Err(std::io::ErrorKind::StorageFull.into())
+        })
+    }
+
+    #[tokio::test]
+    async fn test_failed_write_keeps_previous_dump() {
+        let path = temp_path("failed-write");
+        let options = StorageOptions {
+            sync_retries: Some(0),
+            ..Default::default()
+        };
+        let mut storage = Storage::new(&path, aes(), options).unwrap();
+        storage.set(b"a".to_vec(), b"v".to_vec()).await.unwrap();
+        storage.sync().await.unwrap();
+        let before = std::fs::read(&path).unwrap();
+
+        storage.dump.as_ref().unwrap().file.lock().unwrap().write = write_to_full_disk;
+        storage.set(b"b".to_vec(), b"v".to_vec()).await.unwrap();
+        assert!(matches!(storage.sync().await, Err(StorageError::DiskFull)));
+        drop(storage);
+
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let leftovers = std::fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|file| file.starts_with(name) && file.ends_with(".tmp"));
+        assert_eq!(leftovers.count(), 0);
+        let storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        assert!(storage.data.contains_key(b"a".as_slice()));
+        assert!(!storage.data.contains_key(b"b".as_slice()));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sync_keeps_dump_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        let path = temp_path("permissions");
+        let mut storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        storage.set(b"a".to_vec(), b"v".to_vec()).await.unwrap();
+        storage.sync().await.unwrap();
+        assert_eq!(mode(&path), 0o640);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sync_replaces_symlink_target() {
+        let target = temp_path("symlink-target");
+        let link = temp_path("symlink");
+        Storage::new(&target, aes(), StorageOptions::default()).unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let mut storage = Storage::new(&link, aes(), StorageOptions::default()).unwrap();
+        storage.set(b"a".to_vec(), b"v".to_vec()).await.unwrap();
+        storage.sync().await.unwrap();
+        drop(storage);
+
+        assert!(std::fs::symlink_metadata(&link).unwrap().is_symlink());
+        let storage = Storage::new(&target, aes(), StorageOptions::default()).unwrap();
+        assert!(storage.data.contains_key(b"a".as_slice()));
+    }
+
+    #[tokio::test]
+    async fn test_full_disk_rejects_writes_until_synced() {
+        use ckeylock_core::{Request, request::RequestWrapper, response::ErrorCode};
+
+        let path = temp_path("disk-full");
+        let options = StorageOptions {
+            sync_retries: Some(0),
+            ..Default::default()
+        };
+        let mut storage = Storage::new(&path, aes(), options).unwrap();
+        storage.set(b"a".to_vec(), b"v".to_vec()).await.unwrap();
+        storage.set(b"b".to_vec(), b"v".to_vec()).await.unwrap();
+        let file = Arc::clone(&storage.dump.as_ref().unwrap().file);
+        let disk = std::mem::replace(&mut file.lock().unwrap().write, write_to_full_disk);
+        let executor = crate::executor::Executor::new(storage).await;
+
+        let err = executor
+            .execute(RequestWrapper::new(Request::Delete { key: b"b".to_vec() }))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            crate::Error::StorageError(StorageError::DiskFull)
+        ));
+        assert_eq!(err.code(), ErrorCode::Maintenance);
+        assert!(executor.stats().await.unwrap().disk_full);
+        let set = || {
+            RequestWrapper::new(Request::Set {
+                key: b"c".to_vec(),
+                value: b"v".to_vec(),
+            })
+        };
+        let err = executor.execute(set()).await.unwrap_err();
+        assert!(matches!(
+            err,
+            crate::Error::StorageError(StorageError::DiskFull)
+        ));
+        assert_eq!(
+            executor.get(b"a".to_vec(), false).await.unwrap(),
+            Some(b"v".to_vec())
+        );
+
+        file.lock().unwrap().write = disk;
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while executor.stats().await.unwrap().disk_full {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "failed sync was not retried"
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        executor.execute(set()).await.unwrap();
+        let storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        assert!(storage.data.contains_key(b"a".as_slice()));
+        assert!(!storage.data.contains_key(b"b".as_slice()));
+    }
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used_over_memory_limit() {
+        let path = temp_path("maxmemory");
+        let options = StorageOptions {
+            max_memory_bytes: Some(3 * (2 + 1 + 10)),
+            ..Default::default()
+        };
+        let mut storage = Storage::new(&path, aes(), options).unwrap();
+        for key in ["k1", "k2", "k3"] {
+            storage
+                .set(key.as_bytes().to_vec(), vec![0; 10])
+                .await
+                .unwrap();
+        }
+        storage.get(b"k1".to_vec()).await.unwrap();
+        storage.set(b"k4".to_vec(), vec![0; 10]).await.unwrap();
+
+        let (mut keys, _) = storage.list().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec![b"k1".to_vec(), b"k3".to_vec(), b"k4".to_vec()]);
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.evicted_keys, 1);
+        assert_eq!(stats.memory_bytes, 3 * 13);
+
+        assert!(matches!(
+            storage.set(b"big".to_vec(), vec![0; 64]).await,
+            Err(StorageError::ValueTooLarge { .. })
+        ));
+        assert_eq!(storage.count().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_random_eviction_stays_under_limit() {
+        let path = temp_path("maxmemory-random");
+        let options = StorageOptions {
+            max_memory_bytes: Some(100),
+            eviction_policy: Some(EvictionPolicy::Random),
+            ..Default::default()
+        };
+        let mut storage = Storage::new(&path, aes(), options).unwrap();
+        for i in 0..20u8 {
+            storage.set(vec![i], vec![0; 9]).await.unwrap();
+        }
+        let stats = storage.stats().unwrap();
+        assert!(stats.memory_bytes <= 100);
+        assert_eq!(stats.keys, 9);
+        assert_eq!(stats.evicted_keys, 11);
+        assert_eq!(storage.get(vec![19]).await.unwrap(), Some(vec![0; 9]));
+    }
+
+    #[tokio::test]
+    async fn test_cache_write_modes() {
+        for (mode, cached) in [(CacheWrite::Through, true), (CacheWrite::Around, false)] {
+            let path = temp_path(&format!("cache-write-{:?}", mode));
+            let options = StorageOptions {
+                cache_write: mode,
+                ..Default::default()
+            };
+            let mut storage = Storage::new(&path, aes(), options).unwrap();
+            storage.set(b"key".to_vec(), b"old".to_vec()).await.unwrap();
+            assert_eq!(storage.cache.lock().await.contains(b"key"), cached);
+
+            storage.get(b"key".to_vec()).await.unwrap();
+            storage.set(b"key".to_vec(), b"new".to_vec()).await.unwrap();
+            assert_eq!(storage.cache.lock().await.contains(b"key"), cached);
+            assert_eq!(
+                storage.get(b"key".to_vec()).await.unwrap(),
+                Some(b"new".to_vec())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compact_reclaims_space() {
+        let path = temp_path("compact");
+        let (options, clock) = mock_clock(StorageOptions {
+            lazy_clear: true,
+            ..Default::default()
+        });
+        let mut storage = Storage::new(&path, aes(), options.clone()).unwrap();
+        storage.set(b"kept".to_vec(), b"v".to_vec()).await.unwrap();
+        for i in 0..100 {
+            let key = format!("expiring-{}", i).into_bytes();
+            storage.set(key.clone(), vec![7; 512]).await.unwrap();
+            storage.expire(key, Duration::from_millis(20)).unwrap();
+        }
+        storage.sync().await.unwrap();
+        clock.advance(Duration::from_millis(20));
+
+        let (before, after) = storage.compact().await.unwrap();
+        assert!(before > 50_000, "dump held {} bytes", before);
+        assert!(after < 1_000, "dump held {} bytes", after);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), after);
+        let reloaded = Storage::new(&path, aes(), options.clone()).unwrap();
+        assert_eq!(reloaded.count().unwrap(), 1);
+
+        for i in 0..100 {
+            let key = format!("cleared-{}", i).into_bytes();
+            storage.set(key, vec![7; 512]).await.unwrap();
+        }
+        storage.sync().await.unwrap();
+        // A lazy clear leaves the old dump on disk until something writes.
+        storage.clear().await.unwrap();
+        let (before, after) = storage.compact().await.unwrap();
+        assert!(before > 50_000, "dump held {} bytes", before);
+        assert!(after < 1_000, "dump held {} bytes", after);
+        let reloaded = Storage::new(&path, aes(), options).unwrap();
+        assert_eq!(reloaded.count().unwrap(), 0);
+
+        let mut volatile = Storage::volatile(StorageOptions::default());
+        assert_eq!(volatile.compact().await.unwrap(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_lazy_clear() {
+        for lazy_clear in [false, true] {
+            let path = temp_path(&format!("lazy-clear-{}", lazy_clear));
+            let options = StorageOptions {
+                lazy_clear,
+                ..Default::default()
+            };
+            let mut storage = Storage::new(&path, aes(), options.clone()).unwrap();
+            storage.set(b"old".to_vec(), b"1".to_vec()).await.unwrap();
+            storage.sync().await.unwrap();
+            let before = storage.generation;
+
+            storage.clear().await.unwrap();
+            storage.flush().await.unwrap();
+            for key in [b"a", b"b", b"c"] {
+                storage.set(key.to_vec(), b"2".to_vec()).await.unwrap();
+            }
+            storage.sync().await.unwrap();
+            let writes = storage.generation - before;
+            assert_eq!(writes, if lazy_clear { 1 } else { 2 });
+
+            let reloaded = Storage::new(&path, aes(), options).unwrap();
+            let (mut keys, _) = reloaded.list().unwrap();
+            keys.sort();
+            assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_require_utf8_values() {
+        let invalid = vec![b'o', b'k', 0xff, 0xfe];
+        let path = temp_path("utf8-required");
+        let options = StorageOptions {
+            require_utf8_values: true,
+            ..Default::default()
+        };
+        let mut storage = Storage::new(&path, aes(), options).unwrap();
+        storage
+            .set(b"text".to_vec(), "héllo".as_bytes().to_vec())
+            .await
+            .unwrap();
+        assert!(matches!(
+            storage.set(b"binary".to_vec(), invalid.clone()).await,
+            Err(StorageError::InvalidUtf8 { valid_up_to: 2 })
+        ));
+        assert!(matches!(
+            storage
+                .replace_all(vec![(b"binary".to_vec(), invalid.clone())])
+                .await,
+            Err(StorageError::InvalidUtf8 { .. })
+        ));
+        assert_eq!(storage.count().unwrap(), 1);
+
+        let path = temp_path("utf8-optional");
+        let mut storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        storage.set(b"binary".to_vec(), invalid).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_conditional_writes() {
+        let path = temp_path("conditional");
+        let (options, clock) = mock_clock(StorageOptions::default());
+        let mut storage = Storage::new(&path, aes(), options).unwrap();
+        let ttl = Some(Duration::from_millis(50));
+        assert!(
+            storage
+                .set_if_absent(b"lock".to_vec(), b"a".to_vec(), ttl)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !storage
+                .set_if_absent(b"lock".to_vec(), b"b".to_vec(), ttl)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !storage
+                .expire_if_equals(b"lock".to_vec(), b"b".to_vec(), Duration::from_secs(60))
+                .unwrap()
+        );
+        assert!(
+            !storage
+                .delete_if_equals(b"lock".to_vec(), b"b".to_vec())
+                .await
+                .unwrap()
+        );
+
+        clock.advance(Duration::from_millis(50));
+        assert!(
+            storage
+                .set_if_absent(b"lock".to_vec(), b"b".to_vec(), None)
+                .await
+                .unwrap()
+        );
+        assert!(
+            storage
+                .expire_if_equals(b"lock".to_vec(), b"b".to_vec(), Duration::from_secs(60))
+                .unwrap()
+        );
+        assert!(
+            storage
+                .delete_if_equals(b"lock".to_vec(), b"b".to_vec())
+                .await
+                .unwrap()
+        );
+        assert!(!storage.exists(b"lock".to_vec()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_count_prefix() {
+        let (options, clock) = mock_clock(StorageOptions::default());
+        let mut storage = Storage::volatile(options);
+        for key in [
+            "user:1",
+            "user:2",
+            "user:1:session:a",
+            "user:1:session:b",
+            "users",
+            "group:1",
+        ] {
+            storage.set(key.into(), b"v".to_vec()).await.unwrap();
+        }
+        storage
+            .expire(b"user:2".to_vec(), Duration::from_millis(20))
+            .unwrap();
+        clock.advance(Duration::from_millis(20));
+
+        assert_eq!(storage.count_prefix(b"user").unwrap(), 4);
+        assert_eq!(storage.count_prefix(b"user:").unwrap(), 3);
+        assert_eq!(storage.count_prefix(b"user:1").unwrap(), 3);
+        assert_eq!(storage.count_prefix(b"user:1:session:").unwrap(), 2);
+        assert_eq!(storage.count_prefix(b"user:1:session:c").unwrap(), 0);
+        assert_eq!(storage.count_prefix(b"").unwrap(), storage.count().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_batch_exists_count() {
+        let (options, clock) = mock_clock(StorageOptions::default());
+        let mut storage = Storage::volatile(options);
+        for key in ["a", "b", "c"] {
+            storage.set(key.into(), b"v".to_vec()).await.unwrap();
+        }
+        storage
+            .expire(b"c".to_vec(), Duration::from_millis(20))
+            .unwrap();
+        clock.advance(Duration::from_millis(20));
+
+        let keys: Vec<Vec<u8>> = ["a", "b", "c", "d", "a"].map(Vec::from).to_vec();
+        assert_eq!(storage.batch_exists_count(&keys).unwrap(), 3);
+        assert_eq!(storage.batch_exists_count(&[]).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_batch_set_ex() {
+        let path = temp_path("batch-set-ex");
+        let (options, clock) = mock_clock(StorageOptions::default());
+        let mut storage = Storage::new(&path, aes(), options).unwrap();
+        storage
+            .set(b"persistent".to_vec(), b"old".to_vec())
+            .await
+            .unwrap();
+        storage
+            .expire(b"persistent".to_vec(), Duration::from_millis(10))
+            .unwrap();
+
+        let entries = vec![
+            (
+                b"short".to_vec(),
+                b"1".to_vec(),
+                Some(Duration::from_millis(10)),
+            ),
+            (
+                b"long".to_vec(),
+                b"2".to_vec(),
+                Some(Duration::from_millis(100)),
+            ),
+            (b"persistent".to_vec(), b"3".to_vec(), None),
+        ];
+        assert_eq!(storage.batch_set_ex(entries).await.unwrap(), 3);
+        assert!(storage.take_pending_sync().is_some());
+        assert_eq!(
+            storage.stat(b"long".to_vec()).unwrap().unwrap().ttl_ms,
+            Some(100)
+        );
+        assert_eq!(
+            storage
+                .stat(b"persistent".to_vec())
+                .unwrap()
+                .unwrap()
+                .ttl_ms,
+            None
+        );
+
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(storage.purge_expired().await.unwrap(), 1);
+        assert!(!storage.exists(b"short".to_vec()).unwrap());
+        assert!(storage.exists(b"long".to_vec()).unwrap());
+
+        clock.advance(Duration::from_millis(90));
+        assert_eq!(storage.purge_expired().await.unwrap(), 1);
+        assert_eq!(
+            storage.get(b"persistent".to_vec()).await.unwrap(),
+            Some(b"3".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_if() {
+        let path = temp_path("set-if");
+        let (options, clock) = mock_clock(StorageOptions::default());
+        let mut storage = Storage::new(&path, aes(), options).unwrap();
+        let key = || b"counter".to_vec();
+        let value = |storage: &Storage| storage.get_uncached(key()).unwrap();
+
+        let not_exists = Condition::NotExists;
+        assert!(
+            storage
+                .set_if(key(), b"1".to_vec(), &not_exists)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !storage
+                .set_if(key(), b"2".to_vec(), &not_exists)
+                .await
+                .unwrap()
+        );
+        assert_eq!(value(&storage), Some(b"1".to_vec()));
+
+        let equals = Condition::Equals(b"1".to_vec());
+        assert!(
+            storage
+                .set_if(key(), b"22".to_vec(), &equals)
+                .await
+                .unwrap()
+        );
+        assert!(!storage.set_if(key(), b"3".to_vec(), &equals).await.unwrap());
+        assert_eq!(value(&storage), Some(b"22".to_vec()));
+
+        let shorter = Condition::LengthLessThan(3);
+        assert!(
+            storage
+                .set_if(key(), b"333".to_vec(), &shorter)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !storage
+                .set_if(key(), b"4".to_vec(), &shorter)
+                .await
+                .unwrap()
+        );
+        assert_eq!(value(&storage), Some(b"333".to_vec()));
+
+        // Only NotExists holds for a missing key, and a key whose TTL ran
+        // out counts as missing.
+        storage.expire(key(), Duration::from_millis(20)).unwrap();
+        clock.advance(Duration::from_millis(20));
+        let missing = b"missing".to_vec();
+        assert!(
+            !storage
+                .set_if(
+                    missing.clone(),
+                    b"x".to_vec(),
+                    &Condition::Equals(Vec::new())
+                )
+                .await
+                .unwrap()
+        );
+        assert!(
+            !storage
+                .set_if(missing, b"x".to_vec(), &Condition::LengthLessThan(10))
+                .await
+                .unwrap()
+        );
+        assert!(
+            storage
+                .set_if(key(), b"5".to_vec(), &not_exists)
+                .await
+                .unwrap()
+        );
+        assert_eq!(value(&storage), Some(b"5".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_max_keys() {
+        let path = temp_path("maxkeys");
+        let options = StorageOptions {
+            max_keys: Some(3),
+            ..Default::default()
+        };
+        let mut storage = Storage::new(&path, aes(), options).unwrap();
+        for i in 0..3u8 {
+            storage.set(vec![i], vec![i]).await.unwrap();
+        }
+        assert!(matches!(
+            storage.set(vec![3], vec![3]).await,
+            Err(StorageError::CapacityExceeded { max: 3 })
+        ));
+        storage.set(vec![0], b"overwrite".to_vec()).await.unwrap();
+        assert_eq!(storage.count().unwrap(), 3);
+
+        storage.delete(vec![1]).await.unwrap();
+        storage.set(vec![3], vec![3]).await.unwrap();
+        assert_eq!(storage.count().unwrap(), 3);
+        assert!(matches!(
+            storage
+                .replace_all((0..4u8).map(|i| (vec![i], vec![i])).collect())
+                .await,
+            Err(StorageError::CapacityExceeded { max: 3 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_max_keys_evicts_with_policy() {
+        let path = temp_path("maxkeys-evict");
+        let options = StorageOptions {
+            max_keys: Some(3),
+            eviction_policy: Some(EvictionPolicy::Lru),
+            ..Default::default()
+        };
+        let mut storage = Storage::new(&path, aes(), options).unwrap();
+        for i in 0..10u8 {
+            storage.set(vec![i], vec![i]).await.unwrap();
+        }
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.keys, 3);
+        assert_eq!(stats.evicted_keys, 7);
+        assert_eq!(storage.get(vec![9]).await.unwrap(), Some(vec![9]));
+    }
+
+    #[test]
+    fn test_dump_encoding_is_deterministic() {
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..100u8)
+            .map(|i| (vec![i, i.wrapping_mul(31)], vec![i; 8]))
+            .collect();
+        let forward: Data = entries.iter().cloned().collect();
+        let reverse: Data = entries.iter().rev().cloned().collect();
+        let expirations: Expirations = DashMap::new();
+
+        assert_eq!(
+            encode_dump(&forward, &expirations).unwrap(),
+            encode_dump(&reverse, &expirations).unwrap()
+        );
+        let (decoded, _) = decode_dump(&encode_dump(&forward, &expirations).unwrap()).unwrap();
+        assert_eq!(decoded.len(), entries.len());
+    }
+
+    #[test]
+    fn test_load_legacy_dump() {
+        let path = temp_path("legacy");
+        let legacy: DashMap<Vec<u8>, Vec<u8>> = DashMap::new();
+        legacy.insert(b"key".to_vec(), b"value".to_vec());
+        let content = bincode::serde::encode_to_vec(&legacy, bincode::config::standard()).unwrap();
+        std::fs::write(&path, aes().encrypt(&content, None, &[]).unwrap()).unwrap();
+
+        let storage = Storage::new(&path, aes(), StorageOptions::default()).unwrap();
+        let stored = storage.data.get(b"key".as_slice()).unwrap();
+        assert_eq!(compression::decode(&stored).unwrap(), b"value".to_vec());
+    }
 }
@@ -0,0 +1,21 @@
+//! Hooks that rewrite values on their way into and out of storage, for
+//! things like field-level encryption or validation without forking the
+//! server.
+
+use std::fmt::Debug;
+
+/// Both hooks default to passing the value through untouched. An error from
+/// either fails the request with a bad request error carrying its message.
+///
+/// Everything that reads a value back goes through `on_get`, including the
+/// comparisons of the conditional requests, so `on_get(on_set(v))` should
+/// give back `v` unless rewriting values is the point.
+pub trait Transform: Debug + Send + Sync {
+    fn on_set(&self, _key: &[u8], value: Vec<u8>) -> Result<Vec<u8>, String> {
+        Ok(value)
+    }
+
+    fn on_get(&self, _key: &[u8], value: Vec<u8>) -> Result<Vec<u8>, String> {
+        Ok(value)
+    }
+}
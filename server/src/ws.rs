@@ -1,195 +1,2015 @@
+use crate::handshake;
+use crate::sampling::LogSampler;
 use crate::{Error, executor::Executor};
+use ckeylock_core::ResponseData;
+use ckeylock_core::response::ErrorCode;
+use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio_tungstenite::accept_hdr_async;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 use tokio_tungstenite::tungstenite::{
     handshake::server::{ErrorResponse, Request, Response},
-    protocol::Message,
+    http::{HeaderValue, StatusCode},
+    protocol::{CloseFrame, Message, frame::coding::CloseCode},
 };
+use tokio_tungstenite::{WebSocketStream, accept_hdr_async_with_config};
 use tracing::{debug, error, info, warn};
 
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_MISSED_PONGS: u32 = 3;
+/// How often the remaining connections and requests are logged while the
+/// server drains.
+const DRAIN_LOG_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_UNPAIRED_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_UNPAIRED_BYTES: usize = 16 << 20;
+/// Optional protocol features advertised in every handshake.
+const FEATURES: &[&str] = &["binary_values", "streaming", "sequence_numbers"];
+
+/// How to answer frames the protocol doesn't use, such as binary ones that
+/// aren't value frames.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnsupportedMessage {
+    /// Reply with a `BadRequest` error carrying an empty request id.
+    #[default]
+    Error,
+    /// Close the connection with the "unsupported data" close code.
+    Close,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ServerOptions {
+    pub concurrent_limit: Option<usize>,
+    pub max_requests: Option<usize>,
+    pub log_sample_rate: Option<u64>,
+    pub signing_secret: Option<Vec<u8>>,
+    pub slow_threshold: Option<Duration>,
+    /// Connections that don't finish the WebSocket handshake in this time are
+    /// dropped. Defaults to 10 seconds.
+    pub handshake_timeout: Option<Duration>,
+    pub unsupported_message: UnsupportedMessage,
+    /// Sets `TCP_NODELAY` on accepted sockets so small responses go out
+    /// without waiting on Nagle's algorithm. Defaults to on.
+    pub no_delay: Option<bool>,
+    /// Client messages larger than this are rejected and the connection is
+    /// closed with [`ckeylock_core::close::MESSAGE_TOO_LARGE`]. Defaults to
+    /// 64 MiB.
+    pub max_message_size: Option<usize>,
+    /// How long in-flight requests get to finish on shutdown before the
+    /// remaining connections are closed. Defaults to 30 seconds.
+    pub shutdown_grace: Option<Duration>,
+    /// Ping connections that sent nothing for this long, to keep NAT mappings
+    /// open and notice clients that went away. Off by default.
+    pub ping_interval: Option<Duration>,
+    /// Connections that leave this many pings in a row unanswered are dropped.
+    /// Defaults to 3.
+    pub max_missed_pongs: Option<u32>,
+    /// Handshake requests whose headers run past this many bytes are answered
+    /// with `431 Request Header Fields Too Large`. Defaults to 16 KiB.
+    pub max_handshake_size: Option<usize>,
+    /// Requests and binary values still waiting for their other half after
+    /// this long are dropped, answering the request with an error. Defaults
+    /// to 10 seconds.
+    pub unpaired_timeout: Option<Duration>,
+    /// Bytes of requests and binary values a connection may hold while they
+    /// wait for their other half. Defaults to 16 MiB.
+    pub max_unpaired_bytes: Option<usize>,
+}
+
+/// What was left when a shutdown finished draining.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DrainReport {
+    pub connections: usize,
+    pub requests: usize,
+    /// Whether the grace period ran out and the remaining connections were
+    /// closed without waiting for their requests.
+    pub forced: bool,
+}
+
+/// Open connections and requests being handled, reported while draining.
+#[derive(Default)]
+struct Activity {
+    connections: AtomicUsize,
+    requests: AtomicUsize,
+}
+
+impl Activity {
+    fn report(&self, forced: bool) -> DrainReport {
+        DrainReport {
+            connections: self.connections.load(Ordering::SeqCst),
+            requests: self.requests.load(Ordering::SeqCst),
+            forced,
+        }
+    }
+}
+
+/// Counts one connection or request in [`Activity`] until dropped.
+struct Active<'a>(&'a AtomicUsize);
+
+impl<'a> Active<'a> {
+    fn begin(count: &'a AtomicUsize) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        Self(count)
+    }
+}
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The stores a server serves, each with its own executor and dump.
+/// Connections pick one with the `Ckeylock-Store` handshake header and get
+/// the default store without it.
+pub struct Stores {
+    default: Arc<Executor>,
+    named: HashMap<String, Arc<Executor>>,
+}
+
+impl Stores {
+    pub fn new(default: Arc<Executor>) -> Self {
+        Self {
+            default,
+            named: HashMap::new(),
+        }
+    }
+
+    pub fn with_store(mut self, name: impl Into<String>, executor: Arc<Executor>) -> Self {
+        self.named.insert(name.into(), executor);
+        self
+    }
+
+    fn get(&self, name: Option<&str>) -> Option<&Arc<Executor>> {
+        match name {
+            Some(name) => self.named.get(name),
+            None => Some(&self.default),
+        }
+    }
+}
+
+impl From<Arc<Executor>> for Stores {
+    fn from(executor: Arc<Executor>) -> Self {
+        Self::new(executor)
+    }
+}
+
 pub struct WsServer;
 
 impl WsServer {
-    pub async fn new(
+    /// Serves until `shutdown` completes, then drains: new connections and
+    /// requests are refused, and requests already in flight get
+    /// [`ServerOptions::shutdown_grace`] to finish before the connections
+    /// still open are closed.
+    pub async fn serve_until(
         bind: &str,
         password: Option<String>,
-        executor: Arc<Executor>,
-        concurrent_limit: Option<usize>,
-    ) -> Result<Self, WsServerError> {
+        stores: impl Into<Stores>,
+        options: ServerOptions,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<DrainReport, WsServerError> {
+        let stores = Arc::new(stores.into());
         info!("Starting WebSocket server on {}", bind);
-        let listener = TcpListener::bind(bind).await?;
-        while let Ok((stream, addr)) = listener.accept().await {
+        let listeners = bind_listeners(bind).await?;
+        let options = Arc::new(options);
+        let config_header = connection_config(&options);
+        let sampler = Arc::new(LogSampler::new(options.log_sample_rate));
+        let mut incoming =
+            futures_util::stream::select_all(listeners.into_iter().map(|listener| {
+                futures_util::stream::poll_fn(move |cx| listener.poll_accept(cx).map(Some))
+            }));
+        let activity = Arc::new(Activity::default());
+        let (draining_tx, draining) = tokio::sync::watch::channel(false);
+        let mut connections = tokio::task::JoinSet::new();
+        tokio::pin!(shutdown);
+        loop {
+            let (stream, addr) = tokio::select! {
+                _ = &mut shutdown => break,
+                accepted = incoming.next() => match accepted {
+                    Some(Ok(accepted)) => accepted,
+                    _ => break,
+                },
+            };
+            while connections.try_join_next().is_some() {}
             info!("New connection from {}", addr);
+            configure_stream(&stream, &options);
             let password = password.clone();
-            let executor = executor.clone();
-            tokio::spawn(async move {
-                let callback = |req: &Request,
-                                mut res: Response|
-                 -> Result<Response, ErrorResponse> {
-                    debug!("Handling WebSocket handshake request");
-                    if let Some(header_value) = req.headers().get("Authorization") {
-                        let header_value = header_value.to_str().unwrap();
-                        if let Some(password) = &password {
-                            if header_value == password {
-                                debug!("Authorization successful");
-                                res.headers_mut()
-                                    .insert("Authorization", header_value.parse().unwrap());
+            let stores = Arc::clone(&stores);
+            let options = Arc::clone(&options);
+            let sampler = Arc::clone(&sampler);
+            let activity = Arc::clone(&activity);
+            let draining = draining.clone();
+            let config_header = config_header.clone();
+            connections.spawn(async move {
+                let mut selected = None;
+                #[allow(clippy::result_large_err)]
+                let callback =
+                    |req: &Request, mut res: Response| -> Result<Response, ErrorResponse> {
+                        debug!("Handling WebSocket handshake request");
+                        if let Some(header_value) = req.headers().get("Authorization") {
+                            let header_value = header_value.to_str().unwrap();
+                            if let Some(password) = &password {
+                                if header_value == password {
+                                    debug!("Authorization successful");
+                                    res.headers_mut()
+                                        .insert("Authorization", header_value.parse().unwrap());
+                                } else {
+                                    warn!("Authorization failed: invalid password");
+                                    return Err(unauthorized_response());
+                                }
                             } else {
-                                warn!("Authorization failed: invalid password");
-                                res.headers_mut()
-                                    .insert("WWW-Authenticate", "Basic".parse().unwrap());
-                                res.headers_mut()
-                                    .insert("401 Unauthorized", "Unauthorized".parse().unwrap());
-                                return Err(ErrorResponse::new(Some(
-                                    WsServerError::Unauthorized.to_string(),
-                                )));
+                                warn!("Authorization failed: password required but not provided");
+                                return Err(unauthorized_response());
                             }
                         } else {
-                            warn!("Authorization failed: password required but not provided");
-                            res.headers_mut()
-                                .insert("WWW-Authenticate", "Basic".parse().unwrap());
+                            if password.is_some() {
+                                warn!("Authorization failed: missing Authorization header");
+                                return Err(unauthorized_response());
+                            }
+                        }
+                        let store = match req.headers().get(ckeylock_core::STORE_HEADER) {
+                            Some(value) => match value.to_str() {
+                                Ok(name) => Some(name),
+                                Err(_) => return Err(unknown_store_response()),
+                            },
+                            None => None,
+                        };
+                        if let Some(config) = config_header {
                             res.headers_mut()
-                                .insert("401 Unauthorized", "Unauthorized".parse().unwrap());
-                            return Err(ErrorResponse::new(Some(
-                                WsServerError::Unauthorized.to_string(),
-                            )));
+                                .insert(ckeylock_core::CONFIG_HEADER, config);
                         }
-                    } else {
-                        if password.is_some() {
-                            warn!("Authorization failed: missing Authorization header");
-                            return Err(ErrorResponse::new(Some(
-                                WsServerError::Unauthorized.to_string(),
-                            )));
+                        match stores.get(store) {
+                            Some(executor) => selected = Some(executor.session()),
+                            None => {
+                                warn!("Handshake rejected: unknown store {:?}", store);
+                                return Err(unknown_store_response());
+                            }
                         }
-                    }
-                    debug!("WebSocket handshake successful");
-                    Ok(res)
+                        debug!("WebSocket handshake successful");
+                        Ok(res)
+                    };
+                let handshake_timeout = options
+                    .handshake_timeout
+                    .unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT);
+                let max_head = options
+                    .max_handshake_size
+                    .unwrap_or(handshake::DEFAULT_MAX_HEAD_SIZE);
+                let accept = async {
+                    let stream = handshake::read_head(stream, max_head).await?;
+                    accept_hdr_async_with_config(stream, callback, Some(ws_config(&options))).await
                 };
-                match accept_hdr_async(stream, callback).await {
-                    Ok(stream) => {
+                match tokio::time::timeout(handshake_timeout, accept).await {
+                    Err(_) => {
+                        warn!(
+                            "WebSocket handshake from {} timed out after {:?}",
+                            addr, handshake_timeout
+                        );
+                    }
+                    Ok(Ok(stream)) => {
+                        let Some(executor) = selected else {
+                            unreachable!("A successful handshake selects a store");
+                        };
                         info!("WebSocket connection established");
+                        let _open = Active::begin(&activity.connections);
                         let (write, read) = stream.split();
-                        let write = Arc::new(tokio::sync::Mutex::new(write));
-                        let executor = Arc::clone(&executor);
-
-                        read.for_each_concurrent(concurrent_limit, {
-                            let write = Arc::clone(&write);
-                            let executor = Arc::clone(&executor);
-                            move |msg| {
-                                let write = Arc::clone(&write);
-                                let executor = Arc::clone(&executor);
-                                async move {
-                                    let message = match msg {
-                                        Ok(m) => m,
-                                        Err(e) => {
-                                            error!("WebSocket error: {:?}", e);
-                                            return;
-                                        }
-                                    };
-                                    match message {
-                                        Message::Text(text) => {
-                                            debug!("Received text message.");
-                                            let request = match serde_json::from_str::<
-                                                ckeylock_core::RequestWrapper,
-                                            >(
-                                                &text
-                                            ) {
-                                                Ok(request) => request,
-                                                Err(e) => {
-                                                    error!("Failed to parse request: {:?}", e);
-                                                    let mut write = write.lock().await;
-                                                    if let Err(e) = write
-                                                        .send(Message::Text(e.to_string().into()))
-                                                        .await
-                                                    {
-                                                        error!(
-                                                            "Failed to send error response: {:?}",
-                                                            e
-                                                        );
-                                                    }
-                                                    return;
-                                                }
-                                            };
-                                            let response = executor.execute(request.clone()).await;
-                                            let mut write = write.lock().await;
-                                            match response {
-                                                Ok(response) => {
-                                                    debug!("Request executed successfully");
-                                                    if let Err(e) = write
-                                                        .send(response_into_message(response))
-                                                        .await
-                                                    {
-                                                        error!("Failed to send response: {:?}", e);
-                                                    }
-                                                }
-                                                Err(e) => {
-                                                    error!("Request execution failed: {:?}", e);
-                                                    if let Err(e) = write
-                                                        .send(error_into_message(e, request.id()))
-                                                        .await
-                                                    {
-                                                        error!(
-                                                            "Failed to send error response: {:?}",
-                                                            e
-                                                        );
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        Message::Ping(ping) => {
-                                            debug!("Received ping, sending pong");
-                                            let mut write = write.lock().await;
-                                            if let Err(e) = write.send(Message::Pong(ping)).await {
-                                                error!("Failed to send pong: {:?}", e);
-                                            }
-                                        }
-                                        Message::Close(close) => {
-                                            debug!("Received close message: {:?}", close);
-                                            let mut write = write.lock().await;
-                                            if let Err(e) = write.send(Message::Close(close)).await
-                                            {
-                                                error!("Failed to send close message: {:?}", e);
-                                            }
-                                        }
-                                        _ => {
-                                            debug!("Received unsupported message type");
-                                        }
-                                    }
-                                }
+                        let connection = Arc::new(Connection {
+                            write: tokio::sync::Mutex::new(write),
+                            executor,
+                            options: Arc::clone(&options),
+                            sampler,
+                            accepted: AtomicUsize::new(0),
+                            completed: AtomicUsize::new(0),
+                            bytes_received: AtomicU64::new(0),
+                            bytes_sent: AtomicU64::new(0),
+                            in_flight: Default::default(),
+                            unpaired: Default::default(),
+                            activity: Arc::clone(&activity),
+                            heard_from: AtomicBool::new(false),
+                            missed_pongs: AtomicU32::new(0),
+                        });
+                        // A frame over the size limit ends the connection;
+                        // what follows it on the wire can't be trusted.
+                        let mut rejected = false;
+                        let read = read.take_while(move |msg| {
+                            let keep = !rejected;
+                            rejected |= matches!(msg, Err(WsError::Capacity(_)));
+                            std::future::ready(keep)
+                        });
+                        // Once the server drains, no further requests are
+                        // read, and the connection closes as soon as the
+                        // ones it is handling are answered.
+                        let mut stop = draining.clone();
+                        let read = read.take_until(async move {
+                            let _ = stop.wait_for(|draining| *draining).await;
+                        });
+                        let alive = Arc::clone(&connection);
+                        let read = read.take_until(async move {
+                            match alive.options.ping_interval.filter(|i| !i.is_zero()) {
+                                Some(interval) => alive.keepalive(interval).await,
+                                None => std::future::pending().await,
                             }
-                        })
-                        .await;
+                        });
+                        let handled = read.for_each_concurrent(options.concurrent_limit, |msg| {
+                            let connection = Arc::clone(&connection);
+                            async move { connection.handle(msg).await }
+                        });
+                        tokio::select! {
+                            _ = handled => {}
+                            _ = connection.expire_unpaired() => {}
+                        }
+                        if *draining.borrow() {
+                            let close = Message::Close(Some(CloseFrame {
+                                code: CloseCode::Away,
+                                reason: "Server is shutting down".into(),
+                            }));
+                            connection.send(close, "close message").await;
+                        }
+                        info!(
+                            "Connection from {} closed after receiving {} and sending {} bytes",
+                            addr,
+                            connection.bytes_received.load(Ordering::Relaxed),
+                            connection.bytes_sent.load(Ordering::Relaxed)
+                        );
                     }
-                    Err(e) => {
+                    Ok(Err(e)) => {
                         error!("Error during WebSocket handshake: {:?}", e);
                     }
                 }
             });
         }
-        Ok(Self)
+        drop(incoming);
+
+        let grace = options.shutdown_grace.unwrap_or(DEFAULT_SHUTDOWN_GRACE);
+        let remaining = activity.report(false);
+        info!(
+            "Shutting down, draining {} connections with {} requests in flight for up to {:?}",
+            remaining.connections, remaining.requests, grace
+        );
+        let _ = draining_tx.send(true);
+        let deadline = tokio::time::sleep(grace);
+        tokio::pin!(deadline);
+        let mut progress = tokio::time::interval(DRAIN_LOG_INTERVAL);
+        progress.tick().await;
+        let forced = loop {
+            tokio::select! {
+                joined = connections.join_next() => {
+                    if joined.is_none() {
+                        break false;
+                    }
+                }
+                _ = progress.tick() => {
+                    let remaining = activity.report(false);
+                    info!(
+                        "Draining: {} connections with {} requests in flight remain",
+                        remaining.connections, remaining.requests
+                    );
+                }
+                _ = &mut deadline => break true,
+            }
+        };
+        let report = activity.report(forced);
+        if forced {
+            warn!(
+                "Shutdown grace period of {:?} ran out, closing {} connections with {} requests in flight",
+                grace, report.connections, report.requests
+            );
+            connections.shutdown().await;
+        } else {
+            info!("All connections drained");
+        }
+        Ok(report)
+    }
+}
+
+type WsSink = SplitSink<WebSocketStream<handshake::Rewound<TcpStream>>, Message>;
+
+struct Connection {
+    write: tokio::sync::Mutex<WsSink>,
+    executor: Arc<Executor>,
+    options: Arc<ServerOptions>,
+    sampler: Arc<LogSampler>,
+    accepted: AtomicUsize,
+    completed: AtomicUsize,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    /// Ids of requests still being handled, so a reused id can't get its
+    /// response matched to the wrong request.
+    in_flight: std::sync::Mutex<HashSet<Arc<[u8]>>>,
+    /// Requests whose value hasn't arrived in its binary frame yet, and
+    /// values that came before their request.
+    unpaired: std::sync::Mutex<UnpairedHalves>,
+    activity: Arc<Activity>,
+    /// Set by every frame the client sends, cleared by each keepalive tick.
+    heard_from: AtomicBool,
+    missed_pongs: AtomicU32,
+}
+
+/// One half of a request sent with a binary value, see
+/// [`ckeylock_core::binary`].
+enum Unpaired {
+    Request(ckeylock_core::RequestWrapper),
+    Value(Vec<u8>),
+}
+
+/// Halves waiting in [`Connection::unpaired`], by request id.
+#[derive(Default)]
+struct UnpairedHalves {
+    halves: HashMap<Vec<u8>, Waiting>,
+    bytes: usize,
+}
+
+struct Waiting {
+    half: Unpaired,
+    size: usize,
+    since: Instant,
+}
+
+impl UnpairedHalves {
+    fn remove(&mut self, id: &[u8]) -> Option<Waiting> {
+        let waiting = self.halves.remove(id)?;
+        self.bytes -= waiting.size;
+        Some(waiting)
+    }
+
+    fn insert(&mut self, id: Vec<u8>, waiting: Waiting) {
+        self.bytes += waiting.size;
+        self.halves.insert(id, waiting);
+    }
+
+    /// Removes the halves that have waited longer than `timeout`.
+    fn expire(&mut self, timeout: Duration) -> Vec<(Vec<u8>, Unpaired)> {
+        let expired: Vec<Vec<u8>> = self
+            .halves
+            .iter()
+            .filter(|(_, waiting)| waiting.since.elapsed() >= timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|id| {
+                let waiting = self.remove(&id)?;
+                Some((id, waiting.half))
+            })
+            .collect()
+    }
+}
+
+/// Removes a request id from [`Connection::in_flight`] once it is answered.
+struct InFlight<'a> {
+    ids: &'a std::sync::Mutex<HashSet<Arc<[u8]>>>,
+    id: Arc<[u8]>,
+}
+
+impl<'a> InFlight<'a> {
+    fn begin(ids: &'a std::sync::Mutex<HashSet<Arc<[u8]>>>, id: &[u8]) -> Option<Self> {
+        let mut set = ids.lock().unwrap();
+        if set.contains(id) {
+            return None;
+        }
+        let id: Arc<[u8]> = Arc::from(id);
+        set.insert(Arc::clone(&id));
+        Some(Self { ids, id })
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.ids.lock().unwrap().remove(&*self.id);
+    }
+}
+
+impl Connection {
+    async fn handle(&self, msg: Result<Message, WsError>) {
+        let message = match msg {
+            Ok(m) => m,
+            Err(WsError::Capacity(e)) => {
+                warn!("Rejected oversized frame: {}", e);
+                self.executor.traffic().record_rejected_frame();
+                let close = Message::Close(Some(CloseFrame {
+                    code: CloseCode::from(ckeylock_core::close::MESSAGE_TOO_LARGE),
+                    reason: e.to_string().into(),
+                }));
+                self.send(close, "close message").await;
+                return;
+            }
+            Err(e) => {
+                error!("WebSocket error: {:?}", e);
+                return;
+            }
+        };
+        self.heard_from.store(true, Ordering::Relaxed);
+        self.missed_pongs.store(0, Ordering::Relaxed);
+        let len = message.len() as u64;
+        self.bytes_received.fetch_add(len, Ordering::Relaxed);
+        self.executor.traffic().record_received(len);
+        match message {
+            Message::Text(text) => self.handle_text(&text).await,
+            Message::Ping(ping) => {
+                debug!("Received ping, sending pong");
+                self.send(Message::Pong(ping), "pong").await;
+            }
+            Message::Pong(_) => debug!("Received pong"),
+            Message::Close(close) => {
+                debug!("Received close message: {:?}", close);
+                self.send(Message::Close(close), "close message").await;
+            }
+            Message::Binary(frame) => match ckeylock_core::binary::decode_frame(&frame) {
+                Ok((id, value)) => {
+                    self.pair(id.to_vec(), Unpaired::Value(value.to_vec()), value.len())
+                        .await
+                }
+                Err(e) => {
+                    warn!("Received unsupported binary message: {}", e);
+                    self.reject_unsupported().await;
+                }
+            },
+            _ => {
+                debug!("Received unsupported message type");
+            }
+        }
+    }
+
+    /// Pings the client whenever a whole interval passes without a frame from
+    /// it, and returns once too many pings in a row went unanswered.
+    async fn keepalive(&self, interval: Duration) {
+        let max_missed = self
+            .options
+            .max_missed_pongs
+            .unwrap_or(DEFAULT_MAX_MISSED_PONGS);
+        let mut ticks = tokio::time::interval(interval);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            if self.heard_from.swap(false, Ordering::Relaxed) {
+                continue;
+            }
+            if self.missed_pongs.fetch_add(1, Ordering::Relaxed) >= max_missed {
+                warn!("Dropping connection after {} unanswered pings", max_missed);
+                return;
+            }
+            self.send(Message::Ping(Default::default()), "ping").await;
+        }
+    }
+
+    /// Drops requests and values that waited too long for their other half,
+    /// answering each dropped request with an error. Never returns.
+    async fn expire_unpaired(&self) {
+        let timeout = self
+            .options
+            .unpaired_timeout
+            .unwrap_or(DEFAULT_UNPAIRED_TIMEOUT);
+        let mut ticks = tokio::time::interval(timeout.max(Duration::from_millis(10)) / 2);
+        loop {
+            ticks.tick().await;
+            let expired = self.unpaired.lock().unwrap().expire(timeout);
+            for (id, half) in expired {
+                match half {
+                    Unpaired::Request(_) => {
+                        warn!("Dropping request whose binary value didn't arrive in time");
+                        let message = "The request's binary value didn't arrive in time";
+                        self.send(
+                            error_message(ErrorCode::BadRequest, message.to_string(), id),
+                            "error response",
+                        )
+                        .await;
+                    }
+                    Unpaired::Value(_) => {
+                        warn!("Dropping binary value whose request didn't arrive in time")
+                    }
+                }
+            }
+        }
+    }
+
+    async fn reject_unsupported(&self) {
+        let message = match self.options.unsupported_message {
+            UnsupportedMessage::Error => error_message(
+                ErrorCode::BadRequest,
+                "Unsupported message type, requests must be sent as text".to_string(),
+                Vec::new(),
+            ),
+            UnsupportedMessage::Close => Message::Close(Some(CloseFrame {
+                code: CloseCode::Unsupported,
+                reason: "Unsupported message type".into(),
+            })),
+        };
+        self.send(message, "unsupported message reply").await;
+    }
+
+    async fn handle_text(&self, text: &str) {
+        let sampled = self.sampler.sample();
+        if sampled {
+            debug!("Received text message.");
+        }
+        let request = match serde_json::from_str::<ckeylock_core::RequestWrapper>(text) {
+            Ok(request) => request,
+            Err(e) => {
+                error!("Failed to parse request: {:?}", e);
+                self.send(
+                    error_message(ErrorCode::BadRequest, e.to_string(), Vec::new()),
+                    "error response",
+                )
+                .await;
+                return;
+            }
+        };
+        if request.binary_value() {
+            self.pair(
+                request.id().to_vec(),
+                Unpaired::Request(request),
+                text.len(),
+            )
+            .await;
+            return;
+        }
+        self.handle_request(request, sampled).await;
+    }
+
+    /// Matches a request with the binary frame carrying its value, running it
+    /// once both have arrived, in whichever order. A half alone over the byte
+    /// limit still waits when nothing else does.
+    async fn pair(&self, id: Vec<u8>, half: Unpaired, size: usize) {
+        let max_bytes = self
+            .options
+            .max_unpaired_bytes
+            .unwrap_or(DEFAULT_MAX_UNPAIRED_BYTES);
+        let paired = {
+            let mut unpaired = self.unpaired.lock().unwrap();
+            match (unpaired.remove(&id), half) {
+                (
+                    Some(Waiting {
+                        half: Unpaired::Value(value),
+                        ..
+                    }),
+                    Unpaired::Request(mut request),
+                )
+                | (
+                    Some(Waiting {
+                        half: Unpaired::Request(mut request),
+                        ..
+                    }),
+                    Unpaired::Value(value),
+                ) => {
+                    request.put_value(value);
+                    Ok(Some(request))
+                }
+                (Some(other), _) => {
+                    unpaired.insert(id.clone(), other);
+                    Err("Duplicate request id, a frame with this id is already waiting")
+                }
+                (None, half)
+                    if unpaired.halves.is_empty() || unpaired.bytes + size <= max_bytes =>
+                {
+                    let since = Instant::now();
+                    unpaired.insert(id.clone(), Waiting { half, size, since });
+                    Ok(None)
+                }
+                (None, _) => Err("Too many requests are waiting for their binary value"),
+            }
+        };
+        match paired {
+            Ok(Some(request)) => self.handle_request(request, self.sampler.sample()).await,
+            Ok(None) => {}
+            Err(message) => {
+                warn!("Rejecting binary value frame: {}", message);
+                self.send(
+                    error_message(ErrorCode::BadRequest, message.to_string(), id),
+                    "error response",
+                )
+                .await;
+            }
+        }
+    }
+
+    async fn handle_request(&self, request: ckeylock_core::RequestWrapper, sampled: bool) {
+        let _active = Active::begin(&self.activity.requests);
+        if let Some(secret) = &self.options.signing_secret
+            && !request.verify(secret)
+        {
+            warn!("Rejecting request with invalid or missing signature");
+            self.send(
+                error_message(
+                    ErrorCode::Unauthorized,
+                    "Invalid or missing request signature".to_string(),
+                    request.id().to_vec(),
+                ),
+                "error response",
+            )
+            .await;
+            return;
+        }
+        let Some(in_flight) = InFlight::begin(&self.in_flight, request.id()) else {
+            warn!("Rejecting request reusing an in-flight id");
+            let duplicate = ckeylock_core::response::ErrorResponse {
+                message: "Duplicate request id, another request with this id is in flight"
+                    .to_string(),
+                code: ErrorCode::BadRequest,
+                reqid: request.id().to_vec(),
+                operation: request.req().name().to_string(),
+                retry_after_ms: None,
+            };
+            self.send(error_frame(duplicate), "error response").await;
+            return;
+        };
+        if let Some(limit) = self.options.max_requests
+            && self.accepted.fetch_add(1, Ordering::SeqCst) >= limit
+        {
+            debug!("Request limit reached, rejecting request");
+            self.send(
+                error_message(
+                    ErrorCode::RequestLimitReached,
+                    "Maximum requests per connection reached".to_string(),
+                    in_flight.id.to_vec(),
+                ),
+                "error response",
+            )
+            .await;
+            return;
+        }
+        // The executor takes the request, so keep what is needed afterwards.
+        let operation = request.req().name();
+        let (stream, seq, compress) = (request.stream(), request.seq(), request.compress());
+        let slow_key = self
+            .options
+            .slow_threshold
+            .and_then(|_| request.req().key().map(<[u8]>::to_vec));
+        let started = Instant::now();
+        let response = self.executor.execute(request).await;
+        if let Some(threshold) = self.options.slow_threshold {
+            log_slow_request(operation, slow_key.as_deref(), started.elapsed(), threshold);
+        }
+        let frames = response.map(|response| {
+            let frames = if stream {
+                stream_frames(response, STREAM_FRAME_KEYS)
+            } else {
+                vec![response]
+            };
+            frames
+                .into_iter()
+                .map(|frame| {
+                    let frame = frame.with_seq(seq);
+                    let frame = if compress {
+                        compress_response(frame)
+                    } else {
+                        frame
+                    };
+                    match &self.options.signing_secret {
+                        Some(secret) => sign_response(frame, secret),
+                        None => frame,
+                    }
+                })
+                .collect::<Vec<_>>()
+        });
+        let mut write = self.write.lock().await;
+        match frames {
+            Ok(frames) => {
+                if sampled {
+                    debug!("Request executed successfully");
+                }
+                for frame in frames {
+                    self.write_message(&mut write, response_into_message(frame), "response")
+                        .await;
+                }
+            }
+            Err(e) => {
+                error!("Request execution failed: {:?}", e);
+                self.write_message(
+                    &mut write,
+                    error_into_message(e, in_flight.id.to_vec(), operation),
+                    "error response",
+                )
+                .await;
+            }
+        }
+        if let Some(limit) = self.options.max_requests
+            && self.completed.fetch_add(1, Ordering::SeqCst) + 1 == limit
+        {
+            info!("Connection served {} requests, closing", limit);
+            self.write_message(&mut write, max_requests_close_message(), "close message")
+                .await;
+        }
     }
+
+    async fn send(&self, message: Message, what: &str) {
+        let mut write = self.write.lock().await;
+        self.write_message(&mut write, message, what).await;
+    }
+
+    async fn write_message(&self, write: &mut WsSink, message: Message, what: &str) {
+        let len = message.len() as u64;
+        if let Err(e) = write.send(message).await {
+            error!("Failed to send {}: {:?}", what, e);
+            return;
+        }
+        self.bytes_sent.fetch_add(len, Ordering::Relaxed);
+        self.executor.traffic().record_sent(len);
+    }
+}
+
+fn unknown_store_response() -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some("Unknown store".to_string()));
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    response
+}
+
+fn unauthorized_response() -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(WsServerError::Unauthorized.to_string()));
+    *response.status_mut() = StatusCode::UNAUTHORIZED;
+    response
+        .headers_mut()
+        .insert("WWW-Authenticate", HeaderValue::from_static("Basic"));
+    response
+}
+
+const COMPRESSION_MIN_SIZE: usize = 1024;
+
+fn compress_response(mut response: ckeylock_core::Response) -> ckeylock_core::Response {
+    if let Err(e) = response.compress(COMPRESSION_MIN_SIZE) {
+        error!("Failed to compress response: {:?}", e);
+    }
+    response
 }
 
+/// Keys per frame when a list is streamed.
+const STREAM_FRAME_KEYS: usize = 1000;
+
+/// Splits a list response into frames of at most `per_frame` keys, flagging
+/// all but the last with `more`. Other responses go out as a single frame.
+fn stream_frames(
+    response: ckeylock_core::Response,
+    per_frame: usize,
+) -> Vec<ckeylock_core::Response> {
+    let Some(ResponseData::ListResponse {
+        keys,
+        truncated,
+        total_estimate,
+    }) = response.data()
+    else {
+        return vec![response];
+    };
+    if keys.len() <= per_frame {
+        return vec![response];
+    }
+    let count = keys.len().div_ceil(per_frame);
+    keys.chunks(per_frame)
+        .enumerate()
+        .map(|(index, chunk)| {
+            ckeylock_core::Response::new(
+                Some(ResponseData::ListResponse {
+                    keys: chunk.to_vec(),
+                    truncated: *truncated,
+                    total_estimate: *total_estimate,
+                }),
+                response.message(),
+                response.reqid(),
+            )
+            .with_more(index + 1 < count)
+        })
+        .collect()
+}
+
+const SLOW_LOG_KEY_BYTES: usize = 16;
+
+fn log_slow_request(operation: &str, key: Option<&[u8]>, elapsed: Duration, threshold: Duration) {
+    if elapsed < threshold {
+        return;
+    }
+    let key = match key {
+        Some(key) if key.len() > SLOW_LOG_KEY_BYTES => {
+            format!("{}...", hex::encode(&key[..SLOW_LOG_KEY_BYTES]))
+        }
+        Some(key) => hex::encode(key),
+        None => "-".to_string(),
+    };
+    warn!("Slow request: {} key={} took {:?}", operation, key, elapsed);
+}
+
+fn sign_response(mut response: ckeylock_core::Response, secret: &[u8]) -> ckeylock_core::Response {
+    if let Err(e) = response.sign(secret) {
+        error!("Failed to sign response: {:?}", e);
+    }
+    response
+}
+
+const FALLBACK_ERROR_FRAME: &str =
+    r#"{"message":"Internal server error","code":"internal","reqid":[]}"#;
+
 fn response_into_message(res: ckeylock_core::Response) -> Message {
-    Message::Text(res.to_string().into())
+    let reqid = res.reqid();
+    text_or_fallback(res.to_json(), reqid)
+}
+fn max_requests_close_message() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::from(ckeylock_core::close::MAX_REQUESTS_REACHED),
+        reason: "Maximum requests per connection reached".into(),
+    }))
+}
+fn error_into_message(err: Error, reqid: Vec<u8>, operation: &str) -> Message {
+    error_frame(ckeylock_core::response::ErrorResponse {
+        message: err.to_string(),
+        code: err.code(),
+        reqid,
+        operation: operation.to_string(),
+        retry_after_ms: err.retry_after().map(|after| after.as_millis() as u64),
+    })
 }
-fn error_into_message(err: Error, reqid: Vec<u8>) -> Message {
-    Message::Text(
-        ckeylock_core::response::ErrorResponse {
-            message: err.to_string(),
-            reqid,
+fn error_message(code: ErrorCode, message: String, reqid: Vec<u8>) -> Message {
+    error_frame(ckeylock_core::response::ErrorResponse {
+        message,
+        code,
+        reqid,
+        operation: String::new(),
+        retry_after_ms: None,
+    })
+}
+fn error_frame(response: ckeylock_core::response::ErrorResponse) -> Message {
+    match response.to_json() {
+        Ok(json) => Message::Text(json.into()),
+        Err(e) => {
+            error!("Failed to serialize error response: {:?}", e);
+            Message::Text(FALLBACK_ERROR_FRAME.into())
         }
-        .to_string()
-        .into(),
-    )
+    }
+}
+fn text_or_fallback(json: Result<String, serde_json::Error>, reqid: Vec<u8>) -> Message {
+    match json {
+        Ok(json) => Message::Text(json.into()),
+        Err(e) => {
+            error!("Failed to serialize response: {:?}", e);
+            error_message(
+                ErrorCode::Internal,
+                format!("Failed to serialize response: {}", e),
+                reqid,
+            )
+        }
+    }
+}
+
+/// Resolves `bind` and listens on every address it yields, so a hostname such as
+/// `localhost` that resolves to both an IPv4 and an IPv6 address is served on
+/// both. Addresses that fail to bind are skipped with a warning as long as one
+/// succeeds. A wildcard IPv6 bind like `[::]:5830` keeps the OS default for
+/// dual-stack sockets; on Linux that also accepts IPv4 clients unless
+/// `net.ipv6.bindv6only` is set.
+async fn bind_listeners(bind: &str) -> Result<Vec<TcpListener>, WsServerError> {
+    let invalid = || WsServerError::InvalidBind(bind.to_string());
+    let (host, port) = bind.rsplit_once(':').ok_or_else(invalid)?;
+    if host.is_empty() || port.parse::<u16>().is_err() {
+        return Err(invalid());
+    }
+
+    let mut addrs: Vec<SocketAddr> = tokio::net::lookup_host(bind)
+        .await
+        .map_err(|source| WsServerError::Resolve {
+            bind: bind.to_string(),
+            source,
+        })?
+        .collect();
+    addrs.sort();
+    addrs.dedup();
+    let mut listeners = Vec::new();
+    let mut last_error = None;
+    for addr in addrs {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                info!("Listening on {}", addr);
+                listeners.push(listener);
+            }
+            Err(e) => {
+                warn!("Failed to bind {}: {}", addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    if listeners.is_empty() {
+        return Err(match last_error {
+            Some(source) => WsServerError::Bind {
+                bind: bind.to_string(),
+                source,
+            },
+            None => WsServerError::Resolve {
+                bind: bind.to_string(),
+                source: std::io::Error::new(std::io::ErrorKind::NotFound, "no addresses found"),
+            },
+        });
+    }
+    Ok(listeners)
+}
+
+/// The [`ckeylock_core::CONFIG_HEADER`] value sent with every handshake.
+fn connection_config(options: &ServerOptions) -> Option<HeaderValue> {
+    let config = ckeylock_core::ConnectionConfig {
+        protocol_version: ckeylock_core::PROTOCOL_VERSION,
+        max_message_size: ws_config(options).max_message_size,
+        compression: true,
+        signing_required: options.signing_secret.is_some(),
+        features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+    };
+    match serde_json::to_string(&config).map(HeaderValue::try_from) {
+        Ok(Ok(value)) => Some(value),
+        _ => {
+            error!("Failed to encode the connection config header");
+            None
+        }
+    }
+}
+
+fn ws_config(options: &ServerOptions) -> WebSocketConfig {
+    let config = WebSocketConfig::default();
+    match options.max_message_size {
+        Some(max) => config.max_message_size(Some(max)).max_frame_size(Some(max)),
+        None => config,
+    }
+}
+
+fn configure_stream(stream: &TcpStream, options: &ServerOptions) {
+    if let Err(e) = stream.set_nodelay(options.no_delay.unwrap_or(true)) {
+        warn!("Failed to set TCP_NODELAY: {}", e);
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum WsServerError {
     #[error("Unauthorized")]
     Unauthorized,
+    #[error("Invalid bind address {0:?}, expected host:port such as 127.0.0.1:5830 or [::1]:5830")]
+    InvalidBind(String),
+    #[error("Failed to resolve bind address {bind:?}: {source}")]
+    Resolve {
+        bind: String,
+        source: std::io::Error,
+    },
+    #[error("Failed to bind {bind:?}: {source}")]
+    Bind {
+        bind: String,
+        source: std::io::Error,
+    },
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{AES, hash};
+    use crate::storage::{Storage, StorageOptions};
+    use ckeylock_core::response::ServerMessage;
+    use ckeylock_core::{RequestWrapper, Response as CkResponse};
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+
+    type Client = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+    async fn spawn_server(port: u16, options: ServerOptions) -> String {
+        spawn_server_on(format!("127.0.0.1:{}", port), port, options).await
+    }
+
+    async fn spawn_server_on(bind: String, port: u16, options: ServerOptions) -> String {
+        let path = std::env::temp_dir().join(format!("ckeylock-ws-test-{}.bin", port));
+        let _ = std::fs::remove_file(&path);
+        let storage =
+            Storage::new(&path, AES::new(&hash(b"test")), StorageOptions::default()).unwrap();
+        let executor = Executor::new(storage).await;
+        tokio::spawn({
+            let bind = bind.clone();
+            async move {
+                WsServer::serve_until(&bind, None, executor, options, std::future::pending()).await
+            }
+        });
+        for _ in 0..50 {
+            if tokio::net::TcpStream::connect(&bind).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        format!("ws://{}", bind)
+    }
+
+    async fn request(client: &mut Client, req: ckeylock_core::Request) -> CkResponse {
+        match send_raw(client, RequestWrapper::new(req).to_json().unwrap()).await {
+            ServerMessage::Response(response) => response,
+            ServerMessage::Error(err) => panic!("Unexpected error: {:?}", err),
+        }
+    }
+
+    async fn send_raw(client: &mut Client, json: String) -> ServerMessage {
+        client.send(Message::Text(json.into())).await.unwrap();
+        match client.next().await.unwrap().unwrap() {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_signing() {
+        let url = spawn_server(
+            58302,
+            ServerOptions {
+                signing_secret: Some(b"secret".to_vec()),
+                ..Default::default()
+            },
+        )
+        .await;
+        let (mut client, _) = connect_async(url).await.unwrap();
+        let echo = ckeylock_core::Request::Echo {
+            payload: b"hello".to_vec(),
+        };
+
+        let signed = RequestWrapper::new(echo.clone()).signed(b"secret").unwrap();
+        match send_raw(&mut client, signed.to_json().unwrap()).await {
+            ServerMessage::Response(response) => assert!(response.verify(b"secret")),
+            ServerMessage::Error(err) => panic!("Unexpected error: {:?}", err),
+        }
+
+        let tampered = signed.to_json().unwrap().replace("104", "105");
+        match send_raw(&mut client, tampered).await {
+            ServerMessage::Error(err) => assert_eq!(err.code, ErrorCode::Unauthorized),
+            other => panic!("Expected error, got {:?}", other),
+        }
+
+        let unsigned = RequestWrapper::new(echo).to_json().unwrap();
+        match send_raw(&mut client, unsigned).await {
+            ServerMessage::Error(err) => assert_eq!(err.code, ErrorCode::Unauthorized),
+            other => panic!("Expected error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drops_stalled_handshake() {
+        use tokio::io::AsyncReadExt;
+
+        let url = spawn_server(
+            58303,
+            ServerOptions {
+                handshake_timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+        )
+        .await;
+        let mut stream = TcpStream::connect(url.trim_start_matches("ws://"))
+            .await
+            .unwrap();
+        let mut buf = [0; 16];
+        let read = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf))
+            .await
+            .expect("server kept the stalled connection open");
+        assert_eq!(read.unwrap_or(0), 0);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_message_is_answered() {
+        let url = spawn_server(58304, ServerOptions::default()).await;
+        let (mut client, _) = connect_async(&url).await.unwrap();
+        client
+            .send(Message::Binary(vec![1, 2, 3].into()))
+            .await
+            .unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(2), client.next())
+            .await
+            .expect("server ignored the binary frame")
+            .unwrap()
+            .unwrap();
+        match reply {
+            Message::Text(text) => match serde_json::from_str(&text).unwrap() {
+                ServerMessage::Error(err) => {
+                    assert_eq!(err.code, ErrorCode::BadRequest);
+                    assert!(err.reqid.is_empty());
+                }
+                other => panic!("Expected error, got {:?}", other),
+            },
+            other => panic!("Unexpected message: {:?}", other),
+        }
+        request(&mut client, ckeylock_core::Request::Count).await;
+
+        let url = spawn_server(
+            58305,
+            ServerOptions {
+                unsupported_message: UnsupportedMessage::Close,
+                ..Default::default()
+            },
+        )
+        .await;
+        let (mut client, _) = connect_async(&url).await.unwrap();
+        client
+            .send(Message::Binary(vec![1, 2, 3].into()))
+            .await
+            .unwrap();
+        match client.next().await.unwrap().unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Unsupported),
+            other => panic!("Expected close frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_names_operation() {
+        let url = spawn_server(58308, ServerOptions::default()).await;
+        let (mut client, _) = connect_async(&url).await.unwrap();
+        let json = RequestWrapper::new(ckeylock_core::Request::GetRange {
+            key: b"key".to_vec(),
+            start: 4,
+            end: 1,
+        })
+        .to_json()
+        .unwrap();
+        match send_raw(&mut client, json).await {
+            ServerMessage::Error(err) => {
+                assert_eq!(err.code, ErrorCode::BadRequest);
+                assert_eq!(err.operation, "get_range");
+            }
+            other => panic!("Expected error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streamed_list() {
+        let url = spawn_server(58310, ServerOptions::default()).await;
+        let (mut client, _) = connect_async(&url).await.unwrap();
+        let total = STREAM_FRAME_KEYS * 2 + 500;
+        let entries = (0..total)
+            .map(|i| (format!("key-{:05}", i).into_bytes(), b"v".to_vec()))
+            .collect();
+        request(
+            &mut client,
+            ckeylock_core::Request::ReplaceAll {
+                entries,
+                validate_only: false,
+            },
+        )
+        .await;
+
+        let buffered = request(&mut client, ckeylock_core::Request::List).await;
+        assert!(!buffered.more());
+        match buffered.data() {
+            Some(ResponseData::ListResponse { keys, .. }) => assert_eq!(keys.len(), total),
+            other => panic!("Expected list response, got {:?}", other),
+        }
+
+        let json = RequestWrapper::new(ckeylock_core::Request::List)
+            .streamed()
+            .to_json()
+            .unwrap();
+        let mut frames = vec![match send_raw(&mut client, json).await {
+            ServerMessage::Response(response) => response,
+            other => panic!("Expected response, got {:?}", other),
+        }];
+        while frames.last().unwrap().more() {
+            match client.next().await.unwrap().unwrap() {
+                Message::Text(text) => frames.push(serde_json::from_str(&text).unwrap()),
+                other => panic!("Unexpected message: {:?}", other),
+            }
+        }
+        assert_eq!(frames.len(), 3);
+        let mut keys = Vec::new();
+        for frame in &frames {
+            assert_eq!(frame.reqid(), frames[0].reqid());
+            match frame.data() {
+                Some(ResponseData::ListResponse {
+                    keys: chunk,
+                    total_estimate,
+                    ..
+                }) => {
+                    assert!(chunk.len() <= STREAM_FRAME_KEYS);
+                    assert_eq!(*total_estimate, total);
+                    keys.extend(chunk.iter().cloned());
+                }
+                other => panic!("Expected list response, got {:?}", other),
+            }
+        }
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), total);
+
+        let small = RequestWrapper::new(ckeylock_core::Request::Count)
+            .streamed()
+            .to_json()
+            .unwrap();
+        match send_raw(&mut client, small).await {
+            ServerMessage::Response(response) => assert!(!response.more()),
+            other => panic!("Expected response, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_named_stores_are_independent() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest as _;
+
+        let store = |name: &str| {
+            let path = std::env::temp_dir().join(format!("ckeylock-ws-test-store-{}.bin", name));
+            let _ = std::fs::remove_file(&path);
+            let options = StorageOptions {
+                autosync_interval: Some(Duration::from_millis(20)),
+                ..Default::default()
+            };
+            let storage = Storage::new(&path, AES::new(&hash(name.as_bytes())), options).unwrap();
+            (path, storage)
+        };
+        let (_, default) = store("default");
+        let (path_a, a) = store("a");
+        let (path_b, b) = store("b");
+        let stores = Stores::new(Executor::new(default).await)
+            .with_store("a", Executor::new(a).await)
+            .with_store("b", Executor::new(b).await);
+        let bind = "127.0.0.1:58309";
+        tokio::spawn(WsServer::serve_until(
+            bind,
+            None,
+            stores,
+            ServerOptions::default(),
+            std::future::pending(),
+        ));
+        for _ in 0..50 {
+            if TcpStream::connect(bind).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let connect = |store: Option<&str>| {
+            let mut request = format!("ws://{}", bind).into_client_request().unwrap();
+            if let Some(store) = store {
+                request
+                    .headers_mut()
+                    .insert(ckeylock_core::STORE_HEADER, store.parse().unwrap());
+            }
+            connect_async(request)
+        };
+        let get = |key: &[u8]| ckeylock_core::Request::Get {
+            key: key.to_vec(),
+            bypass_cache: false,
+        };
+        let value = |response: CkResponse| match response.data() {
+            Some(ckeylock_core::ResponseData::GetResponse { value }) => value.clone(),
+            other => panic!("Unexpected response data: {:?}", other),
+        };
+
+        for name in ["a", "b"] {
+            let (mut client, _) = connect(Some(name)).await.unwrap();
+            let set = ckeylock_core::Request::Set {
+                key: b"key".to_vec(),
+                value: name.as_bytes().to_vec(),
+            };
+            request(&mut client, set).await;
+        }
+        for name in ["a", "b"] {
+            let (mut client, _) = connect(Some(name)).await.unwrap();
+            let stored = value(request(&mut client, get(b"key")).await);
+            assert_eq!(stored, Some(name.as_bytes().to_vec()));
+        }
+        let (mut client, _) = connect(None).await.unwrap();
+        assert_eq!(value(request(&mut client, get(b"key")).await), None);
+        match connect(Some("missing")).await {
+            Err(WsError::Http(response)) => assert_eq!(response.status(), StatusCode::NOT_FOUND),
+            other => panic!("Expected a rejected handshake, got {:?}", other.map(|_| ())),
+        }
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        for (name, path) in [("a", &path_a), ("b", &path_b)] {
+            let reloaded = Storage::new(
+                path,
+                AES::new(&hash(name.as_bytes())),
+                StorageOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(reloaded.count().unwrap(), 1);
+            assert_eq!(
+                reloaded.get_uncached(b"key".to_vec()).unwrap(),
+                Some(name.as_bytes().to_vec())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_loopback() {
+        let url = spawn_server_on("[::1]:58307".to_string(), 58307, ServerOptions::default()).await;
+        let (mut client, _) = connect_async(&url).await.unwrap();
+        let response = request(&mut client, ckeylock_core::Request::Count).await;
+        assert!(matches!(
+            response.data(),
+            Some(ckeylock_core::ResponseData::CountResponse { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_no_delay_applied() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        for (no_delay, expected) in [(None, true), (Some(false), false)] {
+            let _client = TcpStream::connect(addr).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            configure_stream(
+                &stream,
+                &ServerOptions {
+                    no_delay,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(stream.nodelay().unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_errors() {
+        for bind in ["localhost", "127.0.0.1:http", ":5830", "[::1]:99999"] {
+            assert!(matches!(
+                bind_listeners(bind).await,
+                Err(WsServerError::InvalidBind(_))
+            ));
+        }
+        assert!(matches!(
+            bind_listeners("no-such-host.invalid:5830").await,
+            Err(WsServerError::Resolve { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_traffic_is_counted() {
+        let url = spawn_server(58306, ServerOptions::default()).await;
+        let (mut client, _) = connect_async(&url).await.unwrap();
+        let mut received = 0;
+        let mut sent = 0;
+        for payload in [vec![1; 10], vec![2; 1000]] {
+            let json = RequestWrapper::new(ckeylock_core::Request::Echo { payload })
+                .to_json()
+                .unwrap();
+            received += json.len() as u64;
+            let reply = send_raw(&mut client, json).await;
+            sent += serde_json::to_string(&reply).unwrap().len() as u64;
+        }
+
+        let json = RequestWrapper::new(ckeylock_core::Request::Stats)
+            .to_json()
+            .unwrap();
+        received += json.len() as u64;
+        match send_raw(&mut client, json).await {
+            ServerMessage::Response(response) => match response.data() {
+                Some(ckeylock_core::ResponseData::StatsResponse { stats }) => {
+                    assert_eq!(stats.bytes_received, received);
+                    assert_eq!(stats.bytes_sent, sent);
+                }
+                other => panic!("Unexpected response data: {:?}", other),
+            },
+            ServerMessage::Error(err) => panic!("Unexpected error: {:?}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_closes_after_max_requests() {
+        let url = spawn_server(
+            58301,
+            ServerOptions {
+                max_requests: Some(2),
+                ..Default::default()
+            },
+        )
+        .await;
+        let (mut client, _) = connect_async(url).await.unwrap();
+
+        request(&mut client, ckeylock_core::Request::Count).await;
+        request(&mut client, ckeylock_core::Request::Count).await;
+
+        match client.next().await.unwrap().unwrap() {
+            Message::Close(Some(frame)) => {
+                assert_eq!(
+                    u16::from(frame.code),
+                    ckeylock_core::close::MAX_REQUESTS_REACHED
+                );
+            }
+            other => panic!("Expected close frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_answers_requests_past_the_limit() {
+        let url = spawn_server(
+            58319,
+            ServerOptions {
+                max_requests: Some(1),
+                ..Default::default()
+            },
+        )
+        .await;
+        let (mut client, _) = connect_async(url).await.unwrap();
+        // The last allowed request is slow, so the next one arrives before
+        // the connection closes.
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..20_000u32)
+            .map(|i| (i.to_be_bytes().to_vec(), vec![0; 16]))
+            .collect();
+        let last = RequestWrapper::new(ckeylock_core::Request::ReplaceAll {
+            entries,
+            validate_only: false,
+        });
+        let over = RequestWrapper::new(ckeylock_core::Request::Count);
+        for wrapper in [&last, &over] {
+            let json = wrapper.to_json().unwrap();
+            client.send(Message::Text(json.into())).await.unwrap();
+        }
+
+        let mut replies = Vec::new();
+        loop {
+            match client.next().await.unwrap().unwrap() {
+                Message::Text(text) => {
+                    replies.push(serde_json::from_str::<ServerMessage>(&text).unwrap())
+                }
+                Message::Close(_) => break,
+                other => panic!("Unexpected message: {:?}", other),
+            }
+        }
+        assert_eq!(replies.len(), 2);
+        assert!(replies.iter().any(|reply| matches!(
+            reply,
+            ServerMessage::Response(response) if response.reqid() == last.id()
+        )));
+        assert!(replies.iter().any(|reply| matches!(
+            reply,
+            ServerMessage::Error(err) if err.reqid == over.id()
+                && err.code == ErrorCode::RequestLimitReached
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_drops_clients_that_stop_answering_pings() {
+        let url = spawn_server(
+            58315,
+            ServerOptions {
+                ping_interval: Some(Duration::from_millis(50)),
+                max_missed_pongs: Some(2),
+                ..Default::default()
+            },
+        )
+        .await;
+        let (mut client, _) = connect_async(url).await.unwrap();
+
+        // Not polling the stream means the pings it receives go unanswered.
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let mut pings = 0;
+        let ended = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                match client.next().await {
+                    Some(Ok(Message::Ping(_))) => pings += 1,
+                    Some(Ok(other)) => panic!("Unexpected message: {:?}", other),
+                    Some(Err(_)) | None => break,
+                }
+            }
+        })
+        .await;
+        assert!(ended.is_ok(), "connection was not dropped");
+        assert_eq!(pings, 2);
+    }
+
+    #[tokio::test]
+    async fn test_answered_pings_keep_connection_open() {
+        let url = spawn_server(
+            58316,
+            ServerOptions {
+                ping_interval: Some(Duration::from_millis(50)),
+                max_missed_pongs: Some(2),
+                ..Default::default()
+            },
+        )
+        .await;
+        let (mut client, _) = connect_async(url).await.unwrap();
+
+        // Polling answers each ping with a pong.
+        let mut pings = 0;
+        let _ = tokio::time::timeout(Duration::from_millis(400), async {
+            while let Some(Ok(Message::Ping(_))) = client.next().await {
+                pings += 1;
+            }
+        })
+        .await;
+        assert!(pings > 2);
+        request(&mut client, ckeylock_core::Request::Count).await;
+    }
+
+    #[tokio::test]
+    async fn test_rejects_duplicate_request_ids() {
+        let url = spawn_server(58312, ServerOptions::default()).await;
+        let (mut client, _) = connect_async(&url).await.unwrap();
+        let id = serde_json::json!([7, 7, 7]);
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..20_000u32)
+            .map(|i| (i.to_be_bytes().to_vec(), vec![0; 16]))
+            .collect();
+        let slow = serde_json::json!({ "req": { "ReplaceAll": { "entries": entries } }, "id": id });
+        let fast = serde_json::json!({ "req": "Count", "id": id });
+        client
+            .send(Message::Text(slow.to_string().into()))
+            .await
+            .unwrap();
+        client
+            .send(Message::Text(fast.to_string().into()))
+            .await
+            .unwrap();
+
+        let mut replies = Vec::new();
+        for _ in 0..2 {
+            match client.next().await.unwrap().unwrap() {
+                Message::Text(text) => {
+                    replies.push(serde_json::from_str::<ServerMessage>(&text).unwrap())
+                }
+                other => panic!("Unexpected message: {:?}", other),
+            }
+        }
+        assert!(replies.iter().any(|reply| matches!(
+            reply,
+            ServerMessage::Response(response) if matches!(
+                response.data(),
+                Some(ResponseData::ReplaceAllResponse { loaded: 20_000 })
+            )
+        )));
+        assert!(replies.iter().any(|reply| matches!(
+            reply,
+            ServerMessage::Error(err) if err.code == ErrorCode::BadRequest
+                && err.operation == "count"
+                && err.message.contains("Duplicate request id")
+        )));
+
+        match send_raw(&mut client, fast.to_string()).await {
+            ServerMessage::Response(response) => assert!(matches!(
+                response.data(),
+                Some(ResponseData::CountResponse { count: 20_000 })
+            )),
+            other => panic!("Expected count response, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_binary_value_frames() {
+        let url = spawn_server(58313, ServerOptions::default()).await;
+        let (mut client, _) = connect_async(&url).await.unwrap();
+        let value: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let set = |key: &[u8]| {
+            let mut request = RequestWrapper::new(ckeylock_core::Request::Set {
+                key: key.to_vec(),
+                value: value.clone(),
+            })
+            .with_binary_value();
+            let value = request.take_value().unwrap();
+            let frame = ckeylock_core::binary::encode_frame(request.id(), &value).unwrap();
+            (request.to_json().unwrap(), Message::Binary(frame.into()))
+        };
+
+        let (json, frame) = set(b"after");
+        client.send(Message::Text(json.into())).await.unwrap();
+        client.send(frame).await.unwrap();
+        let (json, frame) = set(b"before");
+        client.send(frame).await.unwrap();
+        client.send(Message::Text(json.into())).await.unwrap();
+        for _ in 0..2 {
+            match client.next().await.unwrap().unwrap() {
+                Message::Text(text) => assert!(matches!(
+                    serde_json::from_str(&text).unwrap(),
+                    ServerMessage::Response(response)
+                        if matches!(response.data(), Some(ResponseData::SetResponse { .. }))
+                )),
+                other => panic!("Unexpected message: {:?}", other),
+            }
+        }
+
+        for key in [b"after".as_slice(), b"before"] {
+            let get = ckeylock_core::Request::Get {
+                key: key.to_vec(),
+                bypass_cache: false,
+            };
+            match request(&mut client, get).await.data() {
+                Some(ResponseData::GetResponse {
+                    value: Some(stored),
+                }) => {
+                    assert_eq!(stored, &value)
+                }
+                other => panic!("Unexpected response: {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unpaired_halves_expire() {
+        let options = ServerOptions {
+            unpaired_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let url = spawn_server(58320, options).await;
+        let (mut client, _) = connect_async(&url).await.unwrap();
+        let mut set = RequestWrapper::new(ckeylock_core::Request::Set {
+            key: b"key".to_vec(),
+            value: b"new".to_vec(),
+        })
+        .with_binary_value();
+        let value = set.take_value().unwrap();
+        let frame = |value: &[u8]| {
+            let frame = ckeylock_core::binary::encode_frame(set.id(), value).unwrap();
+            Message::Binary(frame.into())
+        };
+
+        // An orphaned value is dropped, so a later request with its id waits
+        // for a value of its own.
+        client.send(frame(b"old")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let json = set.to_json().unwrap();
+        client
+            .send(Message::Text(json.clone().into()))
+            .await
+            .unwrap();
+        client.send(frame(&value)).await.unwrap();
+        match client.next().await.unwrap().unwrap() {
+            Message::Text(text) => assert!(matches!(
+                serde_json::from_str(&text).unwrap(),
+                ServerMessage::Response(response)
+                    if matches!(response.data(), Some(ResponseData::SetResponse { .. }))
+            )),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+        let get = ckeylock_core::Request::Get {
+            key: b"key".to_vec(),
+            bypass_cache: false,
+        };
+        match request(&mut client, get).await.data() {
+            Some(ResponseData::GetResponse {
+                value: Some(stored),
+            }) => {
+                assert_eq!(stored, b"new")
+            }
+            other => panic!("Unexpected response: {:?}", other),
+        }
+
+        // An orphaned request is answered with an error.
+        client.send(Message::Text(json.into())).await.unwrap();
+        match tokio::time::timeout(Duration::from_secs(1), client.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => match serde_json::from_str(&text).unwrap() {
+                ServerMessage::Error(err) => {
+                    assert_eq!(err.code, ErrorCode::BadRequest);
+                    assert_eq!(err.reqid, set.id());
+                }
+                other => panic!("Expected an error, got {:?}", other),
+            },
+            other => panic!("Expected an error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unpaired_halves_capped_by_bytes() {
+        let options = ServerOptions {
+            max_unpaired_bytes: Some(1000),
+            ..Default::default()
+        };
+        let url = spawn_server(58321, options).await;
+        let (mut client, _) = connect_async(&url).await.unwrap();
+        // A lone value over the cap still waits, but nothing joins it.
+        for (id, rejected) in [(b"first", false), (b"other", true)] {
+            let frame = ckeylock_core::binary::encode_frame(id, &[0; 2000]).unwrap();
+            client.send(Message::Binary(frame.into())).await.unwrap();
+            let answer = tokio::time::timeout(Duration::from_millis(200), client.next()).await;
+            match answer {
+                Ok(Some(Ok(Message::Text(text)))) if rejected => {
+                    match serde_json::from_str(&text).unwrap() {
+                        ServerMessage::Error(err) => assert_eq!(err.reqid, id),
+                        other => panic!("Expected an error, got {:?}", other),
+                    }
+                }
+                Err(_) if !rejected => {}
+                other => panic!("Unexpected answer: {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_then_forces_close() {
+        // Storage that never loads holds every request in flight.
+        let (_release, released) = tokio::sync::oneshot::channel::<()>();
+        let load = async move {
+            released.await.ok()?;
+            Some(Storage::volatile(Default::default()))
+        };
+        let executor = Executor::loading(load, Default::default()).await;
+        let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let bind = "127.0.0.1:58314";
+        let options = ServerOptions {
+            shutdown_grace: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+        let server = tokio::spawn(WsServer::serve_until(
+            bind,
+            None,
+            executor,
+            options,
+            async move {
+                let _ = shutdown_rx.await;
+            },
+        ));
+        let url = format!("ws://{}", bind);
+        let mut busy = None;
+        for _ in 0..50 {
+            if let Ok((client, _)) = connect_async(&url).await {
+                busy = Some(client);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut busy = busy.expect("server did not start");
+        let (mut idle, _) = connect_async(&url).await.unwrap();
+        let get = RequestWrapper::new(ckeylock_core::Request::Get {
+            key: b"key".to_vec(),
+            bypass_cache: false,
+        });
+        busy.send(Message::Text(get.to_json().unwrap().into()))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let started = Instant::now();
+        shutdown.send(()).unwrap();
+        // A connection with nothing in flight is closed right away.
+        match tokio::time::timeout(Duration::from_millis(200), idle.next()).await {
+            Ok(Some(Ok(Message::Close(Some(frame))))) => assert_eq!(frame.code, CloseCode::Away),
+            other => panic!("Expected a close frame, got {:?}", other),
+        }
+        assert!(tokio::net::TcpStream::connect(bind).await.is_err());
+
+        let report = server.await.unwrap().unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert_eq!(
+            report,
+            DrainReport {
+                connections: 1,
+                requests: 1,
+                forced: true,
+            }
+        );
+        assert!(!matches!(busy.next().await, Some(Ok(Message::Text(_)))));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_oversized_frames() {
+        let url = spawn_server(
+            58311,
+            ServerOptions {
+                max_message_size: Some(1024),
+                ..Default::default()
+            },
+        )
+        .await;
+        let (mut client, _) = connect_async(&url).await.unwrap();
+        let oversized = RequestWrapper::new(ckeylock_core::Request::Echo {
+            payload: vec![0; 2048],
+        })
+        .to_json()
+        .unwrap();
+        client.send(Message::Text(oversized.into())).await.unwrap();
+        match client.next().await.unwrap().unwrap() {
+            Message::Close(Some(frame)) => {
+                assert_eq!(
+                    u16::from(frame.code),
+                    ckeylock_core::close::MESSAGE_TOO_LARGE
+                );
+                assert!(frame.reason.contains("1024"), "{}", frame.reason);
+            }
+            other => panic!("Expected close frame, got {:?}", other),
+        }
+
+        let (mut client, _) = connect_async(&url).await.unwrap();
+        let response = request(&mut client, ckeylock_core::Request::Stats).await;
+        match response.data() {
+            Some(ResponseData::StatsResponse { stats }) => assert_eq!(stats.rejected_frames, 1),
+            other => panic!("Expected stats response, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_advertises_connection_config() {
+        let url = spawn_server(
+            58317,
+            ServerOptions {
+                max_message_size: Some(1024),
+                signing_secret: Some(b"secret".to_vec()),
+                ..Default::default()
+            },
+        )
+        .await;
+        let (_client, response) = connect_async(&url).await.unwrap();
+        let header = response.headers()[ckeylock_core::CONFIG_HEADER]
+            .to_str()
+            .unwrap();
+        let config: ckeylock_core::ConnectionConfig = serde_json::from_str(header).unwrap();
+        assert_eq!(config.protocol_version, ckeylock_core::PROTOCOL_VERSION);
+        assert_eq!(config.max_message_size, Some(1024));
+        assert!(config.signing_required);
+        assert!(config.features.iter().any(|feature| feature == "streaming"));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_oversized_handshake_headers() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let url = spawn_server(
+            58318,
+            ServerOptions {
+                max_handshake_size: Some(1024),
+                ..Default::default()
+            },
+        )
+        .await;
+        let mut stream = TcpStream::connect(url.trim_start_matches("ws://"))
+            .await
+            .unwrap();
+        let request = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nAuthorization: {}\r\n",
+            "a".repeat(4096)
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut response))
+            .await
+            .expect("server kept reading the oversized handshake")
+            .unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 431"));
+
+        // Handshakes within the limit still go through.
+        connect_async(&url).await.unwrap();
+    }
+
+    struct Unserializable;
+
+    impl serde::Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("unserializable"))
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for Captured {
+        type Writer = Captured;
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn test_slow_request_is_logged() {
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(captured.clone())
+            .with_ansi(false)
+            .finish();
+        let slow = ckeylock_core::Request::Get {
+            key: vec![0xab; 32],
+            bypass_cache: false,
+        };
+        let fast = ckeylock_core::Request::Count;
+        tracing::subscriber::with_default(subscriber, || {
+            log_slow_request(
+                fast.name(),
+                fast.key(),
+                Duration::from_millis(1),
+                Duration::from_millis(10),
+            );
+            log_slow_request(
+                slow.name(),
+                slow.key(),
+                Duration::from_millis(50),
+                Duration::from_millis(10),
+            );
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(output.lines().count(), 1);
+        assert!(output.contains("WARN"));
+        assert!(output.contains(&format!("Slow request: get key={}...", "ab".repeat(16))));
+        assert!(output.contains("50ms"));
+    }
+
+    #[test]
+    fn test_fallback_error_frame_is_valid() {
+        let err: ckeylock_core::response::ErrorResponse =
+            serde_json::from_str(FALLBACK_ERROR_FRAME).unwrap();
+        assert_eq!(err.code, ErrorCode::Internal);
+    }
+
+    #[test]
+    fn test_serialization_failure_falls_back_to_error_frame() {
+        let message = text_or_fallback(serde_json::to_string(&Unserializable), vec![1, 2, 3]);
+        let Message::Text(text) = message else {
+            panic!("Expected text frame");
+        };
+        let err: ckeylock_core::response::ErrorResponse = serde_json::from_str(&text).unwrap();
+        assert_eq!(err.reqid, vec![1, 2, 3]);
+        assert_eq!(err.code, ErrorCode::Internal);
+        assert!(err.message.contains("unserializable"));
+    }
+}